@@ -0,0 +1,691 @@
+//! Operator subcommands (`hoister check`, `hoister update <service>`, …).
+//!
+//! Each subcommand runs once against the engine the agent manages and exits with
+//! one of the `EXIT_*` codes below, so hoister can be driven from cron jobs
+//! and Ansible. Without a subcommand the binary runs the agent as before.
+
+use crate::backup::{self, BackupEntry};
+use crate::config::{self, Config, DISPATCHER_NAMES};
+use crate::docker::{
+    ContainerID, DockerHandler, PINNED_LABEL, UpdateOutcome, get_service_identifier,
+};
+use crate::engines::{self, Engine};
+use crate::notifications::{self, DeploymentResultHandler, start_notification_handler};
+use crate::outbox::Outbox;
+use crate::{DEFAULT_CONFIG_PATH, HoisterError};
+use bollard::query_parameters::InspectContainerOptions;
+use clap::{Parser, Subcommand};
+use hoister_shared::{CreateDeployment, ProjectName, ServiceName};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+
+pub(crate) const EXIT_OK: i32 = 0;
+/// Docker, registry or network error.
+pub(crate) const EXIT_FAILURE: i32 = 1;
+// 2 is clap's exit code for usage errors.
+/// The configuration could not be loaded, or lacks what the command needs.
+pub(crate) const EXIT_CONFIG: i32 = 3;
+/// No tracked container runs the requested service.
+pub(crate) const EXIT_NOT_FOUND: i32 = 4;
+/// The new container failed its health check and the old one was restored.
+pub(crate) const EXIT_ROLLED_BACK: i32 = 5;
+/// `check` found at least one service that would be updated. Same convention
+/// as `dnf check-update`.
+pub(crate) const EXIT_UPDATES_AVAILABLE: i32 = 100;
+
+/// Hoister agent — periodically checks for newer container images and updates
+/// the running containers, rolling back on failure.
+#[derive(Parser, Debug)]
+#[command(name = "hoister", version, about)]
+pub(crate) struct Cli {
+    /// Path to the TOML configuration file. Optional: configuration can also be
+    /// supplied entirely through HOISTER_* environment variables.
+    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH, global = true)]
+    pub(crate) config: PathBuf,
+
+    /// Hostname of the engine to act on, required when `[[engines]]` lists
+    /// more than one.
+    #[arg(long, global = true)]
+    pub(crate) engine: Option<String>,
+
+    /// Run a single operator command instead of the agent.
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// List which services would update, to which digest, and why. Pulls
+    /// nothing. Exits 100 when at least one service would update.
+    Check,
+    /// Update one service now, even if it is pinned by a rollback.
+    Update {
+        /// Service name (compose service or `hoister.identifier` label).
+        service: String,
+    },
+    /// Roll a service back to the image it ran before its last update.
+    Rollback {
+        service: String,
+        /// Digest (`sha256:…`) or `repo@sha256:…` reference to roll back to.
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Show the tracked containers and their state.
+    Status,
+    /// Configuration commands.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Notification commands.
+    Notify {
+        #[command(subcommand)]
+        command: NotifyCommand,
+    },
+    /// Archived backups of service mounts.
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ConfigCommand {
+    /// Load the configuration and list every problem with where it came from.
+    /// Exits 3 if there is at least one error; warnings alone exit 0.
+    Validate,
+    /// Print the JSON Schema of the config file, for editor completion.
+    Schema,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum BackupCommand {
+    /// List backups, oldest first.
+    List {
+        /// Only list backups of this service.
+        service: Option<String>,
+    },
+    /// Stop a service, replace the contents of its mounts with a backup and
+    /// start it again.
+    Restore {
+        service: String,
+        /// Backup ID from `backup list`. Defaults to the newest backup of the
+        /// service.
+        #[arg(long)]
+        id: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum NotifyCommand {
+    /// Send a test notification through the configured dispatchers.
+    Test {
+        /// Only send through this dispatcher.
+        #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(DISPATCHER_NAMES))]
+        only: Option<String>,
+    },
+}
+
+/// Run `command` and return the process exit code.
+pub(crate) async fn run(command: Command, engine: Option<&str>, config_path: &Path) -> i32 {
+    match command {
+        Command::Config {
+            command: ConfigCommand::Validate,
+        } => return validate_config(config_path),
+        Command::Config {
+            command: ConfigCommand::Schema,
+        } => {
+            let schema =
+                serde_json::to_string_pretty(&config::json_schema()).expect("schema serializes");
+            println!("{schema}");
+            return EXIT_OK;
+        }
+        _ => {}
+    }
+
+    let config = match config::load_config(config_path).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:?}");
+            return EXIT_CONFIG;
+        }
+    };
+
+    match command {
+        Command::Config { .. } => unreachable!("handled above"),
+        Command::Notify {
+            command: NotifyCommand::Test { only },
+        } => notify_test(config, only.as_deref()).await,
+        Command::Backup {
+            command: BackupCommand::List { service },
+        } => list_backups(&config, service.as_deref()),
+        command => with_docker(config, engine, command).await,
+    }
+}
+
+fn validate_config(config_path: &Path) -> i32 {
+    let validation = config::validate(config_path);
+    for problem in &validation.problems {
+        println!("{problem}");
+    }
+    if validation.has_errors() {
+        EXIT_CONFIG
+    } else {
+        println!("configuration is valid");
+        EXIT_OK
+    }
+}
+
+async fn notify_test(mut config: Config, only: Option<&str>) -> i32 {
+    if let Some(name) = only {
+        config.dispatcher = config.dispatcher.and_then(|d| d.only(name));
+        if config.dispatcher.is_none() {
+            eprintln!("dispatcher `{name}` is not configured");
+            return EXIT_CONFIG;
+        }
+    }
+    let Some(dispatchers) = notifications::dispatchers(&config) else {
+        eprintln!("no dispatcher configured");
+        return EXIT_CONFIG;
+    };
+    let message = CreateDeployment::test().to_message();
+    let mut code = EXIT_OK;
+    for (name, outcome) in notifications::deliver(&dispatchers, &message).await {
+        match outcome {
+            Ok(()) => println!("{name}: test notification sent"),
+            Err(e) => {
+                eprintln!("{name}: failed to send test notification: {e}");
+                code = EXIT_FAILURE;
+            }
+        }
+    }
+    code
+}
+
+/// Commands that talk to Docker. Deployment events go through the regular
+/// notification handler, so a CLI update shows up in the dashboard and chat
+/// like a scheduled one.
+async fn with_docker(config: Config, engine: Option<&str>, command: Command) -> i32 {
+    let engines = match engines::connect_all(&config).await {
+        Ok(engines) => engines,
+        Err(e) => {
+            eprintln!("could not connect to the engine: {e}");
+            return EXIT_FAILURE;
+        }
+    };
+    let Engine {
+        docker: engine,
+        hostname,
+        project,
+    } = match select_engine(engines, engine) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_CONFIG;
+        }
+    };
+    let config = Arc::new(config);
+    let http_client = match config::build_http_client(&config.controller) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{e:?}");
+            return EXIT_CONFIG;
+        }
+    };
+    let (tx, rx) = mpsc::channel(32);
+    let outbox = Outbox::for_controller(config.controller.as_ref());
+    let handler = {
+        // A one-shot command never reloads its config.
+        let (_, config) = watch::channel(Arc::clone(&config));
+        let outbox = outbox.clone();
+        tokio::spawn(async move {
+            start_notification_handler(config, rx, outbox).await;
+        })
+    };
+
+    let docker = DockerHandler::new(
+        engine,
+        DeploymentResultHandler::new(tx, hostname),
+        http_client.clone(),
+        &config,
+    );
+
+    let code = match command {
+        Command::Check => check(&docker, &project).await,
+        Command::Update { service } => update(&docker, &project, &service).await,
+        Command::Rollback { service, to } => {
+            rollback(&docker, &project, &service, to.as_deref()).await
+        }
+        Command::Status => status(&docker, &project).await,
+        Command::Backup {
+            command: BackupCommand::Restore { service, id },
+        } => restore_backup(&docker, &config, &project, &service, id.as_deref()).await,
+        Command::Config { .. }
+        | Command::Notify { .. }
+        | Command::Backup {
+            command: BackupCommand::List { .. },
+        } => unreachable!("handled in run"),
+    };
+
+    // Dropping the handler closes the event channel; wait for queued events to
+    // reach the controller and chat before exiting. Reports the controller
+    // doesn't take stay queued for the agent.
+    drop(docker);
+    let _ = handler.await;
+    if let Some(controller) = &config.controller {
+        outbox.flush(&http_client, controller).await;
+    }
+    code
+}
+
+/// The engine named by `--engine`, which may only be left out when there is
+/// a single one.
+fn select_engine(engines: Vec<Engine>, name: Option<&str>) -> Result<Engine, String> {
+    let hostnames = || {
+        engines
+            .iter()
+            .map(|e| e.hostname.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match name {
+        Some(name) => match engines.iter().position(|e| e.hostname.as_str() == name) {
+            Some(i) => Ok(engines.into_iter().nth(i).expect("position is in range")),
+            None => Err(format!(
+                "engine `{name}` is not configured; configured engines: {}",
+                hostnames()
+            )),
+        },
+        None if engines.len() == 1 => Ok(engines.into_iter().next().expect("one engine")),
+        None => Err(format!(
+            "more than one engine is configured; pick one with --engine <hostname>: {}",
+            hostnames()
+        )),
+    }
+}
+
+async fn tracked_containers(
+    docker: &DockerHandler,
+    project: &ProjectName,
+) -> Option<Vec<ContainerID>> {
+    match docker.get_containers(project).await {
+        Ok(containers) => Some(containers.into_iter().filter_map(|c| c.id).collect()),
+        Err(e) => {
+            eprintln!("failed to list containers: {e}");
+            None
+        }
+    }
+}
+
+async fn check(docker: &DockerHandler, project: &ProjectName) -> i32 {
+    let Some(containers) = tracked_containers(docker, project).await else {
+        return EXIT_FAILURE;
+    };
+    let mut updates = 0;
+    let mut failed = false;
+    for id in containers {
+        match docker.plan_update(&id).await {
+            Ok(plan) => {
+                let marker = if plan.verdict.would_update() {
+                    updates += 1;
+                    "update"
+                } else {
+                    "-"
+                };
+                println!(
+                    "{marker:<7} {:<24} {:<48} {} -> {}  ({})",
+                    plan.service.as_str(),
+                    plan.image.as_str(),
+                    short_digest(plan.current.as_deref()),
+                    short_digest(plan.available.as_deref()),
+                    plan.verdict,
+                );
+            }
+            Err(e) => {
+                failed = true;
+                println!("error   {id:<24} {e}");
+            }
+        }
+    }
+    if failed {
+        EXIT_FAILURE
+    } else if updates > 0 {
+        EXIT_UPDATES_AVAILABLE
+    } else {
+        EXIT_OK
+    }
+}
+
+async fn update(docker: &DockerHandler, project: &ProjectName, service: &str) -> i32 {
+    let Some(id) = find_service(docker, project, service).await else {
+        return EXIT_NOT_FOUND;
+    };
+    report_outcome(service, docker.update_now(project, &id).await)
+}
+
+async fn rollback(
+    docker: &DockerHandler,
+    project: &ProjectName,
+    service: &str,
+    to: Option<&str>,
+) -> i32 {
+    let Some(id) = find_service(docker, project, service).await else {
+        return EXIT_NOT_FOUND;
+    };
+    let result = docker.rollback_container(project, &id, to).await;
+    if matches!(result, Err(HoisterError::NoRollbackTarget(_))) {
+        eprintln!("{}", result.unwrap_err());
+        return EXIT_CONFIG;
+    }
+    report_outcome(service, result)
+}
+
+fn report_outcome(service: &str, result: Result<UpdateOutcome, HoisterError>) -> i32 {
+    match result {
+        Ok(UpdateOutcome::Updated) => {
+            println!("{service}: updated");
+            EXIT_OK
+        }
+        Ok(UpdateOutcome::RolledBack) => {
+            println!("{service}: new container failed its health check, rolled back");
+            EXIT_ROLLED_BACK
+        }
+        Ok(UpdateOutcome::HandedOver) => {
+            println!("{service}: agent update handed over to a self-update helper container");
+            EXIT_OK
+        }
+        Err(HoisterError::NoUpdateAvailable) => {
+            println!("{service}: already up to date");
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("{service}: {e}");
+            EXIT_FAILURE
+        }
+    }
+}
+
+async fn find_service(
+    docker: &DockerHandler,
+    project: &ProjectName,
+    service: &str,
+) -> Option<ContainerID> {
+    let found = docker
+        .find_container_by_service(project, &ServiceName::new(service))
+        .await;
+    if found.is_none() {
+        eprintln!(
+            "no tracked container for service `{service}` in project `{}`",
+            project.as_str()
+        );
+    }
+    found
+}
+
+async fn status(docker: &DockerHandler, project: &ProjectName) -> i32 {
+    let Some(containers) = tracked_containers(docker, project).await else {
+        return EXIT_FAILURE;
+    };
+    println!("project {}", project.as_str());
+    for id in containers {
+        let service = match get_service_identifier(docker.docker.as_ref(), &id).await {
+            Ok(service) => service,
+            Err(e) => {
+                eprintln!("failed to inspect {id}: {e}");
+                return EXIT_FAILURE;
+            }
+        };
+        let details = match docker
+            .docker
+            .inspect_container(&id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(details) => details,
+            Err(e) => {
+                eprintln!("failed to inspect {id}: {e}");
+                return EXIT_FAILURE;
+            }
+        };
+        let state = details.state.as_ref();
+        let run_state = state
+            .and_then(|s| s.status)
+            .map_or_else(|| "unknown".to_string(), |s| s.to_string());
+        let health = state
+            .and_then(|s| s.health.as_ref())
+            .and_then(|h| h.status)
+            .map_or_else(|| "-".to_string(), |s| s.to_string());
+        let image = details
+            .config
+            .as_ref()
+            .and_then(|c| c.image.clone())
+            .unwrap_or_default();
+        let pinned = details
+            .config
+            .as_ref()
+            .and_then(|c| c.labels.as_ref())
+            .and_then(|l| l.get(PINNED_LABEL))
+            .map(|d| format!("  pinned to {}", short_digest(Some(d))))
+            .unwrap_or_default();
+        println!(
+            "{:<24} {:<48} {:<10} {:<10} {}{pinned}",
+            service.as_str(),
+            image,
+            run_state,
+            health,
+            short_digest(details.image.as_deref()),
+        );
+    }
+    EXIT_OK
+}
+
+/// The `[backups]` directory's backups, or the exit code if there are none
+/// to be had.
+fn backups(config: &Config) -> Result<(&Path, Vec<BackupEntry>), i32> {
+    let Some(backups) = &config.backups else {
+        eprintln!("no [backups] directory configured");
+        return Err(EXIT_CONFIG);
+    };
+    match backup::list(&backups.directory) {
+        Ok(entries) => Ok((&backups.directory, entries)),
+        Err(e) => {
+            eprintln!("{e}");
+            Err(EXIT_FAILURE)
+        }
+    }
+}
+
+fn list_backups(config: &Config, service: Option<&str>) -> i32 {
+    let entries = match backups(config) {
+        Ok((_, entries)) => entries,
+        Err(code) => return code,
+    };
+    for entry in entries
+        .iter()
+        .filter(|e| service.is_none_or(|s| e.service.as_str() == s))
+    {
+        println!(
+            "{:<40} {:<24} {}  {:>9}  {}",
+            entry.id,
+            entry.service.as_str(),
+            entry.created.format("%Y-%m-%d %H:%M:%S"),
+            format_size(entry.size),
+            short_digest(entry.digest.as_deref()),
+        );
+    }
+    EXIT_OK
+}
+
+async fn restore_backup(
+    docker: &DockerHandler,
+    config: &Config,
+    project: &ProjectName,
+    service: &str,
+    id: Option<&str>,
+) -> i32 {
+    let (directory, entries) = match backups(config) {
+        Ok(backups) => backups,
+        Err(code) => return code,
+    };
+    let entry = entries.iter().rev().find(|e| {
+        e.service.as_str() == service && &e.project == project && id.is_none_or(|id| e.id == id)
+    });
+    let Some(entry) = entry else {
+        eprintln!(
+            "no backup of `{service}`{}",
+            id.map(|id| format!(" with ID {id}")).unwrap_or_default()
+        );
+        return EXIT_NOT_FOUND;
+    };
+    let Some(container) = find_service(docker, project, service).await else {
+        return EXIT_NOT_FOUND;
+    };
+    match docker.restore_backup(&container, entry, directory).await {
+        Ok(()) => {
+            println!("{service}: restored backup {}", entry.id);
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("{service}: {e}");
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    if unit == "B" {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {unit}")
+    }
+}
+
+fn short_digest(digest: Option<&str>) -> String {
+    match digest {
+        Some(digest) => {
+            let hex = digest.trim_start_matches("sha256:");
+            format!("sha256:{}", &hex[..hex.len().min(12)])
+        }
+        None => "?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands() {
+        let cli =
+            Cli::try_parse_from(["hoister", "rollback", "web", "--to", "sha256:abc"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Rollback { ref service, to: Some(ref to) }) if service == "web" && to == "sha256:abc"
+        ));
+
+        let cli = Cli::try_parse_from(["hoister", "notify", "test", "--only", "slack"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Notify { command: NotifyCommand::Test { only: Some(ref only) } }) if only == "slack"
+        ));
+
+        let cli = Cli::try_parse_from(["hoister", "--config", "/etc/hoister.toml"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.config, PathBuf::from("/etc/hoister.toml"));
+    }
+
+    #[test]
+    fn parses_engine_after_the_subcommand() {
+        let cli = Cli::try_parse_from(["hoister", "status", "--engine", "edge-1"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Status)));
+        assert_eq!(cli.engine.as_deref(), Some("edge-1"));
+    }
+
+    #[test]
+    fn selects_the_engine_by_hostname() {
+        let engines = || {
+            ["edge-1", "edge-2"]
+                .map(|hostname| Engine {
+                    docker: Arc::new(crate::runtime::fake::FakeRuntime::default()),
+                    hostname: hoister_shared::HostName::new(hostname),
+                    project: ProjectName::new("shop"),
+                })
+                .into()
+        };
+        let selected = select_engine(engines(), Some("edge-2")).unwrap();
+        assert_eq!(selected.hostname.as_str(), "edge-2");
+        assert!(select_engine(engines(), Some("edge-3")).is_err());
+        assert!(select_engine(engines(), None).is_err());
+
+        let mut single: Vec<Engine> = engines();
+        single.truncate(1);
+        assert_eq!(
+            select_engine(single, None).unwrap().hostname.as_str(),
+            "edge-1"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_dispatcher() {
+        assert!(
+            Cli::try_parse_from(["hoister", "notify", "test", "--only", "carrier-pigeon"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn notify_test_exits_with_the_delivery_outcome() {
+        use axum::http::StatusCode;
+        use figment2::providers::Format;
+
+        let status = Arc::new(std::sync::Mutex::new(StatusCode::INTERNAL_SERVER_ERROR));
+        let answer = Arc::clone(&status);
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(move || {
+                let status = *answer.lock().unwrap();
+                async move { status }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = || -> Config {
+            figment2::Figment::new()
+                .merge(figment2::providers::Toml::string(&format!(
+                    "[schedule]\ninterval = 10\n[dispatcher.webhook]\nurl = \"http://{address}/\"\n"
+                )))
+                .extract()
+                .unwrap()
+        };
+
+        assert_eq!(notify_test(config(), None).await, EXIT_FAILURE);
+        *status.lock().unwrap() = StatusCode::OK;
+        assert_eq!(notify_test(config(), Some("webhook")).await, EXIT_OK);
+    }
+
+    #[test]
+    fn short_digest_truncates() {
+        assert_eq!(
+            short_digest(Some("sha256:0123456789abcdef")),
+            "sha256:0123456789ab"
+        );
+        assert_eq!(short_digest(None), "?");
+    }
+
+    #[test]
+    fn format_size_picks_a_unit() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -1,20 +1,23 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use cron::Schedule as CronSchedule;
-use figment2::{
-    Figment,
-    providers::{Env, Format, Toml},
-};
-use hoister_shared::{HostName, ProjectName};
+use hoister_shared::{AgentId, HostName, ProjectName};
+use log::warn;
 use reqwest::Url;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+mod secrets;
+mod validate;
+
+pub(crate) use validate::{ConfigError, Severity, validate};
 
 type ChannelId = u64;
 type ChannelName = String;
 type ChatId = u64;
 type BotToken = String;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Registry {
     pub(crate) ghcr: Option<GithubRegistry>,
     pub(crate) dockerhub: Option<DockerHubRegistry>,
@@ -23,44 +26,49 @@ pub(crate) struct Registry {
     pub(crate) gcr: Option<GcrRegistry>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct GithubRegistry {
     pub(crate) username: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct DockerHubRegistry {
     pub(crate) username: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) password: String,
 }
 
 /// AWS Elastic Container Registry. Credentials are used to dynamically fetch
 /// a short-lived auth token from the ECR API via AWS Signature V4.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct EcrRegistry {
     pub(crate) access_key_id: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) secret_access_key: String,
     pub(crate) region: String,
 }
 
 /// Azure Container Registry.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct AcrRegistry {
     pub(crate) username: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) password: String,
 }
 
 /// Google Container Registry / Google Artifact Registry.
 /// Use `_json_key` as username and the service account JSON as password,
 /// or `oauth2accesstoken` as username with a short-lived access token.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct GcrRegistry {
     pub(crate) username: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) password: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Dispatcher {
     pub(crate) telegram: Option<Telegram>,
     pub(crate) discord: Option<Discord>,
@@ -78,14 +86,78 @@ pub(crate) struct Dispatcher {
     pub(crate) webhook: Option<Webhook>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Names of the `[dispatcher.*]` sections, as accepted by
+/// `hoister notify test --only`.
+#[cfg(feature = "cli")]
+pub(crate) const DISPATCHER_NAMES: &[&str] = &[
+    "telegram",
+    "discord",
+    "discord_webhook",
+    "teams",
+    "slack",
+    "gotify",
+    "email",
+    "ntfy",
+    "pushover",
+    "matrix",
+    "mattermost",
+    "rocketchat",
+    "google_chat",
+    "webhook",
+];
+
+#[cfg(feature = "cli")]
+impl Dispatcher {
+    /// A copy with every dispatcher but `name` removed. `None` if `name` is
+    /// not configured.
+    pub(crate) fn only(self, name: &str) -> Option<Dispatcher> {
+        let mut only = Dispatcher {
+            telegram: None,
+            discord: None,
+            discord_webhook: None,
+            teams: None,
+            slack: None,
+            gotify: None,
+            email: None,
+            ntfy: None,
+            pushover: None,
+            matrix: None,
+            mattermost: None,
+            rocketchat: None,
+            google_chat: None,
+            webhook: None,
+        };
+        match name {
+            "telegram" => only.telegram = Some(self.telegram?),
+            "discord" => only.discord = Some(self.discord?),
+            "discord_webhook" => only.discord_webhook = Some(self.discord_webhook?),
+            "teams" => only.teams = Some(self.teams?),
+            "slack" => only.slack = Some(self.slack?),
+            "gotify" => only.gotify = Some(self.gotify?),
+            "email" => only.email = Some(self.email?),
+            "ntfy" => only.ntfy = Some(self.ntfy?),
+            "pushover" => only.pushover = Some(self.pushover?),
+            "matrix" => only.matrix = Some(self.matrix?),
+            "mattermost" => only.mattermost = Some(self.mattermost?),
+            "rocketchat" => only.rocketchat = Some(self.rocketchat?),
+            "google_chat" => only.google_chat = Some(self.google_chat?),
+            "webhook" => only.webhook = Some(self.webhook?),
+            _ => return None,
+        }
+        Some(only)
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Telegram {
     pub(crate) chat: ChatId,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: BotToken,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Discord {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: BotToken,
     pub(crate) channel: ChannelId,
 }
@@ -94,8 +166,9 @@ pub(crate) struct Discord {
 /// (`https://discord.com/api/webhooks/{id}/{token}`) — no bot token, and the
 /// target channel is fixed when the webhook is created. `username` and
 /// `avatar_url` optionally override the webhook's default identity.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct DiscordWebhook {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
     pub(crate) username: Option<String>,
     pub(crate) avatar_url: Option<Url>,
@@ -104,45 +177,51 @@ pub(crate) struct DiscordWebhook {
 /// Microsoft Teams delivery via an incoming webhook — no app registration, and
 /// the target channel is fixed when the webhook is created. Works with both the
 /// Workflows (Power Automate) webhooks and the legacy connector webhooks.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Teams {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Slack {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
     pub(crate) channel: ChannelName,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Gotify {
     pub(crate) server: Url,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: String,
 }
 
 /// ntfy delivery to a (possibly self-hosted) ntfy server. `access_token` is
 /// only needed for reserved/protected topics.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Ntfy {
     pub(crate) server: Url,
     pub(crate) topic: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) access_token: Option<String>,
 }
 
 /// Pushover delivery. `token` is the application API token, `user` the
 /// recipient user or group key; `device` optionally targets one device.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Pushover {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: String,
     pub(crate) user: String,
     pub(crate) device: Option<String>,
 }
 
 /// Matrix delivery via a homeserver access token to a joined room.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Matrix {
     pub(crate) homeserver: Url,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) access_token: String,
     pub(crate) room_id: String,
 }
@@ -150,8 +229,9 @@ pub(crate) struct Matrix {
 /// Mattermost delivery via an incoming webhook. `channel` and `username`
 /// optionally override the webhook's defaults; a `channel` override only works
 /// if the webhook was created with "allow channel override" enabled.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Mattermost {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
     pub(crate) channel: Option<String>,
     pub(crate) username: Option<String>,
@@ -159,8 +239,9 @@ pub(crate) struct Mattermost {
 
 /// Rocket.Chat delivery via an incoming webhook. `channel` and `alias`
 /// optionally override the webhook's defaults.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct RocketChat {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
     pub(crate) channel: Option<String>,
     pub(crate) alias: Option<String>,
@@ -168,14 +249,15 @@ pub(crate) struct RocketChat {
 
 /// Google Chat delivery via an incoming webhook — the target space is fixed
 /// when the webhook is created (the URL carries the `key`/`token` pair).
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct GoogleChat {
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) webhook: Url,
 }
 
 /// Generic webhook delivery — POSTs each event to `url`. `headers` carries any
 /// auth headers and defaults to empty.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Webhook {
     pub(crate) url: Url,
     #[serde(default)]
@@ -183,54 +265,265 @@ pub(crate) struct Webhook {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct SMTP {
     pub(crate) user: String,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) password: String,
     pub(crate) server: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Email {
     pub(crate) smtp: SMTP,
     pub(crate) from: Option<String>,
     pub(crate) recipient: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Schedule {
+    /// Seconds between checks. Mutually exclusive with `cron`.
     pub(crate) interval: Option<u64>,
+    /// Cron expression with seconds, e.g. `0 */5 * * * * *`.
+    #[schemars(with = "Option<String>")]
     pub(crate) cron: Option<CronSchedule>,
 }
 
+/// Used if a schedule somehow has neither setting; validation rejects that,
+/// so this only guards against a busy loop.
+const FALLBACK_SLEEP: std::time::Duration = std::time::Duration::from_secs(300);
+
 impl Schedule {
-    pub(crate) fn sleep(&self) -> std::time::Duration {
-        let seconds = match &self.cron {
-            None => self
-                .interval
-                .expect("Either `interval` or `cron` has to be defined"),
-            Some(schedule) => {
-                let upcoming = schedule.upcoming(Utc).next().unwrap();
-                upcoming
-                    .signed_duration_since(Utc::now())
-                    .num_seconds()
-                    .max(0) as u64
-            }
-        };
+    /// When the schedule next fires after `after`.
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+        if let Some(schedule) = &self.cron
+            && let Some(next) = schedule.after(&after).next()
+        {
+            return next;
+        }
+        match self.interval {
+            Some(seconds) if seconds > 0 => after + std::time::Duration::from_secs(seconds),
+            _ => after + FALLBACK_SLEEP,
+        }
+    }
+}
+
+/// Archived backups of the mounts of containers labelled
+/// `hoister.backup-volumes`, taken before each update. See `backup.rs`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct Backups {
+    /// Directory the archives and `manifest.json` are written to, as seen by
+    /// the agent. Mount a host directory here.
+    pub(crate) directory: PathBuf,
+    /// Keep at least the newest `keep_last` backups of each service.
+    pub(crate) keep_last: Option<usize>,
+    /// Keep backups younger than `keep_days` days.
+    pub(crate) keep_days: Option<u64>,
+}
+
+/// Periodic removal of what interrupted or failed updates left behind: backup
+/// volumes, replaced containers and replaced images. See `janitor.rs`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct Janitor {
+    #[serde(default = "default_true")]
+    pub(crate) enabled: bool,
+    /// Seconds between sweeps.
+    #[serde(default = "default_janitor_interval")]
+    pub(crate) interval: u64,
+    /// Seconds an artifact has to be left over before it is removed.
+    #[serde(default = "default_janitor_max_age")]
+    pub(crate) max_age: u64,
+}
+
+impl Default for Janitor {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: default_janitor_interval(),
+            max_age: default_janitor_max_age(),
+        }
+    }
+}
+
+fn default_janitor_interval() -> u64 {
+    60 * 60
+}
+
+fn default_janitor_max_age() -> u64 {
+    24 * 60 * 60
+}
 
-        std::time::Duration::from_secs(seconds)
+/// Pattern-based redaction of container output, on top of the env-var
+/// redaction driven by `redact_keywords`. See `redact.rs`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct Redaction {
+    /// Apply the built-in rules for AWS keys, GitHub and Hoister tokens, JWTs,
+    /// passwords in URLs, bearer tokens and private keys.
+    #[serde(default = "default_true")]
+    pub(crate) builtin_rules: bool,
+    /// Extra regular expressions to redact. Where one has a group named
+    /// `secret`, only that group is redacted.
+    #[serde(default)]
+    pub(crate) patterns: Vec<String>,
+    /// Also redact long tokens that look random, by their Shannon entropy.
+    /// Off by default: it can catch IDs that aren't secret.
+    #[serde(default)]
+    pub(crate) entropy: bool,
+    /// Bits per character above which a token counts as random.
+    #[serde(default = "default_entropy_threshold")]
+    pub(crate) entropy_threshold: f64,
+    /// Shorter tokens are never redacted for their entropy.
+    #[serde(default = "default_entropy_min_length")]
+    pub(crate) entropy_min_length: usize,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Self {
+            builtin_rules: true,
+            patterns: Vec::new(),
+            entropy: false,
+            entropy_threshold: default_entropy_threshold(),
+            entropy_min_length: default_entropy_min_length(),
+        }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// Above the 4 bits of hex digests and most words, below the ~4.5 that random
+// base64 of this length reaches.
+fn default_entropy_threshold() -> f64 {
+    4.0
+}
+
+fn default_entropy_min_length() -> usize {
+    24
+}
+
+/// Which containers are managed, and in which Compose projects. See
+/// `selection.rs`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub(crate) struct Selection {
+    #[serde(default)]
+    pub(crate) mode: SelectionMode,
+    /// Only containers matching these globs. An empty list matches
+    /// everything, except that without `projects` only the agent's own
+    /// Compose project is watched.
+    #[serde(default)]
+    pub(crate) include: SelectionRules,
+    /// Never containers matching these globs.
+    #[serde(default)]
+    pub(crate) exclude: SelectionRules,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SelectionMode {
+    /// Update containers labelled `hoister.enable=true`.
+    #[default]
+    Labelled,
+    /// Update every container unless it is labelled `hoister.enable=false`.
+    All,
+}
+
+/// Globs (`*`, `?`) on the Compose project, the service name and the image.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub(crate) struct SelectionRules {
+    #[serde(default)]
+    pub(crate) projects: Vec<String>,
+    #[serde(default)]
+    pub(crate) services: Vec<String>,
+    #[serde(default)]
+    pub(crate) images: Vec<String>,
+}
+
+/// A Docker or Podman engine to manage. See `engines.rs`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct Engine {
+    /// `unix:///var/run/docker.sock`, `tcp://host:2376` or `ssh://user@host`.
+    /// Defaults to the runtime's local socket.
+    pub(crate) host: Option<String>,
+    #[serde(default)]
+    pub(crate) runtime: Runtime,
+    /// Name the engine is reported under. Asked from the engine if not set.
+    #[schemars(with = "Option<String>")]
+    pub(crate) hostname: Option<HostName>,
+    /// Compose project of the agent on this engine. Defaults to the top-level
+    /// `project`, and is detected from a Hoister agent container on the
+    /// engine if neither is set.
+    #[schemars(with = "Option<String>")]
+    pub(crate) project: Option<ProjectName>,
+    /// Client certificate for a `tcp://` engine that requires TLS.
+    pub(crate) tls: Option<EngineTls>,
+}
+
+/// The API an engine speaks.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Runtime {
+    #[default]
+    Docker,
+    /// Podman's Docker-compatible API. Without `host`, the rootless socket
+    /// of the user the agent runs as, or else the rootful one.
+    Podman,
+}
+
+/// PEM files for a TLS connection to an engine, as for `docker --tlsverify`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct EngineTls {
+    pub(crate) ca: PathBuf,
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+/// Registry push webhooks. Each source is enabled by giving it a secret; see
+/// `webhook.rs` for how each registry proves a request is genuine.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct Webhooks {
+    /// Address the webhook listener binds to.
+    #[serde(default = "default_webhook_listen")]
+    pub(crate) listen: String,
+    /// Seconds without a new push before the collected pushes are applied.
+    #[serde(default = "default_webhook_debounce")]
+    pub(crate) debounce: u64,
+    pub(crate) dockerhub: Option<WebhookSource>,
+    pub(crate) ghcr: Option<WebhookSource>,
+    pub(crate) harbor: Option<WebhookSource>,
+    pub(crate) generic: Option<WebhookSource>,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct WebhookSource {
+    #[schemars(extend("x-hoister-secret" = true))]
+    pub(crate) secret: String,
+}
+
+fn default_webhook_listen() -> String {
+    "0.0.0.0:8085".to_string()
+}
+
+fn default_webhook_debounce() -> u64 {
+    10
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub(crate) struct Controller {
     /// Controller URL. Defaults to the hosted instance at hoister.io so
     /// users only need to set `token` to enable the cloud dashboard. Override
     /// when running the controller yourself.
     #[serde(default = "default_controller_url")]
     pub(crate) url: Url,
+    #[schemars(extend("x-hoister-secret" = true))]
     pub(crate) token: Option<String>,
     pub(crate) ca_cert_path: Option<String>,
+    /// Where deployment and pending-update reports wait until the controller
+    /// takes them, see `outbox.rs`. Mount a host directory here to keep them
+    /// across restarts of the agent's container.
+    #[serde(default = "default_queue_directory")]
+    pub(crate) queue_directory: PathBuf,
+}
+
+fn default_queue_directory() -> PathBuf {
+    PathBuf::from("/var/lib/hoister/queue")
 }
 
 pub(crate) fn default_controller_url() -> Url {
@@ -243,23 +536,22 @@ fn default_true() -> bool {
     true
 }
 
-/// Parse a boolean-ish environment variable. Returns `None` when the variable
-/// is unset (so the caller keeps the config-file / default value) and `Some`
-/// when it is set to a recognised truthy/falsy value.
-pub(crate) fn env_bool(name: &str) -> Option<bool> {
-    let raw = std::env::var(name).ok()?;
-    match raw.trim().to_ascii_lowercase().as_str() {
-        "true" | "1" | "yes" | "on" => Some(true),
-        "false" | "0" | "no" | "off" => Some(false),
-        _ => None,
-    }
+fn default_one() -> usize {
+    1
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub(crate) struct Config {
+    #[schemars(with = "Option<String>")]
     pub(crate) project: Option<ProjectName>,
     #[serde(default)]
+    #[schemars(with = "String")]
     pub(crate) hostname: HostName,
+    /// How this agent identifies itself to the controller, which sends each
+    /// command only to the agent managing its host. Defaults to an ID derived
+    /// from the engines' own IDs and projects, see `engines::registration`.
+    #[schemars(with = "Option<String>")]
+    pub(crate) agent_id: Option<AgentId>,
     #[serde(default)]
     pub(crate) send_test_message: bool,
     #[serde(default = "default_true")]
@@ -282,69 +574,154 @@ pub(crate) struct Config {
     /// comma-separated `HOISTER_REDACT_KEYWORDS` env var. Loaded at startup.
     #[serde(default)]
     pub(crate) redact_keywords: Vec<String>,
+    #[serde(default)]
+    pub(crate) redaction: Redaction,
+    /// How many services are updated at the same time. A service is only
+    /// updated once the services it `depends_on` in its Compose file are
+    /// done. Defaults to 1: one after another.
+    #[serde(default = "default_one")]
+    pub(crate) max_parallel_updates: usize,
+    /// How many images are pulled at the same time, across all updates, so
+    /// parallel updates don't saturate the host's bandwidth. Defaults to 1.
+    #[serde(default = "default_one")]
+    pub(crate) max_parallel_pulls: usize,
     pub(crate) schedule: Schedule,
     pub(crate) registry: Option<Registry>,
     pub(crate) controller: Option<Controller>,
     pub(crate) dispatcher: Option<Dispatcher>,
+    pub(crate) webhooks: Option<Webhooks>,
+    pub(crate) backups: Option<Backups>,
+    #[serde(default)]
+    pub(crate) janitor: Janitor,
+    #[serde(default)]
+    pub(crate) selection: Selection,
+    /// Docker engines to manage. Empty means the local one, as named by
+    /// `DOCKER_HOST` or the default socket.
+    #[serde(default)]
+    pub(crate) engines: Vec<Engine>,
+    /// Values of every secret field, resolved from files where configured.
+    /// Registered with the log redaction in `monitor.rs`.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub(crate) secret_values: Vec<String>,
 }
 
-pub(crate) fn build_http_client(controller: &Option<Controller>) -> reqwest::Client {
+impl Config {
+    /// Apply a reloaded config on top of this one. Schedule, registries,
+    /// dispatchers, `auto_update`, the parallelism limits and the redaction
+    /// lists come from `new`; settings wired into long-lived connections and
+    /// listeners at startup are kept, and the names of those that differ are
+    /// returned so the caller can say they need a restart.
+    pub(crate) fn reloaded(&self, new: Config) -> (Config, Vec<&'static str>) {
+        let mut restart_only = Vec::new();
+        if new.project.is_some() && new.project != self.project {
+            restart_only.push("project");
+        }
+        // The default hostname means "ask Docker", which happened at startup.
+        if new.hostname != HostName::default() && new.hostname != self.hostname {
+            restart_only.push("hostname");
+        }
+        if new.agent_id != self.agent_id {
+            restart_only.push("agent_id");
+        }
+        if new.controller != self.controller {
+            restart_only.push("controller");
+        }
+        if new.webhooks != self.webhooks {
+            restart_only.push("webhooks");
+        }
+        if new.report_logs != self.report_logs {
+            restart_only.push("report_logs");
+        }
+        if new.report_metrics != self.report_metrics {
+            restart_only.push("report_metrics");
+        }
+        if new.engines != self.engines {
+            restart_only.push("engines");
+        }
+        let config = Config {
+            project: self.project.clone(),
+            hostname: self.hostname.clone(),
+            agent_id: self.agent_id.clone(),
+            controller: self.controller.clone(),
+            webhooks: self.webhooks.clone(),
+            report_logs: self.report_logs,
+            report_metrics: self.report_metrics,
+            engines: self.engines.clone(),
+            send_test_message: self.send_test_message,
+            ..new
+        };
+        (config, restart_only)
+    }
+}
+
+/// JSON Schema of the config file, for editor completion and validation.
+pub(crate) fn json_schema() -> schemars::Schema {
+    let mut schema = schemars::schema_for!(Config).to_value();
+    secrets::add_file_counterparts(&mut schema);
+    schemars::Schema::try_from(schema).expect("config schema is an object")
+}
+
+/// Read and parse a PEM CA certificate, describing what went wrong if it
+/// can't be used.
+fn read_ca_cert(ca_path: &str) -> Result<reqwest::Certificate, String> {
+    let pem = std::fs::read(ca_path).map_err(|e| format!("cannot read CA cert {ca_path}: {e}"))?;
+    reqwest::Certificate::from_pem(&pem).map_err(|e| format!("cannot parse CA cert {ca_path}: {e}"))
+}
+
+pub(crate) fn build_http_client(
+    controller: &Option<Controller>,
+) -> Result<reqwest::Client, ConfigError> {
+    let problem = |key: Option<&str>, message: String| {
+        ConfigError(vec![validate::ConfigProblem {
+            severity: Severity::Error,
+            source: validate::ProblemSource::Unspecified,
+            key: key.map(str::to_string),
+            message,
+        }])
+    };
     let mut builder = reqwest::Client::builder();
 
+    // The CA bundle is the only part of the client the config controls, so a
+    // failed build is blamed on it when one is set.
+    let mut key = None;
     if let Some(controller) = controller
         && let Some(ca_path) = &controller.ca_cert_path
     {
-        let pem = std::fs::read(ca_path)
-            .unwrap_or_else(|e| panic!("Failed to read CA cert at {ca_path}: {e}"));
-        let cert = reqwest::Certificate::from_pem(&pem)
-            .unwrap_or_else(|e| panic!("Failed to parse CA cert at {ca_path}: {e}"));
+        key = Some("controller.ca_cert_path");
+        let cert = read_ca_cert(ca_path).map_err(|message| problem(key, message))?;
         builder = builder.add_root_certificate(cert);
     }
 
-    builder.build().expect("Failed to build HTTP client")
+    builder.build().map_err(|e| {
+        let message = match std::error::Error::source(&e) {
+            Some(cause) => format!("cannot build the HTTP client: {cause}"),
+            None => format!("cannot build the HTTP client: {e}"),
+        };
+        problem(key, message)
+    })
 }
 
-pub(crate) async fn load_config(config_path: &Path) -> Config {
-    let mut config: Config = Figment::new()
-        .merge(Toml::file(config_path))
-        .merge(Env::prefixed("HOISTER_").split("_"))
-        .extract()
-        .expect("Failed to load config");
-
-    // Workaround: figment's split("_") splits HOISTER_CONTROLLER_CA_CERT_PATH into
-    // controller.ca.cert.path instead of controller.ca_cert_path.
-    if let Some(ref mut controller) = config.controller
-        && controller.ca_cert_path.is_none()
-    {
-        controller.ca_cert_path = std::env::var("HOISTER_CONTROLLER_CA_CERT_PATH").ok();
-    }
-
-    // Same quirk for the top-level snake_case flags: figment's split("_")
-    // mangles HOISTER_REPORT_LOGS / HOISTER_REPORT_METRICS, so read them here.
-    // When present the env var is authoritative (overrides the file/default),
-    // and may turn a setting either on or off.
-    if let Some(v) = env_bool("HOISTER_REPORT_LOGS") {
-        config.report_logs = v;
-    }
-    if let Some(v) = env_bool("HOISTER_REPORT_METRICS") {
-        config.report_metrics = v;
+/// Load and validate the config. Warnings are logged; any error fails the
+/// load, listing every problem found rather than only the first.
+pub(crate) async fn load_config(config_path: &Path) -> Result<Config, ConfigError> {
+    let validation = validate(config_path);
+    let has_errors = validation.has_errors();
+    let (errors, warnings): (Vec<_>, Vec<_>) = validation
+        .problems
+        .into_iter()
+        .partition(|p| p.severity == Severity::Error);
+    for warning in &warnings {
+        warn!("{warning}");
     }
-
-    // `redact_keywords` is a list, which figment's split("_") env provider can't
-    // populate cleanly, so accept a comma-separated HOISTER_REDACT_KEYWORDS that
-    // extends whatever the config file declared.
-    if let Ok(raw) = std::env::var("HOISTER_REDACT_KEYWORDS") {
-        config.redact_keywords.extend(
-            raw.split(',')
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty()),
-        );
+    match validation.config {
+        Some(config) if !has_errors => Ok(config),
+        _ => Err(ConfigError(errors)),
     }
-
-    config
 }
 
 #[cfg(test)]
+#[allow(clippy::result_large_err)]
 mod tests {
     use super::*;
     use std::str::FromStr;
@@ -357,7 +734,6 @@ mod tests {
                 "config-test.toml",
                 r#"
             [schedule]
-            interval=10
             cron="0 * * * * * *"
 
             [registry.ghcr]
@@ -394,7 +770,7 @@ mod tests {
             let config_path = "config-test.toml";
 
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config(config_path.as_ref()));
+            let config = rt.block_on(load_config(config_path.as_ref())).unwrap();
 
             let dispatcher = config.dispatcher.unwrap();
             assert_eq!(config.registry.unwrap().ghcr.unwrap().username, "xxx");
@@ -428,6 +804,32 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_unusable_ca_cert_is_a_config_error() {
+        let path = std::env::temp_dir().join(format!("hoister-bad-ca-{}.pem", std::process::id()));
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let controller = Controller {
+            url: Url::parse("https://controller.example").unwrap(),
+            token: None,
+            ca_cert_path: Some(path.display().to_string()),
+            queue_directory: default_queue_directory(),
+        };
+
+        let err = build_http_client(&Some(controller)).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].key.as_deref(), Some("controller.ca_cert_path"));
+        assert!(
+            err.0[0]
+                .message
+                .starts_with("cannot build the HTTP client: ")
+        );
+    }
+
     #[test]
     fn test_report_metrics_defaults_on_and_can_be_disabled() {
         use figment2::Jail;
@@ -436,7 +838,9 @@ mod tests {
         Jail::expect_with(|jail: &mut Jail| {
             jail.create_file("config-test.toml", "[schedule]\ninterval=10\n")?;
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config("config-test.toml".as_ref()));
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
             assert!(config.report_metrics, "metrics should default to on");
             assert!(!config.report_logs, "logs should default to off");
             Ok(())
@@ -449,7 +853,9 @@ mod tests {
                 "report_metrics=false\n[schedule]\ninterval=10\n",
             )?;
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config("config-test.toml".as_ref()));
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
             assert!(
                 !config.report_metrics,
                 "report_metrics=false should disable"
@@ -463,7 +869,9 @@ mod tests {
             jail.set_env("HOISTER_REPORT_METRICS", "false");
             jail.set_env("HOISTER_REPORT_LOGS", "true");
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config("config-test.toml".as_ref()));
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
             assert!(!config.report_metrics, "env should disable metrics");
             assert!(config.report_logs, "env should enable logs");
             Ok(())
@@ -485,7 +893,9 @@ mod tests {
             jail.set_env("HOISTER_CONTROLLER_TOKEN", "hst_test_token");
 
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config("config-test.toml".as_ref()));
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
 
             let controller = config.controller.expect("controller should be populated");
             assert_eq!(
@@ -511,7 +921,9 @@ mod tests {
             )?;
 
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let config = rt.block_on(load_config("config-test.toml".as_ref()));
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
 
             assert!(
                 config.controller.is_none(),
@@ -521,6 +933,195 @@ mod tests {
         });
     }
 
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_dispatcher_only_keeps_one() {
+        let dispatcher: Dispatcher = serde_json::from_value(serde_json::json!({
+            "slack": {"webhook": "https://hooks.slack.com/x", "channel": "ops"},
+            "ntfy": {"server": "https://ntfy.sh", "topic": "deploys"},
+        }))
+        .unwrap();
+        let only = dispatcher.clone().only("slack").unwrap();
+        assert!(only.slack.is_some());
+        assert!(only.ntfy.is_none());
+        assert!(dispatcher.clone().only("telegram").is_none());
+        assert!(dispatcher.only("nonsense").is_none());
+    }
+
+    #[test]
+    fn test_webhooks_from_toml_and_env() {
+        use figment2::Jail;
+        Jail::expect_with(|jail: &mut Jail| {
+            jail.create_file(
+                "config-test.toml",
+                r#"
+            [schedule]
+            interval=10
+
+            [webhooks.ghcr]
+            secret="from-file"
+            "#,
+            )?;
+            jail.set_env("HOISTER_WEBHOOKS_DOCKERHUB_SECRET", "from-env");
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
+
+            let webhooks = config.webhooks.expect("webhooks should be populated");
+            assert_eq!(webhooks.listen, "0.0.0.0:8085");
+            assert_eq!(webhooks.debounce, 10);
+            assert_eq!(webhooks.ghcr.unwrap().secret, "from-file");
+            assert_eq!(webhooks.dockerhub.unwrap().secret, "from-env");
+            assert!(webhooks.harbor.is_none());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_env_lists_and_exact_paths() {
+        use figment2::Jail;
+        Jail::expect_with(|jail: &mut Jail| {
+            jail.create_file(
+                "config-test.toml",
+                r#"
+            redact_keywords = ["license"]
+
+            [schedule]
+            interval=10
+
+            [controller]
+            url="https://controller.example"
+            "#,
+            )?;
+            jail.set_env("HOISTER_REDACT_KEYWORDS", "pin, otp");
+            jail.set_env("HOISTER_REPORT_LOGS", "yes");
+            jail.set_env("HOISTER__CONTROLLER__TOKEN", "hst_exact");
+            jail.set_env("HOISTER_REDACTION_PATTERNS", "LIC-[0-9]+");
+            jail.set_env("HOISTER_REDACTION_ENTROPY", "true");
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
+
+            assert_eq!(config.redact_keywords, ["license", "pin", "otp"]);
+            assert!(config.report_logs);
+            assert_eq!(
+                config.controller.unwrap().token.as_deref(),
+                Some("hst_exact")
+            );
+            assert_eq!(config.redaction.patterns, ["LIC-[0-9]+"]);
+            assert!(config.redaction.entropy);
+            assert!(config.redaction.builtin_rules);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_secrets_from_files() {
+        use figment2::Jail;
+        Jail::expect_with(|jail: &mut Jail| {
+            let dir = jail.directory().to_path_buf();
+            jail.create_file("ghcr_token", "ghp_from_file\n")?;
+            jail.create_file("controller_token", "hst_from_file")?;
+            jail.create_file(
+                "config-test.toml",
+                &format!(
+                    r#"
+            [schedule]
+            interval=10
+
+            [registry.ghcr]
+            username="foo"
+            token_file="{}"
+
+            [controller]
+            token="file:{}"
+            "#,
+                    dir.join("ghcr_token").display(),
+                    dir.join("controller_token").display(),
+                ),
+            )?;
+            jail.set_env(
+                "HOISTER_WEBHOOKS_GHCR_SECRET_FILE",
+                dir.join("ghcr_token").display(),
+            );
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
+
+            let registry = config.registry.unwrap().ghcr.unwrap();
+            assert_eq!(registry.token, "ghp_from_file");
+            let controller = config.controller.unwrap();
+            assert_eq!(controller.token.as_deref(), Some("hst_from_file"));
+            assert_eq!(
+                config.webhooks.unwrap().ghcr.unwrap().secret,
+                "ghp_from_file"
+            );
+            assert!(config.secret_values.contains(&"hst_from_file".to_string()));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_unreadable_secret_file_is_reported() {
+        use figment2::Jail;
+        Jail::expect_with(|jail: &mut Jail| {
+            jail.create_file(
+                "config-test.toml",
+                "[schedule]\ninterval=10\n[controller]\ntoken_file=\"/does/not/exist\"\n",
+            )?;
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let error = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap_err();
+            assert!(error.0.iter().any(|p| {
+                p.key.as_deref() == Some("controller.token_file")
+                    && p.message.contains("cannot read secret file")
+            }));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_reload_keeps_startup_only_settings() {
+        use figment2::providers::Format;
+        let config = |toml: &str| -> Config {
+            figment2::Figment::new()
+                .merge(figment2::providers::Toml::string(toml))
+                .extract()
+                .unwrap()
+        };
+        let current =
+            config("project=\"app\"\n[schedule]\ninterval=10\n[controller]\ntoken=\"a\"\n");
+        let new = config(
+            "project=\"other\"\nauto_update=false\n[schedule]\ninterval=60\n\
+             [controller]\ntoken=\"b\"\n[registry.ghcr]\nusername=\"u\"\ntoken=\"t\"\n",
+        );
+
+        let (reloaded, restart_only) = current.reloaded(new);
+        assert_eq!(reloaded.schedule.interval, Some(60));
+        assert!(!reloaded.auto_update);
+        assert!(reloaded.registry.is_some());
+        assert_eq!(reloaded.project, current.project);
+        assert_eq!(reloaded.controller, current.controller);
+        assert_eq!(restart_only, ["project", "controller"]);
+    }
+
+    #[test]
+    fn test_schedule_falls_back_instead_of_panicking() {
+        let schedule = Schedule {
+            interval: None,
+            cron: None,
+        };
+        let now = Utc::now();
+        assert_eq!(schedule.next_after(now), now + FALLBACK_SLEEP);
+    }
+
     #[test]
     fn test_schedule_cron() {
         let expression = "0 * * * * * *";
@@ -528,6 +1129,8 @@ mod tests {
             interval: Some(10),
             cron: Some(CronSchedule::from_str(expression).unwrap()),
         };
-        assert!(schedule.sleep().as_secs() < 60); // any schedule should be less than 60 seconds
+        let now = Utc::now();
+        let next = schedule.next_after(now);
+        assert!(next > now && next - now <= chrono::Duration::seconds(60)); // fires every minute
     }
 }
@@ -0,0 +1,156 @@
+//! Secrets read from files instead of the environment or `hoister.toml`.
+//!
+//! Plain env vars show up in `docker inspect` of the agent itself. Every field
+//! marked with [`SECRET`] in the schema can therefore also be given as a file:
+//! either through a `<field>_file` counterpart (`token_file = "/run/secrets/x"`
+//! or `HOISTER_REGISTRY_GHCR_TOKEN_FILE`) or by writing the value as
+//! `file:/run/secrets/x`. Files are read every time the config is loaded.
+
+use figment2::Figment;
+use figment2::providers::Serialized;
+use serde_json::Value as Json;
+
+/// Schema extension marking a field as secret-bearing.
+const SECRET: &str = "x-hoister-secret";
+/// Value prefix that reads the rest of the value as a file path.
+const FILE_PREFIX: &str = "file:";
+/// Suffix of the key naming a file that holds the field's value.
+const FILE_SUFFIX: &str = "_file";
+
+/// A secret that could not be resolved, with the key that caused it.
+pub(super) struct SecretProblem {
+    pub(super) key: Vec<String>,
+    pub(super) message: String,
+}
+
+/// Add a `<field>_file` property next to every secret field, so editors and
+/// the unknown-key check accept it. The field itself is no longer required
+/// since it can come from the file instead.
+pub(super) fn add_file_counterparts(schema: &mut Json) {
+    if let Some(defs) = schema.get_mut("$defs").and_then(Json::as_object_mut) {
+        defs.values_mut().for_each(add_to_object);
+    }
+    add_to_object(schema);
+}
+
+fn add_to_object(schema: &mut Json) {
+    let Some(props) = schema.get_mut("properties").and_then(Json::as_object_mut) else {
+        return;
+    };
+    let secrets: Vec<String> = props
+        .iter()
+        .filter(|(_, field)| is_secret(field))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in &secrets {
+        props.insert(
+            format!("{name}{FILE_SUFFIX}"),
+            serde_json::json!({
+                "type": "string",
+                "description": format!("Path of a file holding `{name}`, e.g. a Docker secret."),
+            }),
+        );
+    }
+    if let Some(required) = schema.get_mut("required").and_then(Json::as_array_mut) {
+        required.retain(|name| !secrets.iter().any(|s| name == s));
+    }
+}
+
+fn is_secret(field: &Json) -> bool {
+    field.get(SECRET).and_then(Json::as_bool) == Some(true)
+}
+
+/// Config paths of every secret field in `schema`, e.g. `registry.ghcr.token`.
+fn secret_paths(root: &Json) -> Vec<Vec<String>> {
+    let mut paths = Vec::new();
+    collect(root, root, &mut Vec::new(), &mut paths);
+    paths
+}
+
+fn collect(root: &Json, schema: &Json, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+    let schema = super::validate::resolve(root, schema);
+    let Some(props) = schema.get("properties").and_then(Json::as_object) else {
+        return;
+    };
+    for (name, field) in props {
+        path.push(name.clone());
+        if is_secret(field) {
+            paths.push(path.clone());
+        } else {
+            collect(root, field, path, paths);
+        }
+        path.pop();
+    }
+}
+
+/// Replace every `<field>_file` and `file:` reference in `figment` with the
+/// file's contents. Returns the updated figment, the value of every secret
+/// that is set (for log redaction), and the references that failed.
+pub(super) fn resolve(
+    mut figment: Figment,
+    root: &Json,
+) -> (Figment, Vec<String>, Vec<SecretProblem>) {
+    let mut values = Vec::new();
+    let mut problems = Vec::new();
+    for path in secret_paths(root) {
+        let key = path.join(".");
+        let mut file_path = path.clone();
+        if let Some(last) = file_path.last_mut() {
+            last.push_str(FILE_SUFFIX);
+        }
+        let file_key = file_path.join(".");
+
+        let direct = figment.find_value(&key).ok();
+        let from_file = figment.find_value(&file_key).ok();
+        let (file, reference_key) = match (direct.as_ref().and_then(|v| v.as_str()), from_file) {
+            (Some(_), Some(_)) => {
+                problems.push(SecretProblem {
+                    key: path.clone(),
+                    message: format!("set either `{key}` or `{file_key}`, not both"),
+                });
+                continue;
+            }
+            (_, Some(file)) => match file.into_string() {
+                Some(file) => (file, file_path),
+                None => {
+                    problems.push(SecretProblem {
+                        key: file_path,
+                        message: "must be a file path".to_string(),
+                    });
+                    continue;
+                }
+            },
+            (Some(value), None) => match value.strip_prefix(FILE_PREFIX) {
+                Some(file) => (file.to_string(), path.clone()),
+                None => {
+                    values.push(value.to_string());
+                    continue;
+                }
+            },
+            (None, None) => continue,
+        };
+
+        match read_secret(&file) {
+            Ok(secret) => {
+                figment = figment.merge(Serialized::default(&key, &secret));
+                values.push(secret);
+            }
+            Err(message) => problems.push(SecretProblem {
+                key: reference_key,
+                message,
+            }),
+        }
+    }
+    (figment, values, problems)
+}
+
+/// Read a secret file, dropping the trailing newline most tools add.
+fn read_secret(file: &str) -> Result<String, String> {
+    let contents = std::fs::read_to_string(file)
+        .map_err(|e| format!("cannot read secret file {file}: {e}"))?;
+    let secret = contents.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(format!("secret file {file} is empty"));
+    }
+    Ok(secret.to_string())
+}
@@ -0,0 +1,998 @@
+//! One validation pass over the agent configuration.
+//!
+//! Instead of stopping at the first problem, loading collects everything that
+//! is wrong — unknown keys, values of the wrong type, conflicting schedule
+//! settings, invalid URLs, unreadable files — and attributes each problem to
+//! where it came from: a line in the TOML file or an environment variable.
+//!
+//! Environment variables are mapped onto config keys with the help of the
+//! config's JSON Schema, so `HOISTER_CONTROLLER_CA_CERT_PATH` resolves to
+//! `controller.ca_cert_path` rather than `controller.ca.cert.path`. For the
+//! cases where the single-underscore form is ambiguous, `HOISTER__` takes an
+//! exact path with `__` between levels: `HOISTER__CONTROLLER__CA_CERT_PATH`.
+
+use super::{
+    AcrRegistry, Config, Controller, DiscordWebhook, DockerHubRegistry, EcrRegistry, Email,
+    GcrRegistry, GithubRegistry, GoogleChat, Gotify, Matrix, Mattermost, Ntfy, Pushover,
+    RocketChat, Schedule, Slack, Teams, Telegram, Webhook, Webhooks,
+};
+use chrono::Utc;
+use figment2::providers::{Format, Toml};
+use figment2::value::{Dict, Map, Value};
+use figment2::{Figment, Metadata, Profile, Provider, Source};
+use serde::de::DeserializeOwned;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use toml_edit::TableLike;
+
+/// Prefix of the environment variables read as configuration.
+const ENV_PREFIX: &str = "HOISTER_";
+/// Prefix of the unambiguous form, with `__` between nesting levels.
+const ENV_PREFIX_EXACT: &str = "HOISTER__";
+/// Provider name used to recognise values that came from the environment.
+const ENV_PROVIDER_NAME: &str = "environment variable";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+/// Where a configuration value (and therefore a problem with it) came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ProblemSource {
+    File {
+        path: PathBuf,
+        line: Option<usize>,
+    },
+    Env(String),
+    /// A built-in default, or a problem not tied to a single value.
+    Unspecified,
+}
+
+impl fmt::Display for ProblemSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File {
+                path,
+                line: Some(line),
+            } => write!(f, "{}:{line}", path.display()),
+            Self::File { path, line: None } => write!(f, "{}", path.display()),
+            Self::Env(name) => write!(f, "env {name}"),
+            Self::Unspecified => write!(f, "config"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConfigProblem {
+    pub(crate) severity: Severity,
+    pub(crate) source: ProblemSource,
+    /// Dotted config key the problem is about, if any.
+    pub(crate) key: Option<String>,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}: ", self.source)?;
+        if let Some(key) = &self.key {
+            write!(f, "`{key}`: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Every error found while loading the configuration.
+pub(crate) struct ConfigError(pub(crate) Vec<ConfigProblem>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+// `main` returns errors through `Debug`; show the same readable list.
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:\n{self}")
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// What a validation pass found: the config, if it could be built at all,
+/// and every problem along the way.
+pub(crate) struct Validation {
+    pub(crate) config: Option<Config>,
+    pub(crate) problems: Vec<ConfigProblem>,
+}
+
+impl Validation {
+    pub(crate) fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+}
+
+/// Load `config_path` plus the `HOISTER_*` environment and validate the
+/// result.
+pub(crate) fn validate(config_path: &Path) -> Validation {
+    validate_with_env(config_path, std::env::vars())
+}
+
+fn validate_with_env(
+    config_path: &Path,
+    env: impl Iterator<Item = (String, String)>,
+) -> Validation {
+    let schema = super::json_schema();
+    let root = schema.as_value();
+    let mut ctx = Context {
+        path: config_path.to_path_buf(),
+        doc: None,
+        problems: Vec::new(),
+    };
+
+    match std::fs::read_to_string(config_path) {
+        Ok(raw) => match toml_edit::Document::parse(raw.clone()) {
+            Ok(doc) => {
+                unknown_toml_keys(root, root, doc.as_table(), &mut Vec::new(), &doc, &mut ctx);
+                ctx.doc = Some(doc);
+            }
+            Err(e) => {
+                let line = e.span().map(|span| line_at(&raw, span.start));
+                ctx.problems.push(ConfigProblem {
+                    severity: Severity::Error,
+                    source: ctx.file_source(line),
+                    key: None,
+                    message: format!("invalid TOML: {}", e.message()),
+                });
+                // Nothing below is meaningful without the file.
+                return Validation {
+                    config: None,
+                    problems: ctx.problems,
+                };
+            }
+        },
+        // The file is optional: everything can come from the environment.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if config_path != Path::new(crate::DEFAULT_CONFIG_PATH) {
+                ctx.problems.push(ConfigProblem {
+                    severity: Severity::Warning,
+                    source: ctx.file_source(None),
+                    key: None,
+                    message: "config file not found, using environment variables only".to_string(),
+                });
+            }
+        }
+        Err(e) => {
+            ctx.problems.push(ConfigProblem {
+                severity: Severity::Error,
+                source: ctx.file_source(None),
+                key: None,
+                message: format!("cannot read config file: {e}"),
+            });
+            return Validation {
+                config: None,
+                problems: ctx.problems,
+            };
+        }
+    }
+
+    let env = SchemaEnv::from_vars(root, env, &mut ctx.problems);
+    let figment = Figment::new()
+        .merge(Toml::file(config_path))
+        // `admerge` so that a list from the environment (e.g.
+        // `HOISTER_REDACT_KEYWORDS`) extends the file's list instead of
+        // replacing it.
+        .admerge(env);
+    let (figment, secret_values, secret_problems) = super::secrets::resolve(figment, root);
+    for problem in secret_problems {
+        let source = ctx.source_of(&figment, &problem.key);
+        ctx.error(source, &problem.key.join("."), problem.message);
+    }
+
+    // Lossy, so booleans from the environment may be spelled `yes`/`on`/`1`.
+    let config = match figment.extract_lossy::<Config>() {
+        Ok(config) => Some(Config {
+            secret_values,
+            ..config
+        }),
+        Err(full) => {
+            extraction_problems(&figment, full, &mut ctx);
+            None
+        }
+    };
+
+    if let Some(config) = &config {
+        semantic_problems(config, &figment, &mut ctx);
+    }
+
+    Validation {
+        config,
+        problems: ctx.problems,
+    }
+}
+
+struct Context {
+    path: PathBuf,
+    doc: Option<toml_edit::Document<String>>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Context {
+    fn file_source(&self, line: Option<usize>) -> ProblemSource {
+        ProblemSource::File {
+            path: self.path.clone(),
+            line,
+        }
+    }
+
+    /// Where the value at `key` came from.
+    fn source_of(&self, figment: &Figment, key: &[String]) -> ProblemSource {
+        let Some(metadata) = figment.find_metadata(&key.join(".")) else {
+            return ProblemSource::Unspecified;
+        };
+        self.source_from_metadata(metadata, key)
+    }
+
+    fn source_from_metadata(&self, metadata: &Metadata, key: &[String]) -> ProblemSource {
+        match &metadata.source {
+            Some(Source::File(_)) => self.file_source(self.line_of(key)),
+            _ if metadata.name == ENV_PROVIDER_NAME => {
+                ProblemSource::Env(metadata.interpolate(&Profile::Default, key))
+            }
+            _ => ProblemSource::Unspecified,
+        }
+    }
+
+    /// Line of the deepest key of `path` present in the TOML file.
+    fn line_of(&self, path: &[String]) -> Option<usize> {
+        let doc = self.doc.as_ref()?;
+        let mut table: &dyn TableLike = doc.as_table();
+        let mut line = None;
+        for segment in path {
+            let Some((key, item)) = table.get_key_value(segment) else {
+                break;
+            };
+            if let Some(span) = key.span() {
+                line = Some(line_at(doc.raw(), span.start));
+            }
+            match item.as_table_like() {
+                Some(next) => table = next,
+                None => break,
+            }
+        }
+        line
+    }
+
+    fn error(&mut self, source: ProblemSource, key: &str, message: impl Into<String>) {
+        self.problems.push(ConfigProblem {
+            severity: Severity::Error,
+            source,
+            key: Some(key.to_string()),
+            message: message.into(),
+        });
+    }
+}
+
+/// 1-based line number of byte `offset` in `raw`.
+fn line_at(raw: &str, offset: usize) -> usize {
+    1 + raw[..offset.min(raw.len())].matches('\n').count()
+}
+
+/// Resolve `$ref`s and `Option` wrappers down to the schema of the value.
+pub(super) fn resolve<'a>(root: &'a Json, schema: &'a Json) -> &'a Json {
+    if let Some(reference) = schema.get("$ref").and_then(Json::as_str) {
+        let name = reference.trim_start_matches("#/$defs/");
+        return resolve(root, &root["$defs"][name]);
+    }
+    if let Some(variants) = schema.get("anyOf").and_then(Json::as_array) {
+        let not_null = variants
+            .iter()
+            .find(|v| v.get("type").and_then(Json::as_str) != Some("null"));
+        if let Some(variant) = not_null {
+            return resolve(root, variant);
+        }
+    }
+    schema
+}
+
+fn properties(schema: &Json) -> Option<&serde_json::Map<String, Json>> {
+    schema.get("properties").and_then(Json::as_object)
+}
+
+/// A free-form map such as `[dispatcher.webhook.headers]`.
+fn is_map(schema: &Json) -> bool {
+    properties(schema).is_none() && schema.get("additionalProperties").is_some()
+}
+
+fn is_array(schema: &Json) -> bool {
+    match schema.get("type") {
+        Some(Json::String(t)) => t == "array",
+        Some(Json::Array(types)) => types.iter().any(|t| t == "array"),
+        _ => false,
+    }
+}
+
+/// Map the `_`-separated words of a `HOISTER_*` variable onto a config key.
+/// Field names can contain `_` themselves, so at each level the longest run
+/// of words naming a field wins (`discord_webhook` before `discord`).
+fn resolve_words(root: &Json, schema: &Json, words: &[String]) -> Option<Vec<String>> {
+    let schema = resolve(root, schema);
+    if words.is_empty() {
+        return Some(Vec::new());
+    }
+    if is_map(schema) {
+        return Some(vec![words.join("_")]);
+    }
+    let props = properties(schema)?;
+    (1..=words.len()).rev().find_map(|n| {
+        let name = words[..n].join("_");
+        let field = props.get(&name)?;
+        let mut rest = resolve_words(root, field, &words[n..])?;
+        rest.insert(0, name);
+        Some(rest)
+    })
+}
+
+/// Whether `path` names a config key, and the schema of its value.
+fn schema_at<'a>(root: &'a Json, path: &[String]) -> Option<&'a Json> {
+    let mut schema = resolve(root, root);
+    for segment in path {
+        if is_map(schema) {
+            return Some(resolve(root, &schema["additionalProperties"]));
+        }
+        schema = resolve(root, properties(schema)?.get(segment)?);
+    }
+    Some(schema)
+}
+
+fn unknown_toml_keys(
+    root: &Json,
+    schema: &Json,
+    table: &dyn TableLike,
+    path: &mut Vec<String>,
+    doc: &toml_edit::Document<String>,
+    ctx: &mut Context,
+) {
+    let schema = resolve(root, schema);
+    let Some(props) = properties(schema) else {
+        return;
+    };
+    for (name, item) in table.iter() {
+        path.push(name.to_string());
+        match props.get(name) {
+            Some(field) => {
+                if let Some(inner) = item.as_table_like() {
+                    unknown_toml_keys(root, field, inner, path, doc, ctx);
+                }
+            }
+            None => {
+                let line = table
+                    .key(name)
+                    .and_then(|k| k.span())
+                    .map(|span| line_at(doc.raw(), span.start));
+                let hint = closest(name, props.keys().map(String::as_str))
+                    .map(|known| format!(", did you mean `{known}`?"))
+                    .unwrap_or_default();
+                ctx.problems.push(ConfigProblem {
+                    severity: Severity::Error,
+                    source: ctx.file_source(line),
+                    key: Some(path.join(".")),
+                    message: format!("unknown key{hint}"),
+                });
+            }
+        }
+        path.pop();
+    }
+}
+
+/// The known name closest to `name`, if it is a plausible typo.
+fn closest<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    known
+        .map(|k| (levenshtein(name, k), k))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(current)
+            };
+            prev = current;
+        }
+    }
+    row[b.len()]
+}
+
+/// `HOISTER_*` and `HOISTER__*` environment variables, mapped onto config
+/// keys via the schema. Remembers which variable set which key so problems can
+/// name it.
+struct SchemaEnv {
+    dict: Dict,
+    names: HashMap<String, String>,
+}
+
+impl SchemaEnv {
+    fn from_vars(
+        root: &Json,
+        vars: impl Iterator<Item = (String, String)>,
+        problems: &mut Vec<ConfigProblem>,
+    ) -> Self {
+        let mut legacy = Vec::new();
+        let mut exact = Vec::new();
+        for (name, value) in vars {
+            if let Some(rest) = name.strip_prefix(ENV_PREFIX_EXACT) {
+                let path: Vec<String> = rest.split("__").map(str::to_ascii_lowercase).collect();
+                let known = schema_at(root, &path).is_some() && path.iter().all(|s| !s.is_empty());
+                exact.push((name, value, known.then_some(path)));
+            } else if let Some(rest) = name.strip_prefix(ENV_PREFIX) {
+                let words: Vec<String> = rest.split('_').map(str::to_ascii_lowercase).collect();
+                let path = resolve_words(root, root, &words).filter(|p| !p.is_empty());
+                legacy.push((name, value, path));
+            }
+        }
+        // Sort so that the result doesn't depend on the environment's order,
+        // and let the exact form win over the legacy one.
+        legacy.sort();
+        exact.sort();
+
+        let mut env = SchemaEnv {
+            dict: Dict::new(),
+            names: HashMap::new(),
+        };
+        for (name, raw, path) in legacy.into_iter().chain(exact) {
+            let Some(path) = path else {
+                problems.push(ConfigProblem {
+                    severity: Severity::Warning,
+                    source: ProblemSource::Env(name),
+                    key: None,
+                    message: "not a known setting, ignored".to_string(),
+                });
+                continue;
+            };
+            let is_list = schema_at(root, &path).is_some_and(is_array);
+            let value = if is_list && !raw.trim_start().starts_with('[') {
+                // Lists are comma-separated in the environment.
+                Value::from(
+                    raw.split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect::<Vec<_>>(),
+                )
+            } else {
+                raw.parse::<Value>().unwrap_or_else(|never| match never {})
+            };
+            env.names.insert(path.join("."), name);
+            insert(&mut env.dict, &path, value);
+        }
+        env
+    }
+}
+
+fn insert(dict: &mut Dict, path: &[String], value: Value) {
+    let (last, parents) = path.split_last().expect("path is never empty");
+    let mut dict = dict;
+    for segment in parents {
+        let entry = dict
+            .entry(segment.clone())
+            .or_insert_with(|| Value::from(Dict::new()));
+        if !matches!(entry, Value::Dict(..)) {
+            *entry = Value::from(Dict::new());
+        }
+        let Value::Dict(_, inner) = entry else {
+            unreachable!("just made sure this is a dict")
+        };
+        dict = inner;
+    }
+    dict.insert(last.clone(), value);
+}
+
+impl Provider for SchemaEnv {
+    fn metadata(&self) -> Metadata {
+        let names = self.names.clone();
+        Metadata::named(ENV_PROVIDER_NAME).interpolater(move |_, keys: &[&str]| {
+            // The value may be a parent of the key the problem is about, so
+            // fall back to the closest variable above it.
+            (0..=keys.len())
+                .rev()
+                .find_map(|n| names.get(&keys[..n].join(".")).cloned())
+                .unwrap_or_else(|| format!("{ENV_PREFIX_EXACT}{}", keys.join("__").to_uppercase()))
+        })
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment2::Error> {
+        Ok(Profile::Default.collect(self.dict.clone()))
+    }
+}
+
+/// Deserialisation stops at the first error, so extract every section on
+/// its own to find the problems in the others too.
+fn extraction_problems(figment: &Figment, full: figment2::Error, ctx: &mut Context) {
+    let sections: &[(&str, SectionCheck)] = &[
+        ("schedule", check::<Schedule>),
+        ("controller", check::<Controller>),
+        ("webhooks", check::<Webhooks>),
+        ("registry.ghcr", check::<GithubRegistry>),
+        ("registry.dockerhub", check::<DockerHubRegistry>),
+        ("registry.ecr", check::<EcrRegistry>),
+        ("registry.acr", check::<AcrRegistry>),
+        ("registry.gcr", check::<GcrRegistry>),
+        ("dispatcher.telegram", check::<Telegram>),
+        ("dispatcher.discord", check::<super::Discord>),
+        ("dispatcher.discord_webhook", check::<DiscordWebhook>),
+        ("dispatcher.teams", check::<Teams>),
+        ("dispatcher.slack", check::<Slack>),
+        ("dispatcher.gotify", check::<Gotify>),
+        ("dispatcher.email", check::<Email>),
+        ("dispatcher.ntfy", check::<Ntfy>),
+        ("dispatcher.pushover", check::<Pushover>),
+        ("dispatcher.matrix", check::<Matrix>),
+        ("dispatcher.mattermost", check::<Mattermost>),
+        ("dispatcher.rocketchat", check::<RocketChat>),
+        ("dispatcher.google_chat", check::<GoogleChat>),
+        ("dispatcher.webhook", check::<Webhook>),
+    ];
+    let mut reported = Vec::new();
+    for (section, check) in sections {
+        if figment.find_value(section).is_err() {
+            continue;
+        }
+        if let Some(errors) = check(figment, section) {
+            let prefix: Vec<String> = section.split('.').map(str::to_string).collect();
+            for e in errors {
+                push_figment_error(figment, e, &prefix, ctx);
+            }
+            reported.push(prefix);
+        }
+    }
+
+    // Whatever the sections didn't cover: top-level keys, missing sections.
+    for e in full {
+        if !reported.iter().any(|prefix| e.path.starts_with(prefix)) {
+            push_figment_error(figment, e, &[], ctx);
+        }
+    }
+}
+
+/// Deserialise one section on its own, returning its errors.
+type SectionCheck = fn(&Figment, &str) -> Option<figment2::Error>;
+
+fn check<T: DeserializeOwned>(figment: &Figment, section: &str) -> Option<figment2::Error> {
+    figment.extract_inner_lossy::<T>(section).err()
+}
+
+fn push_figment_error(figment: &Figment, e: figment2::Error, prefix: &[String], ctx: &mut Context) {
+    // `extract_inner` appends the section to the end of the error's path.
+    let inner = e.path.strip_suffix(prefix).unwrap_or(&e.path);
+    let path = [prefix, inner].concat();
+    let source = match &e.metadata {
+        Some(metadata) => ctx.source_from_metadata(metadata, &path),
+        None => ctx.source_of(figment, &path),
+    };
+    let key = (!path.is_empty()).then(|| path.join("."));
+    ctx.problems.push(ConfigProblem {
+        severity: Severity::Error,
+        source,
+        key,
+        message: e.kind.to_string(),
+    });
+}
+
+/// Checks that need the whole, well-typed config.
+fn semantic_problems(config: &Config, figment: &Figment, ctx: &mut Context) {
+    let key = |k: &str| k.split('.').map(str::to_string).collect::<Vec<_>>();
+
+    let Schedule { interval, cron } = &config.schedule;
+    match (interval, cron) {
+        (None, None) => ctx.error(
+            ctx.source_of(figment, &key("schedule")),
+            "schedule",
+            "set either `interval` (seconds) or `cron`",
+        ),
+        (Some(_), Some(_)) => {
+            let source = ctx.source_of(figment, &key("schedule.cron"));
+            ctx.error(
+                source,
+                "schedule",
+                "`interval` and `cron` are mutually exclusive, set only one",
+            );
+        }
+        (Some(0), None) => {
+            let source = ctx.source_of(figment, &key("schedule.interval"));
+            ctx.error(source, "schedule.interval", "must be at least 1 second");
+        }
+        (None, Some(cron)) if cron.upcoming(Utc).next().is_none() => {
+            let source = ctx.source_of(figment, &key("schedule.cron"));
+            ctx.error(source, "schedule.cron", "expression never fires");
+        }
+        _ => {}
+    }
+
+    for (name, value) in [
+        ("max_parallel_updates", config.max_parallel_updates),
+        ("max_parallel_pulls", config.max_parallel_pulls),
+    ] {
+        if value == 0 {
+            let source = ctx.source_of(figment, &key(name));
+            ctx.error(source, name, "must be at least 1");
+        }
+    }
+
+    if let Some(controller) = &config.controller {
+        if !matches!(controller.url.scheme(), "http" | "https") {
+            let source = ctx.source_of(figment, &key("controller.url"));
+            ctx.error(
+                source,
+                "controller.url",
+                "must be an http:// or https:// URL",
+            );
+        }
+        if let Some(ca_path) = &controller.ca_cert_path
+            && let Err(message) = super::read_ca_cert(ca_path)
+        {
+            let source = ctx.source_of(figment, &key("controller.ca_cert_path"));
+            ctx.error(source, "controller.ca_cert_path", message);
+        }
+    }
+
+    if let Some(backups) = &config.backups {
+        if !backups.directory.is_dir() {
+            let source = ctx.source_of(figment, &key("backups.directory"));
+            ctx.error(
+                source,
+                "backups.directory",
+                format!("{} is not a directory", backups.directory.display()),
+            );
+        }
+        if backups.keep_last == Some(0) {
+            let source = ctx.source_of(figment, &key("backups.keep_last"));
+            ctx.error(source, "backups.keep_last", "must be at least 1");
+        }
+        if backups.keep_days == Some(0) {
+            let source = ctx.source_of(figment, &key("backups.keep_days"));
+            ctx.error(source, "backups.keep_days", "must be at least 1");
+        }
+    }
+
+    for (name, value) in [
+        ("janitor.interval", config.janitor.interval),
+        ("janitor.max_age", config.janitor.max_age),
+    ] {
+        if value == 0 {
+            let source = ctx.source_of(figment, &key(name));
+            ctx.error(source, name, "must be at least 1");
+        }
+    }
+
+    for pattern in &config.redaction.patterns {
+        if let Err(e) = crate::redact::compile(pattern) {
+            let source = ctx.source_of(figment, &key("redaction.patterns"));
+            ctx.error(source, "redaction.patterns", format!("`{pattern}`: {e}"));
+        }
+    }
+
+    for engine in &config.engines {
+        let source = ctx.source_of(figment, &key("engines"));
+        let transport = engine.host.as_deref().map(crate::engines::Transport::parse);
+        match transport {
+            Some(Err(message)) => ctx.error(source, "engines.host", message),
+            Some(Ok(crate::engines::Transport::Tcp(_))) => {}
+            _ if engine.tls.is_some() => ctx.error(
+                source,
+                "engines.tls",
+                match &engine.host {
+                    Some(host) => format!("`{host}`: only tcp:// engines use TLS"),
+                    None => format!(
+                        "{}: only tcp:// engines use TLS",
+                        crate::engines::address(engine)
+                    ),
+                },
+            ),
+            _ => {}
+        }
+        let files = engine
+            .tls
+            .iter()
+            .flat_map(|tls| [&tls.ca, &tls.cert, &tls.key]);
+        for file in files.filter(|file| !file.is_file()) {
+            let source = ctx.source_of(figment, &key("engines"));
+            ctx.error(
+                source,
+                "engines.tls",
+                format!("{} is not a file", file.display()),
+            );
+        }
+    }
+
+    if let Some(webhooks) = &config.webhooks
+        && webhooks.listen.parse::<SocketAddr>().is_err()
+    {
+        let source = ctx.source_of(figment, &key("webhooks.listen"));
+        ctx.error(
+            source,
+            "webhooks.listen",
+            format!("`{}` is not an address like 0.0.0.0:8085", webhooks.listen),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn write(dir: &Path, contents: &str) -> PathBuf {
+        let path = dir.join("hoister.toml");
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn tempdir() -> PathBuf {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "hoister-validate-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn legacy_env_names_resolve_against_the_schema() {
+        let schema = crate::config::json_schema();
+        let root = schema.as_value();
+        let words = |s: &str| s.split('_').map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            resolve_words(root, root, &words("controller_ca_cert_path")),
+            Some(vec!["controller".into(), "ca_cert_path".into()])
+        );
+        assert_eq!(
+            resolve_words(root, root, &words("report_logs")),
+            Some(vec!["report_logs".into()])
+        );
+        assert_eq!(
+            resolve_words(root, root, &words("dispatcher_discord_webhook_webhook")),
+            Some(vec![
+                "dispatcher".into(),
+                "discord_webhook".into(),
+                "webhook".into()
+            ])
+        );
+        assert_eq!(
+            resolve_words(root, root, &words("dispatcher_webhook_headers_x_api_key")),
+            Some(vec![
+                "dispatcher".into(),
+                "webhook".into(),
+                "headers".into(),
+                "x_api_key".into()
+            ])
+        );
+        assert_eq!(resolve_words(root, root, &words("schedule_name")), None);
+    }
+
+    #[test]
+    fn collects_every_problem_with_its_source() {
+        let dir = tempdir();
+        let path = write(
+            &dir,
+            r#"auto_update = true
+colour = "blue"
+
+[schedule]
+interval = 10
+cron = "0 * * * * * *"
+
+[controller]
+url = "not a url"
+
+[dispatcher.slack]
+webhook = "also not a url"
+channel = "ops"
+"#,
+        );
+        let result = validate_with_env(
+            &path,
+            env(&[
+                ("HOISTER__CONTROLLER__CA_CERT_PATH", "/does/not/exist.pem"),
+                ("HOISTER_SOMETHING_ELSE", "1"),
+            ]),
+        );
+        let problems = result.problems;
+        let find = |key: &str| {
+            problems
+                .iter()
+                .find(|p| p.key.as_deref() == Some(key))
+                .unwrap_or_else(|| panic!("no problem for {key}: {problems:#?}"))
+        };
+
+        let unknown = find("colour");
+        assert_eq!(
+            unknown.source,
+            ProblemSource::File {
+                path: path.clone(),
+                line: Some(2)
+            }
+        );
+        let url = find("controller.url");
+        assert_eq!(
+            url.source,
+            ProblemSource::File {
+                path: path.clone(),
+                line: Some(9)
+            }
+        );
+        let webhook = find("dispatcher.slack.webhook");
+        assert_eq!(
+            webhook.source,
+            ProblemSource::File {
+                path: path.clone(),
+                line: Some(12)
+            }
+        );
+        assert!(problems.iter().any(|p| p.severity == Severity::Warning
+            && p.source == ProblemSource::Env("HOISTER_SOMETHING_ELSE".into())));
+        assert!(result.config.is_none());
+    }
+
+    #[test]
+    fn semantic_checks_name_the_env_var() {
+        let dir = tempdir();
+        let path = write(&dir, "[schedule]\ninterval = 10\n");
+        let result = validate_with_env(
+            &path,
+            env(&[
+                ("HOISTER__CONTROLLER__CA_CERT_PATH", "/does/not/exist.pem"),
+                ("HOISTER_SCHEDULE_CRON", "0 * * * * * *"),
+                ("HOISTER_MAX_PARALLEL_PULLS", "0"),
+            ]),
+        );
+        let ca = result
+            .problems
+            .iter()
+            .find(|p| p.key.as_deref() == Some("controller.ca_cert_path"))
+            .expect("unreadable CA cert is reported");
+        assert_eq!(
+            ca.source,
+            ProblemSource::Env("HOISTER__CONTROLLER__CA_CERT_PATH".into())
+        );
+        let schedule = result
+            .problems
+            .iter()
+            .find(|p| p.key.as_deref() == Some("schedule"))
+            .expect("conflicting schedule is reported");
+        assert_eq!(
+            schedule.source,
+            ProblemSource::Env("HOISTER_SCHEDULE_CRON".into())
+        );
+        let pulls = result
+            .problems
+            .iter()
+            .find(|p| p.key.as_deref() == Some("max_parallel_pulls"))
+            .expect("zero pull limit is reported");
+        assert_eq!(
+            pulls.source,
+            ProblemSource::Env("HOISTER_MAX_PARALLEL_PULLS".into())
+        );
+        assert!(result.has_errors());
+    }
+
+    #[test]
+    fn missing_schedule_is_an_error_not_a_panic() {
+        let dir = tempdir();
+        let path = write(&dir, "auto_update = false\n");
+        let result = validate_with_env(&path, env(&[("HOISTER_SCHEDULE_INTERVAL", "0")]));
+        assert!(
+            result
+                .problems
+                .iter()
+                .any(|p| p.key.as_deref() == Some("schedule.interval"))
+        );
+
+        let result = validate_with_env(&path, env(&[]));
+        assert!(result.has_errors());
+    }
+
+    #[test]
+    fn checks_engine_addresses_and_certificates() {
+        let dir = tempdir();
+        let path = write(
+            &dir,
+            r#"[schedule]
+interval = 10
+
+[[engines]]
+host = "ssh://deploy@edge-1"
+
+[[engines]]
+host = "edge-2:2376"
+
+[[engines]]
+host = "unix:///var/run/docker.sock"
+tls = { ca = "/certs/ca.pem", cert = "/certs/cert.pem", key = "/certs/key.pem" }
+
+[[engines]]
+runtime = "podman"
+
+[[engines]]
+runtime = "podman"
+tls = { ca = "/certs/ca.pem", cert = "/certs/cert.pem", key = "/certs/key.pem" }
+"#,
+        );
+        let result = validate_with_env(&path, env(&[]));
+        let messages = |key: &str| {
+            result
+                .problems
+                .iter()
+                .filter(|p| p.key.as_deref() == Some(key))
+                .map(|p| p.message.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages("engines.host"),
+            ["`edge-2:2376` is not a unix://, tcp:// or ssh:// address"]
+        );
+        let tls = messages("engines.tls");
+        assert!(tls.contains(&"`unix:///var/run/docker.sock`: only tcp:// engines use TLS"));
+        assert!(tls.contains(&"the local Podman service: only tcp:// engines use TLS"));
+        assert!(tls.contains(&"/certs/ca.pem is not a file"));
+        assert!(result.has_errors());
+    }
+
+    #[test]
+    fn reports_redaction_patterns_that_dont_compile() {
+        let dir = tempdir();
+        let path = write(
+            &dir,
+            "[schedule]\ninterval = 10\n[redaction]\npatterns = [\"LIC-[0-9]+\", \"(unclosed\"]\n",
+        );
+        let result = validate_with_env(&path, env(&[]));
+        let problems = result
+            .problems
+            .iter()
+            .filter(|p| p.key.as_deref() == Some("redaction.patterns"))
+            .collect::<Vec<_>>();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.starts_with("`(unclosed`: "));
+        assert!(result.has_errors());
+    }
+
+    #[test]
+    fn suggests_close_key() {
+        let dir = tempdir();
+        let path = write(&dir, "[schedule]\nintervall = 10\n");
+        let result = validate_with_env(&path, env(&[]));
+        let problem = result
+            .problems
+            .iter()
+            .find(|p| p.key.as_deref() == Some("schedule.intervall"))
+            .unwrap();
+        assert!(problem.message.contains("did you mean `interval`"));
+    }
+}
@@ -0,0 +1,287 @@
+//! The Docker engines the agent manages, set by `[[engines]]`.
+//!
+//! Without any, the agent manages the local engine named by `DOCKER_HOST` or
+//! the default socket. An engine may also be a Podman service, see
+//! `runtime.rs`. Each engine has its own connection, the hostname its
+//! containers are reported under and its own Compose project; `main.rs` runs
+//! an update loop, monitor and metrics collector per engine on top of the one
+//! connection.
+
+use crate::config::{Config, Engine as EngineConfig, Runtime};
+use crate::docker::get_project_name;
+use crate::runtime::{ContainerRuntime, Podman};
+use bollard::{API_DEFAULT_VERSION, Docker};
+use hoister_shared::wire::{AgentEngine, AgentRegistration};
+use hoister_shared::{AgentId, HostName, ProjectName};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Seconds a request to an engine may take, bollard's default.
+const TIMEOUT: u64 = 120;
+
+/// A connected engine.
+pub(crate) struct Engine {
+    pub(crate) docker: Arc<dyn ContainerRuntime>,
+    pub(crate) hostname: HostName,
+    pub(crate) project: ProjectName,
+}
+
+/// How an engine's `host` is reached.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Transport<'a> {
+    Unix(&'a str),
+    Tcp(&'a str),
+    Ssh(&'a str),
+}
+
+impl<'a> Transport<'a> {
+    pub(crate) fn parse(host: &'a str) -> Result<Self, String> {
+        let host = host.trim();
+        if let Some(path) = host.strip_prefix("unix://") {
+            Ok(Self::Unix(path))
+        } else if host.starts_with("tcp://") || host.starts_with("http://") {
+            Ok(Self::Tcp(host))
+        } else if host.starts_with("ssh://") {
+            Ok(Self::Ssh(host))
+        } else {
+            Err(format!(
+                "`{host}` is not a unix://, tcp:// or ssh:// address"
+            ))
+        }
+    }
+}
+
+/// Connect to every configured engine, or the local one if none are, and
+/// find out what each is reported as. Fails if any engine can't be set up.
+pub(crate) async fn connect_all(config: &Config) -> Result<Vec<Engine>, Box<dyn Error>> {
+    let mut engines = Vec::new();
+    if config.engines.is_empty() {
+        let docker: Arc<dyn ContainerRuntime> = Arc::new(Docker::connect_with_local_defaults()?);
+        let hostname = if config.hostname != HostName::default() {
+            config.hostname.clone()
+        } else {
+            ask_hostname(docker.as_ref()).await.unwrap_or_default()
+        };
+        let project = project(docker.as_ref(), config.project.as_ref())
+            .await
+            .map_err(|e| {
+                format!(
+                    "Could not determine the compose project after retrying: {e}. \
+                     Set HOISTER_PROJECT (or `project` in /hoister.toml) to configure it explicitly."
+                )
+            })?;
+        engines.push(Engine {
+            docker,
+            hostname,
+            project,
+        });
+    }
+    for engine in &config.engines {
+        let docker = connect(engine)?;
+        let address = address(engine);
+        let hostname = match &engine.hostname {
+            Some(hostname) => hostname.clone(),
+            None => ask_hostname(docker.as_ref())
+                .await
+                .ok_or_else(|| format!("{address}: could not get the engine's name"))?,
+        };
+        let project = project(
+            docker.as_ref(),
+            engine.project.as_ref().or(config.project.as_ref()),
+        )
+        .await
+        .map_err(|e| format!("{address}: {e}; set `project` for this engine"))?;
+        info!(
+            "Managing engine {address} as host {} (project {})",
+            hostname.as_str(),
+            project.as_str()
+        );
+        engines.push(Engine {
+            docker,
+            hostname,
+            project,
+        });
+    }
+    if let Some(hostname) = duplicate_hostname(&engines) {
+        return Err(format!(
+            "several engines report as host {}; set a distinct `hostname` on each",
+            hostname.as_str()
+        )
+        .into());
+    }
+    Ok(engines)
+}
+
+/// Who this agent is to the controller: the engines it manages, under the
+/// configured `agent_id` or else one derived from the engines. Each engine
+/// counts with its daemon's ID and its project, so the ID survives restarts
+/// and tells apart agents on hosts of the same name.
+pub(crate) async fn registration(config: &Config, engines: &[Engine]) -> AgentRegistration {
+    let agent_id = match &config.agent_id {
+        Some(agent_id) => agent_id.clone(),
+        None => {
+            let mut keys = Vec::new();
+            for engine in engines {
+                let daemon = match engine.docker.info().await {
+                    Ok(info) => info.id.filter(|id| !id.is_empty()),
+                    Err(e) => {
+                        warn!("docker info failed: {e}");
+                        None
+                    }
+                };
+                let daemon = daemon.unwrap_or_else(|| engine.hostname.as_str().to_string());
+                keys.push(format!("{daemon}/{}", engine.project.as_str()));
+            }
+            derive_agent_id(keys)
+        }
+    };
+    AgentRegistration {
+        agent_id,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        engines: engines
+            .iter()
+            .map(|engine| AgentEngine {
+                hostname: engine.hostname.clone(),
+                project_name: engine.project.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// A short hash of the engines' `daemon/project` keys, in any order.
+fn derive_agent_id(mut keys: Vec<String>) -> AgentId {
+    keys.sort();
+    let digest = Sha256::digest(keys.join("\n"));
+    AgentId::new(
+        digest[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>(),
+    )
+}
+
+/// Open a connection to `engine`. Nothing is sent until it is first used.
+fn connect(engine: &EngineConfig) -> Result<Arc<dyn ContainerRuntime>, Box<dyn Error>> {
+    let host = match (&engine.host, engine.runtime) {
+        (Some(host), _) => host.clone(),
+        (None, Runtime::Docker) => return Ok(Arc::new(Docker::connect_with_local_defaults()?)),
+        (None, Runtime::Podman) => local_podman_host(),
+    };
+    let docker = match (Transport::parse(&host)?, &engine.tls) {
+        (Transport::Unix(path), _) => {
+            Docker::connect_with_unix(path, TIMEOUT, API_DEFAULT_VERSION)?
+        }
+        (Transport::Tcp(host), Some(tls)) => {
+            // Workspace builds enable both rustls backends, and rustls won't
+            // pick one by itself. Fails harmlessly if one is installed already.
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            Docker::connect_with_ssl(
+                host,
+                &tls.key,
+                &tls.cert,
+                &tls.ca,
+                TIMEOUT,
+                API_DEFAULT_VERSION,
+            )?
+        }
+        (Transport::Tcp(host), None) => {
+            Docker::connect_with_http(host, TIMEOUT, API_DEFAULT_VERSION)?
+        }
+        (Transport::Ssh(host), _) => Docker::connect_with_ssh(host, TIMEOUT, API_DEFAULT_VERSION)?,
+    };
+    Ok(match engine.runtime {
+        Runtime::Docker => Arc::new(docker),
+        Runtime::Podman => Arc::new(Podman::new(docker)),
+    })
+}
+
+/// Where the local Podman service is: `CONTAINER_HOST` as the `podman
+/// --remote` client reads it, or else its socket.
+fn local_podman_host() -> String {
+    std::env::var("CONTAINER_HOST").unwrap_or_else(|_| {
+        let xdg_runtime_dir = std::env::var("XDG_RUNTIME_DIR").ok();
+        let socket = Podman::local_socket(xdg_runtime_dir.as_deref(), Path::exists);
+        format!("unix://{}", socket.display())
+    })
+}
+
+/// How `engine` is named in messages.
+pub(crate) fn address(engine: &EngineConfig) -> &str {
+    match (&engine.host, engine.runtime) {
+        (Some(host), _) => host,
+        (None, Runtime::Docker) => "the local Docker engine",
+        (None, Runtime::Podman) => "the local Podman service",
+    }
+}
+
+/// The engine's own name, as `docker info --format '{{.Name}}'` prints it.
+pub(crate) async fn ask_hostname(docker: &dyn ContainerRuntime) -> Option<HostName> {
+    match docker.info().await {
+        Ok(info) => info.name.filter(|n| !n.is_empty()).map(HostName::new),
+        Err(e) => {
+            warn!("docker info failed: {e}");
+            None
+        }
+    }
+}
+
+async fn project(
+    docker: &dyn ContainerRuntime,
+    configured: Option<&ProjectName>,
+) -> Result<ProjectName, Box<dyn Error>> {
+    match configured {
+        Some(project) => Ok(project.clone()),
+        None => get_project_name(docker).await,
+    }
+}
+
+fn duplicate_hostname(engines: &[Engine]) -> Option<&HostName> {
+    let mut seen = HashSet::new();
+    engines
+        .iter()
+        .find(|engine| !seen.insert(&engine.hostname))
+        .map(|engine| &engine.hostname)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hosts() {
+        assert_eq!(
+            Transport::parse("unix:///var/run/docker.sock"),
+            Ok(Transport::Unix("/var/run/docker.sock"))
+        );
+        assert_eq!(
+            Transport::parse("tcp://10.0.0.2:2376"),
+            Ok(Transport::Tcp("tcp://10.0.0.2:2376"))
+        );
+        assert_eq!(
+            Transport::parse(" ssh://deploy@edge-1"),
+            Ok(Transport::Ssh("ssh://deploy@edge-1"))
+        );
+        assert!(Transport::parse("/var/run/docker.sock").is_err());
+        assert!(Transport::parse("npipe:////./pipe/docker_engine").is_err());
+    }
+
+    #[test]
+    fn agent_ids_depend_on_engines_not_their_order() {
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+        let id = derive_agent_id(keys(&["daemon-a/shop", "daemon-b/shop"]));
+        assert_eq!(id.as_str().len(), 16);
+        assert_eq!(
+            id,
+            derive_agent_id(keys(&["daemon-b/shop", "daemon-a/shop"]))
+        );
+        // The same hostname on another daemon is another agent.
+        assert_ne!(
+            id,
+            derive_agent_id(keys(&["daemon-c/shop", "daemon-b/shop"]))
+        );
+    }
+}
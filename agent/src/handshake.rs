@@ -0,0 +1,147 @@
+//! The agent's self-description, sent to the controller per managed host.
+//!
+//! Sent before every SSE connection attempt, so the controller learns about
+//! the agent when it starts and again whenever it reconnects. The controller
+//! keeps the last one per host; the dashboard uses it to flag outdated agents
+//! and to hide actions, e.g. log requests, that the agent would ignore.
+
+use crate::config::Config;
+use crate::runtime::ContainerRuntime;
+use crate::scheduler::describe_global;
+use bollard::models::SystemInfo;
+use hoister_shared::wire::{AgentFeatures, EngineInfo, PostAgentInfo};
+use hoister_shared::{AgentId, HostName};
+use log::warn;
+use reqwest::Client;
+use std::sync::Arc;
+use tokio::sync::watch;
+use url::Url;
+
+pub(crate) struct Handshake {
+    /// The managed engines, under the hostname each is reported as.
+    engines: Vec<(HostName, Arc<dyn ContainerRuntime>)>,
+    agent_id: AgentId,
+    /// Read on every send, so a reloaded schedule is reported on reconnect.
+    config_rx: watch::Receiver<Arc<Config>>,
+    controller_url: Url,
+    token: Option<String>,
+}
+
+impl Handshake {
+    pub(crate) fn new(
+        engines: Vec<(HostName, Arc<dyn ContainerRuntime>)>,
+        agent_id: AgentId,
+        config_rx: watch::Receiver<Arc<Config>>,
+        controller_url: Url,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            engines,
+            agent_id,
+            config_rx,
+            controller_url,
+            token,
+        }
+    }
+
+    /// POST the agent's info for every engine. Failures are only logged: the
+    /// agent works without it, the dashboard just knows less about it.
+    pub(crate) async fn send(&self, client: &Client) {
+        for (hostname, docker) in &self.engines {
+            let engine = match docker.info().await {
+                Ok(info) => engine_info(info),
+                Err(e) => {
+                    warn!("docker info failed for {}: {e}", hostname.as_str());
+                    EngineInfo::default()
+                }
+            };
+            let info = agent_info(&self.config_rx.borrow(), self.agent_id.clone(), engine);
+            if let Err(e) = self.post(client, hostname, &info).await {
+                warn!("Failed to send agent info for {}: {e}", hostname.as_str());
+            }
+        }
+    }
+
+    async fn post(
+        &self,
+        client: &Client,
+        hostname: &HostName,
+        info: &PostAgentInfo,
+    ) -> Result<(), reqwest::Error> {
+        let url = self
+            .controller_url
+            .join(&format!("agent-info/{}", hostname.as_str()))
+            .expect("controller agent-info URL should be valid");
+        let mut req = client.post(url).json(info);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn agent_info(config: &Config, agent_id: AgentId, engine: EngineInfo) -> PostAgentInfo {
+    PostAgentInfo {
+        agent_id,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        schedule: describe_global(&config.schedule),
+        features: AgentFeatures {
+            auto_update: config.auto_update,
+            report_logs: config.report_logs,
+            report_metrics: config.report_metrics,
+        },
+        engine,
+    }
+}
+
+fn engine_info(info: SystemInfo) -> EngineInfo {
+    EngineInfo {
+        version: info.server_version,
+        os: info.operating_system,
+        kernel_version: info.kernel_version,
+        arch: info.architecture,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use crate::config::load_config;
+
+    #[test]
+    fn reports_features_and_schedule_from_the_config() {
+        use figment2::Jail;
+        Jail::expect_with(|jail: &mut Jail| {
+            jail.create_file(
+                "config-test.toml",
+                "auto_update=false\nreport_logs=true\n[schedule]\ninterval=300\n",
+            )?;
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let config = rt
+                .block_on(load_config("config-test.toml".as_ref()))
+                .unwrap();
+            let engine = engine_info(SystemInfo {
+                server_version: Some("27.3.1".to_string()),
+                ..Default::default()
+            });
+            let info = agent_info(&config, AgentId::new("a1"), engine);
+
+            assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(info.schedule.as_deref(), Some("every 5m"));
+            assert_eq!(
+                info.features,
+                AgentFeatures {
+                    auto_update: false,
+                    report_logs: true,
+                    report_metrics: true,
+                }
+            );
+            assert_eq!(info.engine.version.as_deref(), Some("27.3.1"));
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,403 @@
+//! Periodic removal of what interrupted or failed updates leave behind.
+//!
+//! Hoister labels what an update creates: backup volumes carry
+//! `hoister.artifact=volume-backup`, and the new container names the old
+//! container it replaces (`hoister.replaces`) and the image it is meant to
+//! remove (`hoister.stale-image`). The update normally removes all of them
+//! itself. If it is interrupted, or a removal fails, a sweep finds them by
+//! these labels once they are older than `[janitor] max_age` and removes
+//! them, unless an update in progress recorded them in the `Journal`. What was
+//! reclaimed is reported to the controller.
+
+use crate::HoisterError;
+use crate::config::{Config, Controller};
+use crate::docker::{DockerHandler, REMOVE_OPTIONS};
+use crate::reload;
+use bollard::models::{ContainerSummaryStateEnum, SystemDataUsageResponse};
+use bollard::query_parameters::{DataUsageOptions, RemoveImageOptions, RemoveVolumeOptions};
+use chrono::{DateTime, Utc};
+use hoister_shared::wire::PostCleanupReport;
+use hoister_shared::{HostName, ProjectName};
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+pub(crate) const ARTIFACT_LABEL: &str = "hoister.artifact";
+pub(crate) const VOLUME_BACKUP: &str = "volume-backup";
+/// On a backup volume: the volume it is a copy of.
+pub(crate) const BACKUP_OF_LABEL: &str = "hoister.backup-of";
+/// On a container created by an update: the ID of the container it replaced.
+pub(crate) const REPLACES_LABEL: &str = "hoister.replaces";
+/// On a container created by an update: the image the update removes once the
+/// container is healthy.
+pub(crate) const STALE_IMAGE_LABEL: &str = "hoister.stale-image";
+
+/// Artifacts (volume names, container and image IDs) of updates in progress,
+/// which sweeps leave alone.
+#[derive(Default)]
+pub(crate) struct Journal(Mutex<HashSet<String>>);
+
+impl Journal {
+    /// Start recording the artifacts of one update. They are released when
+    /// the entry is dropped.
+    pub(crate) fn entry(&self) -> JournalEntry<'_> {
+        JournalEntry {
+            journal: self,
+            artifacts: Vec::new(),
+        }
+    }
+
+    fn contains(&self, artifact: &str) -> bool {
+        self.0.lock().expect("journal poisoned").contains(artifact)
+    }
+}
+
+pub(crate) struct JournalEntry<'a> {
+    journal: &'a Journal,
+    artifacts: Vec<String>,
+}
+
+impl JournalEntry<'_> {
+    pub(crate) fn record(&mut self, artifact: &str) {
+        self.journal
+            .0
+            .lock()
+            .expect("journal poisoned")
+            .insert(artifact.to_string());
+        self.artifacts.push(artifact.to_string());
+    }
+}
+
+impl Drop for JournalEntry<'_> {
+    fn drop(&mut self) {
+        let mut journal = self.journal.0.lock().expect("journal poisoned");
+        for artifact in &self.artifacts {
+            journal.remove(artifact);
+        }
+    }
+}
+
+/// What a sweep removes, each with the size Docker reports for it.
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    volumes: Vec<(String, u64)>,
+    containers: Vec<(String, u64)>,
+    images: Vec<(String, u64)>,
+}
+
+/// Pick the labelled artifacts in `usage` that were left before `cutoff` and
+/// are not in the journal.
+fn plan(usage: &SystemDataUsageResponse, journal: &Journal, cutoff: DateTime<Utc>) -> Plan {
+    let size = |bytes: Option<i64>| bytes.unwrap_or(0).max(0) as u64;
+    let mut plan = Plan::default();
+
+    for volume in usage.volumes.iter().flatten() {
+        let created = volume
+            .created_at
+            .as_deref()
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok());
+        if volume.labels.get(ARTIFACT_LABEL).map(String::as_str) == Some(VOLUME_BACKUP)
+            && created.is_some_and(|c| c < cutoff)
+            && !journal.contains(&volume.name)
+        {
+            let bytes = volume.usage_data.as_ref().map(|u| u.size);
+            plan.volumes.push((volume.name.clone(), size(bytes)));
+        }
+    }
+
+    let containers = usage.containers.as_deref().unwrap_or_default();
+    // Containers created by an update long enough ago for the update to be
+    // over, and what they name as left behind.
+    let replacers: Vec<_> = containers
+        .iter()
+        .filter(|c| c.created.is_some_and(|t| t < cutoff.timestamp()))
+        .filter_map(|c| c.labels.as_ref())
+        .collect();
+
+    for replaced in replacers.iter().filter_map(|l| l.get(REPLACES_LABEL)) {
+        let Some(old) = containers.iter().find(|c| c.id.as_ref() == Some(replaced)) else {
+            continue;
+        };
+        if old.state != Some(ContainerSummaryStateEnum::RUNNING) && !journal.contains(replaced) {
+            plan.containers.push((replaced.clone(), size(old.size_rw)));
+        }
+    }
+
+    for stale in replacers.iter().filter_map(|l| l.get(STALE_IMAGE_LABEL)) {
+        let Some(image) = usage.images.iter().flatten().find(|i| &i.id == stale) else {
+            continue;
+        };
+        // Old containers removed in this sweep no longer count as users.
+        let in_use = containers.iter().any(|c| {
+            c.image_id.as_ref() == Some(stale)
+                && !plan
+                    .containers
+                    .iter()
+                    .any(|(id, _)| c.id.as_ref() == Some(id))
+        });
+        if !in_use && !journal.contains(stale) && !plan.images.iter().any(|(id, _)| id == stale) {
+            plan.images.push((stale.clone(), size(Some(image.size))));
+        }
+    }
+    plan
+}
+
+/// Remove the artifacts left over for longer than `max_age`.
+async fn sweep(
+    docker: &DockerHandler,
+    max_age: Duration,
+) -> Result<PostCleanupReport, HoisterError> {
+    let usage = docker.docker.df(None::<DataUsageOptions>).await?;
+    let cutoff = Utc::now() - max_age;
+    let plan = plan(&usage, &docker.journal, cutoff);
+    let mut report = PostCleanupReport::default();
+
+    for (name, bytes) in plan.volumes {
+        match docker
+            .docker
+            .remove_volume(&name, None::<RemoveVolumeOptions>)
+            .await
+        {
+            Ok(()) => {
+                info!("Removed leftover backup volume {name}");
+                report.volumes += 1;
+                report.reclaimed_bytes += bytes;
+            }
+            Err(e) => warn!("Failed to remove leftover backup volume {name}: {e}"),
+        }
+    }
+    // Containers before images: the old container still uses the old image.
+    for (id, bytes) in plan.containers {
+        match docker
+            .docker
+            .remove_container(&id, Some(REMOVE_OPTIONS))
+            .await
+        {
+            Ok(()) => {
+                info!("Removed replaced container {id}");
+                report.containers += 1;
+                report.reclaimed_bytes += bytes;
+            }
+            Err(e) => warn!("Failed to remove replaced container {id}: {e}"),
+        }
+    }
+    for (id, bytes) in plan.images {
+        match docker
+            .docker
+            .remove_image(&id, None::<RemoveImageOptions>, None)
+            .await
+        {
+            Ok(_) => {
+                info!("Removed replaced image {id}");
+                report.images += 1;
+                report.reclaimed_bytes += bytes;
+            }
+            Err(e) => debug!("Replaced image {id} stays: {e}"),
+        }
+    }
+    Ok(report)
+}
+
+async fn send_to_controller(
+    client: &reqwest::Client,
+    controller: &Controller,
+    hostname: &HostName,
+    project: &ProjectName,
+    report: &PostCleanupReport,
+) -> Result<(), reqwest::Error> {
+    let url = controller
+        .url
+        .join(format!("cleanup/{}/{}", hostname.0, project.0).as_str())
+        .expect("failed to join url");
+    let mut req = client.post(url).json(report);
+    if let Some(token) = &controller.token {
+        req = req.bearer_auth(token);
+    }
+    req.send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Sweep every `[janitor] interval` seconds until the process exits.
+pub(crate) async fn start(
+    docker: Arc<DockerHandler>,
+    mut config_rx: watch::Receiver<Arc<Config>>,
+    project: ProjectName,
+    client: reqwest::Client,
+) {
+    let mut next = Instant::now();
+    loop {
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        let janitor = &config.janitor;
+        if janitor.enabled && Instant::now() >= next {
+            next = Instant::now() + Duration::from_secs(janitor.interval);
+            match sweep(&docker, Duration::from_secs(janitor.max_age)).await {
+                Ok(report) if report == PostCleanupReport::default() => {
+                    debug!("Janitor found nothing to remove");
+                }
+                Ok(report) => {
+                    info!(
+                        "Janitor removed {} volumes, {} containers and {} images ({} bytes)",
+                        report.volumes, report.containers, report.images, report.reclaimed_bytes
+                    );
+                    if let Some(controller) = &config.controller
+                        && let Err(e) = send_to_controller(
+                            &client,
+                            controller,
+                            docker.hostname(),
+                            &project,
+                            &report,
+                        )
+                        .await
+                    {
+                        warn!("Failed to report cleanup to the controller: {e}");
+                    }
+                }
+                Err(e) => warn!("Janitor sweep failed: {e}"),
+            }
+        }
+        let wait = if janitor.enabled {
+            next.saturating_duration_since(Instant::now())
+        } else {
+            Duration::from_secs(janitor.interval)
+        };
+        reload::sleep_or_reload(&mut config_rx, wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{ContainerSummary, ImageSummary, Volume};
+    use std::collections::HashMap;
+
+    const OLD: i64 = 1_000;
+    const CUTOFF: i64 = 2_000;
+    const NEW: i64 = 3_000;
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn backup_volume(name: &str, created: i64) -> Volume {
+        Volume {
+            name: name.to_string(),
+            created_at: Some(at(created).to_rfc3339()),
+            labels: HashMap::from([(ARTIFACT_LABEL.to_string(), VOLUME_BACKUP.to_string())]),
+            ..Default::default()
+        }
+    }
+
+    fn container(
+        id: &str,
+        image: &str,
+        created: i64,
+        state: ContainerSummaryStateEnum,
+        labels: &[(&str, &str)],
+    ) -> ContainerSummary {
+        ContainerSummary {
+            id: Some(id.to_string()),
+            image_id: Some(image.to_string()),
+            created: Some(created),
+            state: Some(state),
+            size_rw: Some(10),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn image(id: &str) -> ImageSummary {
+        ImageSummary {
+            id: id.to_string(),
+            size: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plans_old_unjournaled_artifacts() {
+        use ContainerSummaryStateEnum::{EXITED, RUNNING};
+        let usage = SystemDataUsageResponse {
+            volumes: Some(vec![
+                backup_volume("db-backup-1", OLD),
+                backup_volume("db-backup-2", NEW),
+                backup_volume("journaled", OLD),
+                Volume {
+                    name: "db".to_string(),
+                    created_at: Some(at(OLD).to_rfc3339()),
+                    ..Default::default()
+                },
+            ]),
+            containers: Some(vec![
+                container("old", "sha256:a", OLD, EXITED, &[]),
+                container(
+                    "new",
+                    "sha256:b",
+                    OLD,
+                    RUNNING,
+                    &[(REPLACES_LABEL, "old"), (STALE_IMAGE_LABEL, "sha256:a")],
+                ),
+                // Replaced a moment ago: the update may still be running.
+                container("other-old", "sha256:c", OLD, EXITED, &[]),
+                container(
+                    "other-new",
+                    "sha256:d",
+                    NEW,
+                    RUNNING,
+                    &[
+                        (REPLACES_LABEL, "other-old"),
+                        (STALE_IMAGE_LABEL, "sha256:c"),
+                    ],
+                ),
+            ]),
+            images: Some(vec![image("sha256:a"), image("sha256:c")]),
+            ..Default::default()
+        };
+        let journal = Journal::default();
+        let mut entry = journal.entry();
+        entry.record("journaled");
+
+        let plan = plan(&usage, &journal, at(CUTOFF));
+        assert_eq!(
+            plan,
+            Plan {
+                volumes: vec![("db-backup-1".to_string(), 0)],
+                containers: vec![("old".to_string(), 10)],
+                images: vec![("sha256:a".to_string(), 100)],
+            }
+        );
+
+        drop(entry);
+        assert!(!journal.contains("journaled"));
+    }
+
+    #[test]
+    fn keeps_images_other_containers_use() {
+        use ContainerSummaryStateEnum::RUNNING;
+        let usage = SystemDataUsageResponse {
+            containers: Some(vec![
+                container("sidecar", "sha256:a", OLD, RUNNING, &[]),
+                container(
+                    "new",
+                    "sha256:b",
+                    OLD,
+                    RUNNING,
+                    &[(REPLACES_LABEL, "gone"), (STALE_IMAGE_LABEL, "sha256:a")],
+                ),
+            ]),
+            images: Some(vec![image("sha256:a")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            plan(&usage, &Journal::default(), at(CUTOFF)),
+            Plan::default()
+        );
+    }
+}
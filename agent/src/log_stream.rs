@@ -0,0 +1,266 @@
+//! Live log streams: follow a container's logs and send them to the controller
+//! in chunks, for the dashboard's `docker logs -f` view.
+//!
+//! Chunks go out about once a second while the container is writing, and
+//! empty ones every few seconds while it's quiet. The controller answers a
+//! chunk with `410 Gone` once the stream was cancelled or nobody watches it
+//! anymore, which is how the agent learns to stop. Only whole lines are
+//! redacted and sent, so a secret can't escape redaction by being split
+//! across two chunks.
+
+use bollard::container::LogOutput;
+use futures_util::{Stream, StreamExt};
+use hoister_shared::wire::{CommandStatus, PostLogStreamChunk};
+use std::time::Duration;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// How often buffered lines are sent.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the agent goes without sending before it sends an empty chunk,
+/// to find out whether the stream was cancelled.
+const KEEPALIVE: Duration = Duration::from_secs(5);
+/// Output buffered beyond this is sent right away, even without a newline.
+const MAX_CHUNK_BYTES: usize = 64 * 1024;
+
+/// What the controller made of a chunk.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Delivery {
+    Accepted,
+    /// The stream is over; stop following.
+    Gone,
+    /// Not delivered, e.g. the controller is unreachable. The stream goes on.
+    Failed(String),
+}
+
+/// Why a stream ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum StreamEnd {
+    TimeLimit,
+    Cancelled,
+    /// The container stopped, so its logs ended.
+    LogsEnded,
+    Failed(String),
+}
+
+impl StreamEnd {
+    /// What to report for the stream's command.
+    pub(crate) fn status(self) -> (CommandStatus, Option<String>) {
+        match self {
+            Self::TimeLimit => (
+                CommandStatus::Succeeded,
+                Some("reached the time limit".to_string()),
+            ),
+            Self::Cancelled => (CommandStatus::Succeeded, Some("cancelled".to_string())),
+            Self::LogsEnded => (
+                CommandStatus::Succeeded,
+                Some("the container stopped".to_string()),
+            ),
+            Self::Failed(detail) => (CommandStatus::Failed, Some(detail)),
+        }
+    }
+}
+
+/// Output read but not sent yet.
+#[derive(Default)]
+struct Pending {
+    bytes: Vec<u8>,
+}
+
+impl Pending {
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// The complete lines, or everything once a line grows past
+    /// `MAX_CHUNK_BYTES`.
+    fn take_lines(&mut self) -> Option<String> {
+        let end = match self.bytes.iter().rposition(|&b| b == b'\n') {
+            _ if self.bytes.len() >= MAX_CHUNK_BYTES => self.bytes.len(),
+            Some(newline) => newline + 1,
+            None => return None,
+        };
+        let rest = self.bytes.split_off(end);
+        let lines = std::mem::replace(&mut self.bytes, rest);
+        Some(String::from_utf8_lossy(&lines).into_owned())
+    }
+
+    fn take_all(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.bytes)).into_owned()
+    }
+}
+
+/// Send `logs` through `send` until the stream is cancelled, the logs end or
+/// `limit` passes. Every chunk goes through `redact` first.
+pub(crate) async fn follow<S, R, F, Fut>(
+    mut logs: S,
+    redact: R,
+    limit: Duration,
+    mut send: F,
+) -> StreamEnd
+where
+    S: Stream<Item = Result<LogOutput, bollard::errors::Error>> + Unpin,
+    R: Fn(&mut String),
+    F: FnMut(PostLogStreamChunk) -> Fut,
+    Fut: Future<Output = Delivery>,
+{
+    let deadline = time::sleep(limit);
+    tokio::pin!(deadline);
+    let mut flush = time::interval(FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut pending = Pending::default();
+    let mut last_sent = Instant::now();
+
+    let end = loop {
+        let lines = tokio::select! {
+            _ = &mut deadline => break StreamEnd::TimeLimit,
+            output = logs.next() => match output {
+                Some(Ok(output)) => {
+                    pending.push(&output.into_bytes());
+                    if pending.bytes.len() < MAX_CHUNK_BYTES {
+                        continue;
+                    }
+                    pending.take_lines()
+                }
+                Some(Err(e)) => break StreamEnd::Failed(format!("reading the logs failed: {e}")),
+                None => break StreamEnd::LogsEnded,
+            },
+            _ = flush.tick() => pending.take_lines(),
+        };
+        let logs = match lines {
+            Some(lines) => lines,
+            None if last_sent.elapsed() >= KEEPALIVE => String::new(),
+            None => continue,
+        };
+        last_sent = Instant::now();
+        if let Some(end) = send_chunk(&mut send, &redact, logs, false).await {
+            break end;
+        }
+    };
+
+    // Whatever is left goes out with the last chunk, unless the controller
+    // already said the stream is over.
+    if end != StreamEnd::Cancelled {
+        let logs = pending.take_all();
+        if let Some(StreamEnd::Cancelled) = send_chunk(&mut send, &redact, logs, true).await {
+            return StreamEnd::Cancelled;
+        }
+    }
+    end
+}
+
+/// Redact and send one chunk. `Some` when the stream should end.
+async fn send_chunk<R, F, Fut>(
+    send: &mut F,
+    redact: &R,
+    mut logs: String,
+    end: bool,
+) -> Option<StreamEnd>
+where
+    R: Fn(&mut String),
+    F: FnMut(PostLogStreamChunk) -> Fut,
+    Fut: Future<Output = Delivery>,
+{
+    redact(&mut logs);
+    match send(PostLogStreamChunk { logs, end }).await {
+        Delivery::Accepted => None,
+        Delivery::Gone => Some(StreamEnd::Cancelled),
+        Delivery::Failed(e) => {
+            log::warn!("Failed to send log chunk: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures_util::stream;
+    use std::sync::{Arc, Mutex};
+
+    fn output(text: &str) -> Result<LogOutput, bollard::errors::Error> {
+        Ok(LogOutput::StdOut {
+            message: Bytes::from(text.to_string()),
+        })
+    }
+
+    fn recorder(
+        delivery: impl Fn() -> Delivery,
+    ) -> (
+        Arc<Mutex<Vec<PostLogStreamChunk>>>,
+        impl FnMut(PostLogStreamChunk) -> std::future::Ready<Delivery>,
+    ) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let record = sent.clone();
+        let send = move |chunk| {
+            record.lock().unwrap().push(chunk);
+            std::future::ready(delivery())
+        };
+        (sent, send)
+    }
+
+    #[tokio::test]
+    async fn lines_split_across_outputs_are_redacted_whole() {
+        let logs = stream::iter(vec![
+            output("token=hunter"),
+            output("2hunter2\nnext "),
+            output("line\n"),
+        ]);
+        let redact = |text: &mut String| *text = text.replace("hunter2hunter2", "***");
+        let (sent, send) = recorder(|| Delivery::Accepted);
+
+        let end = follow(logs, redact, Duration::from_secs(60), send).await;
+
+        assert_eq!(end, StreamEnd::LogsEnded);
+        let sent = sent.lock().unwrap();
+        let text: String = sent.iter().map(|chunk| chunk.logs.as_str()).collect();
+        assert_eq!(text, "token=***\nnext line\n");
+        assert!(sent.last().unwrap().end);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_streams_stop_when_the_controller_says_so() {
+        let (sent, send) = recorder(|| Delivery::Gone);
+
+        let end = follow(
+            stream::pending(),
+            |_: &mut String| {},
+            Duration::from_secs(60),
+            send,
+        )
+        .await;
+
+        assert_eq!(end, StreamEnd::Cancelled);
+        // One empty keepalive chunk, and nothing after the 410.
+        assert_eq!(*sent.lock().unwrap(), vec![PostLogStreamChunk::default()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_end_at_the_time_limit() {
+        let (sent, send) = recorder(|| Delivery::Failed("unreachable".to_string()));
+
+        let end = follow(
+            stream::pending(),
+            |_: &mut String| {},
+            Duration::from_secs(12),
+            send,
+        )
+        .await;
+
+        assert_eq!(end, StreamEnd::TimeLimit);
+        // Undelivered keepalives don't end the stream; the last chunk says it ends.
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.last().unwrap().end);
+    }
+
+    #[test]
+    fn long_lines_are_sent_without_waiting_for_the_newline() {
+        let mut pending = Pending::default();
+        pending.push(b"partial");
+        assert_eq!(pending.take_lines(), None);
+        pending.push(&vec![b'x'; MAX_CHUNK_BYTES]);
+        assert_eq!(pending.take_lines().unwrap().len(), MAX_CHUNK_BYTES + 7);
+        assert!(pending.bytes.is_empty());
+    }
+}
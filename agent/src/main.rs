@@ -1,11 +1,27 @@
 //! Fetch info of all running containers concurrently
+mod backup;
+#[cfg(feature = "cli")]
+mod cli;
 mod config;
 mod docker;
 mod ecr;
+mod engines;
+mod handshake;
+mod janitor;
+mod log_stream;
 mod metrics;
 mod monitor;
 mod notifications;
+mod outbox;
+mod redact;
+mod reload;
+mod restarts;
+mod runtime;
+mod scheduler;
+mod selection;
+mod self_update;
 mod sse;
+mod webhook;
 
 use bollard::Docker;
 
@@ -16,10 +32,12 @@ use log::{debug, error, info, warn};
 
 use bollard::errors::Error as BollardError;
 
-use crate::docker::{ContainerID, DockerHandler, get_project_name};
+use crate::docker::{ContainerID, DockerHandler, get_service_identifier};
+use crate::scheduler::{Due, Scheduler, ServiceSchedule, in_waves};
 use bollard::models::ContainerCreateResponse;
+use chrono::Utc;
 use env_logger::Env;
-use futures_util::StreamExt;
+use futures_util::{StreamExt, future, stream};
 use std::collections::HashMap;
 use std::default::Default;
 
@@ -30,45 +48,41 @@ use thiserror::Error;
 use tokio::time::sleep;
 
 use crate::notifications::{
-    DeploymentResultHandler, send_pending_update_to_controller, setup_dispatcher,
-    start_notification_handler,
+    DeploymentResultHandler, send_pending_update_to_controller, start_notification_handler,
 };
 
+use crate::outbox::Outbox;
 use crate::sse::SSEHandler;
 use hoister_shared::ProjectName;
+use hoister_shared::wire::PostPendingUpdate;
 use std::error::Error;
 use std::path::PathBuf;
 #[allow(unused_imports)]
 use std::{env, process};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 /// Default config path, used when no `--config` is given on the command line and
 /// in the container build. A missing file is tolerated: configuration can be
 /// supplied entirely through `HOISTER_*` environment variables.
 const DEFAULT_CONFIG_PATH: &str = "/hoister.toml";
 
-/// Hoister agent — periodically checks for newer container images and updates
-/// the running containers, rolling back on failure.
-#[cfg(feature = "cli")]
-#[derive(Parser, Debug)]
-#[command(name = "hoister", version, about)]
-struct Cli {
-    /// Path to the TOML configuration file. Optional: configuration can also be
-    /// supplied entirely through HOISTER_* environment variables.
-    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
-    config: PathBuf,
-}
+/// How long an engine's update loop waits after a failed pass.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
 
-/// Resolve the config path. With the `cli` feature this comes from the
-/// command line; in the container build (feature off) it is the fixed path.
+/// Parse the command line. With the `cli` feature this yields the config path
+/// and an optional operator subcommand with the engine it targets; in the
+/// container build (feature off) the config path is fixed and the agent always
+/// runs.
 #[cfg(feature = "cli")]
-fn config_path() -> PathBuf {
-    Cli::parse().config
+fn parse_args() -> (PathBuf, Option<(cli::Command, Option<String>)>) {
+    let cli = cli::Cli::parse();
+    let engine = cli.engine;
+    (cli.config, cli.command.map(|command| (command, engine)))
 }
 
 #[cfg(not(feature = "cli"))]
-fn config_path() -> PathBuf {
-    PathBuf::from(DEFAULT_CONFIG_PATH)
+fn parse_args() -> (PathBuf, Option<std::convert::Infallible>) {
+    (PathBuf::from(DEFAULT_CONFIG_PATH), None)
 }
 
 #[derive(Debug, Error)]
@@ -85,68 +99,91 @@ enum HoisterError {
     ProjectNameDetectionFailed,
     #[error("ECR authentication failed: {0}")]
     EcrAuth(String),
+    #[error("backup failed: {0}")]
+    Backup(String),
+    #[error("{0} failed its health check after a restart: {1}")]
+    RestartFailed(String, String),
+    #[error("an update of this service is already running")]
+    UpdateInProgress,
+    #[cfg(feature = "cli")]
+    #[error("no previous image recorded for {0}; pass --to <digest>")]
+    NoRollbackTarget(String),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     // Resolve the config path first so `-h`/`--help` and `--version` work
     // (when the `cli` feature is on) without touching Docker or the config file.
-    let config_path = config_path();
+    let (config_path, command) = parse_args();
 
     #[cfg(target_os = "linux")]
     set_group_id();
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // Operator subcommands print their own results to stdout; keep the log
+    // output on stderr down to problems.
+    let default_filter = if command.is_some() { "warn" } else { "info" };
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_filter)).init();
+
+    // Started by the agent itself as the helper container of a self-update.
+    if let Ok(agent_id) = env::var(self_update::HELPER_ENV) {
+        process::exit(self_update::run_helper(&config_path, agent_id).await);
+    }
+
+    #[cfg(feature = "cli")]
+    if let Some((command, engine)) = command {
+        process::exit(cli::run(command, engine.as_deref(), &config_path).await);
+    }
 
     // The config file is optional: figment merges in `HOISTER_*` env vars and
     // defaults, so a missing file is fine and the agent can run from env alone.
-    let mut config = config::load_config(&config_path).await;
-
-    // If no hostname was configured, ask the Docker daemon for the host's name
-    // (equivalent to `docker info --format '{{.Name}}'`). This gives a stable,
-    // human-readable identifier without requiring manual config.
-    if config.hostname == hoister_shared::HostName::default() {
-        match Docker::connect_with_local_defaults() {
-            Ok(docker) => match docker.info().await {
-                Ok(info) => {
-                    if let Some(name) = info.name.filter(|n| !n.is_empty()) {
-                        config.hostname = hoister_shared::HostName::new(name);
-                    }
-                }
-                Err(e) => warn!("docker info failed, hostname stays 'undefined': {e}"),
-            },
-            Err(e) => warn!("could not connect to Docker to resolve hostname: {e}"),
-        }
+    // Every problem found is reported at once, each with its file line or
+    // env var name.
+    let mut config = config::load_config(&config_path).await?;
+
+    // Each of `[[engines]]` is asked for its name in `engines.rs`.
+    if config.engines.is_empty() {
+        resolve_hostname(&mut config).await;
     }
 
     let config = Arc::new(config);
-    let http_client = config::build_http_client(&config.controller);
+    let http_client = config::build_http_client(&config.controller)?;
 
-    // Register operator-supplied redaction keywords before any container is
-    // inspected, so custom secrets are scrubbed from the very first report.
-    monitor::init_extra_keywords(config.redact_keywords.clone());
+    // Register operator-supplied redaction keywords and the agent's own secrets
+    // before any container is inspected, so they are scrubbed from the very
+    // first report.
+    monitor::set_extra_keywords(config.redact_keywords.clone());
+    monitor::register_agent_secrets(&config.secret_values);
+    redact::configure(&config.redaction);
+
+    // Reloads publish the new config here; see `reload.rs`.
+    let (config_tx, config_rx) = watch::channel(Arc::clone(&config));
 
     let (tx_notification, rx_notification) = mpsc::channel(32);
     let (tx_sse, rx_sse) = mpsc::channel(32);
 
-    let result_handler = DeploymentResultHandler::new(tx_notification, config.hostname.clone());
-
     // The notification handler always runs: it forwards events to the
     // controller (if configured) and to chatterbox (if configured). Skipping
     // the spawn when chatterbox is absent drops the receiver, which made
     // every later send panic and also silently disabled controller reporting.
-    let dispatcher = setup_dispatcher(&config);
+    // Reports for the controller go through the outbox, which keeps them
+    // until the controller takes them.
+    let outbox = Outbox::for_controller(config.controller.as_ref());
+    if let Some(controller) = &config.controller {
+        tokio::spawn(outbox.clone().run(http_client.clone(), controller.clone()));
+    }
     {
-        let c = Arc::clone(&config);
-        let client = http_client.clone();
+        let c = config_rx.clone();
+        let outbox = outbox.clone();
         tokio::spawn(async move {
-            start_notification_handler(&c, rx_notification, dispatcher, client).await;
+            start_notification_handler(c, rx_notification, outbox).await;
         });
     }
 
     info!("Starting hoister");
     if config.send_test_message {
         info!("Sending tests message");
-        result_handler.test_message().await;
+        DeploymentResultHandler::new(tx_notification, config.hostname.clone())
+            .test_message()
+            .await;
         // await 1 second to allow the message to be sent
         sleep(Duration::from_secs(1)).await;
         return Ok(());
@@ -161,29 +198,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let docker = Arc::new(DockerHandler::new(
-        result_handler,
-        config.registry.clone(),
-        http_client.clone(),
-        config.report_logs,
-    ));
-
-    let project_name = match &config.project {
-        Some(pn) => pn.clone(),
-        // `get_project_name` already retries in-process with backoff; only bail
-        // out once that is exhausted, with an actionable hint instead of a bare
-        // `ProjectNameDetectionFailed`.
-        None => match get_project_name(&docker.docker).await {
-            Ok(pn) => pn,
-            Err(e) => {
-                error!(
-                    "Could not determine the compose project after retrying: {e}. \
-                     Set HOISTER_PROJECT (or `project` in /hoister.toml) to configure it explicitly."
-                );
-                return Err(e);
-            }
-        },
+    // One connection per engine, shared by everything that talks to it.
+    let engines = match engines::connect_all(&config).await {
+        Ok(engines) => engines,
+        Err(e) => {
+            error!("{e}");
+            return Err(e);
+        }
+    };
+    // Sent to the controller on connecting, to receive the commands for these
+    // engines.
+    let registration = match &config.controller {
+        Some(_) => Some(engines::registration(&config, &engines).await),
+        None => None,
     };
+    // Who the agent is, sent to the controller alongside the registration.
+    let handshake = match (&config.controller, &registration) {
+        (Some(controller_config), Some(registration)) => Some(handshake::Handshake::new(
+            engines
+                .iter()
+                .map(|engine| (engine.hostname.clone(), Arc::clone(&engine.docker)))
+                .collect(),
+            registration.agent_id.clone(),
+            config_rx.clone(),
+            controller_config.url.clone(),
+            controller_config.token.clone(),
+        )),
+        _ => None,
+    };
+    let engines: Vec<(Arc<DockerHandler>, ProjectName)> = engines
+        .into_iter()
+        .map(|engine| {
+            let docker = Arc::new(DockerHandler::new(
+                engine.docker,
+                DeploymentResultHandler::new(tx_notification.clone(), engine.hostname),
+                http_client.clone(),
+                &config,
+            ));
+            (docker, engine.project)
+        })
+        .collect();
+    let handlers: Vec<Arc<DockerHandler>> = engines
+        .iter()
+        .map(|(docker, _)| Arc::clone(docker))
+        .collect();
 
     if let Some(controller_config) = &config.controller {
         let url_str = controller_config.url.as_str();
@@ -201,63 +259,91 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
             info!("Mode: self-hosted — reporting to {}", url_str);
         }
         let mut url_sse = controller_config.url.clone();
-        let url_state = controller_config.url.clone();
         url_sse.set_path("sse");
-        let pn = project_name.clone();
-        let hn = config.hostname.clone();
         let sse_client = http_client.clone();
-        let monitor_client = http_client.clone();
-        let report_logs = config.report_logs;
-        let report_metrics = config.report_metrics;
         let token_sse = controller_config.token.clone();
-        let token_monitor = controller_config.token.clone();
+        let registration = registration.expect("registered with a controller configured");
+        let handshake = handshake.expect("registered with a controller configured");
+        info!(
+            "Connecting to the controller as agent {}",
+            registration.agent_id.as_str()
+        );
         tokio::spawn(async move {
-            sse::consume_sse(url_sse.as_str(), token_sse, tx_sse, sse_client).await
+            sse::consume_sse(
+                url_sse.as_str(),
+                token_sse,
+                registration,
+                handshake,
+                tx_sse,
+                sse_client,
+            )
+            .await
         });
 
         // SSE handler reacts to controller events (retries, apply-update, and
-        // on-demand log requests). It ships requested logs back to the
-        // controller, so it carries its own client/base-URL/token plus the
-        // `report_logs` gate. Only spawned when a controller is configured —
-        // there is no event source otherwise.
-        let mut sse_handler = SSEHandler::new(
-            docker.clone(),
-            rx_sse,
-            config.hostname.clone(),
-            report_logs,
+        // on-demand log requests) and routes each to the engine it is
+        // addressed to. It ships requested logs and command progress back to
+        // the controller, so it carries its own client/base-URL/token plus the
+        // `report_logs` gate.
+        // Only spawned when a controller is configured — there is no event
+        // source otherwise.
+        let sse_handler = SSEHandler::new(
+            handlers.clone(),
+            config.report_logs,
             http_client.clone(),
             controller_config.url.clone(),
             controller_config.token.clone(),
         );
         tokio::spawn(async move {
-            sse_handler.start().await;
-        });
-        let metrics_state = url_state.clone();
-        let pn_state = pn.clone();
-        let hn_state = hn.clone();
-        tokio::spawn(async move {
-            monitor::start(
-                &url_state,
-                token_monitor,
-                pn_state,
-                hn_state,
-                monitor_client,
-                report_logs,
-            )
-            .await
-            .expect("Failed to start monitor");
+            sse_handler.start(rx_sse).await;
         });
-        // Resource-usage collection is opt-in: it adds a per-minute stats call
-        // per container and ships CPU/memory figures the operator may not want
-        // leaving the host.
-        if report_metrics {
-            let metrics_client = http_client.clone();
-            let token_metrics = controller_config.token.clone();
+
+        for (docker, project) in &engines {
+            let url_state = controller_config.url.clone();
+            let token_monitor = controller_config.token.clone();
+            let engine = docker.docker.clone();
+            let pn = project.clone();
+            let hn = docker.hostname().clone();
+            let monitor_client = http_client.clone();
+            let monitor_config = config_rx.clone();
             tokio::spawn(async move {
-                metrics::start(&metrics_state, token_metrics, pn, hn, metrics_client)
+                monitor::start(
+                    engine,
+                    &url_state,
+                    token_monitor,
+                    pn,
+                    hn,
+                    monitor_client,
+                    monitor_config,
+                )
+                .await
+                .expect("Failed to start monitor");
+            });
+            // Resource-usage collection is opt-in: it adds a per-minute stats
+            // call per container and ships CPU/memory figures the operator
+            // may not want leaving the host.
+            if config.report_metrics {
+                let metrics_state = controller_config.url.clone();
+                let token_metrics = controller_config.token.clone();
+                let engine = docker.docker.clone();
+                let pn = project.clone();
+                let hn = docker.hostname().clone();
+                let metrics_client = http_client.clone();
+                let metrics_config = config_rx.clone();
+                tokio::spawn(async move {
+                    metrics::start(
+                        engine,
+                        &metrics_state,
+                        token_metrics,
+                        pn,
+                        hn,
+                        metrics_client,
+                        metrics_config,
+                    )
                     .await
                     .expect("Failed to start metrics collector");
-            });
+                });
+            }
         }
     } else {
         info!(
@@ -265,78 +351,196 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
         );
     }
 
+    // Registry push webhooks work in every mode: they only shorten the time
+    // until the next check, the check itself is the same as the scheduled one.
+    if let Some(webhooks) = config.webhooks.clone() {
+        let c = config_rx.clone();
+        let e = engines.clone();
+        let outbox = outbox.clone();
+        tokio::spawn(async move {
+            if let Err(e) = webhook::start(webhooks, c, e, outbox).await {
+                error!("Registry webhook listener stopped: {e}");
+            }
+        });
+    }
+
+    for (docker, project) in &engines {
+        tokio::spawn(janitor::start(
+            Arc::clone(docker),
+            config_rx.clone(),
+            project.clone(),
+            http_client.clone(),
+        ));
+        tokio::spawn(restarts::start(Arc::clone(docker), project.clone()));
+    }
+
+    tokio::spawn(reload::watch_config(config_path, handlers, config_tx));
+
+    let update_loops = engines
+        .iter()
+        .map(|(docker, project)| update_loop(docker, project, config_rx.clone(), &outbox));
+    future::join_all(update_loops).await;
+    Ok(())
+}
+
+/// Check the containers of one engine whenever they are due, forever. A
+/// failed pass, e.g. while the engine is unreachable, is retried after
+/// [`RETRY_AFTER`].
+async fn update_loop(
+    docker: &DockerHandler,
+    project_name: &ProjectName,
+    mut config_rx: watch::Receiver<Arc<config::Config>>,
+    outbox: &Outbox,
+) {
+    let hostname = docker.hostname().as_str();
+    let mut scheduler = Scheduler::default();
     loop {
-        debug!("---------- start checking containers ----------");
-        if config.auto_update {
-            run_update_check(&docker, &project_name).await?;
-        } else {
-            run_update_check_only(&docker, &project_name, &config, &http_client).await?;
-        }
-        let sleep = config.schedule.sleep();
-        debug!("---------- end checking containers ----------");
+        // A snapshot per pass: a reload during a pass applies from the next.
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        debug!("---------- start checking containers on {hostname} ----------");
+        let sleep =
+            match run_due_checks(docker, project_name, &config, outbox, &mut scheduler).await {
+                Ok(sleep) => sleep,
+                Err(e) => {
+                    error!("Checking the containers on {hostname} failed: {e}");
+                    RETRY_AFTER
+                }
+            };
+        debug!("---------- end checking containers on {hostname} ----------");
         debug!("sleeping for {} seconds...", sleep.as_secs_f64());
 
-        tokio::time::sleep(sleep).await;
+        reload::sleep_or_reload(&mut config_rx, sleep).await;
     }
 }
 
-async fn run_update_check(
+/// Check every tracked container whose schedule is due, and return how long
+/// until the next one is. Up to `max_parallel_updates` containers are checked
+/// at once, dependencies before the services that depend on them.
+async fn run_due_checks(
     docker: &DockerHandler,
     project_name: &ProjectName,
-) -> Result<(), Box<dyn Error>> {
+    config: &config::Config,
+    outbox: &Outbox,
+    scheduler: &mut Scheduler,
+) -> Result<Duration, Box<dyn Error>> {
+    let now = Utc::now();
     let containers = docker.get_containers(project_name).await?;
+    let mut present = Vec::new();
+    let mut due = Vec::new();
     for container in containers {
-        debug!("Checking container {:?}", container.id);
-        let container_id: ContainerID = container.id.expect("container ID missing");
-        let result = docker.update_container(project_name, &container_id).await;
+        let container_id: ContainerID = container.id.clone().expect("container ID missing");
+        let service = match get_service_identifier(docker.docker.as_ref(), &container_id).await {
+            Ok(service) => service,
+            Err(e) => {
+                warn!("Skipping container {container_id}: {e}");
+                continue;
+            }
+        };
+        let key = (selection::project_of(&container, project_name), service);
+        let schedule =
+            ServiceSchedule::from_labels(container.labels.as_ref()).unwrap_or_else(|e| {
+                warn!("{}: {e}; using the default schedule", key.1.as_str());
+                ServiceSchedule::Default
+            });
+        present.push(key.clone());
+        if scheduler.is_due(&key, &schedule, &config.schedule, now) {
+            due.push(Due::new(
+                (container_id, key, schedule),
+                container.labels.as_ref(),
+            ));
+        }
+    }
+
+    for wave in in_waves(due) {
+        stream::iter(&wave)
+            .for_each_concurrent(
+                config.max_parallel_updates.max(1),
+                |(container_id, (project, _), _)| {
+                    check_container(docker, project, config, outbox, container_id)
+                },
+            )
+            .await;
+        for (_, key, schedule) in wave {
+            scheduler.checked(key, schedule, now);
+        }
+    }
+    scheduler.finish_pass(&present, &config.schedule, now);
+    Ok(scheduler.next_wake(&config.schedule, Utc::now()))
+}
+
+/// Update a container, or only look for an update if `auto_update` is off.
+async fn check_container(
+    docker: &DockerHandler,
+    project_name: &ProjectName,
+    config: &config::Config,
+    outbox: &Outbox,
+    container_id: &ContainerID,
+) {
+    if config.auto_update {
+        debug!("Checking container {container_id}");
+        let result = docker.update_container(project_name, container_id).await;
         debug!("result: {result:?}");
+    } else {
+        debug!("Checking (no-apply) container {container_id}");
+        check_container_only(docker, project_name, config, outbox, container_id).await;
     }
-    Ok(())
 }
 
-async fn run_update_check_only(
+/// Check a single container for an update without applying it, and report a
+/// found update to the controller as pending.
+pub(crate) async fn check_container_only(
     docker: &DockerHandler,
     project_name: &ProjectName,
     config: &config::Config,
-    client: &reqwest::Client,
-) -> Result<(), Box<dyn Error>> {
-    let containers = docker.get_containers(project_name).await?;
-    for container in containers {
-        debug!("Checking (no-apply) container {:?}", container.id);
-        let container_id: ContainerID = container.id.expect("container ID missing");
-        match docker
-            .check_update_available(project_name, &container_id)
-            .await
-        {
-            Ok((service, image, digest)) => {
-                info!(
-                    "Update available for {}: {}",
-                    service.as_str(),
-                    image.as_str()
-                );
-                if let Err(e) = send_pending_update_to_controller(
-                    config,
-                    client,
-                    &config.hostname,
-                    project_name,
-                    &service,
-                    &image,
-                    &digest,
-                )
-                .await
-                {
-                    warn!("Failed to report pending update: {e}");
-                }
-            }
-            Err(HoisterError::NoUpdateAvailable) => {
-                debug!("No update available for container {container_id}");
-            }
-            Err(e) => {
-                warn!("Error checking update for container {container_id}: {e}");
-            }
+    outbox: &Outbox,
+    container_id: &ContainerID,
+) {
+    match docker
+        .check_update_available(project_name, container_id)
+        .await
+    {
+        Ok((service, image, digest)) => {
+            info!(
+                "Update available for {}: {}",
+                service.as_str(),
+                image.as_str()
+            );
+            send_pending_update_to_controller(
+                config,
+                outbox,
+                PostPendingUpdate {
+                    hostname: docker.hostname().clone(),
+                    project_name: project_name.clone(),
+                    service_name: service,
+                    image_name: image,
+                    new_digest: digest,
+                    event_id: None,
+                },
+            );
+        }
+        Err(HoisterError::NoUpdateAvailable) => {
+            debug!("No update available for container {container_id}");
+        }
+        Err(e) => {
+            warn!("Error checking update for container {container_id}: {e}");
         }
     }
-    Ok(())
+}
+
+/// If no hostname was configured, ask the Docker daemon for the host's name
+/// (equivalent to `docker info --format '{{.Name}}'`). This gives a stable,
+/// human-readable identifier without requiring manual config.
+async fn resolve_hostname(config: &mut config::Config) {
+    if config.hostname != hoister_shared::HostName::default() {
+        return;
+    }
+    match Docker::connect_with_local_defaults() {
+        Ok(docker) => match engines::ask_hostname(&docker).await {
+            Some(hostname) => config.hostname = hostname,
+            None => warn!("hostname stays 'undefined'"),
+        },
+        Err(e) => warn!("could not connect to Docker to resolve hostname: {e}"),
+    }
 }
 
 #[cfg(target_os = "linux")]
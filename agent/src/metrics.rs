@@ -15,9 +15,11 @@
 //! the latest reading on each tick.
 
 use crate::HoisterError;
+use crate::config::{Config, Selection};
 use crate::docker::get_service_identifier;
 use crate::monitor::list_tracked_containers;
-use bollard::Docker;
+use crate::runtime::ContainerRuntime;
+use crate::selection;
 use bollard::models::ContainerStatsResponse;
 use bollard::query_parameters::StatsOptionsBuilder;
 use futures_util::StreamExt;
@@ -26,7 +28,9 @@ use hoister_shared::{HostName, ProjectName, ServiceName};
 use log::{debug, error, info};
 use reqwest::Url;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time;
 
 /// How often we sample stats. Decoupled from the 5s state monitor — per-minute
@@ -46,14 +50,18 @@ struct CpuCounters {
     online_cpus: u32,
 }
 
+/// One sample per tracked service, grouped by the project it is reported
+/// under.
 async fn collect_samples(
     project_name: &ProjectName,
-    docker: &Docker,
-    prev_cpu: &mut HashMap<ServiceName, CpuCounters>,
-) -> Result<HashMap<ServiceName, ContainerMetricSample>, HoisterError> {
-    let containers = list_tracked_containers(project_name, docker).await?;
-
-    let mut samples = HashMap::new();
+    docker: &dyn ContainerRuntime,
+    selection: &Selection,
+    prev_cpu: &mut HashMap<(ProjectName, ServiceName), CpuCounters>,
+) -> Result<HashMap<ProjectName, HashMap<ServiceName, ContainerMetricSample>>, HoisterError> {
+    let containers = list_tracked_containers(project_name, docker, selection).await?;
+
+    let mut samples: HashMap<ProjectName, HashMap<ServiceName, ContainerMetricSample>> =
+        HashMap::new();
     let mut seen = std::collections::HashSet::new();
     for container in containers {
         let Some(container_id) = &container.id else {
@@ -62,7 +70,7 @@ async fn collect_samples(
         // Only running containers produce meaningful stats; a stopped
         // container yields an immediately-closing stream.
         let service_identifier = match get_service_identifier(docker, container_id).await {
-            Ok(id) => id,
+            Ok(id) => (selection::project_of(&container, project_name), id),
             Err(e) => {
                 error!("Failed to resolve service identifier for {container_id}: {e}");
                 continue;
@@ -77,7 +85,8 @@ async fn collect_samples(
                 if let Some((sample, cur)) = sample_from_stats(&stats, prev) {
                     seen.insert(service_identifier.clone());
                     prev_cpu.insert(service_identifier.clone(), cur);
-                    samples.insert(service_identifier, sample);
+                    let (project, service) = service_identifier;
+                    samples.entry(project).or_default().insert(service, sample);
                 }
             }
             Some(Err(e)) => debug!("stats unavailable for {container_id}: {e}"),
@@ -269,40 +278,44 @@ async fn send_to_backend(
 }
 
 pub(crate) async fn start(
+    docker: Arc<dyn ContainerRuntime>,
     controller_url: &Url,
     token: Option<String>,
     project_name: ProjectName,
     hostname: HostName,
     client: reqwest::Client,
+    config: watch::Receiver<Arc<Config>>,
 ) -> Result<(), Box<dyn std::error::Error + 'static>> {
     info!("Starting metrics collector (interval: {SAMPLE_INTERVAL:?})");
-    let docker = Docker::connect_with_socket_defaults()?;
     let mut interval = time::interval(SAMPLE_INTERVAL);
     // Cumulative CPU counters from the previous tick, per service, so CPU% is
     // an average over the whole interval rather than a sub-second snapshot.
-    let mut prev_cpu: HashMap<ServiceName, CpuCounters> = HashMap::new();
+    let mut prev_cpu: HashMap<(ProjectName, ServiceName), CpuCounters> = HashMap::new();
 
     loop {
         interval.tick().await;
 
-        match collect_samples(&project_name, &docker, &mut prev_cpu).await {
-            Ok(samples) if samples.is_empty() => {
+        let selection = config.borrow().selection.clone();
+        match collect_samples(&project_name, docker.as_ref(), &selection, &mut prev_cpu).await {
+            Ok(projects) if projects.is_empty() => {
                 debug!("No metrics samples collected this tick");
             }
-            Ok(samples) => {
-                if let Err(e) = send_to_backend(
-                    &client,
-                    controller_url,
-                    token.as_deref(),
-                    project_name.clone(),
-                    hostname.clone(),
-                    &samples,
-                )
-                .await
-                {
-                    error!("Failed to send metrics to backend: {e}");
-                } else {
-                    debug!("Sent metrics for {} services", samples.len());
+            Ok(projects) => {
+                for (project, samples) in projects {
+                    if let Err(e) = send_to_backend(
+                        &client,
+                        controller_url,
+                        token.as_deref(),
+                        project,
+                        hostname.clone(),
+                        &samples,
+                    )
+                    .await
+                    {
+                        error!("Failed to send metrics to backend: {e}");
+                    } else {
+                        debug!("Sent metrics for {} services", samples.len());
+                    }
                 }
             }
             Err(e) => error!("Error collecting metrics: {e}"),
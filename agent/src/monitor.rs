@@ -1,17 +1,28 @@
 use crate::HoisterError;
+use crate::config::{Config, Selection};
 use crate::docker::get_service_identifier;
-use bollard::Docker;
+use crate::redact;
+use crate::runtime::ContainerRuntime;
+use crate::scheduler::ServiceSchedule;
+use crate::selection;
 use bollard::models::{ContainerInspectResponse, ContainerStateStatusEnum, ContainerSummary};
-use bollard::query_parameters::{ListContainersOptions, LogsOptionsBuilder};
+use bollard::query_parameters::LogsOptionsBuilder;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use futures_util::StreamExt;
-use hoister_shared::wire::{PostContainerStateRequest, ServiceState};
+use hoister_shared::merge_patch;
+use hoister_shared::wire::{PostContainerStateDelta, PostContainerStateRequest, ServiceState};
 use hoister_shared::{HostName, ProjectName, ServiceName};
 use log::{debug, error, info, warn};
 use reqwest::Url;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time;
 
 /// Substring placed in env-var values and log output by the agent before
@@ -22,7 +33,7 @@ pub(crate) const REDACTION_MARKER: &str = "***REDACTED***";
 
 /// Built-in case-insensitive substrings that mark an env-var key as sensitive.
 /// Operators extend this at runtime via `redact_keywords` in the config; see
-/// [`init_extra_keywords`].
+/// [`set_extra_keywords`].
 const DEFAULT_SENSITIVE_KEYWORDS: &[&str] = &[
     "telegram_chat_id",
     "discord_channel_id",
@@ -44,64 +55,78 @@ const DEFAULT_SENSITIVE_KEYWORDS: &[&str] = &[
     "cookie",
 ];
 
-/// Operator-supplied extra keywords (already lower-cased), set once at startup
-/// from config. Empty until [`init_extra_keywords`] runs, so redaction always
-/// falls back to the built-in list.
-static EXTRA_SENSITIVE_KEYWORDS: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+/// Operator-supplied extra keywords (already lower-cased), set from config at
+/// startup and on every reload. Empty until [`set_extra_keywords`] runs, so
+/// redaction always falls back to the built-in list.
+static EXTRA_SENSITIVE_KEYWORDS: std::sync::RwLock<Vec<String>> =
+    std::sync::RwLock::new(Vec::new());
+
+/// Register the operator-supplied redaction keywords loaded from config,
+/// replacing any set before. Entries are trimmed, lower-cased, and empties
+/// dropped so matching stays case-insensitive like the built-ins.
+pub(crate) fn set_extra_keywords(keywords: Vec<String>) {
+    *EXTRA_SENSITIVE_KEYWORDS
+        .write()
+        .expect("keywords lock poisoned") = normalise_keywords(keywords);
+}
 
-/// Register the operator-supplied redaction keywords loaded from config. Called
-/// once at startup; later calls are ignored. Entries are trimmed, lower-cased,
-/// and empties dropped so matching stays case-insensitive like the built-ins.
-pub(crate) fn init_extra_keywords(keywords: Vec<String>) {
-    let normalised = keywords
+fn normalise_keywords(keywords: Vec<String>) -> Vec<String> {
+    keywords
         .into_iter()
         .map(|k| k.trim().to_lowercase())
         .filter(|k| !k.is_empty())
-        .collect();
-    let _ = EXTRA_SENSITIVE_KEYWORDS.set(normalised);
+        .collect()
+}
+
+/// Values of the agent's own secrets — registry passwords, notifier tokens,
+/// the controller token — scrubbed from forwarded logs wherever they appear.
+/// Replaced on every config load, since secret files are re-read then.
+static AGENT_SECRETS: std::sync::RwLock<Vec<String>> = std::sync::RwLock::new(Vec::new());
+
+/// Register the resolved secret values of the loaded config for redaction.
+pub(crate) fn register_agent_secrets(values: &[String]) {
+    *AGENT_SECRETS.write().expect("secrets lock poisoned") = values.to_vec();
+}
+
+fn agent_secrets() -> Vec<String> {
+    AGENT_SECRETS.read().expect("secrets lock poisoned").clone()
 }
 
 /// True when the (already lower-cased) env-var key contains any sensitive
 /// keyword, built-in or operator-supplied.
 fn key_is_sensitive(key_lower: &str) -> bool {
+    key_matches(
+        key_lower,
+        &EXTRA_SENSITIVE_KEYWORDS
+            .read()
+            .expect("keywords lock poisoned"),
+    )
+}
+
+/// Like [`key_is_sensitive`], with `extra` as the operator-supplied keywords.
+fn key_matches(key_lower: &str, extra: &[String]) -> bool {
     DEFAULT_SENSITIVE_KEYWORDS
         .iter()
         .any(|keyword| key_lower.contains(keyword))
-        || EXTRA_SENSITIVE_KEYWORDS
-            .get()
-            .is_some_and(|extra| extra.iter().any(|keyword| key_lower.contains(keyword)))
+        || extra.iter().any(|keyword| key_lower.contains(keyword))
 }
 
 /// Max bytes of container log tail we ship to the controller. Cap is intentional:
 /// crash-loop logs are usually short, and we don't want a chatty container to
 /// blow up the request payload.
 const MAX_LOG_BYTES: usize = 16 * 1024;
-const LOG_TAIL_LINES: &str = "50";
+pub(crate) const LOG_TAIL_LINES: &str = "50";
 
-/// List the containers this agent should report on: scoped to the compose
-/// project (in release builds) and with any `hoister.hide=true` containers
-/// excluded. Shared by the state monitor and the metrics collector so the
-/// two always observe the same set of containers.
+/// List the containers this agent should report on: everything in scope of
+/// `[selection]`, with any `hoister.hide=true` containers excluded. Shared by
+/// the state monitor and the metrics collector so the two always observe the
+/// same set of containers, and the updater works within the same scope.
 pub(crate) async fn list_tracked_containers(
-    #[allow(unused_variables)] project_name: &ProjectName,
-    docker: &Docker,
+    project_name: &ProjectName,
+    docker: &dyn ContainerRuntime,
+    selection: &Selection,
 ) -> Result<Vec<ContainerSummary>, HoisterError> {
-    #[allow(unused_mut, unused_variables)]
-    let mut filters = HashMap::new();
-    #[cfg(not(debug_assertions))]
-    {
-        let label_filters = vec![format!(
-            "com.docker.compose.project={}",
-            project_name.as_str()
-        )];
-        filters.insert("label".to_string(), label_filters);
-    }
-    let options = ListContainersOptions {
-        filters: Some(filters),
-        ..Default::default()
-    };
-
-    let containers = docker.list_containers(Some(options)).await?;
+    let containers = selection::list(docker, selection, project_name).await?;
 
     Ok(containers
         .into_iter()
@@ -116,18 +141,24 @@ pub(crate) async fn list_tracked_containers(
         .collect::<Vec<ContainerSummary>>())
 }
 
+/// The state of every tracked container, grouped by the project it is
+/// reported under. The agent's own project is always included, so the
+/// controller hears from it even when it has no containers.
 async fn fetch_container_info(
     project_name: &ProjectName,
-    docker: &Docker,
+    docker: &dyn ContainerRuntime,
     report_logs: bool,
-) -> Result<HashMap<ServiceName, ServiceState>, HoisterError> {
-    let containers = list_tracked_containers(project_name, docker).await?;
-
-    let mut states = HashMap::new();
+    config: &Config,
+) -> Result<HashMap<ProjectName, HashMap<ServiceName, ServiceState>>, HoisterError> {
+    let containers = list_tracked_containers(project_name, docker, &config.selection).await?;
+    let global_schedule = &config.schedule;
 
+    let mut projects: HashMap<ProjectName, HashMap<ServiceName, ServiceState>> =
+        HashMap::from([(project_name.clone(), HashMap::new())]);
     for container in containers {
         if let Some(container_id) = &container.id {
             let service_identifier = get_service_identifier(docker, container_id).await?;
+            let project = selection::project_of(&container, project_name);
 
             let inspect = docker
                 .inspect_container(
@@ -155,12 +186,20 @@ async fn fetch_container_info(
                         None
                     };
 
+                    let labels = inspect.config.as_ref().and_then(|c| c.labels.as_ref());
+                    let schedule = ServiceSchedule::from_labels(labels)
+                        .unwrap_or(ServiceSchedule::Default)
+                        .describe(global_schedule);
                     redact_credentials(&mut inspect);
                     strip_health_check_output(&mut inspect);
                     prune_inspect(&mut inspect);
-                    states.insert(
+                    projects.entry(project.clone()).or_default().insert(
                         service_identifier.clone(),
-                        ServiceState { inspect, last_logs },
+                        ServiceState {
+                            inspect,
+                            last_logs,
+                            schedule: Some(schedule),
+                        },
                     );
                 }
                 Err(e) => error!("Error inspecting container {container_id}: {e}"),
@@ -168,7 +207,7 @@ async fn fetch_container_info(
         }
     }
 
-    Ok(states)
+    Ok(projects)
 }
 
 /// Only attach a log tail when the container is in a state where logs explain
@@ -200,7 +239,7 @@ fn should_fetch_logs(inspect: &ContainerInspectResponse) -> bool {
 /// long-lived original and its tail would otherwise include stale pre-update
 /// lines. `0` returns the full tail.
 pub(crate) async fn fetch_log_tail(
-    docker: &Docker,
+    docker: &dyn ContainerRuntime,
     container_id: &str,
     inspect: &ContainerInspectResponse,
     since: i32,
@@ -243,11 +282,32 @@ pub(crate) async fn fetch_log_tail(
     }
 
     let mut text = String::from_utf8_lossy(&buf).into_owned();
-    let sensitive_values = collect_sensitive_env_values(inspect);
-    redact_values(&mut text, &sensitive_values);
+    log_redactor(inspect)(&mut text);
     Ok(Some(text))
 }
 
+/// Redacts a container's log output: the values of its sensitive env vars, the
+/// agent's own secrets, and whatever the rules in `redact.rs` find. Build it
+/// once per container and apply it to each piece of output, e.g. every chunk
+/// of a live stream.
+pub(crate) fn log_redactor(inspect: &ContainerInspectResponse) -> impl Fn(&mut String) + use<> {
+    let mut sensitive_values = collect_sensitive_env_values(inspect);
+    sensitive_values.extend(agent_secrets());
+    move |text: &mut String| {
+        redact_values(text, &sensitive_values);
+        redact::redact(text);
+    }
+}
+
+/// Redacts output that doesn't come with a container's env, e.g. health-check
+/// probes and backup commands: the agent's own secrets and the rules' matches.
+pub(crate) fn redact_output(text: &str) -> String {
+    let mut text = text.to_string();
+    redact_values(&mut text, &agent_secrets());
+    redact::redact(&mut text);
+    text
+}
+
 /// Pull the *values* of any env vars whose key looks sensitive. We use these
 /// to scrub the same secrets out of the log output, in case the application
 /// inside the container logged them.
@@ -340,6 +400,16 @@ fn prune_inspect(inspect: &mut ContainerInspectResponse) {
 }
 
 fn redact_credentials(inspect: &mut ContainerInspectResponse) {
+    let extra = EXTRA_SENSITIVE_KEYWORDS
+        .read()
+        .expect("keywords lock poisoned")
+        .clone();
+    redact_env(inspect, &extra, &agent_secrets());
+}
+
+/// Blank the env vars of `inspect` whose key matches a sensitive keyword, the
+/// built-ins or `extra`, or whose value is one of `secrets`.
+fn redact_env(inspect: &mut ContainerInspectResponse, extra: &[String], secrets: &[String]) {
     if let Some(config) = inspect.config.as_mut()
         && let Some(env_vars) = config.env.as_mut()
     {
@@ -347,11 +417,14 @@ fn redact_credentials(inspect: &mut ContainerInspectResponse) {
             .iter()
             .map(|env_var| {
                 // Split on first '=' to get key=value
-                if let Some((key, _value)) = env_var.split_once('=') {
+                if let Some((key, value)) = env_var.split_once('=') {
                     let key_lower = key.to_lowercase();
 
-                    // Check if the key contains any sensitive keyword
-                    if key_is_sensitive(&key_lower) {
+                    // Check if the key contains any sensitive keyword, or the
+                    // value is one of the agent's own secrets
+                    if key_matches(&key_lower, extra)
+                        || (!value.is_empty() && secrets.iter().any(|secret| secret == value))
+                    {
                         format!("{key}={REDACTION_MARKER}")
                     } else {
                         env_var.clone()
@@ -364,6 +437,46 @@ fn redact_credentials(inspect: &mut ContainerInspectResponse) {
     }
 }
 
+/// Bodies above this are gzipped. Smaller ones, e.g. most deltas, don't
+/// gain enough to be worth it.
+const COMPRESS_ABOVE: usize = 1024;
+
+/// What the controller last stored for a project: the state, for diffing the
+/// next one against, and the version it is stored under.
+struct Acked {
+    version: u64,
+    state: Value,
+}
+
+async fn post_json(
+    client: &reqwest::Client,
+    url: Url,
+    token: Option<&str>,
+    body: Vec<u8>,
+) -> Result<(), reqwest::Error> {
+    let mut req = client.post(url).header("Content-Type", "application/json");
+    req = match gzip(&body) {
+        Some(compressed) => req.header("Content-Encoding", "gzip").body(compressed),
+        None => req.body(body),
+    };
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let response = req.send().await?;
+    response.error_for_status()?;
+    Ok(())
+}
+
+/// `body` gzipped, or `None` when it's small enough to send as is.
+fn gzip(body: &[u8]) -> Option<Vec<u8>> {
+    if body.len() <= COMPRESS_ABOVE {
+        return None;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()
+}
+
 async fn send_to_backend(
     client: &reqwest::Client,
     controller_url: &Url,
@@ -375,17 +488,22 @@ async fn send_to_backend(
     let url = controller_url
         .join(format!("container/state/{}/{}", hostname.0, project_name.0).as_str())
         .expect("failed to join url");
+    post_json(client, url, token, body).await
+}
 
-    let mut req = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .body(body);
-    if let Some(token) = token {
-        req = req.bearer_auth(token);
-    }
-    let response = req.send().await?;
-    response.error_for_status()?;
-    Ok(())
+async fn send_delta(
+    client: &reqwest::Client,
+    controller_url: &Url,
+    token: Option<&str>,
+    project_name: &ProjectName,
+    hostname: &HostName,
+    delta: &PostContainerStateDelta,
+) -> Result<(), reqwest::Error> {
+    let url = controller_url
+        .join(format!("container/state/{}/{}/delta", hostname.0, project_name.0).as_str())
+        .expect("failed to join url");
+    let body = serde_json::to_vec(delta).expect("a JSON value always serializes");
+    post_json(client, url, token, body).await
 }
 
 async fn send_heartbeat(
@@ -421,55 +539,72 @@ fn hash_bytes(bytes: &[u8]) -> u64 {
 }
 
 pub(crate) async fn start(
+    docker: Arc<dyn ContainerRuntime>,
     controller_url: &Url,
     token: Option<String>,
     project_name: ProjectName,
     hostname: HostName,
     client: reqwest::Client,
-    report_logs: bool,
+    config: watch::Receiver<Arc<Config>>,
 ) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    // Only takes effect after a restart, see `Config::reloaded`.
+    let report_logs = config.borrow().report_logs;
     info!(
         "Starting monitor (log forwarding: {})",
         if report_logs { "enabled" } else { "disabled" }
     );
-    let docker = Docker::connect_with_socket_defaults()?;
     let mut interval = time::interval(Duration::from_secs(60));
-    let mut prev_hash: Option<u64> = None;
+    // One report per project, each with its own change detection.
+    let mut acked: HashMap<ProjectName, Acked> = HashMap::new();
+    // Cleared when the controller predates deltas, so each change isn't
+    // tried as a delta first.
+    let mut send_deltas = true;
 
     loop {
         interval.tick().await;
 
-        match fetch_container_info(&project_name, &docker, report_logs).await {
-            Ok(current_states) => {
-                let request = PostContainerStateRequest {
-                    project_name: project_name.clone(),
-                    payload: current_states,
-                };
-                // Route through `serde_json::Value` (a sorted `BTreeMap`, since
-                // serde_json is built without `preserve_order`) so object keys
-                // are emitted in a stable order. bollard deserializes inspect
-                // fields like `Config.Labels` and `NetworkSettings.Networks`
-                // into `HashMap`s whose iteration order is randomized per
-                // instance; serializing the struct directly would reshuffle keys
-                // on every poll, changing the hash even when nothing changed and
-                // defeating the diff/heartbeat compression below.
-                let body = match serde_json::to_value(&request)
-                    .and_then(|value| serde_json::to_vec(&value))
-                {
-                    Ok(b) => b,
-                    Err(e) => {
-                        error!("Failed to serialize state: {e}");
-                        continue;
-                    }
-                };
-                let hash = hash_bytes(&body);
-                if prev_hash == Some(hash) {
-                    debug!("State unchanged, sending heartbeat");
+        let config = Arc::clone(&config.borrow());
+        let projects = match fetch_container_info(
+            &project_name,
+            docker.as_ref(),
+            report_logs,
+            &config,
+        )
+        .await
+        {
+            Ok(projects) => projects,
+            Err(e) => {
+                error!("Error fetching container info: {e}");
+                continue;
+            }
+        };
+        acked.retain(|project, _| projects.contains_key(project));
+        for (project, current_states) in projects {
+            // `serde_json::Value` objects are sorted `BTreeMap`s, since
+            // serde_json is built without `preserve_order`, so the state
+            // serializes with its keys in a stable order. bollard
+            // deserializes inspect fields like `Config.Labels` and
+            // `NetworkSettings.Networks` into `HashMap`s whose iteration order
+            // is randomized per instance; hashing the struct directly would
+            // change the version on every poll even when nothing changed.
+            let mut state = match serde_json::to_value(&current_states) {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("Failed to serialize state: {e}");
+                    continue;
+                }
+            };
+            merge_patch::strip_nulls(&mut state);
+            let version = hash_bytes(state.to_string().as_bytes());
+
+            if let Some(previous) = acked.get(&project) {
+                if previous.version == version {
+                    debug!("State of {} unchanged, sending heartbeat", project.as_str());
                     if let Err(e) = send_heartbeat(
                         &client,
                         controller_url,
                         token.as_deref(),
-                        &project_name,
+                        &project,
                         &hostname,
                     )
                     .await
@@ -478,26 +613,74 @@ pub(crate) async fn start(
                     }
                     continue;
                 }
-                if let Err(e) = send_to_backend(
-                    &client,
-                    controller_url,
-                    token.as_deref(),
-                    &project_name,
-                    &hostname,
-                    body,
-                )
-                .await
-                {
-                    error!("Failed to send to backend: {e}");
-                } else {
-                    prev_hash = Some(hash);
-                    debug!(
-                        "Successfully sent {} containers to backend",
-                        request.payload.len()
-                    );
+                if send_deltas {
+                    let delta = PostContainerStateDelta {
+                        base_version: previous.version,
+                        version,
+                        patch: merge_patch::diff(&previous.state, &state),
+                    };
+                    match send_delta(
+                        &client,
+                        controller_url,
+                        token.as_deref(),
+                        &project,
+                        &hostname,
+                        &delta,
+                    )
+                    .await
+                    {
+                        Ok(()) => {
+                            debug!("Sent changes of {} to backend", project.as_str());
+                            acked.insert(project, Acked { version, state });
+                            continue;
+                        }
+                        // The controller has another version or none; resync
+                        // with the full state below.
+                        Err(e) if e.status() == Some(reqwest::StatusCode::CONFLICT) => {
+                            debug!("Controller state of {} diverged", project.as_str());
+                        }
+                        Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                            info!("Controller doesn't accept state deltas, sending full state");
+                            send_deltas = false;
+                        }
+                        Err(e) => {
+                            error!("Failed to send state delta: {e}");
+                            continue;
+                        }
+                    }
                 }
             }
-            Err(e) => error!("Error fetching container info: {e}"),
+
+            let request = PostContainerStateRequest {
+                project_name: project.clone(),
+                payload: current_states,
+                version: Some(version),
+            };
+            let body = match serde_json::to_vec(&request) {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("Failed to serialize state: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = send_to_backend(
+                &client,
+                controller_url,
+                token.as_deref(),
+                &project,
+                &hostname,
+                body,
+            )
+            .await
+            {
+                error!("Failed to send to backend: {e}");
+            } else {
+                debug!(
+                    "Successfully sent {} containers to backend",
+                    request.payload.len()
+                );
+                acked.insert(project, Acked { version, state });
+            }
         }
     }
 }
@@ -579,12 +762,38 @@ mod tests {
     #[test]
     fn key_is_sensitive_matches_builtin_and_extra_keywords() {
         // Built-in keyword matches regardless of operator config.
-        assert!(key_is_sensitive("database_password"));
+        assert!(key_matches("database_password", &[]));
         // An otherwise-benign key only matches once it's registered as a custom
-        // keyword. `init_extra_keywords` sets a process-global OnceLock, so this
-        // also implicitly covers the case-insensitive normalisation.
-        assert!(!key_is_sensitive("acme_license_serial"));
-        init_extra_keywords(vec!["  LICENSE_Serial ".to_string(), String::new()]);
-        assert!(key_is_sensitive("acme_license_serial"));
+        // keyword. This also covers the case-insensitive normalisation.
+        assert!(!key_matches("acme_license_serial", &[]));
+        let extra = normalise_keywords(vec!["  LICENSE_Serial ".to_string(), String::new()]);
+        assert_eq!(extra, vec!["license_serial".to_string()]);
+        assert!(key_matches("acme_license_serial", &extra));
+        assert!(!key_matches("device_pin", &extra));
+    }
+
+    #[test]
+    fn redact_credentials_hides_agent_secrets_under_any_key() {
+        let mut inspect =
+            inspect_with_env(vec!["REGISTRY_LOGIN=ghp_agent-registry-token", "PORT=8080"]);
+        redact_env(&mut inspect, &[], &["ghp_agent-registry-token".to_string()]);
+        let env = inspect.config.unwrap().env.unwrap();
+        assert_eq!(env[0], format!("REGISTRY_LOGIN={REDACTION_MARKER}"));
+        assert_eq!(env[1], "PORT=8080");
+    }
+
+    #[test]
+    fn only_large_bodies_are_gzipped() {
+        use std::io::Read;
+
+        assert_eq!(gzip(b"{}"), None);
+        let body = serde_json::to_vec(&serde_json::json!({ "logs": "a".repeat(4096) })).unwrap();
+        let compressed = gzip(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
     }
 }
@@ -1,16 +1,19 @@
 use crate::HoisterError;
 use crate::config::Config;
+use crate::outbox::{Outbox, Report};
 use chatterbox::message::{Dispatcher, Message};
+use hoister_shared::wire::PostPendingUpdate;
 use hoister_shared::{
     CreateDeployment, DeploymentStatus, HostName, ImageDigest, ImageName, ProjectName, ServiceName,
 };
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::broadcast::error::SendError;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 
 /// A failed image pull recurs on every scheduled check (often once a minute)
 /// until the operator fixes the credentials or image reference. Reporting each
@@ -59,6 +62,10 @@ impl DeploymentResultHandler {
         }
     }
 
+    pub(crate) fn hostname(&self) -> &HostName {
+        &self.hostname
+    }
+
     pub(crate) async fn inform_container_failed(
         &self,
         project: ProjectName,
@@ -75,6 +82,7 @@ impl DeploymentResultHandler {
             status: DeploymentStatus::Failed,
             hostname: self.hostname.clone(),
             logs,
+            event_id: None,
         })
         .await;
     }
@@ -95,6 +103,7 @@ impl DeploymentResultHandler {
             status: DeploymentStatus::RollbackFinished,
             hostname: self.hostname.clone(),
             logs,
+            event_id: None,
         })
         .await;
     }
@@ -128,6 +137,7 @@ impl DeploymentResultHandler {
             status: DeploymentStatus::Failed,
             hostname: self.hostname.clone(),
             logs: Some(error),
+            event_id: None,
         })
         .await;
     }
@@ -185,6 +195,66 @@ impl DeploymentResultHandler {
             status: DeploymentStatus::Success,
             hostname: self.hostname.clone(),
             logs: None,
+            event_id: None,
+        })
+        .await;
+    }
+
+    /// Report an update and the restarts of the services that restart with
+    /// it as one event. `summary` lists how each restart went; if one did not
+    /// come back healthy, the event is a failure even though the update
+    /// itself stays in place.
+    pub(crate) async fn inform_update_with_restarts(
+        &self,
+        project: ProjectName,
+        service: ServiceName,
+        image: ImageName,
+        digest: ImageDigest,
+        summary: String,
+        healthy: bool,
+    ) {
+        let status = if healthy {
+            self.clear_pull_failure(&project, &service, &image);
+            DeploymentStatus::Success
+        } else {
+            DeploymentStatus::Failed
+        };
+        self.send(CreateDeployment {
+            project,
+            service,
+            image,
+            digest,
+            status,
+            hostname: self.hostname.clone(),
+            logs: Some(summary),
+            event_id: None,
+        })
+        .await;
+    }
+
+    /// Report a scheduled restart; `failure` carries why the container did
+    /// not come back healthy.
+    pub(crate) async fn inform_restart(
+        &self,
+        project: ProjectName,
+        service: ServiceName,
+        image: ImageName,
+        digest: ImageDigest,
+        failure: Option<String>,
+    ) {
+        let status = match failure {
+            None => DeploymentStatus::Restarted,
+            Some(_) => DeploymentStatus::RestartFailed,
+        };
+        self.send(CreateDeployment {
+            project,
+            service,
+            image,
+            digest,
+            status,
+            hostname: self.hostname.clone(),
+            logs: failure,
+            event_id: None,
         })
         .await;
     }
@@ -203,95 +273,45 @@ impl DeploymentResultHandler {
     }
 }
 
-pub(crate) async fn send_pending_update_to_controller(
+/// Queue an update found while `auto_update` is off for the controller, which
+/// lists it as pending.
+pub(crate) fn send_pending_update_to_controller(
     config: &Config,
-    client: &reqwest::Client,
-    hostname: &HostName,
-    project: &ProjectName,
-    service: &ServiceName,
-    image: &ImageName,
-    digest: &ImageDigest,
-) -> Result<(), NotificationError> {
-    let controller = match &config.controller {
-        Some(c) => c,
-        None => {
-            info!("No controller configured, skipping pending update notification");
-            return Ok(());
-        }
-    };
-
-    let url = controller.url.join("/pending-updates")?;
-    let token = controller.token.as_deref().unwrap_or_default();
-
-    #[derive(serde::Serialize)]
-    struct PendingUpdateRequest<'a> {
-        hostname: &'a HostName,
-        project_name: &'a ProjectName,
-        service_name: &'a ServiceName,
-        image_name: &'a ImageName,
-        new_digest: &'a ImageDigest,
+    outbox: &Outbox,
+    update: PostPendingUpdate,
+) {
+    if config.controller.is_none() {
+        info!("No controller configured, skipping pending update notification");
+        return;
     }
-
-    let body = PendingUpdateRequest {
-        hostname,
-        project_name: project,
-        service_name: service,
-        image_name: image,
-        new_digest: digest,
-    };
-
-    let res = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {token}"))
-        .json(&body)
-        .send()
-        .await;
-    debug!("pending update response: {res:?}");
-    Ok(())
+    outbox.push(Report::PendingUpdate(update));
 }
 
 pub(super) async fn start_notification_handler(
-    config: &Config,
+    mut config: watch::Receiver<Arc<Config>>,
     mut rx: Receiver<CreateDeployment>,
-    dispatcher: Option<Dispatcher>,
-    client: reqwest::Client,
+    outbox: Outbox,
 ) {
+    let mut dispatcher = setup_dispatcher(&config.borrow_and_update());
     while let Some(deployment_message) = rx.recv().await {
-        send(config, &deployment_message, dispatcher.as_ref(), &client).await;
+        // Pick up a reloaded config between messages, so every message goes
+        // out through one consistent set of dispatchers.
+        if config.has_changed().unwrap_or(false) {
+            dispatcher = setup_dispatcher(&config.borrow_and_update());
+        }
+        let current = Arc::clone(&config.borrow());
+        send(&current, deployment_message, dispatcher.as_ref(), &outbox).await;
     }
 }
 
-async fn send_to_controller(
-    deployment_message: &CreateDeployment,
-    config: &Config,
-    client: &reqwest::Client,
-) -> Result<(), NotificationError> {
-    let (url, token) = match config.controller {
-        Some(ref controller) => {
-            debug!(
-                "sending deployment request to controller: {}",
-                controller.url
-            );
-            let token = controller.token.as_deref().unwrap_or_default();
-            (&controller.url, token)
-        }
-        None => {
-            info!("HOISTER_CONTROLLER_URL not defined");
-            return Ok(());
-        }
-    };
-
-    let url = url.join("/deployments")?;
-    let res = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {token}"))
-        .json(&deployment_message)
-        .send()
-        .await;
-    debug!("response: {res:?}");
-    Ok(())
+/// Queue the event for the controller, see `outbox.rs`.
+fn send_to_controller(deployment_message: CreateDeployment, config: &Config, outbox: &Outbox) {
+    if config.controller.is_none() {
+        info!("HOISTER_CONTROLLER_URL not defined");
+        return;
+    }
+    debug!("queueing deployment report for the controller");
+    outbox.push(Report::Deployment(deployment_message));
 }
 
 async fn send_to_chatterbox(
@@ -302,7 +322,8 @@ async fn send_to_chatterbox(
         return Ok(());
     };
     match deployment_message.status {
-        DeploymentStatus::NoUpdate => Ok(()),
+        // A routine restart is only worth a message when it fails.
+        DeploymentStatus::NoUpdate | DeploymentStatus::Restarted => Ok(()),
         _ => {
             let message = deployment_message.to_message();
             dispatcher.dispatch(&message)?;
@@ -313,25 +334,23 @@ async fn send_to_chatterbox(
 
 pub(crate) async fn send(
     config: &Config,
-    deployment_message: &CreateDeployment,
+    deployment_message: CreateDeployment,
     dispatcher: Option<&Dispatcher>,
-    client: &reqwest::Client,
+    outbox: &Outbox,
 ) {
     debug!("sending deployment request");
-    let (result1, result2) = tokio::join!(
-        send_to_controller(deployment_message, config, client),
-        send_to_chatterbox(deployment_message, dispatcher)
-    );
-
-    if let Err(e) = result1 {
-        error!("Failed to send to controller: {e:?}");
-    }
-    if let Err(e) = result2 {
+    if let Err(e) = send_to_chatterbox(&deployment_message, dispatcher).await {
         error!("Failed to send to chatterbox: {e:?}");
     }
+    send_to_controller(deployment_message, config, outbox);
 }
 
 pub(crate) fn setup_dispatcher(config: &Config) -> Option<Dispatcher> {
+    dispatchers(config).map(Dispatcher::new)
+}
+
+/// The dispatchers configured in `config`, if any.
+pub(crate) fn dispatchers(config: &Config) -> Option<chatterbox::dispatcher::Sender> {
     if std::env::var("HOISTER_SLACK_WEBHOOK_URL").is_ok()
         || std::env::var("HOISTER_SLACK_CHANNEL").is_ok()
         || std::env::var("HOISTER_TELEGRAM_BOT_TOKEN").is_ok()
@@ -462,7 +481,7 @@ pub(crate) fn setup_dispatcher(config: &Config) -> Option<Dispatcher> {
         }
     });
 
-    let sender = chatterbox::dispatcher::Sender {
+    Some(chatterbox::dispatcher::Sender {
         slack,
         telegram,
         discord,
@@ -480,9 +499,159 @@ pub(crate) fn setup_dispatcher(config: &Config) -> Option<Dispatcher> {
         rocketchat,
         google_chat,
         webhook,
+    })
+}
+
+/// Send `message` through each of `dispatchers` and wait for the outcome.
+/// Unlike [`Dispatcher::dispatch`], which leaves delivery to background
+/// tasks, this tells whether each dispatcher got the message through.
+#[cfg(feature = "cli")]
+pub(crate) async fn deliver(
+    dispatchers: &chatterbox::dispatcher::Sender,
+    message: &Message,
+) -> Vec<(&'static str, Result<(), String>)> {
+    use chatterbox::dispatcher::{
+        discord_webhook, email, google_chat, gotify, matrix, mattermost, ntfy, pushover,
+        rocketchat, slack, teams, webhook,
     };
+    let message = || message.clone();
+    let failed = |e: Box<dyn std::error::Error>| e.to_string();
+    let mut outcomes = Vec::new();
+    if let Some(t) = &dispatchers.telegram {
+        outcomes.push(("telegram", send_telegram(t, message()).await));
+    }
+    if let Some(d) = &dispatchers.discord {
+        outcomes.push(("discord", send_discord(d, message()).await));
+    }
+    if let Some(d) = &dispatchers.discord_webhook {
+        let sent = discord_webhook::send_message(
+            &d.webhook_url,
+            d.username.as_deref(),
+            d.avatar_url.as_deref(),
+            message(),
+        )
+        .await;
+        outcomes.push(("discord_webhook", sent.map_err(failed)));
+    }
+    if let Some(t) = &dispatchers.teams {
+        let sent = teams::send_message(&t.webhook_url, message()).await;
+        outcomes.push(("teams", sent.map_err(failed)));
+    }
+    if let Some(s) = &dispatchers.slack {
+        let sent = slack::send_message(&s.webhook_url, &s.channel, message()).await;
+        outcomes.push(("slack", sent.map_err(|e| e.to_string())));
+    }
+    if let Some(g) = &dispatchers.gotify {
+        let sent = gotify::send_message(g.server_url.as_str(), &g.app_token, message()).await;
+        outcomes.push(("gotify", sent.map_err(failed)));
+    }
+    if let Some(e) = &dispatchers.email {
+        let sent = email::send_message(
+            &e.smtp_server,
+            &e.smtp_user,
+            &e.smtp_password,
+            &e.sender_address,
+            &e.sender_name,
+            &e.receiver_address,
+            message(),
+        )
+        .await;
+        outcomes.push(("email", sent.map_err(failed)));
+    }
+    if let Some(n) = &dispatchers.ntfy {
+        let sent = ntfy::send_message(
+            n.server_url.as_str(),
+            &n.topic,
+            n.access_token.as_deref(),
+            message(),
+        )
+        .await;
+        outcomes.push(("ntfy", sent.map_err(failed)));
+    }
+    if let Some(p) = &dispatchers.pushover {
+        let sent = pushover::send_message(&p.token, &p.user, p.device.as_deref(), message()).await;
+        outcomes.push(("pushover", sent.map_err(failed)));
+    }
+    if let Some(m) = &dispatchers.matrix {
+        let sent =
+            matrix::send_message(&m.homeserver_url, &m.access_token, &m.room_id, message()).await;
+        outcomes.push(("matrix", sent.map_err(failed)));
+    }
+    if let Some(m) = &dispatchers.mattermost {
+        let sent = mattermost::send_message(
+            &m.webhook_url,
+            m.channel.as_deref(),
+            m.username.as_deref(),
+            message(),
+        )
+        .await;
+        outcomes.push(("mattermost", sent.map_err(failed)));
+    }
+    if let Some(r) = &dispatchers.rocketchat {
+        let sent = rocketchat::send_message(
+            &r.webhook_url,
+            r.channel.as_deref(),
+            r.alias.as_deref(),
+            message(),
+        )
+        .await;
+        outcomes.push(("rocketchat", sent.map_err(failed)));
+    }
+    if let Some(g) = &dispatchers.google_chat {
+        let sent = google_chat::send_message(&g.webhook_url, message()).await;
+        outcomes.push(("google_chat", sent.map_err(failed)));
+    }
+    if let Some(w) = &dispatchers.webhook {
+        let sent = webhook::send_message(&w.url, &w.headers, message()).await;
+        outcomes.push(("webhook", sent.map_err(failed)));
+    }
+    outcomes
+}
+
+/// Telegram's sender is private to chatterbox; this is the same request,
+/// with the answer checked.
+#[cfg(feature = "cli")]
+async fn send_telegram(
+    telegram: &chatterbox::dispatcher::telegram::Telegram,
+    message: Message,
+) -> Result<(), String> {
+    reqwest::Client::new()
+        .post(format!(
+            "https://api.telegram.org/bot{}/sendMessage",
+            telegram.bot_token
+        ))
+        .json(&serde_json::json!({
+            "chat_id": telegram.chat_id.to_string(),
+            "text": format!("<b>{}</b>\n\n{}", message.title, message.body),
+            "parse_mode": "html",
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
 
-    Some(Dispatcher::new(sender))
+/// Like [`send_telegram`], for Discord bots.
+#[cfg(feature = "cli")]
+async fn send_discord(
+    discord: &chatterbox::dispatcher::discord::Discord,
+    message: Message,
+) -> Result<(), String> {
+    reqwest::Client::new()
+        .post(format!(
+            "https://discord.com/api/v10/channels/{}/messages",
+            discord.channel_id
+        ))
+        .header("Authorization", format!("Bot {}", discord.bot_token))
+        .json(&serde_json::json!({
+            "embeds": [{ "title": message.title, "description": message.body }],
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(drop)
+        .map_err(|e| e.to_string())
 }
 
 impl From<HoisterError> for Option<Message> {
@@ -618,6 +787,7 @@ mod tests {
             status: DeploymentStatus::Success,
             hostname: HostName::default(),
             logs: None,
+            event_id: None,
         };
         assert!(send_to_chatterbox(&msg, None).await.is_ok());
     }
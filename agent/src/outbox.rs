@@ -0,0 +1,469 @@
+//! Reports the controller must not miss, kept until it takes them.
+//!
+//! Deployment events and pending updates are written to `[controller]
+//! queue_directory`, one file per report, and sent in order by [`Outbox::run`].
+//! A report that fails to arrive, e.g. while the controller is down, is
+//! retried with backoff. Each report carries an event ID, so the controller
+//! records it only once when a retry follows a delivery whose answer was lost.
+//! A report the controller keeps failing to store moves behind the others.
+//!
+//! Without a usable directory the queue is kept in memory: reports still
+//! survive a controller outage, but not a restart of the agent.
+
+use crate::config::Controller;
+use hoister_shared::CreateDeployment;
+use hoister_shared::wire::PostPendingUpdate;
+use log::{debug, info, warn};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Reports kept at most; the oldest is dropped to make room beyond that.
+const MAX_QUEUED: usize = 1000;
+/// Wait after the first failed delivery, doubled after each further one.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Server errors in a row after which a report moves to the back of the
+/// queue, so one the controller can't store doesn't hold up the others.
+const MAX_SERVER_ERRORS: u32 = 5;
+
+/// A report for the controller, as it is stored in the queue.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "body", rename_all = "snake_case")]
+pub(crate) enum Report {
+    Deployment(CreateDeployment),
+    PendingUpdate(PostPendingUpdate),
+}
+
+impl Report {
+    fn event_id(&mut self) -> &mut Option<Uuid> {
+        match self {
+            Self::Deployment(deployment) => &mut deployment.event_id,
+            Self::PendingUpdate(update) => &mut update.event_id,
+        }
+    }
+
+    fn path(&self) -> &'static str {
+        match self {
+            Self::Deployment(_) => "/deployments",
+            Self::PendingUpdate(_) => "/pending-updates",
+        }
+    }
+
+    fn body(&self) -> serde_json::Result<Vec<u8>> {
+        match self {
+            Self::Deployment(deployment) => serde_json::to_vec(deployment),
+            Self::PendingUpdate(update) => serde_json::to_vec(update),
+        }
+    }
+}
+
+struct Queued {
+    /// The report's file, named so the files sort in the order they were
+    /// queued.
+    name: String,
+    report: Report,
+    /// Server errors the controller answered this report with in a row.
+    server_errors: u32,
+}
+
+/// The queue, shared by the tasks that report and the one that delivers.
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    directory: Option<PathBuf>,
+    queue: Mutex<VecDeque<Queued>>,
+    queued: Notify,
+}
+
+enum Delivery {
+    Empty,
+    Sent,
+    Failed(String),
+}
+
+impl Outbox {
+    /// Open the queue in `directory`, picking up the reports a previous run
+    /// left behind.
+    pub(crate) fn open(directory: &Path) -> Self {
+        let (directory, queue) = match load(directory) {
+            Ok(queue) => {
+                if !queue.is_empty() {
+                    info!(
+                        "{} queued reports for the controller from a previous run",
+                        queue.len()
+                    );
+                }
+                (Some(directory.to_path_buf()), queue)
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot use {} for the controller queue, keeping it in memory: {e}",
+                    directory.display()
+                );
+                (None, VecDeque::new())
+            }
+        };
+        Self::with(directory, queue)
+    }
+
+    /// The queue for `controller`'s `queue_directory`. Nothing is reported,
+    /// and so nothing stored, without a controller.
+    pub(crate) fn for_controller(controller: Option<&Controller>) -> Self {
+        match controller {
+            Some(controller) => Self::open(&controller.queue_directory),
+            None => Self::in_memory(),
+        }
+    }
+
+    /// A queue that is never written to disk.
+    pub(crate) fn in_memory() -> Self {
+        Self::with(None, VecDeque::new())
+    }
+
+    fn with(directory: Option<PathBuf>, queue: VecDeque<Queued>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                directory,
+                queue: Mutex::new(queue),
+                queued: Notify::new(),
+            }),
+        }
+    }
+
+    /// Queue `report` for delivery, giving it an event ID.
+    pub(crate) fn push(&self, mut report: Report) {
+        let event_id = *report.event_id().get_or_insert_with(Uuid::new_v4);
+        let name = format!("{:020}-{event_id}.json", now_nanos());
+        if let Some(directory) = &self.inner.directory
+            && let Err(e) = write(directory, &name, &report)
+        {
+            warn!("Failed to store report {event_id}, keeping it in memory only: {e}");
+        }
+
+        let mut queue = self.lock();
+        if queue.len() >= MAX_QUEUED
+            && let Some(oldest) = queue.pop_front()
+        {
+            warn!("More than {MAX_QUEUED} reports for the controller queued, dropping the oldest");
+            self.delete(&oldest.name);
+        }
+        queue.push_back(Queued {
+            name,
+            report,
+            server_errors: 0,
+        });
+        drop(queue);
+        self.inner.queued.notify_one();
+    }
+
+    /// Send the queued reports to `controller` as they come, forever.
+    pub(crate) async fn run(self, client: Client, controller: Controller) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.deliver_next(&client, &controller).await {
+                Delivery::Empty => self.inner.queued.notified().await,
+                Delivery::Sent => backoff = MIN_BACKOFF,
+                Delivery::Failed(e) => {
+                    warn!(
+                        "Failed to report to the controller, retrying in {}s: {e}",
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Try once to send everything queued, e.g. before a one-shot command
+    /// exits. What doesn't arrive stays queued for the agent.
+    pub(crate) async fn flush(&self, client: &Client, controller: &Controller) {
+        loop {
+            match self.deliver_next(client, controller).await {
+                Delivery::Empty => return,
+                Delivery::Sent => {}
+                Delivery::Failed(e) => {
+                    warn!(
+                        "Failed to report to the controller, {} reports stay queued: {e}",
+                        self.lock().len()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn deliver_next(&self, client: &Client, controller: &Controller) -> Delivery {
+        let (name, path, body) = {
+            let queue = self.lock();
+            let Some(next) = queue.front() else {
+                return Delivery::Empty;
+            };
+            (next.name.clone(), next.report.path(), next.report.body())
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Dropping report {name}, it can't be encoded: {e}");
+                self.remove(&name);
+                return Delivery::Sent;
+            }
+        };
+
+        let url = controller
+            .url
+            .join(path)
+            .expect("controller report URL should be valid");
+        let mut req = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body);
+        if let Some(token) = &controller.token {
+            req = req.bearer_auth(token);
+        }
+        match req.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("report {name} delivered");
+                self.remove(&name);
+                Delivery::Sent
+            }
+            Ok(response) if is_rejected(response.status()) => {
+                warn!(
+                    "The controller rejected report {name} ({}), dropping it",
+                    response.status()
+                );
+                self.remove(&name);
+                Delivery::Sent
+            }
+            Ok(response) if response.status().is_server_error() => {
+                self.server_error(&name);
+                Delivery::Failed(format!("answered {}", response.status()))
+            }
+            Ok(response) => Delivery::Failed(format!("answered {}", response.status())),
+            Err(e) => Delivery::Failed(e.to_string()),
+        }
+    }
+
+    /// Take the report `name` off the queue once it is dealt with.
+    fn remove(&self, name: &str) {
+        let mut queue = self.lock();
+        if queue.front().is_some_and(|next| next.name == name) {
+            queue.pop_front();
+        }
+        drop(queue);
+        self.delete(name);
+    }
+
+    /// Count a server error for the report `name`, parking it behind the
+    /// others after `MAX_SERVER_ERRORS` in a row. It is renamed so it keeps
+    /// its new place after a restart.
+    fn server_error(&self, name: &str) {
+        let mut queue = self.lock();
+        let Some(next) = queue.front_mut().filter(|next| next.name == name) else {
+            return;
+        };
+        next.server_errors += 1;
+        if next.server_errors < MAX_SERVER_ERRORS || queue.len() == 1 {
+            return;
+        }
+        let mut parked = queue.pop_front().expect("front was just checked");
+        let (_, event_id) = parked.name.split_once('-').unwrap_or(("", &parked.name));
+        let renamed = format!("{:020}-{event_id}", now_nanos());
+        if let Some(directory) = &self.inner.directory
+            && let Err(e) = std::fs::rename(directory.join(&parked.name), directory.join(&renamed))
+        {
+            warn!("Failed to move report {name} to the back of the queue: {e}");
+        }
+        warn!(
+            "The controller failed to store report {name} {MAX_SERVER_ERRORS} times, \
+             moving it behind the other {} reports",
+            queue.len()
+        );
+        parked.name = renamed;
+        parked.server_errors = 0;
+        queue.push_back(parked);
+    }
+
+    fn delete(&self, name: &str) {
+        if let Some(directory) = &self.inner.directory
+            && let Err(e) = std::fs::remove_file(directory.join(name))
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove delivered report {name}: {e}");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Queued>> {
+        self.inner.queue.lock().expect("outbox lock poisoned")
+    }
+}
+
+/// Whether the controller turned a report down for good: the body itself is
+/// bad, or its event ID belongs to another report, so retrying it won't help.
+/// Everything else is retried, since the operator or the controller can fix
+/// it, e.g. auth failures, plan limits and server errors.
+fn is_rejected(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_REQUEST
+            | StatusCode::CONFLICT
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::UNPROCESSABLE_ENTITY
+    )
+}
+
+/// Names of queued files start with this, so they sort by queueing time.
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Read the reports in `directory`, oldest first, creating it if needed.
+fn load(directory: &Path) -> std::io::Result<VecDeque<Queued>> {
+    std::fs::create_dir_all(directory)?;
+    let mut names: Vec<String> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".json"))
+        .collect();
+    names.sort();
+
+    let mut queue = VecDeque::new();
+    for name in names {
+        let path = directory.join(&name);
+        let report = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()));
+        match report {
+            Ok(report) => queue.push_back(Queued {
+                name,
+                report,
+                server_errors: 0,
+            }),
+            Err(e) => {
+                warn!("Dropping unreadable queued report {}: {e}", path.display());
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+    Ok(queue)
+}
+
+/// Store `report` as `name`, through a temporary file so a crash never
+/// leaves half a report behind.
+fn write(directory: &Path, name: &str, report: &Report) -> std::io::Result<()> {
+    let tmp = directory.join(format!("{name}.tmp"));
+    std::fs::write(&tmp, serde_json::to_vec(report)?)?;
+    std::fs::rename(&tmp, directory.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hoister_shared::{DeploymentStatus, HostName, ImageDigest, ImageName, ProjectName};
+
+    fn pending_update(service: &str) -> Report {
+        Report::PendingUpdate(PostPendingUpdate {
+            hostname: HostName::new("host"),
+            project_name: ProjectName::new("proj"),
+            service_name: hoister_shared::ServiceName::new(service),
+            image_name: ImageName::new("acme/api:1"),
+            new_digest: ImageDigest::new("sha256:new"),
+            event_id: None,
+        })
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<(String, Option<Uuid>)> {
+        outbox
+            .lock()
+            .iter_mut()
+            .map(|queued| {
+                let service = match &queued.report {
+                    Report::PendingUpdate(update) => update.service_name.as_str().to_string(),
+                    Report::Deployment(deployment) => deployment.service.as_str().to_string(),
+                };
+                (service, *queued.report.event_id())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_survive_a_restart_in_order() {
+        let dir = std::env::temp_dir().join(format!("hoister-outbox-test-{}", Uuid::new_v4()));
+        let outbox = Outbox::open(&dir);
+        outbox.push(pending_update("api"));
+        outbox.push(Report::Deployment(CreateDeployment {
+            status: DeploymentStatus::Success,
+            ..CreateDeployment::test()
+        }));
+        outbox.push(pending_update("db"));
+        let before = queued(&outbox);
+        assert!(before.iter().all(|(_, event_id)| event_id.is_some()));
+
+        // Delivering the first report removes its file.
+        let first = outbox.lock().front().unwrap().name.clone();
+        outbox.remove(&first);
+
+        let reopened = Outbox::open(&dir);
+        assert_eq!(queued(&reopened), before[1..]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest_report() {
+        let outbox = Outbox::in_memory();
+        for i in 0..=MAX_QUEUED {
+            outbox.push(pending_update(&i.to_string()));
+        }
+        let services: Vec<_> = queued(&outbox).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(services.len(), MAX_QUEUED);
+        assert_eq!(services[0], "1");
+    }
+
+    #[test]
+    fn a_report_failing_with_server_errors_moves_behind_the_others() {
+        let dir = std::env::temp_dir().join(format!("hoister-outbox-test-{}", Uuid::new_v4()));
+        let outbox = Outbox::open(&dir);
+        outbox.push(pending_update("api"));
+        outbox.push(pending_update("db"));
+        let failing = outbox.lock().front().unwrap().name.clone();
+
+        for _ in 1..MAX_SERVER_ERRORS {
+            outbox.server_error(&failing);
+        }
+        assert_eq!(queued(&outbox)[0].0, "api");
+        outbox.server_error(&failing);
+        let services: Vec<_> = queued(&outbox).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(services, ["db", "api"]);
+
+        // It stays behind after a restart.
+        let reopened = Outbox::open(&dir);
+        assert_eq!(queued(&reopened), queued(&outbox));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_client_errors_about_the_report_are_final() {
+        assert!(is_rejected(StatusCode::BAD_REQUEST));
+        assert!(is_rejected(StatusCode::PAYLOAD_TOO_LARGE));
+        assert!(is_rejected(StatusCode::UNPROCESSABLE_ENTITY));
+        assert!(is_rejected(StatusCode::CONFLICT));
+        assert!(!is_rejected(StatusCode::PAYMENT_REQUIRED));
+        assert!(!is_rejected(StatusCode::NOT_FOUND));
+        assert!(!is_rejected(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_rejected(StatusCode::UNAUTHORIZED));
+        assert!(!is_rejected(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_rejected(StatusCode::BAD_GATEWAY));
+    }
+}
@@ -0,0 +1,156 @@
+//! Hot reload of the agent configuration.
+//!
+//! The config is re-read on SIGHUP and whenever the config file's contents
+//! change. A new config that fails validation is rejected and the current one
+//! stays in effect. A valid one is published on a `watch` channel. Everything
+//! that reads the config takes a snapshot when it starts a unit of work, so an
+//! update in progress finishes with the config it started with.
+
+use crate::config::{self, Config};
+use crate::docker::DockerHandler;
+use crate::{monitor, redact};
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often the config file is compared against the last loaded contents.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reload on SIGHUP and on config file changes until the process exits.
+pub(crate) async fn watch_config(
+    config_path: PathBuf,
+    engines: Vec<Arc<DockerHandler>>,
+    tx: watch::Sender<Arc<Config>>,
+) {
+    let mut hangup = Hangup::new();
+    // Compare contents rather than mtimes: mounted ConfigMaps and secrets are
+    // swapped via symlinks, which leave the file's own mtime unchanged.
+    let mut last = std::fs::read(&config_path).ok();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("Received SIGHUP, reloading configuration"),
+            _ = poll.tick() => {
+                let current = std::fs::read(&config_path).ok();
+                if current == last {
+                    continue;
+                }
+                info!("{} changed, reloading configuration", config_path.display());
+            }
+        }
+        last = std::fs::read(&config_path).ok();
+        let current = Arc::clone(&tx.borrow());
+        if let Some(config) = load(&config_path, &current).await {
+            apply(config, &engines, &tx);
+        }
+    }
+}
+
+/// Load and validate the config file again and merge it into `current`.
+/// `None` if the new config is invalid.
+async fn load(config_path: &Path, current: &Config) -> Option<Config> {
+    let new = match config::load_config(config_path).await {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Rejected the new configuration, keeping the current one:\n{e}");
+            return None;
+        }
+    };
+    let (config, restart_only) = current.reloaded(new);
+    for key in restart_only {
+        warn!("`{key}` changed; this takes effect after a restart");
+    }
+    Some(config)
+}
+
+/// Swap in a reloaded config: redaction lists and rules, and each engine's
+/// registry credentials, pull limit, backup settings and container selection
+/// first, then everything reading the `watch` channel.
+fn apply(config: Config, engines: &[Arc<DockerHandler>], tx: &watch::Sender<Arc<Config>>) {
+    monitor::set_extra_keywords(config.redact_keywords.clone());
+    monitor::register_agent_secrets(&config.secret_values);
+    redact::configure(&config.redaction);
+    for docker in engines {
+        docker.set_registries(config.registry.clone());
+        docker.set_max_parallel_pulls(config.max_parallel_pulls);
+        docker.set_backups(config.backups.clone());
+        docker.set_selection(config.selection.clone());
+    }
+    tx.send_replace(Arc::new(config));
+    info!("Configuration reloaded");
+}
+
+/// Sleep for `duration`, or until the config is reloaded, so the caller can
+/// re-plan with the new schedule instead of waiting out the old one.
+pub(crate) async fn sleep_or_reload(config: &mut watch::Receiver<Arc<Config>>, duration: Duration) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        changed = config.changed() => {
+            if changed.is_err() {
+                // Nothing reloads any more; wait out the schedule.
+                tokio::time::sleep(duration).await;
+            }
+        }
+    }
+}
+
+/// SIGHUP notifications. Never fires where the handler can't be installed.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|e| warn!("Cannot listen for SIGHUP, only watching the file: {e}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use figment2::Jail;
+
+    #[test]
+    fn invalid_reload_keeps_the_current_config() {
+        Jail::expect_with(|jail: &mut Jail| {
+            jail.create_file("hoister.toml", "[schedule]\ninterval=10\n")?;
+            let path = Path::new("hoister.toml");
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                let current = config::load_config(path).await.unwrap();
+
+                std::fs::write(path, "[schedule]\ninterval=0\n").unwrap();
+                assert!(load(path, &current).await.is_none());
+
+                std::fs::write(path, "redact_keywords=[\"pin\"]\n[schedule]\ninterval=30\n")
+                    .unwrap();
+                let reloaded = load(path, &current).await.unwrap();
+                assert_eq!(reloaded.schedule.interval, Some(30));
+                assert_eq!(reloaded.redact_keywords, ["pin"]);
+            });
+            Ok(())
+        });
+    }
+}
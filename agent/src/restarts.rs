@@ -0,0 +1,329 @@
+//! Scheduled restarts.
+//!
+//! `hoister.restart-schedule=<cron>` restarts a container at the given times
+//! whether or not a new image exists, e.g. to keep a leaking service in check.
+//! The restart is held to the same health check as an update and reported as
+//! a deployment event. Replicas are restarted one at a time. With
+//! `hoister.restart-dependents=true` the services that `depends_on` it are
+//! restarted after it.
+//!
+//! An update restarts the services named in the updated container's
+//! `hoister.restart-with` label, and those whose Compose `depends_on` on it
+//! sets `restart: true`, e.g. services that cache a config server's output.
+
+use crate::docker::{ContainerID, DockerHandler, get_service_identifier};
+use crate::scheduler::{self, ServiceKey};
+use crate::selection;
+use chrono::{DateTime, Utc};
+use cron::Schedule as CronSchedule;
+use hoister_shared::ProjectName;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const RESTART_SCHEDULE_LABEL: &str = "hoister.restart-schedule";
+pub(crate) const RESTART_DEPENDENTS_LABEL: &str = "hoister.restart-dependents";
+pub(crate) const RESTART_WITH_LABEL: &str = "hoister.restart-with";
+const IDENTIFIER_LABEL: &str = "hoister.identifier";
+
+/// How often the containers are listed again, to pick up new and relabelled
+/// ones between restarts.
+const RESCAN: Duration = Duration::from_secs(60);
+
+/// The restart schedule set by a container's labels, if any.
+pub(crate) fn restart_schedule(
+    labels: Option<&HashMap<String, String>>,
+) -> Option<Result<CronSchedule, String>> {
+    let cron = labels?.get(RESTART_SCHEDULE_LABEL)?.trim();
+    Some(
+        CronSchedule::from_str(cron)
+            .map_err(|e| format!("invalid `{RESTART_SCHEDULE_LABEL}` {cron:?}: {e}")),
+    )
+}
+
+/// Whether the container labelled `labels` is restarted after the container
+/// labelled `updated` was updated.
+pub(crate) fn restarts_with_update(
+    labels: Option<&HashMap<String, String>>,
+    updated: Option<&HashMap<String, String>>,
+) -> bool {
+    let label = |labels: Option<&HashMap<String, String>>, name| {
+        labels
+            .and_then(|l| l.get(name))
+            .map(|v| v.trim().to_string())
+    };
+    let named = label(updated, RESTART_WITH_LABEL).is_some_and(|names| {
+        names.split(',').map(str::trim).any(|name| {
+            label(labels, IDENTIFIER_LABEL).as_deref() == Some(name)
+                || scheduler::compose_service(labels).as_deref() == Some(name)
+        })
+    });
+    named
+        || scheduler::compose_service(updated)
+            .is_some_and(|service| scheduler::restarts_with(labels).contains(&service))
+}
+
+/// When each service with a restart schedule is restarted next.
+#[derive(Debug, Default)]
+struct Restarts {
+    next: HashMap<ServiceKey, (CronSchedule, Option<DateTime<Utc>>)>,
+}
+
+impl Restarts {
+    /// The services among `found` that are due for a restart at `now`.
+    /// A service seen for the first time, or with a changed schedule, waits
+    /// for its next fire time; services no longer found are forgotten.
+    fn due(
+        &mut self,
+        found: Vec<(ServiceKey, CronSchedule)>,
+        now: DateTime<Utc>,
+    ) -> Vec<ServiceKey> {
+        let mut due = Vec::new();
+        let mut next = HashMap::new();
+        for (service, schedule) in found {
+            let fires_at = match self.next.remove(&service) {
+                Some((previous, Some(at))) if previous == schedule && at <= now => {
+                    due.push(service.clone());
+                    schedule.after(&now).next()
+                }
+                Some((previous, at)) if previous == schedule => at,
+                _ => schedule.after(&now).next(),
+            };
+            next.insert(service, (schedule, fires_at));
+        }
+        self.next = next;
+        due
+    }
+
+    /// How long until the next restart, or the next rescan if that is sooner.
+    fn next_wake(&self, now: DateTime<Utc>) -> Duration {
+        self.next
+            .values()
+            .filter_map(|(_, at)| *at)
+            .map(|at| (at - now).to_std().unwrap_or(Duration::ZERO))
+            .fold(RESCAN, Duration::min)
+    }
+}
+
+/// Restart labelled containers of `project` on their schedules, forever.
+pub(crate) async fn start(docker: Arc<DockerHandler>, project: ProjectName) {
+    let mut restarts = Restarts::default();
+    loop {
+        if let Err(e) = restart_due(&docker, &project, &mut restarts).await {
+            warn!("Scheduled restarts: {e}");
+        }
+        tokio::time::sleep(restarts.next_wake(Utc::now())).await;
+    }
+}
+
+async fn restart_due(
+    docker: &DockerHandler,
+    project: &ProjectName,
+    restarts: &mut Restarts,
+) -> Result<(), String> {
+    let now = Utc::now();
+    let containers = docker
+        .get_containers(project)
+        .await
+        .map_err(|e| format!("could not list containers: {e}"))?;
+    let mut found = Vec::new();
+    // Every replica of a service, in the order they are listed.
+    let mut targets: HashMap<ServiceKey, (Vec<ContainerID>, bool)> = HashMap::new();
+    for container in containers {
+        let Some(schedule) = restart_schedule(container.labels.as_ref()) else {
+            continue;
+        };
+        let Some(container_id) = container.id.clone() else {
+            continue;
+        };
+        let service = match get_service_identifier(docker.docker.as_ref(), &container_id).await {
+            Ok(service) => (selection::project_of(&container, project), service),
+            Err(e) => {
+                warn!("Skipping scheduled restart of {container_id}: {e}");
+                continue;
+            }
+        };
+        let schedule = match schedule {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                warn!("{}: {e}; not restarting it", service.1.as_str());
+                continue;
+            }
+        };
+        let dependents = container
+            .labels
+            .as_ref()
+            .and_then(|l| l.get(RESTART_DEPENDENTS_LABEL))
+            .is_some_and(|v| v.trim() == "true");
+        match targets.get_mut(&service) {
+            Some((replicas, _)) => replicas.push(container_id),
+            None => {
+                found.push((service.clone(), schedule));
+                targets.insert(service, (vec![container_id], dependents));
+            }
+        }
+    }
+
+    for service in restarts.due(found, now) {
+        let (replicas, dependents) = &targets[&service];
+        let (project, service) = &service;
+        debug!("Scheduled restart of {} is due", service.as_str());
+        // One replica after another, so the others keep serving. A replica
+        // that fails its health check stops the rest.
+        let mut restarted = true;
+        for container_id in replicas {
+            if let Err(e) = docker.restart_container(project, container_id).await {
+                warn!("Scheduled restart failed: {e}");
+                restarted = false;
+                break;
+            }
+        }
+        if restarted
+            && *dependents
+            && let Err(e) = docker.restart_dependents(project, &replicas[0]).await
+        {
+            warn!("Restarting dependents of {}: {e}", service.as_str());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::tests::{handler, statuses};
+    use crate::runtime::fake::FakeRuntime;
+    use hoister_shared::{DeploymentStatus, ServiceName};
+
+    fn cron(expr: &str) -> CronSchedule {
+        CronSchedule::from_str(expr).unwrap()
+    }
+
+    #[test]
+    fn parses_label() {
+        let labels =
+            |value: &str| HashMap::from([(RESTART_SCHEDULE_LABEL.to_string(), value.to_string())]);
+        assert!(restart_schedule(None).is_none());
+        assert!(restart_schedule(Some(&HashMap::new())).is_none());
+        assert!(
+            restart_schedule(Some(&labels("0 0 3 * * * *")))
+                .unwrap()
+                .is_ok()
+        );
+        assert!(restart_schedule(Some(&labels("nightly"))).unwrap().is_err());
+    }
+
+    #[test]
+    fn restarts_with_named_and_depending_services() {
+        let labels = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let config_server = labels(&[
+            ("com.docker.compose.service", "config-server"),
+            (RESTART_WITH_LABEL, "api, reports"),
+        ]);
+        let api = labels(&[("com.docker.compose.service", "api")]);
+        let reports = labels(&[(IDENTIFIER_LABEL, "reports")]);
+        let worker = labels(&[
+            ("com.docker.compose.service", "worker"),
+            (
+                "com.docker.compose.depends_on",
+                "config-server:service_healthy:true,db:service_started:false",
+            ),
+        ]);
+        let db_user = labels(&[(
+            "com.docker.compose.depends_on",
+            "config-server:service_started:false",
+        )]);
+
+        assert!(restarts_with_update(Some(&api), Some(&config_server)));
+        assert!(restarts_with_update(Some(&reports), Some(&config_server)));
+        assert!(restarts_with_update(Some(&worker), Some(&config_server)));
+        assert!(!restarts_with_update(Some(&db_user), Some(&config_server)));
+        assert!(!restarts_with_update(Some(&config_server), Some(&api)));
+    }
+
+    #[test]
+    fn restarts_fire_on_their_schedule() {
+        let service = (ProjectName::new("shop"), ServiceName::new("legacy"));
+        let every_minute = cron("0 * * * * * *");
+        let start = DateTime::parse_from_rfc3339("2026-01-01T03:00:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let mut restarts = Restarts::default();
+        // A newly seen service waits for its next fire time.
+        assert!(
+            restarts
+                .due(vec![(service.clone(), every_minute.clone())], start)
+                .is_empty()
+        );
+        assert_eq!(restarts.next_wake(start), Duration::from_secs(30));
+
+        let later = start + Duration::from_secs(31);
+        assert_eq!(
+            restarts.due(vec![(service.clone(), every_minute.clone())], later),
+            std::slice::from_ref(&service)
+        );
+        // Not again until the following minute.
+        assert!(
+            restarts
+                .due(vec![(service.clone(), every_minute.clone())], later)
+                .is_empty()
+        );
+
+        // A changed schedule starts over; a nightly one leaves the rescan as
+        // the next wake-up.
+        let nightly = cron("0 0 3 * * * *");
+        let much_later = later + Duration::from_secs(120);
+        assert!(
+            restarts
+                .due(vec![(service.clone(), nightly)], much_later)
+                .is_empty()
+        );
+        assert_eq!(restarts.next_wake(much_later), RESCAN);
+
+        // Gone containers are forgotten.
+        restarts.due(vec![], much_later);
+        assert!(restarts.next.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_replica_is_restarted() {
+        let fake = Arc::new(FakeRuntime::default());
+        fake.add_image("acme/legacy:1", "sha256:legacy");
+        for replica in ["1", "2"] {
+            fake.run(
+                &format!("legacy-{replica}"),
+                "acme/legacy:1",
+                &[
+                    ("hoister.enable", "true"),
+                    ("com.docker.compose.project", "shop"),
+                    ("com.docker.compose.service", "legacy"),
+                    ("com.docker.compose.container-number", replica),
+                    (RESTART_SCHEDULE_LABEL, "0 * * * * * *"),
+                ],
+            )
+            .await;
+        }
+        let (docker, mut rx) = handler(&fake);
+        let project = ProjectName::new("shop");
+        let mut restarts = Restarts::default();
+        restarts.next.insert(
+            (project.clone(), ServiceName::new("legacy")),
+            (cron("0 * * * * * *"), Some(Utc::now())),
+        );
+
+        restart_due(&docker, &project, &mut restarts).await.unwrap();
+
+        assert!(matches!(
+            statuses(&mut rx)[..],
+            [DeploymentStatus::Restarted, DeploymentStatus::Restarted]
+        ));
+    }
+}
@@ -0,0 +1,698 @@
+//! The container engine behind a `DockerHandler`.
+//!
+//! Everything the agent asks of an engine goes through [`ContainerRuntime`],
+//! whose methods mirror bollard's. `Docker` implements it directly; [`Podman`]
+//! talks to Podman's Docker-compatible API, which is found at other socket
+//! paths and reports pulls differently. Tests run the update and rollback
+//! paths against the in-memory engine in `runtime/fake.rs`.
+
+use bollard::Docker;
+use bollard::auth::DockerCredentials;
+use bollard::container::LogOutput;
+use bollard::errors::Error;
+use bollard::exec::{CreateExecResults, StartExecOptions, StartExecResults};
+use bollard::models::{
+    ContainerCreateBody, ContainerCreateResponse, ContainerInspectResponse, ContainerStatsResponse,
+    ContainerSummary, ContainerWaitResponse, ExecConfig, ExecInspectResponse,
+    ImageDeleteResponseItem, ImageInspect, SystemDataUsageResponse, SystemInfo, Volume,
+    VolumeCreateOptions,
+};
+use bollard::query_parameters::{
+    CreateContainerOptions, CreateImageOptions, DataUsageOptions, DownloadFromContainerOptions,
+    InspectContainerOptions, ListContainersOptions, LogsOptions, RemoveContainerOptions,
+    RemoveImageOptions, RemoveVolumeOptions, RenameContainerOptions, RestartContainerOptions,
+    StartContainerOptions, StatsOptions, StopContainerOptions, WaitContainerOptions,
+};
+#[cfg(feature = "cli")]
+use bollard::{
+    models::DistributionInspect,
+    query_parameters::{TagImageOptions, UploadToContainerOptions},
+};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::{FutureExt, StreamExt};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+pub(crate) mod fake;
+
+/// The operations the agent needs from a container engine.
+pub(crate) trait ContainerRuntime: Send + Sync {
+    /// Engine-wide information, e.g. the engine's name.
+    fn info(&self) -> BoxFuture<'_, Result<SystemInfo, Error>>;
+
+    /// Disk usage of containers, images and volumes.
+    fn df(
+        &self,
+        options: Option<DataUsageOptions>,
+    ) -> BoxFuture<'_, Result<SystemDataUsageResponse, Error>>;
+
+    fn list_containers(
+        &self,
+        options: Option<ListContainersOptions>,
+    ) -> BoxFuture<'_, Result<Vec<ContainerSummary>, Error>>;
+
+    fn inspect_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<InspectContainerOptions>,
+    ) -> BoxFuture<'a, Result<ContainerInspectResponse, Error>>;
+
+    fn create_container(
+        &self,
+        options: Option<CreateContainerOptions>,
+        config: ContainerCreateBody,
+    ) -> BoxFuture<'_, Result<ContainerCreateResponse, Error>>;
+
+    fn rename_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: RenameContainerOptions,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn start_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn stop_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StopContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn restart_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RestartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn remove_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RemoveContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn wait_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<WaitContainerOptions>,
+    ) -> BoxStream<'a, Result<ContainerWaitResponse, Error>>;
+
+    fn logs<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<LogsOptions>,
+    ) -> BoxStream<'a, Result<LogOutput, Error>>;
+
+    fn stats<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StatsOptions>,
+    ) -> BoxStream<'a, Result<ContainerStatsResponse, Error>>;
+
+    /// Pull `repo:tag`, where `tag` may also be a `sha256:` digest. Returns
+    /// whether an image other than the local one was downloaded.
+    fn pull_image<'a>(
+        &'a self,
+        repo: &'a str,
+        tag: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<bool, Error>>;
+
+    fn inspect_image<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Result<ImageInspect, Error>>;
+
+    /// Ask the registry about `image` without pulling it.
+    #[cfg(feature = "cli")]
+    fn inspect_registry_image<'a>(
+        &'a self,
+        image: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<DistributionInspect, Error>>;
+
+    #[cfg(feature = "cli")]
+    fn tag_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<TagImageOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn remove_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<RemoveImageOptions>,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<Vec<ImageDeleteResponseItem>, Error>>;
+
+    fn create_exec<'a>(
+        &'a self,
+        container: &'a str,
+        config: ExecConfig,
+    ) -> BoxFuture<'a, Result<CreateExecResults, Error>>;
+
+    fn start_exec<'a>(
+        &'a self,
+        exec: &'a str,
+        options: Option<StartExecOptions>,
+    ) -> BoxFuture<'a, Result<StartExecResults, Error>>;
+
+    fn inspect_exec<'a>(
+        &'a self,
+        exec: &'a str,
+    ) -> BoxFuture<'a, Result<ExecInspectResponse, Error>>;
+
+    /// Extract the tar archive `tar` into the container.
+    #[cfg(feature = "cli")]
+    fn upload_to_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<UploadToContainerOptions>,
+        tar: BoxStream<'static, Bytes>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// A path in the container as a tar archive.
+    fn download_from_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<DownloadFromContainerOptions>,
+    ) -> BoxStream<'a, Result<Bytes, Error>>;
+
+    fn create_volume(&self, options: VolumeCreateOptions) -> BoxFuture<'_, Result<Volume, Error>>;
+
+    fn inspect_volume<'a>(&'a self, volume: &'a str) -> BoxFuture<'a, Result<Volume, Error>>;
+
+    fn remove_volume<'a>(
+        &'a self,
+        volume: &'a str,
+        options: Option<RemoveVolumeOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl ContainerRuntime for Docker {
+    fn info(&self) -> BoxFuture<'_, Result<SystemInfo, Error>> {
+        Docker::info(self).boxed()
+    }
+
+    fn df(
+        &self,
+        options: Option<DataUsageOptions>,
+    ) -> BoxFuture<'_, Result<SystemDataUsageResponse, Error>> {
+        Docker::df(self, options).boxed()
+    }
+
+    fn list_containers(
+        &self,
+        options: Option<ListContainersOptions>,
+    ) -> BoxFuture<'_, Result<Vec<ContainerSummary>, Error>> {
+        Docker::list_containers(self, options).boxed()
+    }
+
+    fn inspect_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<InspectContainerOptions>,
+    ) -> BoxFuture<'a, Result<ContainerInspectResponse, Error>> {
+        Docker::inspect_container(self, container, options).boxed()
+    }
+
+    fn create_container(
+        &self,
+        options: Option<CreateContainerOptions>,
+        config: ContainerCreateBody,
+    ) -> BoxFuture<'_, Result<ContainerCreateResponse, Error>> {
+        Docker::create_container(self, options, config).boxed()
+    }
+
+    fn rename_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: RenameContainerOptions,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::rename_container(self, container, options).boxed()
+    }
+
+    fn start_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::start_container(self, container, options).boxed()
+    }
+
+    fn stop_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StopContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::stop_container(self, container, options).boxed()
+    }
+
+    fn restart_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RestartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::restart_container(self, container, options).boxed()
+    }
+
+    fn remove_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RemoveContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::remove_container(self, container, options).boxed()
+    }
+
+    fn wait_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<WaitContainerOptions>,
+    ) -> BoxStream<'a, Result<ContainerWaitResponse, Error>> {
+        Docker::wait_container(self, container, options).boxed()
+    }
+
+    fn logs<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<LogsOptions>,
+    ) -> BoxStream<'a, Result<LogOutput, Error>> {
+        Docker::logs(self, container, options).boxed()
+    }
+
+    fn stats<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StatsOptions>,
+    ) -> BoxStream<'a, Result<ContainerStatsResponse, Error>> {
+        Docker::stats(self, container, options).boxed()
+    }
+
+    /// Docker says in the progress messages whether it downloaded anything.
+    fn pull_image<'a>(
+        &'a self,
+        repo: &'a str,
+        tag: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        async move {
+            let mut downloaded = false;
+            let mut progress = self.create_image(Some(pull_options(repo, tag)), None, credentials);
+            while let Some(output) = progress.next().await {
+                let output = output?;
+                debug!("{output:?}");
+                if let Some(status) = &output.status
+                    && (status.contains("Download complete")
+                        || status.contains("Pull complete")
+                        || status.contains("Downloaded newer image for"))
+                {
+                    downloaded = true;
+                }
+            }
+            Ok(downloaded)
+        }
+        .boxed()
+    }
+
+    fn inspect_image<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Result<ImageInspect, Error>> {
+        Docker::inspect_image(self, image).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn inspect_registry_image<'a>(
+        &'a self,
+        image: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<DistributionInspect, Error>> {
+        Docker::inspect_registry_image(self, image, credentials).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn tag_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<TagImageOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::tag_image(self, image, options).boxed()
+    }
+
+    fn remove_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<RemoveImageOptions>,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<Vec<ImageDeleteResponseItem>, Error>> {
+        Docker::remove_image(self, image, options, credentials).boxed()
+    }
+
+    fn create_exec<'a>(
+        &'a self,
+        container: &'a str,
+        config: ExecConfig,
+    ) -> BoxFuture<'a, Result<CreateExecResults, Error>> {
+        Docker::create_exec(self, container, config).boxed()
+    }
+
+    fn start_exec<'a>(
+        &'a self,
+        exec: &'a str,
+        options: Option<StartExecOptions>,
+    ) -> BoxFuture<'a, Result<StartExecResults, Error>> {
+        Docker::start_exec(self, exec, options).boxed()
+    }
+
+    fn inspect_exec<'a>(
+        &'a self,
+        exec: &'a str,
+    ) -> BoxFuture<'a, Result<ExecInspectResponse, Error>> {
+        Docker::inspect_exec(self, exec).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn upload_to_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<UploadToContainerOptions>,
+        tar: BoxStream<'static, Bytes>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::upload_to_container(self, container, options, bollard::body_stream(tar)).boxed()
+    }
+
+    fn download_from_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<DownloadFromContainerOptions>,
+    ) -> BoxStream<'a, Result<Bytes, Error>> {
+        Docker::download_from_container(self, container, options).boxed()
+    }
+
+    fn create_volume(&self, options: VolumeCreateOptions) -> BoxFuture<'_, Result<Volume, Error>> {
+        Docker::create_volume(self, options).boxed()
+    }
+
+    fn inspect_volume<'a>(&'a self, volume: &'a str) -> BoxFuture<'a, Result<Volume, Error>> {
+        Docker::inspect_volume(self, volume).boxed()
+    }
+
+    fn remove_volume<'a>(
+        &'a self,
+        volume: &'a str,
+        options: Option<RemoveVolumeOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Docker::remove_volume(self, volume, options).boxed()
+    }
+}
+
+fn pull_options(repo: &str, tag: &str) -> CreateImageOptions {
+    CreateImageOptions {
+        from_image: Some(repo.to_string()),
+        tag: Some(tag.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Podman through its Docker-compatible API.
+///
+/// Podman reports a pull differently: it lists every layer it resolves
+/// whether or not it had it already, and never says "Downloaded newer image
+/// for". Whether a pull brought a new image is told from the image ID
+/// instead. Everything else behaves like Docker.
+pub(crate) struct Podman {
+    docker: Docker,
+}
+
+impl Podman {
+    pub(crate) fn new(docker: Docker) -> Self {
+        Self { docker }
+    }
+
+    /// The socket of the local Podman service: the current user's rootless
+    /// one below `$XDG_RUNTIME_DIR` if it exists, otherwise the rootful one.
+    pub(crate) fn local_socket(
+        xdg_runtime_dir: Option<&str>,
+        exists: impl Fn(&Path) -> bool,
+    ) -> PathBuf {
+        xdg_runtime_dir
+            .map(|dir| Path::new(dir).join("podman/podman.sock"))
+            .filter(|socket| exists(socket))
+            .unwrap_or_else(|| PathBuf::from("/run/podman/podman.sock"))
+    }
+
+    async fn image_id(&self, reference: &str) -> Option<String> {
+        Docker::inspect_image(&self.docker, reference)
+            .await
+            .ok()?
+            .id
+    }
+}
+
+/// How `tag` of `repo` is referred to: `repo@sha256:…` for a digest.
+fn reference(repo: &str, tag: &str) -> String {
+    if tag.starts_with("sha256:") {
+        format!("{repo}@{tag}")
+    } else {
+        format!("{repo}:{tag}")
+    }
+}
+
+impl ContainerRuntime for Podman {
+    fn info(&self) -> BoxFuture<'_, Result<SystemInfo, Error>> {
+        self.docker.info().boxed()
+    }
+
+    fn df(
+        &self,
+        options: Option<DataUsageOptions>,
+    ) -> BoxFuture<'_, Result<SystemDataUsageResponse, Error>> {
+        self.docker.df(options).boxed()
+    }
+
+    fn list_containers(
+        &self,
+        options: Option<ListContainersOptions>,
+    ) -> BoxFuture<'_, Result<Vec<ContainerSummary>, Error>> {
+        self.docker.list_containers(options).boxed()
+    }
+
+    fn inspect_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<InspectContainerOptions>,
+    ) -> BoxFuture<'a, Result<ContainerInspectResponse, Error>> {
+        self.docker.inspect_container(container, options).boxed()
+    }
+
+    fn create_container(
+        &self,
+        options: Option<CreateContainerOptions>,
+        config: ContainerCreateBody,
+    ) -> BoxFuture<'_, Result<ContainerCreateResponse, Error>> {
+        self.docker.create_container(options, config).boxed()
+    }
+
+    fn rename_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: RenameContainerOptions,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.rename_container(container, options).boxed()
+    }
+
+    fn start_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.start_container(container, options).boxed()
+    }
+
+    fn stop_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StopContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.stop_container(container, options).boxed()
+    }
+
+    fn restart_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RestartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.restart_container(container, options).boxed()
+    }
+
+    fn remove_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<RemoveContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.remove_container(container, options).boxed()
+    }
+
+    fn wait_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<WaitContainerOptions>,
+    ) -> BoxStream<'a, Result<ContainerWaitResponse, Error>> {
+        self.docker.wait_container(container, options).boxed()
+    }
+
+    fn logs<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<LogsOptions>,
+    ) -> BoxStream<'a, Result<LogOutput, Error>> {
+        self.docker.logs(container, options).boxed()
+    }
+
+    fn stats<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<StatsOptions>,
+    ) -> BoxStream<'a, Result<ContainerStatsResponse, Error>> {
+        self.docker.stats(container, options).boxed()
+    }
+
+    fn pull_image<'a>(
+        &'a self,
+        repo: &'a str,
+        tag: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        async move {
+            let reference = reference(repo, tag);
+            let before = self.image_id(&reference).await;
+            let mut progress =
+                self.docker
+                    .create_image(Some(pull_options(repo, tag)), None, credentials);
+            while let Some(output) = progress.next().await {
+                debug!("{:?}", output?);
+            }
+            let after = self.image_id(&reference).await;
+            Ok(after.is_some() && after != before)
+        }
+        .boxed()
+    }
+
+    fn inspect_image<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Result<ImageInspect, Error>> {
+        self.docker.inspect_image(image).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn inspect_registry_image<'a>(
+        &'a self,
+        image: &'a str,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<DistributionInspect, Error>> {
+        self.docker
+            .inspect_registry_image(image, credentials)
+            .boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn tag_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<TagImageOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.tag_image(image, options).boxed()
+    }
+
+    fn remove_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<RemoveImageOptions>,
+        credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<Vec<ImageDeleteResponseItem>, Error>> {
+        self.docker
+            .remove_image(image, options, credentials)
+            .boxed()
+    }
+
+    fn create_exec<'a>(
+        &'a self,
+        container: &'a str,
+        config: ExecConfig,
+    ) -> BoxFuture<'a, Result<CreateExecResults, Error>> {
+        self.docker.create_exec(container, config).boxed()
+    }
+
+    fn start_exec<'a>(
+        &'a self,
+        exec: &'a str,
+        options: Option<StartExecOptions>,
+    ) -> BoxFuture<'a, Result<StartExecResults, Error>> {
+        self.docker.start_exec(exec, options).boxed()
+    }
+
+    fn inspect_exec<'a>(
+        &'a self,
+        exec: &'a str,
+    ) -> BoxFuture<'a, Result<ExecInspectResponse, Error>> {
+        self.docker.inspect_exec(exec).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn upload_to_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<UploadToContainerOptions>,
+        tar: BoxStream<'static, Bytes>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker
+            .upload_to_container(container, options, bollard::body_stream(tar))
+            .boxed()
+    }
+
+    fn download_from_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: Option<DownloadFromContainerOptions>,
+    ) -> BoxStream<'a, Result<Bytes, Error>> {
+        self.docker
+            .download_from_container(container, options)
+            .boxed()
+    }
+
+    fn create_volume(&self, options: VolumeCreateOptions) -> BoxFuture<'_, Result<Volume, Error>> {
+        self.docker.create_volume(options).boxed()
+    }
+
+    fn inspect_volume<'a>(&'a self, volume: &'a str) -> BoxFuture<'a, Result<Volume, Error>> {
+        self.docker.inspect_volume(volume).boxed()
+    }
+
+    fn remove_volume<'a>(
+        &'a self,
+        volume: &'a str,
+        options: Option<RemoveVolumeOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.docker.remove_volume(volume, options).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_rootless_podman_socket() {
+        let rootless = Podman::local_socket(Some("/run/user/1000"), |_| true);
+        assert_eq!(rootless, Path::new("/run/user/1000/podman/podman.sock"));
+        let rootful = Podman::local_socket(Some("/run/user/1000"), |_| false);
+        assert_eq!(rootful, Path::new("/run/podman/podman.sock"));
+        assert_eq!(
+            Podman::local_socket(None, |_| true),
+            Path::new("/run/podman/podman.sock")
+        );
+    }
+
+    #[test]
+    fn refers_to_digests_with_at() {
+        assert_eq!(reference("nginx", "1.27"), "nginx:1.27");
+        assert_eq!(reference("nginx", "sha256:abc"), "nginx@sha256:abc");
+    }
+}
@@ -0,0 +1,563 @@
+//! An in-memory engine for tests.
+//!
+//! Containers, local images, a registry and volumes live in a map. A container
+//! runs once started unless its image was marked broken, in which case it
+//! exits right away like a crashing service. Exec, archives, logs and stats
+//! are not supported.
+
+use super::{ContainerRuntime, reference};
+use bollard::auth::DockerCredentials;
+use bollard::container::LogOutput;
+use bollard::errors::Error;
+use bollard::exec::{CreateExecResults, StartExecOptions, StartExecResults};
+use bollard::models::{
+    ContainerConfig, ContainerCreateBody, ContainerCreateResponse, ContainerInspectResponse,
+    ContainerState, ContainerStateStatusEnum, ContainerStatsResponse, ContainerSummary,
+    ContainerWaitResponse, ExecConfig, ExecInspectResponse, ImageDeleteResponseItem, ImageInspect,
+    NetworkSettings, SystemDataUsageResponse, SystemInfo, Volume, VolumeCreateOptions,
+};
+use bollard::query_parameters::{
+    CreateContainerOptions, DataUsageOptions, DownloadFromContainerOptions,
+    InspectContainerOptions, ListContainersOptions, LogsOptions, RemoveContainerOptions,
+    RemoveImageOptions, RemoveVolumeOptions, RenameContainerOptions, RestartContainerOptions,
+    StartContainerOptions, StatsOptions, StopContainerOptions, WaitContainerOptions,
+};
+#[cfg(feature = "cli")]
+use bollard::{
+    models::DistributionInspect,
+    query_parameters::{TagImageOptions, UploadToContainerOptions},
+};
+use bytes::Bytes;
+use futures_util::future::{self, BoxFuture};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{FutureExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub(crate) struct FakeRuntime {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    containers: Vec<ContainerInspectResponse>,
+    /// Local image references (`repo:tag`, `repo@digest`) and the image
+    /// IDs they point to.
+    images: HashMap<String, String>,
+    /// Local image IDs, including untagged ones.
+    image_ids: HashSet<String>,
+    /// What pulling a reference gives.
+    registry: HashMap<String, String>,
+    /// Images whose containers exit as soon as they are started.
+    broken: HashSet<String>,
+    volumes: HashMap<String, Volume>,
+    next_id: usize,
+}
+
+fn not_found(what: &str) -> Error {
+    Error::DockerResponseServerError {
+        status_code: 404,
+        message: format!("No such object: {what}"),
+    }
+}
+
+fn conflict(message: String) -> Error {
+    Error::DockerResponseServerError {
+        status_code: 409,
+        message,
+    }
+}
+
+fn unsupported<T: Send + 'static>(operation: &str) -> BoxFuture<'static, Result<T, Error>> {
+    future::ready(Err(Error::DockerResponseServerError {
+        status_code: 501,
+        message: format!("{operation} is not supported by the fake engine"),
+    }))
+    .boxed()
+}
+
+impl State {
+    fn find(&self, container: &str) -> Option<usize> {
+        self.containers.iter().position(|c| {
+            c.id.as_deref() == Some(container)
+                || c.name.as_deref().map(|n| n.trim_start_matches('/')) == Some(container)
+        })
+    }
+
+    fn container(&mut self, container: &str) -> Result<&mut ContainerInspectResponse, Error> {
+        let index = self.find(container).ok_or_else(|| not_found(container))?;
+        Ok(&mut self.containers[index])
+    }
+
+    fn image_id(&self, image: &str) -> Option<String> {
+        self.images
+            .get(image)
+            .or_else(|| self.image_ids.get(image))
+            .cloned()
+    }
+
+    fn add_image(&mut self, reference: String, id: String) {
+        self.image_ids.insert(id.clone());
+        self.images.insert(reference, id);
+    }
+
+    fn set_running(&mut self, container: &str, running: bool) -> Result<(), Error> {
+        let image = self.container(container)?.image.clone().unwrap_or_default();
+        let crashes = running && self.broken.contains(&image);
+        let state = self
+            .container(container)?
+            .state
+            .get_or_insert_with(Default::default);
+        state.running = Some(running && !crashes);
+        state.status = Some(if running && !crashes {
+            ContainerStateStatusEnum::RUNNING
+        } else {
+            ContainerStateStatusEnum::EXITED
+        });
+        state.exit_code = Some(if crashes { 1 } else { 0 });
+        Ok(())
+    }
+}
+
+impl FakeRuntime {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("fake engine lock poisoned")
+    }
+
+    /// Have `reference` locally as image `id`.
+    pub(crate) fn add_image(&self, reference: &str, id: &str) {
+        self.state()
+            .add_image(reference.to_string(), id.to_string());
+    }
+
+    /// Make pulling `reference` give image `id`.
+    pub(crate) fn publish(&self, reference: &str, id: &str) {
+        self.state()
+            .registry
+            .insert(reference.to_string(), id.to_string());
+    }
+
+    /// Make containers of image `id` crash on start.
+    pub(crate) fn break_image(&self, id: &str) {
+        self.state().broken.insert(id.to_string());
+    }
+
+    /// Create and start a container `name` of the local image `image`.
+    /// Returns its ID.
+    pub(crate) async fn run(&self, name: &str, image: &str, labels: &[(&str, &str)]) -> String {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let created = self
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                }),
+                ContainerCreateBody {
+                    image: Some(image.to_string()),
+                    labels: Some(labels),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("container is created");
+        self.start_container(&created.id, None)
+            .await
+            .expect("container starts");
+        created.id
+    }
+
+    /// The container with ID or name `container`.
+    pub(crate) fn container(&self, container: &str) -> Option<ContainerInspectResponse> {
+        let state = self.state();
+        state.find(container).map(|i| state.containers[i].clone())
+    }
+
+    /// Names of all containers.
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.state()
+            .containers
+            .iter()
+            .filter_map(|c| c.name.as_deref())
+            .map(|n| n.trim_start_matches('/').to_string())
+            .collect()
+    }
+
+    /// Whether an image with ID `id` is still present.
+    pub(crate) fn has_image(&self, id: &str) -> bool {
+        self.state().image_ids.contains(id)
+    }
+}
+
+impl ContainerRuntime for FakeRuntime {
+    fn info(&self) -> BoxFuture<'_, Result<SystemInfo, Error>> {
+        future::ready(Ok(SystemInfo {
+            name: Some("fake".to_string()),
+            ..Default::default()
+        }))
+        .boxed()
+    }
+
+    fn df(
+        &self,
+        _options: Option<DataUsageOptions>,
+    ) -> BoxFuture<'_, Result<SystemDataUsageResponse, Error>> {
+        future::ready(Ok(SystemDataUsageResponse::default())).boxed()
+    }
+
+    fn list_containers(
+        &self,
+        options: Option<ListContainersOptions>,
+    ) -> BoxFuture<'_, Result<Vec<ContainerSummary>, Error>> {
+        let labels = options
+            .and_then(|o| o.filters)
+            .and_then(|mut f| f.remove("label"))
+            .unwrap_or_default();
+        let summaries = self
+            .state()
+            .containers
+            .iter()
+            .filter(|c| {
+                let own = c.config.as_ref().and_then(|c| c.labels.as_ref());
+                labels.iter().all(|filter| {
+                    let (key, value) = filter.split_once('=').unwrap_or((filter, ""));
+                    own.and_then(|l| l.get(key))
+                        .is_some_and(|v| value.is_empty() || v == value)
+                })
+            })
+            .map(|c| ContainerSummary {
+                id: c.id.clone(),
+                names: c.name.clone().map(|n| vec![n]),
+                image: c.config.as_ref().and_then(|c| c.image.clone()),
+                image_id: c.image.clone(),
+                labels: c.config.as_ref().and_then(|c| c.labels.clone()),
+                ..Default::default()
+            })
+            .collect();
+        future::ready(Ok(summaries)).boxed()
+    }
+
+    fn inspect_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<InspectContainerOptions>,
+    ) -> BoxFuture<'a, Result<ContainerInspectResponse, Error>> {
+        let found = self
+            .container(container)
+            .ok_or_else(|| not_found(container));
+        future::ready(found).boxed()
+    }
+
+    fn create_container(
+        &self,
+        options: Option<CreateContainerOptions>,
+        config: ContainerCreateBody,
+    ) -> BoxFuture<'_, Result<ContainerCreateResponse, Error>> {
+        let mut state = self.state();
+        let created = (|| {
+            let image = config.image.clone().unwrap_or_default();
+            let image_id = state.image_id(&image).ok_or_else(|| not_found(&image))?;
+            state.next_id += 1;
+            let id = format!("{:064x}", state.next_id);
+            let name = options
+                .and_then(|o| o.name)
+                .unwrap_or_else(|| id[..12].to_string());
+            if state.find(&name).is_some() {
+                return Err(conflict(format!("name {name} is already in use")));
+            }
+            state.containers.push(ContainerInspectResponse {
+                id: Some(id.clone()),
+                name: Some(format!("/{name}")),
+                image: Some(image_id),
+                config: Some(ContainerConfig {
+                    image: config.image,
+                    env: config.env,
+                    labels: config.labels,
+                    ..Default::default()
+                }),
+                host_config: config.host_config,
+                network_settings: Some(NetworkSettings {
+                    networks: config.networking_config.and_then(|n| n.endpoints_config),
+                    ..Default::default()
+                }),
+                state: Some(ContainerState {
+                    status: Some(ContainerStateStatusEnum::CREATED),
+                    running: Some(false),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+            Ok(ContainerCreateResponse {
+                id,
+                warnings: Vec::new(),
+            })
+        })();
+        future::ready(created).boxed()
+    }
+
+    fn rename_container<'a>(
+        &'a self,
+        container: &'a str,
+        options: RenameContainerOptions,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut state = self.state();
+        let renamed = if state.find(&options.name).is_some() {
+            Err(conflict(format!("name {} is already in use", options.name)))
+        } else {
+            state
+                .container(container)
+                .map(|c| c.name = Some(format!("/{}", options.name)))
+        };
+        future::ready(renamed).boxed()
+    }
+
+    fn start_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<StartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        future::ready(self.state().set_running(container, true)).boxed()
+    }
+
+    fn stop_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<StopContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        future::ready(self.state().set_running(container, false)).boxed()
+    }
+
+    fn restart_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<RestartContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        future::ready(self.state().set_running(container, true)).boxed()
+    }
+
+    fn remove_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<RemoveContainerOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut state = self.state();
+        let removed = match state.find(container) {
+            Some(index) => {
+                state.containers.remove(index);
+                Ok(())
+            }
+            None => Err(not_found(container)),
+        };
+        future::ready(removed).boxed()
+    }
+
+    fn wait_container<'a>(
+        &'a self,
+        container: &'a str,
+        _options: Option<WaitContainerOptions>,
+    ) -> BoxStream<'a, Result<ContainerWaitResponse, Error>> {
+        let exit = self
+            .container(container)
+            .ok_or_else(|| not_found(container))
+            .map(|c| ContainerWaitResponse {
+                status_code: c.state.and_then(|s| s.exit_code).unwrap_or(0),
+                error: None,
+            });
+        stream::iter([exit]).boxed()
+    }
+
+    fn logs<'a>(
+        &'a self,
+        _container: &'a str,
+        _options: Option<LogsOptions>,
+    ) -> BoxStream<'a, Result<LogOutput, Error>> {
+        stream::empty().boxed()
+    }
+
+    fn stats<'a>(
+        &'a self,
+        _container: &'a str,
+        _options: Option<StatsOptions>,
+    ) -> BoxStream<'a, Result<ContainerStatsResponse, Error>> {
+        stream::empty().boxed()
+    }
+
+    fn pull_image<'a>(
+        &'a self,
+        repo: &'a str,
+        tag: &'a str,
+        _credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        let reference = reference(repo, tag);
+        let mut state = self.state();
+        let pulled = match state.registry.get(&reference).cloned() {
+            Some(id) => {
+                let newer = state.images.get(&reference) != Some(&id);
+                state.add_image(reference, id);
+                Ok(newer)
+            }
+            None => Err(not_found(&reference)),
+        };
+        future::ready(pulled).boxed()
+    }
+
+    fn inspect_image<'a>(&'a self, image: &'a str) -> BoxFuture<'a, Result<ImageInspect, Error>> {
+        let state = self.state();
+        let found = state
+            .image_id(image)
+            .map(|id| {
+                let repo_digests = state
+                    .images
+                    .iter()
+                    .filter(|(reference, image)| **image == id && reference.contains('@'))
+                    .map(|(reference, _)| reference.clone())
+                    .collect();
+                ImageInspect {
+                    id: Some(id),
+                    repo_digests: Some(repo_digests),
+                    ..Default::default()
+                }
+            })
+            .ok_or_else(|| not_found(image));
+        future::ready(found).boxed()
+    }
+
+    #[cfg(feature = "cli")]
+    fn inspect_registry_image<'a>(
+        &'a self,
+        _image: &'a str,
+        _credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<DistributionInspect, Error>> {
+        unsupported("inspecting registry images")
+    }
+
+    #[cfg(feature = "cli")]
+    fn tag_image<'a>(
+        &'a self,
+        image: &'a str,
+        options: Option<TagImageOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut state = self.state();
+        let tagged = match (state.image_id(image), options) {
+            (Some(id), Some(options)) => {
+                let tag = options.tag.unwrap_or_else(|| "latest".to_string());
+                let repo = options.repo.unwrap_or_default();
+                state.add_image(format!("{repo}:{tag}"), id);
+                Ok(())
+            }
+            _ => Err(not_found(image)),
+        };
+        future::ready(tagged).boxed()
+    }
+
+    fn remove_image<'a>(
+        &'a self,
+        image: &'a str,
+        _options: Option<RemoveImageOptions>,
+        _credentials: Option<DockerCredentials>,
+    ) -> BoxFuture<'a, Result<Vec<ImageDeleteResponseItem>, Error>> {
+        let mut state = self.state();
+        let removed = match state.image_id(image) {
+            Some(id)
+                if state
+                    .containers
+                    .iter()
+                    .any(|c| c.image.as_ref() == Some(&id)) =>
+            {
+                Err(conflict(format!("image {id} is being used by a container")))
+            }
+            Some(id) => {
+                state.images.retain(|_, image| *image != id);
+                state.image_ids.remove(&id);
+                Ok(vec![ImageDeleteResponseItem {
+                    deleted: Some(id),
+                    untagged: None,
+                }])
+            }
+            None => Err(not_found(image)),
+        };
+        future::ready(removed).boxed()
+    }
+
+    fn create_exec<'a>(
+        &'a self,
+        _container: &'a str,
+        _config: ExecConfig,
+    ) -> BoxFuture<'a, Result<CreateExecResults, Error>> {
+        unsupported("exec")
+    }
+
+    fn start_exec<'a>(
+        &'a self,
+        _exec: &'a str,
+        _options: Option<StartExecOptions>,
+    ) -> BoxFuture<'a, Result<StartExecResults, Error>> {
+        unsupported("exec")
+    }
+
+    fn inspect_exec<'a>(
+        &'a self,
+        _exec: &'a str,
+    ) -> BoxFuture<'a, Result<ExecInspectResponse, Error>> {
+        unsupported("exec")
+    }
+
+    #[cfg(feature = "cli")]
+    fn upload_to_container<'a>(
+        &'a self,
+        _container: &'a str,
+        _options: Option<UploadToContainerOptions>,
+        _tar: BoxStream<'static, Bytes>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        unsupported("uploading archives")
+    }
+
+    fn download_from_container<'a>(
+        &'a self,
+        _container: &'a str,
+        _options: Option<DownloadFromContainerOptions>,
+    ) -> BoxStream<'a, Result<Bytes, Error>> {
+        unsupported("downloading archives").into_stream().boxed()
+    }
+
+    fn create_volume(&self, options: VolumeCreateOptions) -> BoxFuture<'_, Result<Volume, Error>> {
+        let volume = Volume {
+            name: options.name.unwrap_or_default(),
+            driver: options.driver.unwrap_or_else(|| "local".to_string()),
+            options: options.driver_opts.unwrap_or_default(),
+            labels: options.labels.unwrap_or_default(),
+            ..Default::default()
+        };
+        self.state()
+            .volumes
+            .insert(volume.name.clone(), volume.clone());
+        future::ready(Ok(volume)).boxed()
+    }
+
+    fn inspect_volume<'a>(&'a self, volume: &'a str) -> BoxFuture<'a, Result<Volume, Error>> {
+        let found = self
+            .state()
+            .volumes
+            .get(volume)
+            .cloned()
+            .ok_or_else(|| not_found(volume));
+        future::ready(found).boxed()
+    }
+
+    fn remove_volume<'a>(
+        &'a self,
+        volume: &'a str,
+        _options: Option<RemoveVolumeOptions>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let removed = self
+            .state()
+            .volumes
+            .remove(volume)
+            .map(|_| ())
+            .ok_or_else(|| not_found(volume));
+        future::ready(removed).boxed()
+    }
+}
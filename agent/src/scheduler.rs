@@ -0,0 +1,374 @@
+//! Per-service check schedules.
+//!
+//! A container can override the global `[schedule]` with one of two labels:
+//! `hoister.schedule=<cron>` or `hoister.interval=<duration>` (`90s`, `5m`,
+//! `1h`, `7d`, `2w`, or plain seconds). The main loop keeps the time each
+//! service was last checked and wakes for the earliest one that is due.
+//!
+//! The services due in one pass are checked in [`in_waves`]: a service whose
+//! Compose file `depends_on` another due service waits for it, everything
+//! else runs side by side, up to `max_parallel_updates` at a time.
+
+use crate::config::Schedule;
+use chrono::{DateTime, Utc};
+use cron::Schedule as CronSchedule;
+use hoister_shared::{ProjectName, ServiceName};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub(crate) const SCHEDULE_LABEL: &str = "hoister.schedule";
+pub(crate) const INTERVAL_LABEL: &str = "hoister.interval";
+const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+/// Set by Compose from `depends_on`: `db:service_healthy:false,cache:...`.
+const DEPENDS_ON_LABEL: &str = "com.docker.compose.depends_on";
+
+/// A service, within the Compose project it belongs to.
+pub(crate) type ServiceKey = (ProjectName, ServiceName);
+
+/// The schedule a single service is checked on.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ServiceSchedule {
+    /// The global `[schedule]`.
+    Default,
+    Cron(Box<CronSchedule>),
+    Interval(Duration),
+}
+
+impl ServiceSchedule {
+    /// The schedule set by a container's labels.
+    pub(crate) fn from_labels(labels: Option<&HashMap<String, String>>) -> Result<Self, String> {
+        let label = |name| labels.and_then(|l| l.get(name)).map(|v| v.trim());
+        match (label(SCHEDULE_LABEL), label(INTERVAL_LABEL)) {
+            (None, None) => Ok(Self::Default),
+            (Some(_), Some(_)) => Err(format!(
+                "`{SCHEDULE_LABEL}` and `{INTERVAL_LABEL}` are mutually exclusive"
+            )),
+            (Some(cron), None) => CronSchedule::from_str(cron)
+                .map(|cron| Self::Cron(Box::new(cron)))
+                .map_err(|e| format!("invalid `{SCHEDULE_LABEL}` {cron:?}: {e}")),
+            (None, Some(interval)) => match parse_duration(interval) {
+                Some(duration) if !duration.is_zero() => Ok(Self::Interval(duration)),
+                _ => Err(format!(
+                    "invalid `{INTERVAL_LABEL}` {interval:?}, expected e.g. 90s, 5m, 1h or 7d"
+                )),
+            },
+        }
+    }
+
+    /// When a service last checked at `last` is next due.
+    pub(crate) fn next_due(&self, last: DateTime<Utc>, global: &Schedule) -> DateTime<Utc> {
+        match self {
+            Self::Default => global.next_after(last),
+            Self::Cron(cron) => cron.after(&last).next().unwrap_or(last + FALLBACK),
+            Self::Interval(interval) => last + *interval,
+        }
+    }
+
+    /// Human-readable form, reported to the controller with the service's state.
+    pub(crate) fn describe(&self, global: &Schedule) -> String {
+        match self {
+            Self::Default => match describe_global(global) {
+                Some(global) => format!("{global} (default)"),
+                None => "default".to_string(),
+            },
+            Self::Cron(cron) => format!("cron {cron}"),
+            Self::Interval(interval) => format!("every {}", format_duration(interval.as_secs())),
+        }
+    }
+}
+
+/// Human-readable form of the global `[schedule]`, reported to the controller
+/// with the agent's info.
+pub(crate) fn describe_global(global: &Schedule) -> Option<String> {
+    match (&global.cron, global.interval) {
+        (Some(cron), _) => Some(format!("cron {cron}")),
+        (None, Some(seconds)) => Some(format!("every {}", format_duration(seconds))),
+        (None, None) => None,
+    }
+}
+
+/// Used if a cron expression has no future fire time.
+const FALLBACK: Duration = Duration::from_secs(300);
+
+/// Parse `90s`, `5m`, `1h`, `7d`, `2w` or plain seconds.
+pub(crate) fn parse_duration(raw: &str) -> Option<Duration> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.checked_mul(seconds).map(Duration::from_secs)
+}
+
+fn format_duration(seconds: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(7 * 86400, "w"), (86400, "d"), (3600, "h"), (60, "m")];
+    UNITS
+        .iter()
+        .find(|(size, _)| seconds >= *size && seconds.is_multiple_of(*size))
+        .map(|(size, unit)| format!("{}{unit}", seconds / size))
+        .unwrap_or_else(|| format!("{seconds}s"))
+}
+
+/// When each service was last checked, and when the global schedule last
+/// fired. Services not seen before are due immediately; new containers are
+/// picked up whenever the global schedule fires.
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    last_checked: HashMap<ServiceKey, (DateTime<Utc>, ServiceSchedule)>,
+    last_global: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    /// Whether `service`, on `schedule`, should be checked at `now`.
+    pub(crate) fn is_due(
+        &self,
+        service: &ServiceKey,
+        schedule: &ServiceSchedule,
+        global: &Schedule,
+        now: DateTime<Utc>,
+    ) -> bool {
+        match self.last_checked.get(service) {
+            // A changed label takes effect right away.
+            Some((last, previous)) if previous == schedule => {
+                schedule.next_due(*last, global) <= now
+            }
+            _ => true,
+        }
+    }
+
+    pub(crate) fn checked(
+        &mut self,
+        service: ServiceKey,
+        schedule: ServiceSchedule,
+        now: DateTime<Utc>,
+    ) {
+        self.last_checked.insert(service, (now, schedule));
+    }
+
+    /// Finish a pass over the containers present at `now`: forget services
+    /// that are gone and note whether the global schedule fired.
+    pub(crate) fn finish_pass(
+        &mut self,
+        present: &[ServiceKey],
+        global: &Schedule,
+        now: DateTime<Utc>,
+    ) {
+        self.last_checked
+            .retain(|service, _| present.contains(service));
+        let global_due = self
+            .last_global
+            .is_none_or(|last| global.next_after(last) <= now);
+        if global_due {
+            self.last_global = Some(now);
+        }
+    }
+
+    /// How long until the next service, or the global schedule, is due.
+    pub(crate) fn next_wake(&self, global: &Schedule, now: DateTime<Utc>) -> Duration {
+        let global_due = self.last_global.map_or(now, |last| global.next_after(last));
+        let next = self
+            .last_checked
+            .values()
+            .map(|(last, schedule)| schedule.next_due(*last, global))
+            .fold(global_due, DateTime::min);
+        (next - now).to_std().unwrap_or(Duration::ZERO)
+    }
+}
+
+/// A service due for a check, with the Compose services it depends on.
+pub(crate) struct Due<T> {
+    pub(crate) item: T,
+    service: Option<String>,
+    depends_on: Vec<String>,
+}
+
+impl<T> Due<T> {
+    pub(crate) fn new(item: T, labels: Option<&HashMap<String, String>>) -> Self {
+        Self {
+            item,
+            service: compose_service(labels),
+            depends_on: depends_on(labels),
+        }
+    }
+}
+
+/// The Compose service a container belongs to.
+pub(crate) fn compose_service(labels: Option<&HashMap<String, String>>) -> Option<String> {
+    labels.and_then(|l| l.get(COMPOSE_SERVICE_LABEL)).cloned()
+}
+
+/// The Compose services a container `depends_on`.
+pub(crate) fn depends_on(labels: Option<&HashMap<String, String>>) -> Vec<String> {
+    dependencies(labels).map(|(service, _)| service).collect()
+}
+
+/// The Compose services a container `depends_on` with `restart: true`, i.e.
+/// that it should be restarted with.
+pub(crate) fn restarts_with(labels: Option<&HashMap<String, String>>) -> Vec<String> {
+    dependencies(labels)
+        .filter(|(_, restart)| *restart)
+        .map(|(service, _)| service)
+        .collect()
+}
+
+/// Each `service:condition:restart` entry of the `depends_on` label.
+fn dependencies(
+    labels: Option<&HashMap<String, String>>,
+) -> impl Iterator<Item = (String, bool)> + '_ {
+    labels
+        .and_then(|l| l.get(DEPENDS_ON_LABEL))
+        .into_iter()
+        .flat_map(|deps| deps.split(','))
+        .filter_map(|dep| {
+            let mut parts = dep.split(':').map(str::trim);
+            let service = parts.next().filter(|s| !s.is_empty())?;
+            Some((service.to_string(), parts.nth(1) == Some("true")))
+        })
+}
+
+/// Order `due` into waves: each wave only holds services whose due
+/// dependencies are in earlier waves, so a wave can run concurrently.
+/// Dependencies that aren't due this pass don't hold anything back.
+pub(crate) fn in_waves<T>(due: Vec<Due<T>>) -> Vec<Vec<T>> {
+    let mut waves = Vec::new();
+    let mut remaining = due;
+    while !remaining.is_empty() {
+        let pending: HashSet<String> = remaining
+            .iter()
+            .filter_map(|due| due.service.clone())
+            .collect();
+        let (ready, blocked): (Vec<_>, Vec<_>) = remaining.into_iter().partition(|due| {
+            !due.depends_on
+                .iter()
+                .any(|dep| pending.contains(dep) && due.service.as_ref() != Some(dep))
+        });
+        if ready.is_empty() {
+            // Compose refuses cyclic `depends_on`, but labels can be edited
+            // by hand. Fall back to one service at a time.
+            warn!("services depend on each other in a cycle, updating them one by one");
+            waves.extend(blocked.into_iter().map(|due| vec![due.item]));
+            break;
+        }
+        waves.push(ready.into_iter().map(|due| due.item).collect());
+        remaining = blocked;
+    }
+    waves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn every(seconds: u64) -> Schedule {
+        Schedule {
+            interval: Some(seconds),
+            cron: None,
+        }
+    }
+
+    #[test]
+    fn parses_labels() {
+        assert_eq!(
+            ServiceSchedule::from_labels(None).unwrap(),
+            ServiceSchedule::Default
+        );
+        assert_eq!(
+            ServiceSchedule::from_labels(Some(&labels(&[(INTERVAL_LABEL, "5m")]))).unwrap(),
+            ServiceSchedule::Interval(Duration::from_secs(300))
+        );
+        let weekly = labels(&[(SCHEDULE_LABEL, "0 0 3 * * Sun *")]);
+        assert!(matches!(
+            ServiceSchedule::from_labels(Some(&weekly)).unwrap(),
+            ServiceSchedule::Cron(_)
+        ));
+        assert!(
+            ServiceSchedule::from_labels(Some(&labels(&[
+                (SCHEDULE_LABEL, "0 0 3 * * Sun *"),
+                (INTERVAL_LABEL, "5m")
+            ])))
+            .is_err()
+        );
+        assert!(ServiceSchedule::from_labels(Some(&labels(&[(INTERVAL_LABEL, "0")]))).is_err());
+        assert!(ServiceSchedule::from_labels(Some(&labels(&[(INTERVAL_LABEL, "5x")]))).is_err());
+    }
+
+    #[test]
+    fn durations_roundtrip() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(1_209_600)));
+        assert_eq!(format_duration(90), "90s");
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(604_800), "1w");
+    }
+
+    #[test]
+    fn services_are_checked_on_their_own_schedule() {
+        let global = every(300);
+        let project = ProjectName::new("shop");
+        let fast = (project.clone(), ServiceName::new("dev"));
+        let slow = (project, ServiceName::new("db"));
+        let fast_schedule = ServiceSchedule::Interval(Duration::from_secs(60));
+        let slow_schedule = ServiceSchedule::Interval(Duration::from_secs(7 * 86400));
+        let start = Utc::now();
+
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler.is_due(&fast, &fast_schedule, &global, start));
+        scheduler.checked(fast.clone(), fast_schedule.clone(), start);
+        scheduler.checked(slow.clone(), slow_schedule.clone(), start);
+        scheduler.finish_pass(&[fast.clone(), slow.clone()], &global, start);
+        assert_eq!(scheduler.next_wake(&global, start), Duration::from_secs(60));
+
+        let later = start + Duration::from_secs(61);
+        assert!(scheduler.is_due(&fast, &fast_schedule, &global, later));
+        assert!(!scheduler.is_due(&slow, &slow_schedule, &global, later));
+        // Relabelling a service makes it due right away.
+        assert!(scheduler.is_due(&slow, &ServiceSchedule::Default, &global, later));
+    }
+
+    #[test]
+    fn dependencies_are_updated_first() {
+        let due = |name: &str, deps: &str| {
+            let mut pairs = vec![(COMPOSE_SERVICE_LABEL, name)];
+            if !deps.is_empty() {
+                pairs.push((DEPENDS_ON_LABEL, deps));
+            }
+            Due::new(name.to_string(), Some(&labels(&pairs)))
+        };
+        let waves = in_waves(vec![
+            due(
+                "app",
+                "db:service_healthy:false,cache:service_started:false",
+            ),
+            due("db", ""),
+            due("worker", "app:service_started:true"),
+            due("cache", ""),
+            due("docs", "search:service_started:false"),
+        ]);
+        assert_eq!(
+            waves,
+            [vec!["db", "cache", "docs"], vec!["app"], vec!["worker"]]
+        );
+
+        let cycle = in_waves(vec![
+            due("a", "b:service_started"),
+            due("b", "a:service_started"),
+        ]);
+        assert_eq!(cycle, [vec!["a"], vec!["b"]]);
+    }
+}
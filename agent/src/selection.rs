@@ -0,0 +1,260 @@
+//! Which containers the agent manages, set by `[selection]`.
+//!
+//! The scope is every container of the watched Compose projects that passes
+//! the include and exclude globs on project, service and image. Without
+//! `include.projects` only the agent's own project is watched; with it,
+//! several projects can be, and `*` also takes in containers that were not
+//! started by Compose. State and metrics are reported for the whole scope.
+//!
+//! Of those, `mode = "labelled"` (the default) updates the containers
+//! labelled `hoister.enable=true`; `mode = "all"` updates all of them except
+//! the ones labelled `hoister.enable=false`.
+
+use crate::config::{Selection, SelectionMode, SelectionRules};
+use crate::runtime::ContainerRuntime;
+use bollard::errors::Error as BollardError;
+use bollard::models::ContainerSummary;
+use bollard::query_parameters::ListContainersOptions;
+use hoister_shared::ProjectName;
+use std::collections::HashMap;
+
+const ENABLE_LABEL: &str = "hoister.enable";
+const PROJECT_LABEL: &str = "com.docker.compose.project";
+const SERVICE_LABEL: &str = "com.docker.compose.service";
+const IDENTIFIER_LABEL: &str = "hoister.identifier";
+
+impl Selection {
+    /// Whether `container` is within the watched projects and passes the
+    /// include and exclude globs. `own` is the agent's Compose project.
+    pub(crate) fn in_scope(&self, container: &ContainerSummary, own: &ProjectName) -> bool {
+        let labels = container.labels.as_ref();
+        let project = label(labels, PROJECT_LABEL).unwrap_or_default();
+        let service = service_name(container);
+        let image = container.image.as_deref().unwrap_or_default();
+
+        let watched = if self.include.projects.is_empty() {
+            // Development builds run outside of Compose and watch everything.
+            cfg!(debug_assertions) || project == own.as_str()
+        } else {
+            any_match(&self.include.projects, project)
+        };
+        let included = |globs: &[String], value: &str| globs.is_empty() || any_match(globs, value);
+        watched
+            && included(&self.include.services, &service)
+            && included(&self.include.images, image)
+            && !excluded(&self.exclude, project, &service, image)
+    }
+
+    /// Whether `container` is updated: in scope, and opted in the way `mode`
+    /// asks for.
+    pub(crate) fn manages(&self, container: &ContainerSummary, own: &ProjectName) -> bool {
+        let enable = label(container.labels.as_ref(), ENABLE_LABEL).map(str::trim);
+        let opted_in = match self.mode {
+            SelectionMode::Labelled => enable == Some("true"),
+            SelectionMode::All => enable != Some("false"),
+        };
+        opted_in && self.in_scope(container, own)
+    }
+
+    /// Whether only the agent's own project is watched, so the listing can
+    /// be narrowed down by Docker already.
+    fn own_project_only(&self) -> bool {
+        self.include.projects.is_empty() && !cfg!(debug_assertions)
+    }
+}
+
+/// The containers in scope of `selection`.
+pub(crate) async fn list(
+    docker: &dyn ContainerRuntime,
+    selection: &Selection,
+    own: &ProjectName,
+) -> Result<Vec<ContainerSummary>, BollardError> {
+    let mut filters = HashMap::new();
+    if selection.own_project_only() {
+        filters.insert(
+            "label".to_string(),
+            vec![format!("{PROJECT_LABEL}={}", own.as_str())],
+        );
+    }
+    let options = ListContainersOptions {
+        filters: Some(filters),
+        ..Default::default()
+    };
+    let containers = docker.list_containers(Some(options)).await?;
+    Ok(containers
+        .into_iter()
+        .filter(|c| selection.in_scope(c, own))
+        .collect())
+}
+
+/// The project `container` is reported under: its Compose project, or the
+/// agent's own for containers not started by Compose.
+pub(crate) fn project_of(container: &ContainerSummary, own: &ProjectName) -> ProjectName {
+    label(container.labels.as_ref(), PROJECT_LABEL)
+        .map(ProjectName::new)
+        .unwrap_or_else(|| own.clone())
+}
+
+/// The name the service globs are matched against, picked like
+/// `get_service_identifier` does.
+fn service_name(container: &ContainerSummary) -> String {
+    let labels = container.labels.as_ref();
+    label(labels, IDENTIFIER_LABEL)
+        .or_else(|| label(labels, SERVICE_LABEL))
+        .map(str::to_string)
+        .or_else(|| {
+            container
+                .names
+                .as_ref()
+                .and_then(|names| names.first())
+                .map(|name| name.trim_start_matches('/').to_string())
+        })
+        .unwrap_or_default()
+}
+
+fn excluded(rules: &SelectionRules, project: &str, service: &str, image: &str) -> bool {
+    any_match(&rules.projects, project)
+        || any_match(&rules.services, service)
+        || any_match(&rules.images, image)
+}
+
+fn label<'a>(labels: Option<&'a HashMap<String, String>>, name: &str) -> Option<&'a str> {
+    labels.and_then(|l| l.get(name)).map(String::as_str)
+}
+
+fn any_match(globs: &[String], value: &str) -> bool {
+    globs.iter().any(|glob| glob_match(glob.trim(), value))
+}
+
+/// Match `value` against a glob where `*` stands for any run of characters
+/// and `?` for a single one.
+fn glob_match(glob: &str, value: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut g, mut v) = (0, 0);
+    // Where the last `*` was, and how much of `value` it has taken so far.
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, v));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                g += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_g, star_v)) => {
+                    g = star_g + 1;
+                    v = star_v + 1;
+                    star = Some((star_g, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(
+        project: Option<&str>,
+        service: &str,
+        image: &str,
+        enable: Option<&str>,
+    ) -> ContainerSummary {
+        let mut labels = HashMap::from([(SERVICE_LABEL.to_string(), service.to_string())]);
+        if let Some(project) = project {
+            labels.insert(PROJECT_LABEL.to_string(), project.to_string());
+        }
+        if let Some(enable) = enable {
+            labels.insert(ENABLE_LABEL.to_string(), enable.to_string());
+        }
+        ContainerSummary {
+            image: Some(image.to_string()),
+            labels: Some(labels),
+            ..Default::default()
+        }
+    }
+
+    fn globs(globs: &[&str]) -> Vec<String> {
+        globs.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn globs_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("shop-*", "shop-eu"));
+        assert!(glob_match(
+            "ghcr.io/acme/*:latest",
+            "ghcr.io/acme/api:latest"
+        ));
+        assert!(glob_match("db?", "db1"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("shop-*", "workshop-eu"));
+        assert!(!glob_match("db?", "db"));
+    }
+
+    #[test]
+    fn mode_decides_who_has_to_opt_in() {
+        let own = ProjectName::new("shop");
+        let labelled = Selection::default();
+        let all = Selection {
+            mode: SelectionMode::All,
+            ..Default::default()
+        };
+        let plain = container(Some("shop"), "api", "acme/api", None);
+        let enabled = container(Some("shop"), "api", "acme/api", Some("true"));
+        let opted_out = container(Some("shop"), "api", "acme/api", Some("false"));
+
+        assert!(!labelled.manages(&plain, &own));
+        assert!(labelled.manages(&enabled, &own));
+        assert!(all.manages(&plain, &own));
+        assert!(!all.manages(&opted_out, &own));
+        // Reporting covers the whole scope either way.
+        assert!(labelled.in_scope(&plain, &own));
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let own = ProjectName::new("shop");
+        let selection = Selection {
+            mode: SelectionMode::All,
+            include: SelectionRules {
+                projects: globs(&["shop", "billing-*"]),
+                images: globs(&["ghcr.io/acme/*"]),
+                ..Default::default()
+            },
+            exclude: SelectionRules {
+                services: globs(&["*-debug"]),
+                ..Default::default()
+            },
+        };
+        let managed = |project, service, image| {
+            selection.manages(&container(project, service, image, None), &own)
+        };
+        assert!(managed(Some("shop"), "api", "ghcr.io/acme/api:1"));
+        assert!(managed(Some("billing-eu"), "worker", "ghcr.io/acme/worker"));
+        assert!(!managed(Some("blog"), "web", "ghcr.io/acme/web"));
+        assert!(!managed(Some("shop"), "cache", "redis:7"));
+        assert!(!managed(Some("shop"), "api-debug", "ghcr.io/acme/api:1"));
+        // Containers outside of Compose only match a project glob like `*`.
+        assert!(!managed(None, "adhoc", "ghcr.io/acme/tool"));
+
+        let everything = Selection {
+            mode: SelectionMode::All,
+            include: SelectionRules {
+                projects: globs(&["*"]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let adhoc = container(None, "adhoc", "ghcr.io/acme/tool", None);
+        assert!(everything.manages(&adhoc, &own));
+        assert_eq!(project_of(&adhoc, &own), own);
+    }
+}
@@ -0,0 +1,191 @@
+//! Updating the agent's own container.
+//!
+//! An agent can't recreate its own container: stopping it kills the process
+//! doing the update. Instead it pulls its new image and starts a short-lived
+//! helper container from that image, with [`HELPER_ENV`] set to the agent's
+//! container ID. The helper runs the same binary, sees the variable, and
+//! replaces the agent like any other update. On top of the usual health check
+//! the new agent has to report in to the controller within
+//! [`CHECK_IN_TIMEOUT`], or the helper rolls back to the old one.
+
+use crate::config::{self, Config};
+use crate::docker::{self, DockerHandler, UpdateOutcome};
+use crate::notifications::{DeploymentResultHandler, start_notification_handler};
+use crate::outbox::Outbox;
+use crate::{HoisterError, resolve_hostname};
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use hoister_shared::{HostName, ProjectName};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use url::Url;
+
+/// Set on the helper container to the ID of the agent container to replace.
+pub(crate) const HELPER_ENV: &str = "HOISTER_SELF_UPDATE_OF";
+
+/// How long the new agent has to report in after it started.
+const CHECK_IN_TIMEOUT: Duration = Duration::from_secs(180);
+const CHECK_IN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits for the new agent to report in to the controller.
+pub(crate) struct CheckIn {
+    /// `None` without a controller: the health check is all there is.
+    url: Option<Url>,
+    token: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct LastSeen {
+    last_updated: String,
+}
+
+impl CheckIn {
+    fn new(
+        config: &Config,
+        client: reqwest::Client,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> Result<Self, url::ParseError> {
+        let Some(controller) = &config.controller else {
+            return Ok(Self {
+                url: None,
+                token: String::new(),
+                client,
+            });
+        };
+        let url = controller.url.join(&format!(
+            "/container/state/{}/{}/last-seen",
+            hostname.as_str(),
+            project.as_str()
+        ))?;
+        Ok(Self {
+            url: Some(url),
+            token: controller.token.clone().unwrap_or_default(),
+            client,
+        })
+    }
+
+    /// When the agent last reported, for [`CheckIn::wait`]. Taken once the old
+    /// agent is stopped: until then it keeps reporting, and its reports would
+    /// pass for the new agent's. If the controller can't tell, e.g. before the
+    /// agent's first report, it is the current time instead.
+    pub(crate) async fn baseline(&self) -> DateTime<Utc> {
+        match self.last_seen().await {
+            Some(seen) => seen,
+            None => {
+                if self.url.is_some() {
+                    warn!("No last report of the agent found, waiting for one from now on");
+                }
+                Utc::now()
+            }
+        }
+    }
+
+    async fn last_seen(&self) -> Option<DateTime<Utc>> {
+        let url = self.url.as_ref()?;
+        let response = self
+            .client
+            .get(url.clone())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await
+            .inspect_err(|e| debug!("Asking the controller for the agent's last report: {e}"))
+            .ok()?;
+        let last_seen: LastSeen = response.error_for_status().ok()?.json().await.ok()?;
+        DateTime::parse_from_rfc3339(&last_seen.last_updated)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    }
+
+    /// Wait until the controller has a report newer than `baseline`.
+    pub(crate) async fn wait(&self, baseline: DateTime<Utc>) -> Result<(), String> {
+        if self.url.is_none() {
+            debug!("No controller configured, not waiting for the new agent to report in");
+            return Ok(());
+        }
+        let deadline = tokio::time::Instant::now() + CHECK_IN_TIMEOUT;
+        loop {
+            if let Some(seen) = self.last_seen().await
+                && seen > baseline
+            {
+                info!("New agent reported in to the controller at {seen}");
+                return Ok(());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(format!(
+                    "the new agent did not report in to the controller within {}s",
+                    CHECK_IN_TIMEOUT.as_secs()
+                ));
+            }
+            tokio::time::sleep(CHECK_IN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Entry point of the helper container: replace the agent container
+/// `agent_id` and return the process exit code.
+pub(crate) async fn run_helper(config_path: &Path, agent_id: String) -> i32 {
+    info!("Self-update helper replacing agent container {agent_id}");
+    match replace_agent(config_path, &agent_id).await {
+        Ok(UpdateOutcome::Updated) => {
+            info!("Agent updated");
+            0
+        }
+        Ok(outcome) => {
+            warn!("Agent update did not go through: {outcome:?}");
+            1
+        }
+        Err(e) => {
+            error!("Agent self-update failed: {e}");
+            1
+        }
+    }
+}
+
+async fn replace_agent(
+    config_path: &Path,
+    agent_id: &str,
+) -> Result<UpdateOutcome, Box<dyn std::error::Error>> {
+    let mut config = config::load_config(config_path).await?;
+    resolve_hostname(&mut config).await;
+    let config = Arc::new(config);
+    let http_client = config::build_http_client(&config.controller)?;
+
+    // Deployment reports go out like the agent's own; the handler drains the
+    // channel once the update is done and the sender is dropped.
+    let (tx, rx) = mpsc::channel(32);
+    let (_config_tx, config_rx) = watch::channel(Arc::clone(&config));
+    let outbox = Outbox::for_controller(config.controller.as_ref());
+    let notifications = tokio::spawn(start_notification_handler(config_rx, rx, outbox.clone()));
+
+    let outcome = {
+        let docker_handler = DockerHandler::new(
+            Arc::new(Docker::connect_with_local_defaults()?),
+            DeploymentResultHandler::new(tx, config.hostname.clone()),
+            http_client.clone(),
+            &config,
+        );
+        let project = match &config.project {
+            Some(project) => project.clone(),
+            None => docker::get_project_name(docker_handler.docker.as_ref()).await?,
+        };
+        let check_in = CheckIn::new(&config, http_client.clone(), &config.hostname, &project)?;
+        docker_handler
+            .with_check_in(check_in)
+            .apply_update_container(&project, &agent_id.to_string())
+            .await
+    };
+    if notifications.await.is_err() {
+        warn!("Notification handler stopped before all reports were sent");
+    }
+    // What the controller doesn't take now stays in the queue directory.
+    if let Some(controller) = &config.controller {
+        outbox.flush(&http_client, controller).await;
+    }
+    outcome.map_err(|e: HoisterError| e.into())
+}
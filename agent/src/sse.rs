@@ -1,7 +1,13 @@
-use crate::docker::DockerHandler;
-use hoister_shared::wire::{ControllerEvent, PostContainerLogsRequest};
+use crate::HoisterError;
+use crate::docker::{DockerHandler, UpdateOutcome};
+use crate::handshake::Handshake;
+use crate::log_stream::Delivery;
+use hoister_shared::wire::{
+    AgentRegistration, Command, CommandStatus, ControllerEvent, LOG_STREAM_LIMIT_SECS,
+    PostCommandStatus, PostContainerLogsRequest, PostLogStreamChunk,
+};
 use hoister_shared::{HostName, ProjectName, ServiceName};
-use log::{info, warn};
+use log::{debug, info, warn};
 use reqwest::Client;
 use std::sync::Arc;
 use thiserror::Error;
@@ -10,15 +16,16 @@ use tokio::sync::mpsc::Sender;
 use tokio::time::{Duration, sleep};
 use tokio_stream::StreamExt;
 use url::Url;
+use uuid::Uuid;
 
 pub struct SSEHandler {
-    docker: Arc<DockerHandler>,
-    rx: mpsc::Receiver<ControllerEvent>,
-    hostname: HostName,
+    /// One handler per managed engine; events addressed to a host go to the
+    /// engine reported under that name.
+    engines: Vec<Arc<DockerHandler>>,
     /// Mirror of `HOISTER_REPORT_LOGS`. Log requests are ignored unless set,
     /// since container logs can carry secrets keyword redaction won't catch.
     report_logs: bool,
-    /// Used to ship requested logs back to the controller.
+    /// Used to ship requested logs and command progress back to the controller.
     client: Client,
     /// Base controller URL (e.g. `https://api.hoister.io/`), the same one the
     /// monitor POSTs container state to.
@@ -28,18 +35,14 @@ pub struct SSEHandler {
 
 impl SSEHandler {
     pub(crate) fn new(
-        docker: Arc<DockerHandler>,
-        rx: mpsc::Receiver<ControllerEvent>,
-        hostname: HostName,
+        engines: Vec<Arc<DockerHandler>>,
         report_logs: bool,
         client: Client,
         controller_url: Url,
         token: Option<String>,
     ) -> Self {
         Self {
-            docker,
-            rx,
-            hostname,
+            engines,
             report_logs,
             client,
             controller_url,
@@ -47,81 +50,212 @@ impl SSEHandler {
         }
     }
 
-    pub(crate) async fn start(&mut self) {
-        while let Some(message) = self.rx.recv().await {
-            match message {
-                ControllerEvent::Retry((project_name, container_id)) => {
-                    self.docker
-                        .update_container(&project_name, &container_id)
-                        .await
-                        .expect("TODO: panic message");
+    /// Carry out commands as they arrive. Each runs in its own task, so a long
+    /// update doesn't hold up acknowledging the commands behind it.
+    pub(crate) async fn start(self, mut rx: mpsc::Receiver<Command>) {
+        let handler = Arc::new(self);
+        while let Some(command) = rx.recv().await {
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.handle(command).await });
+        }
+    }
+
+    /// Run one command on the engine it is addressed to, and report its
+    /// progress. The controller only sends this agent commands for its own
+    /// engines; one for another host, e.g. queued before a restart with other
+    /// engines, is ignored without a report and times out.
+    async fn handle(&self, Command { id, event }: Command) {
+        match event {
+            ControllerEvent::Retry((target_host, project_name, container_id)) => {
+                let Some(docker) = self.engine(&target_host) else {
+                    return;
+                };
+                self.report(id, CommandStatus::Received, None).await;
+                self.report(id, CommandStatus::Running, None).await;
+                let result = docker.update_container(&project_name, &container_id).await;
+                if let Err(e) = &result {
+                    warn!("Retry of {container_id} failed: {e}");
                 }
-                ControllerEvent::ApplyUpdate((target_host, project_name, service_name)) => {
-                    if target_host == self.hostname {
-                        if let Some(container_id) = self
-                            .docker
-                            .find_container_by_service(&project_name, &service_name)
-                            .await
-                        {
-                            if let Err(e) = self
-                                .docker
-                                .apply_update_container(&project_name, &container_id)
-                                .await
-                            {
-                                warn!("Failed to apply update for {}: {e}", service_name.as_str());
-                            }
-                        } else {
-                            warn!(
-                                "ApplyUpdate: no container found for service {} in project {}",
-                                service_name.as_str(),
-                                project_name.as_str()
-                            );
-                        }
-                    }
+                let (status, detail) = update_status(&result);
+                self.report(id, status, detail).await;
+            }
+            ControllerEvent::ApplyUpdate((target_host, project_name, service_name)) => {
+                let Some(docker) = self.engine(&target_host) else {
+                    return;
+                };
+                self.report(id, CommandStatus::Received, None).await;
+                let Some(container_id) = docker
+                    .find_container_by_service(&project_name, &service_name)
+                    .await
+                else {
+                    let detail = format!(
+                        "no container found for service {} in project {}",
+                        service_name.as_str(),
+                        project_name.as_str()
+                    );
+                    warn!("ApplyUpdate: {detail}");
+                    self.report(id, CommandStatus::Failed, Some(detail)).await;
+                    return;
+                };
+                self.report(id, CommandStatus::Running, None).await;
+                let result = docker
+                    .apply_update_container(&project_name, &container_id)
+                    .await;
+                if let Err(e) = &result {
+                    warn!("Failed to apply update for {}: {e}", service_name.as_str());
                 }
-                ControllerEvent::RequestLogs((target_host, project_name, service_name)) => {
-                    if target_host == self.hostname {
-                        self.handle_log_request(&project_name, &service_name).await;
-                    }
+                let (status, detail) = update_status(&result);
+                self.report(id, status, detail).await;
+            }
+            ControllerEvent::RequestLogs((target_host, project_name, service_name)) => {
+                if let Some(docker) = self.engine(&target_host) {
+                    self.report(id, CommandStatus::Received, None).await;
+                    let (status, detail) = self
+                        .handle_log_request(docker, &project_name, &service_name)
+                        .await;
+                    self.report(id, status, detail).await;
+                }
+            }
+            ControllerEvent::StreamLogs((target_host, project_name, service_name)) => {
+                if let Some(docker) = self.engine(&target_host) {
+                    self.report(id, CommandStatus::Received, None).await;
+                    let (status, detail) = self
+                        .handle_log_stream(id, docker, &project_name, &service_name)
+                        .await;
+                    self.report(id, status, detail).await;
                 }
             }
         }
     }
 
+    /// The engine reported as `hostname`, if this agent manages it.
+    fn engine(&self, hostname: &HostName) -> Option<&DockerHandler> {
+        self.engines
+            .iter()
+            .find(|docker| docker.hostname() == hostname)
+            .map(Arc::as_ref)
+    }
+
     /// Honour an on-demand `RequestLogs` event: fetch the service's current log
     /// tail and ship it to the controller's in-memory store. Gated on
     /// `report_logs` so an operator who never opted in leaks nothing, even if
-    /// someone triggers a request from the dashboard.
-    async fn handle_log_request(&self, project_name: &ProjectName, service_name: &ServiceName) {
-        if !self.report_logs {
-            info!(
-                "Ignoring log request for service {} — set HOISTER_REPORT_LOGS=true to enable",
-                service_name.as_str()
-            );
-            return;
+    /// someone triggers a request from the dashboard. Returns what to report
+    /// for the command.
+    async fn handle_log_request(
+        &self,
+        docker: &DockerHandler,
+        project_name: &ProjectName,
+        service_name: &ServiceName,
+    ) -> (CommandStatus, Option<String>) {
+        if let Some(declined) = self.decline_logs(service_name) {
+            return declined;
         }
 
         // Always answer the request, even with an empty body, so the dashboard
         // can distinguish "no logs" from "still waiting".
-        let logs = self
-            .docker
+        let logs = docker
             .fetch_service_logs(project_name, service_name)
             .await
             .unwrap_or_default();
 
-        if let Err(e) = self
-            .post_requested_logs(project_name, service_name, logs)
+        match self
+            .post_requested_logs(docker.hostname(), project_name, service_name, logs)
             .await
         {
-            warn!(
-                "Failed to ship requested logs for service {}: {e}",
-                service_name.as_str()
-            );
+            Ok(()) => (CommandStatus::Succeeded, None),
+            Err(e) => {
+                warn!(
+                    "Failed to ship requested logs for service {}: {e}",
+                    service_name.as_str()
+                );
+                (CommandStatus::Failed, Some(e.to_string()))
+            }
+        }
+    }
+
+    /// Honour a `StreamLogs` event: follow the service's logs and send them as
+    /// chunks of stream `id` until it's cancelled or times out. Gated on
+    /// `report_logs` like `handle_log_request`.
+    async fn handle_log_stream(
+        &self,
+        id: Uuid,
+        docker: &DockerHandler,
+        project_name: &ProjectName,
+        service_name: &ServiceName,
+    ) -> (CommandStatus, Option<String>) {
+        if let Some(declined) = self.decline_logs(service_name) {
+            return declined;
+        }
+        self.report(id, CommandStatus::Running, None).await;
+        docker
+            .stream_service_logs(
+                project_name,
+                service_name,
+                Duration::from_secs(LOG_STREAM_LIMIT_SECS),
+                |chunk| self.post_log_chunk(id, chunk),
+            )
+            .await
+            .status()
+    }
+
+    /// What to report for a log request when `report_logs` is off, or `None`
+    /// to go ahead.
+    fn decline_logs(&self, service_name: &ServiceName) -> Option<(CommandStatus, Option<String>)> {
+        if self.report_logs {
+            return None;
+        }
+        info!(
+            "Ignoring log request for service {} — set HOISTER_REPORT_LOGS=true to enable",
+            service_name.as_str()
+        );
+        Some((
+            CommandStatus::Failed,
+            Some("the agent does not report logs (HOISTER_REPORT_LOGS)".to_string()),
+        ))
+    }
+
+    async fn post_log_chunk(&self, id: Uuid, chunk: PostLogStreamChunk) -> Delivery {
+        let url = self
+            .controller_url
+            .join(&format!("log-streams/{id}"))
+            .expect("controller log stream URL should be valid");
+        let mut req = self.client.post(url).json(&chunk);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        match req.send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => Delivery::Accepted,
+            Err(e) if e.status() == Some(reqwest::StatusCode::GONE) => Delivery::Gone,
+            Err(e) => Delivery::Failed(e.to_string()),
+        }
+    }
+
+    /// Tell the controller how far this agent got with command `id`. Failures
+    /// are only logged; the controller times the command out instead.
+    async fn report(&self, id: Uuid, status: CommandStatus, detail: Option<String>) {
+        let url = self
+            .controller_url
+            .join(&format!("commands/{id}"))
+            .expect("controller command URL should be valid");
+        let mut req = self
+            .client
+            .post(url)
+            .json(&PostCommandStatus { status, detail });
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let sent = match req.send().await {
+            Ok(response) => response.error_for_status().map(drop),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            warn!("Failed to report command {id} as {}: {e}", status.as_str());
         }
     }
 
     async fn post_requested_logs(
         &self,
+        hostname: &HostName,
         project_name: &ProjectName,
         service_name: &ServiceName,
         logs: String,
@@ -130,7 +264,7 @@ impl SSEHandler {
             .controller_url
             .join(&format!(
                 "container/logs/{}/{}/{}",
-                self.hostname.as_str(),
+                hostname.as_str(),
                 project_name.as_str(),
                 service_name.as_str()
             ))
@@ -148,23 +282,58 @@ impl SSEHandler {
     }
 }
 
+/// What to report for an update command that ended with `result`.
+fn update_status(result: &Result<UpdateOutcome, HoisterError>) -> (CommandStatus, Option<String>) {
+    match result {
+        Ok(UpdateOutcome::Updated) => (CommandStatus::Succeeded, None),
+        Ok(UpdateOutcome::RolledBack) => (CommandStatus::RolledBack, None),
+        Ok(UpdateOutcome::HandedOver) => (
+            CommandStatus::Succeeded,
+            Some("handed over to the self-update helper".to_string()),
+        ),
+        Err(HoisterError::NoUpdateAvailable) => (
+            CommandStatus::Succeeded,
+            Some("already up to date".to_string()),
+        ),
+        Err(e) => (CommandStatus::Failed, Some(e.to_string())),
+    }
+}
+
 #[derive(Debug, Error)]
 pub(super) enum SSEError {
     #[error(transparent)]
     Http(#[from] reqwest::Error),
+    #[error("the command handler stopped")]
+    HandlerStopped,
 }
 
 pub(crate) async fn consume_sse(
     url: &str,
     token: Option<String>,
-    tx_sse: Sender<ControllerEvent>,
+    registration: AgentRegistration,
+    handshake: Handshake,
+    tx_sse: Sender<Command>,
     client: Client,
 ) -> Result<(), SSEError> {
+    // The controller queues commands; on reconnect it replays the ones sent
+    // after the last one this agent saw.
+    let mut last_event_id = None;
     loop {
         info!("Connecting to SSE...");
+        handshake.send(&client).await;
 
-        match try_consume_stream(&client, url, token.as_deref(), &tx_sse).await {
+        match try_consume_stream(
+            &client,
+            url,
+            token.as_deref(),
+            &registration,
+            &tx_sse,
+            &mut last_event_id,
+        )
+        .await
+        {
             Ok(_) => info!("Stream ended normally"),
+            Err(SSEError::HandlerStopped) => return Err(SSEError::HandlerStopped),
             Err(e) => warn!("Stream error: {e}"),
         }
 
@@ -177,12 +346,18 @@ async fn try_consume_stream(
     client: &Client,
     url: &str,
     token: Option<&str>,
-    tx_sse: &Sender<ControllerEvent>,
+    registration: &AgentRegistration,
+    tx_sse: &Sender<Command>,
+    last_event_id: &mut Option<String>,
 ) -> Result<(), SSEError> {
-    let mut req = client.get(url);
+    // The controller streams only the commands for the registered engines.
+    let mut req = client.post(url).json(registration);
     if let Some(token) = token {
         req = req.bearer_auth(token);
     }
+    if let Some(id) = last_event_id.as_deref() {
+        req = req.header("Last-Event-ID", id);
+    }
     let response = req.send().await?;
     let response = response.error_for_status()?;
 
@@ -198,15 +373,63 @@ async fn try_consume_stream(
             let message = buffer[..pos].to_string();
             buffer = buffer[pos + 2..].to_string();
 
+            let event_id = message
+                .lines()
+                .find_map(|line| line.strip_prefix("id: "))
+                .map(str::to_string);
             for line in message.lines() {
                 if let Some(data) = line.strip_prefix("data: ") {
-                    let event: ControllerEvent = serde_json::from_str(data).unwrap();
-                    println!("Received: {event:?}");
-                    tx_sse.send(event).await.unwrap();
+                    // A controller newer than this agent may send commands it
+                    // doesn't know yet; skip them rather than dropping the stream.
+                    let command: Command = match serde_json::from_str(data) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            warn!("Ignoring unreadable controller command: {e}");
+                            continue;
+                        }
+                    };
+                    debug!("Received command {}: {:?}", command.id, command.event);
+                    tx_sse
+                        .send(command)
+                        .await
+                        .map_err(|_| SSEError::HandlerStopped)?;
                 }
             }
+            // Skipped commands count as seen too, or every reconnect would
+            // replay them again.
+            if event_id.is_some() {
+                *last_event_id = event_id;
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_outcomes_map_to_command_status() {
+        assert_eq!(
+            update_status(&Ok(UpdateOutcome::Updated)),
+            (CommandStatus::Succeeded, None)
+        );
+        assert_eq!(
+            update_status(&Ok(UpdateOutcome::RolledBack)),
+            (CommandStatus::RolledBack, None)
+        );
+        // A service that is already current is not a failure.
+        assert_eq!(
+            update_status(&Err(HoisterError::NoUpdateAvailable)).0,
+            CommandStatus::Succeeded
+        );
+        let (status, detail) = update_status(&Err(HoisterError::ImagePullFailed {
+            image: "web:1".into(),
+            message: "denied".into(),
+        }));
+        assert_eq!(status, CommandStatus::Failed);
+        assert!(detail.unwrap().contains("web"));
+    }
+}
@@ -0,0 +1,639 @@
+//! Registry push webhooks.
+//!
+//! Registries can notify the agent the moment a new image is pushed, so an
+//! update doesn't have to wait for the next scheduled check. Each source has
+//! its own endpoint and its own way of proving the request is genuine:
+//!
+//! * `POST /webhooks/dockerhub?secret=<secret>` — Docker Hub can't sign its
+//!   payloads, so the shared secret travels in the URL.
+//! * `POST /webhooks/ghcr` — GitHub `registry_package` / `package` events,
+//!   signed with `X-Hub-Signature-256`.
+//! * `POST /webhooks/harbor` — Harbor sends the configured "auth header"
+//!   verbatim in `Authorization`.
+//! * `POST /webhooks/generic` — `{"repository": "...", "tag": "..."}`, signed
+//!   with `X-Hoister-Signature-256` (same scheme as GitHub).
+//!
+//! A source without a configured secret is disabled and answers `404`.
+//! Accepted pushes are debounced: a burst of pushes (e.g. a multi-arch build
+//! publishing several manifests) results in a single update per container.
+
+use crate::HoisterError;
+use crate::config::{Config, Webhooks};
+use crate::docker::{ContainerID, DockerHandler};
+use crate::outbox::Outbox;
+use crate::selection;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use bollard::query_parameters::InspectContainerOptions;
+use hmac::{Hmac, Mac};
+use hoister_shared::ProjectName;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, timeout_at};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Upper bound on how long a continuous stream of pushes can postpone the
+/// update. Without it a busy CI pipeline could starve the debouncer.
+const MAX_DEBOUNCE_WAIT: Duration = Duration::from_secs(300);
+
+/// A pushed image, normalised with [`normalize_repository`] so it can be
+/// compared against the image reference of a running container.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct PushEvent {
+    pub(crate) repository: String,
+    pub(crate) tag: String,
+}
+
+impl PushEvent {
+    fn new(repository: &str, tag: &str) -> Self {
+        Self {
+            repository: normalize_repository(repository),
+            tag: tag.to_string(),
+        }
+    }
+
+    /// Build an event from a full image reference such as
+    /// `ghcr.io/acme/app:1.2`. A reference without a tag is `latest`.
+    fn from_reference(reference: &str) -> Self {
+        let (repository, tag) = split_reference(reference);
+        Self::new(repository, tag)
+    }
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    config: Arc<Webhooks>,
+    tx: mpsc::Sender<PushEvent>,
+}
+
+#[derive(Deserialize)]
+struct SecretQuery {
+    secret: Option<String>,
+}
+
+/// Serve the webhook endpoints and run the debouncer that turns accepted
+/// pushes into updates. Returns once the listener fails to bind or the server
+/// stops.
+pub(crate) async fn start(
+    webhooks: Webhooks,
+    config: watch::Receiver<Arc<Config>>,
+    engines: Vec<(Arc<DockerHandler>, ProjectName)>,
+    outbox: Outbox,
+) -> std::io::Result<()> {
+    let (tx, rx) = mpsc::channel(64);
+    let debounce = Duration::from_secs(webhooks.debounce);
+    let listen = webhooks.listen.clone();
+
+    tokio::spawn(async move {
+        debounce_pushes(rx, debounce, |events| {
+            // The config current when the pushes are applied, so a reloaded
+            // `auto_update` takes effect here too.
+            let config = Arc::clone(&config.borrow());
+            let engines = engines.clone();
+            let outbox = outbox.clone();
+            async move {
+                for (docker, project) in &engines {
+                    apply_pushes(&events, &config, docker, project, &outbox).await;
+                }
+            }
+        })
+        .await
+    });
+
+    let state = WebhookState {
+        config: Arc::new(webhooks),
+        tx,
+    };
+    let app = router(state);
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    info!("Listening for registry webhooks on {listen}");
+    axum::serve(listener, app).await
+}
+
+fn router(state: WebhookState) -> Router {
+    Router::new()
+        .route("/webhooks/dockerhub", post(dockerhub))
+        .route("/webhooks/ghcr", post(ghcr))
+        .route("/webhooks/harbor", post(harbor))
+        .route("/webhooks/generic", post(generic))
+        .with_state(state)
+}
+
+async fn dockerhub(
+    State(state): State<WebhookState>,
+    Query(query): Query<SecretQuery>,
+    body: Bytes,
+) -> StatusCode {
+    let Some(source) = &state.config.dockerhub else {
+        return StatusCode::NOT_FOUND;
+    };
+    let presented = query.secret.unwrap_or_default();
+    if !constant_time_eq(presented.as_bytes(), source.secret.as_bytes()) {
+        warn!("rejected Docker Hub webhook: secret mismatch");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    enqueue(&state, parse_dockerhub(&payload)).await
+}
+
+async fn ghcr(State(state): State<WebhookState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let Some(source) = &state.config.ghcr else {
+        return StatusCode::NOT_FOUND;
+    };
+    let signature = header_str(&headers, "x-hub-signature-256");
+    if !verify_signature(&source.secret, &body, signature) {
+        warn!("rejected GitHub webhook: invalid signature");
+        return StatusCode::UNAUTHORIZED;
+    }
+    // GitHub sends a `ping` when the webhook is created; acknowledge it so the
+    // delivery shows up as successful in the repository settings.
+    if header_str(&headers, "x-github-event") == Some("ping") {
+        return StatusCode::OK;
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    enqueue(&state, parse_ghcr(&payload)).await
+}
+
+async fn harbor(State(state): State<WebhookState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let Some(source) = &state.config.harbor else {
+        return StatusCode::NOT_FOUND;
+    };
+    let presented = header_str(&headers, "authorization").unwrap_or_default();
+    if !constant_time_eq(presented.as_bytes(), source.secret.as_bytes()) {
+        warn!("rejected Harbor webhook: auth header mismatch");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    enqueue(&state, parse_harbor(&payload)).await
+}
+
+async fn generic(State(state): State<WebhookState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let Some(source) = &state.config.generic else {
+        return StatusCode::NOT_FOUND;
+    };
+    let signature = header_str(&headers, "x-hoister-signature-256");
+    if !verify_signature(&source.secret, &body, signature) {
+        warn!("rejected generic webhook: invalid signature");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    enqueue(&state, parse_generic(&payload)).await
+}
+
+async fn enqueue(state: &WebhookState, events: Vec<PushEvent>) -> StatusCode {
+    if events.is_empty() {
+        // Valid, authenticated payload that isn't a push (e.g. a delete or a
+        // scan-completed event). Nothing to do, but not the sender's fault.
+        return StatusCode::NO_CONTENT;
+    }
+    for event in events {
+        debug!("webhook push: {}:{}", event.repository, event.tag);
+        if state.tx.send(event).await.is_err() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    }
+    StatusCode::ACCEPTED
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Verify a `sha256=<hex>` HMAC signature over `body`. The comparison is done
+/// by the `hmac` crate in constant time.
+fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let Some(hex) = signature.and_then(|s| s.strip_prefix("sha256=")) else {
+        return false;
+    };
+    let Some(expected) = decode_hex(hex) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn parse_dockerhub(payload: &Value) -> Vec<PushEvent> {
+    let repo = payload["repository"]["repo_name"].as_str();
+    let tag = payload["push_data"]["tag"].as_str();
+    match (repo, tag) {
+        (Some(repo), Some(tag)) if !tag.is_empty() => vec![PushEvent::new(repo, tag)],
+        _ => vec![],
+    }
+}
+
+fn parse_ghcr(payload: &Value) -> Vec<PushEvent> {
+    let package = payload
+        .get("registry_package")
+        .or_else(|| payload.get("package"));
+    let Some(package) = package else {
+        return vec![];
+    };
+    if !matches!(payload["action"].as_str(), Some("published" | "updated")) {
+        return vec![];
+    }
+    if !package["package_type"]
+        .as_str()
+        .is_some_and(|t| t.eq_ignore_ascii_case("container"))
+    {
+        return vec![];
+    }
+    let version = &package["package_version"];
+    let tag = version["container_metadata"]["tag"]["name"]
+        .as_str()
+        .filter(|t| !t.is_empty());
+    // Untagged pushes (the per-platform manifests of a multi-arch image) don't
+    // move any tag a container could be running.
+    let Some(tag) = tag else {
+        return vec![];
+    };
+    if let Some(url) = version["package_url"].as_str() {
+        let (repository, _) = split_reference(url);
+        return vec![PushEvent::new(repository, tag)];
+    }
+    let owner = package["owner"]["login"].as_str();
+    let name = package["name"].as_str();
+    match (owner, name) {
+        (Some(owner), Some(name)) => vec![PushEvent::new(&format!("ghcr.io/{owner}/{name}"), tag)],
+        _ => vec![],
+    }
+}
+
+fn parse_harbor(payload: &Value) -> Vec<PushEvent> {
+    let is_push = payload["type"]
+        .as_str()
+        .is_some_and(|t| t.to_ascii_uppercase().contains("PUSH"));
+    if !is_push {
+        return vec![];
+    }
+    payload["event_data"]["resources"]
+        .as_array()
+        .map(|resources| {
+            resources
+                .iter()
+                .filter_map(|r| {
+                    let url = r["resource_url"].as_str()?;
+                    let (repository, url_tag) = split_reference(url);
+                    let tag = r["tag"]
+                        .as_str()
+                        .filter(|t| !t.is_empty())
+                        .unwrap_or(url_tag);
+                    Some(PushEvent::new(repository, tag))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_generic(payload: &Value) -> Vec<PushEvent> {
+    let Some(repository) = payload["repository"].as_str() else {
+        return vec![];
+    };
+    match payload["tag"].as_str() {
+        Some(tag) => vec![PushEvent::new(repository, tag)],
+        None => vec![PushEvent::from_reference(repository)],
+    }
+}
+
+/// Split an image reference into repository and tag, ignoring any digest.
+/// Unlike [`hoister_shared::ImageName::split`] this copes with registry ports
+/// (`registry:5000/app:tag`). Without a tag the reference means `latest`.
+pub(crate) fn split_reference(reference: &str) -> (&str, &str) {
+    let reference = reference.split('@').next().unwrap_or(reference);
+    let last_slash = reference.rfind('/').map_or(0, |i| i + 1);
+    match reference[last_slash..].rfind(':') {
+        Some(i) => (
+            &reference[..last_slash + i],
+            &reference[last_slash + i + 1..],
+        ),
+        None => (reference, "latest"),
+    }
+}
+
+/// Normalise a repository name so that the different spellings of the same
+/// Docker Hub image compare equal: `nginx`, `library/nginx`,
+/// `docker.io/library/nginx` and `index.docker.io/library/nginx` all map to
+/// `library/nginx`. Other registries are only lower-cased.
+pub(crate) fn normalize_repository(repository: &str) -> String {
+    let repository = repository
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .to_ascii_lowercase();
+    let (first, rest) = match repository.split_once('/') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (repository.as_str(), None),
+    };
+    let is_registry_host = first.contains('.') || first.contains(':') || first == "localhost";
+    let path = match (is_registry_host, rest) {
+        (true, Some(rest))
+            if matches!(
+                first,
+                "docker.io" | "index.docker.io" | "registry-1.docker.io"
+            ) =>
+        {
+            rest.to_string()
+        }
+        (true, _) => return repository,
+        (false, _) => repository.clone(),
+    };
+    if path.contains('/') {
+        path
+    } else {
+        format!("library/{path}")
+    }
+}
+
+/// Collect push events until none arrived for `quiet`, then hand the distinct
+/// set to `apply`. A steady stream of pushes is flushed after
+/// [`MAX_DEBOUNCE_WAIT`] at the latest.
+async fn debounce_pushes<F, Fut>(mut rx: mpsc::Receiver<PushEvent>, quiet: Duration, apply: F)
+where
+    F: Fn(HashSet<PushEvent>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(first) = rx.recv().await {
+        let mut pending = HashSet::from([first]);
+        let hard_deadline = Instant::now() + MAX_DEBOUNCE_WAIT;
+        loop {
+            let deadline = (Instant::now() + quiet).min(hard_deadline);
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(Some(event)) => {
+                    pending.insert(event);
+                }
+                // Channel closed or quiet period elapsed: flush what we have.
+                Ok(None) | Err(_) => break,
+            }
+        }
+        apply(pending).await;
+    }
+}
+
+/// Run the same action a scheduled check would, but only for the containers
+/// whose image matches one of the pushed repository/tag pairs.
+async fn apply_pushes(
+    events: &HashSet<PushEvent>,
+    config: &Config,
+    docker: &DockerHandler,
+    project: &ProjectName,
+    outbox: &Outbox,
+) {
+    let containers = match docker.get_containers(project).await {
+        Ok(containers) => containers,
+        Err(e) => {
+            warn!("webhook: failed to list containers: {e}");
+            return;
+        }
+    };
+    let mut matching: Vec<(ProjectName, ContainerID)> = Vec::new();
+    for container in containers {
+        let Some(container_id) = container.id.clone() else {
+            continue;
+        };
+        // The summary's `image` turns into a bare image ID once the tag has
+        // moved on, so compare against the reference the container was
+        // created from.
+        let image = match docker
+            .docker
+            .inspect_container(&container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(inspect) => inspect.config.and_then(|c| c.image),
+            Err(e) => {
+                debug!("webhook: inspect of {container_id} failed: {e}");
+                None
+            }
+        };
+        if image.is_some_and(|image| events.contains(&PushEvent::from_reference(&image))) {
+            matching.push((selection::project_of(&container, project), container_id));
+        }
+    }
+
+    if matching.is_empty() {
+        debug!("webhook: no tracked container runs any of the pushed images");
+        return;
+    }
+    for (project, container_id) in matching {
+        info!("webhook: new image pushed for container {container_id}, checking now");
+        if config.auto_update {
+            match docker.update_container(&project, &container_id).await {
+                Ok(_) => {}
+                Err(HoisterError::NoUpdateAvailable) => {
+                    debug!("webhook: container {container_id} is already up to date");
+                }
+                Err(e) => error!("webhook: updating container {container_id} failed: {e}"),
+            }
+        } else {
+            crate::check_container_only(docker, &project, config, outbox, &container_id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("sha256={hex}")
+    }
+
+    #[test]
+    fn signature_roundtrip() {
+        let body = br#"{"repository":"app"}"#;
+        let sig = sign("s3cret", body);
+        assert!(verify_signature("s3cret", body, Some(&sig)));
+        assert!(!verify_signature("other", body, Some(&sig)));
+        assert!(!verify_signature("s3cret", b"tampered", Some(&sig)));
+        assert!(!verify_signature("s3cret", body, None));
+        assert!(!verify_signature("s3cret", body, Some("sha256=zz")));
+    }
+
+    #[test]
+    fn docker_hub_spellings_normalise_equal() {
+        for spelling in [
+            "nginx",
+            "library/nginx",
+            "docker.io/library/nginx",
+            "index.docker.io/library/nginx",
+            "docker.io/nginx",
+        ] {
+            assert_eq!(
+                normalize_repository(spelling),
+                "library/nginx",
+                "{spelling}"
+            );
+        }
+        assert_eq!(normalize_repository("Acme/App"), "acme/app");
+        assert_eq!(normalize_repository("ghcr.io/Acme/app"), "ghcr.io/acme/app");
+        assert_eq!(
+            normalize_repository("localhost:5000/app"),
+            "localhost:5000/app"
+        );
+    }
+
+    #[test]
+    fn split_reference_handles_ports_and_digests() {
+        assert_eq!(split_reference("nginx"), ("nginx", "latest"));
+        assert_eq!(split_reference("nginx:1.27"), ("nginx", "1.27"));
+        assert_eq!(
+            split_reference("registry:5000/app"),
+            ("registry:5000/app", "latest")
+        );
+        assert_eq!(
+            split_reference("registry:5000/app:v2@sha256:abc"),
+            ("registry:5000/app", "v2")
+        );
+    }
+
+    #[test]
+    fn parses_docker_hub_payload() {
+        let payload = json!({
+            "push_data": {"tag": "latest", "pusher": "acme"},
+            "repository": {"repo_name": "acme/app", "namespace": "acme", "name": "app"}
+        });
+        assert_eq!(
+            parse_dockerhub(&payload),
+            vec![PushEvent::from_reference("docker.io/acme/app:latest")]
+        );
+    }
+
+    #[test]
+    fn parses_ghcr_payload_and_skips_untagged() {
+        let payload = json!({
+            "action": "published",
+            "registry_package": {
+                "name": "app",
+                "package_type": "CONTAINER",
+                "owner": {"login": "Acme"},
+                "package_version": {
+                    "package_url": "ghcr.io/acme/app:1.2.0",
+                    "container_metadata": {"tag": {"name": "1.2.0", "digest": "sha256:abc"}}
+                }
+            }
+        });
+        assert_eq!(
+            parse_ghcr(&payload),
+            vec![PushEvent::new("ghcr.io/acme/app", "1.2.0")]
+        );
+
+        let untagged = json!({
+            "action": "published",
+            "registry_package": {
+                "name": "app",
+                "package_type": "container",
+                "owner": {"login": "acme"},
+                "package_version": {"container_metadata": {"tag": {"name": ""}}}
+            }
+        });
+        assert!(parse_ghcr(&untagged).is_empty());
+    }
+
+    #[test]
+    fn parses_harbor_push_only() {
+        let payload = json!({
+            "type": "PUSH_ARTIFACT",
+            "event_data": {
+                "resources": [{
+                    "digest": "sha256:abc",
+                    "tag": "stable",
+                    "resource_url": "harbor.example.com/team/app:stable"
+                }],
+                "repository": {"repo_full_name": "team/app"}
+            }
+        });
+        assert_eq!(
+            parse_harbor(&payload),
+            vec![PushEvent::new("harbor.example.com/team/app", "stable")]
+        );
+
+        let pulled = json!({"type": "PULL_ARTIFACT", "event_data": payload["event_data"]});
+        assert!(parse_harbor(&pulled).is_empty());
+    }
+
+    #[test]
+    fn parses_generic_payload() {
+        assert_eq!(
+            parse_generic(&json!({"repository": "ghcr.io/acme/app", "tag": "v1"})),
+            vec![PushEvent::new("ghcr.io/acme/app", "v1")]
+        );
+        assert_eq!(
+            parse_generic(&json!({"repository": "ghcr.io/acme/app:v1"})),
+            vec![PushEvent::new("ghcr.io/acme/app", "v1")]
+        );
+        assert!(parse_generic(&json!({"tag": "v1"})).is_empty());
+    }
+
+    #[tokio::test]
+    async fn burst_of_pushes_is_applied_once() {
+        let (tx, rx) = mpsc::channel(8);
+        let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&batches);
+        let handle = tokio::spawn(debounce_pushes(
+            rx,
+            Duration::from_millis(200),
+            move |events| {
+                recorded.lock().unwrap().push(events);
+                async {}
+            },
+        ));
+
+        for _ in 0..3 {
+            tx.send(PushEvent::new("acme/app", "latest")).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tx.send(PushEvent::new("acme/worker", "latest"))
+            .await
+            .unwrap();
+        drop(tx);
+        handle.await.unwrap();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 2);
+    }
+}
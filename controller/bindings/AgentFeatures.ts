@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * The agent's opt-in behaviour. A dashboard hides what the agent would ignore,
+ * e.g. log requests without `report_logs`.
+ */
+export type AgentFeatures = { auto_update: boolean, report_logs: boolean, report_metrics: boolean, };
@@ -0,0 +1,9 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { HostName } from "./HostName";
+import type { PostAgentInfo } from "./PostAgentInfo";
+
+export type AgentInfoResponse = { hostname: HostName, info: PostAgentInfo, reported_at: string, 
+/**
+ * The agent is older than the release this controller was built with.
+ */
+outdated: boolean, latest_version: string, };
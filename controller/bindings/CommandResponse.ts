@@ -0,0 +1,20 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AgentId } from "./AgentId";
+import type { CommandStatus } from "./CommandStatus";
+import type { HostName } from "./HostName";
+import type { ProjectName } from "./ProjectName";
+import type { ServiceName } from "./ServiceName";
+
+export type CommandResponse = { id: string, 
+/**
+ * `apply_update`, `request_logs`, `stream_logs` or `retry`.
+ */
+kind: string, 
+/**
+ * The agent the command was sent to.
+ */
+agent_id: AgentId, hostname: HostName, project_name: ProjectName, service_name: ServiceName | null, status: CommandStatus, detail: string | null, created_at: string, updated_at: string, 
+/**
+ * When the command times out if no agent has picked it up.
+ */
+expires_at: string, };
@@ -9,4 +9,8 @@ export type ContainerStateResponse = { hostname: HostName, project_name: Project
  * nested maps — Labels, ExposedPorts, Networks, etc. — serialize in a
  * stable alphabetical order rather than HashMap's arbitrary one.
  */
-container_inspections: any, last_logs: string | null, last_updated: string, };
+container_inspections: any, last_logs: string | null, 
+/**
+ * Update-check schedule the agent reported for the service.
+ */
+schedule: string | null, last_updated: string, };
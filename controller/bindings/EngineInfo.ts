@@ -0,0 +1,14 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * The container engine of the host, as it describes itself.
+ */
+export type EngineInfo = { 
+/**
+ * The engine's release, e.g. `27.3.1`.
+ */
+version: string | null, 
+/**
+ * The host's operating system, e.g. `Debian GNU/Linux 12 (bookworm)`.
+ */
+os: string | null, kernel_version: string | null, arch: string | null, };
@@ -0,0 +1,23 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AgentFeatures } from "./AgentFeatures";
+import type { AgentId } from "./AgentId";
+import type { EngineInfo } from "./EngineInfo";
+
+/**
+ * Body of POST /agent-info/{hostname}: what the agent managing the host runs
+ * and which of its optional features are on. Sent when the agent starts and
+ * whenever it connects for commands.
+ */
+export type PostAgentInfo = { agent_id: AgentId, 
+/**
+ * The agent's release, e.g. `1.1.0`.
+ */
+version: string, 
+/**
+ * The agent's platform as Rust names it, e.g. `linux` and `x86_64`.
+ */
+os: string, arch: string, 
+/**
+ * The global update-check schedule, e.g. `every 5m` or `cron 0 0 3 * * Sun *`.
+ */
+schedule: string | null, features: AgentFeatures, engine: EngineInfo, };
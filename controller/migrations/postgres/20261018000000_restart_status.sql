@@ -0,0 +1,7 @@
+-- Allow the deployment statuses added after the initial schema: 7 (update
+-- available), 8 (scheduled restart) and 9 (scheduled restart failed).
+
+ALTER TABLE deployment DROP CONSTRAINT deployment_status_check;
+
+ALTER TABLE deployment
+    ADD CONSTRAINT deployment_status_check CHECK (status IN (0, 1, 2, 3, 4, 5, 6, 7, 8, 9));
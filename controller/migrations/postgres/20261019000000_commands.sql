@@ -0,0 +1,14 @@
+-- Commands sent to agents over SSE and the progress the agents report back.
+-- `event` holds the JSON `ControllerEvent`; `status` is a `CommandStatus`.
+
+CREATE TABLE command (
+    id UUID PRIMARY KEY,
+    user_id VARCHAR(128) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    event JSONB NOT NULL,
+    status VARCHAR(16) NOT NULL,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX command_user_id_idx ON command(user_id, created_at);
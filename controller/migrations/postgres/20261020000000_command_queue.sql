@@ -0,0 +1,10 @@
+-- Commands stay queued for agents that are offline until `expires_at`. Rows
+-- from before the queue expire right away.
+
+ALTER TABLE command ADD COLUMN expires_at TIMESTAMPTZ;
+
+UPDATE command SET expires_at = created_at;
+
+ALTER TABLE command ALTER COLUMN expires_at SET NOT NULL;
+
+CREATE INDEX command_pending_idx ON command(user_id, status, created_at);
@@ -0,0 +1,8 @@
+-- What the agent managing a host last reported about itself on connecting:
+-- version, platform, schedule, enabled features and engine. One row per host.
+
+CREATE TABLE agent_info (
+    host_id UUID PRIMARY KEY REFERENCES host(id) ON DELETE CASCADE,
+    info JSONB NOT NULL,
+    reported_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,5 @@
+-- The version the agent gave the stored state. Agents send later reports as
+-- merge patches against it; rows written before this carry none and get a
+-- full report first.
+
+ALTER TABLE compose_state ADD COLUMN version BIGINT;
@@ -0,0 +1,14 @@
+-- The engines each agent registered when it last connected, so commands for
+-- an agent that is offline can still be addressed to it and queued. One row
+-- per host and project, owned by the agent that registered it last.
+
+CREATE TABLE agent_engine (
+    user_id VARCHAR(128) NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    hostname VARCHAR(253) NOT NULL,
+    project_name TEXT NOT NULL,
+    agent_id TEXT NOT NULL,
+    registered_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_id, hostname, project_name)
+);
+
+CREATE INDEX agent_engine_agent_idx ON agent_engine(user_id, agent_id);
@@ -0,0 +1,16 @@
+-- Agents resume their command queue after the last command they saw. The
+-- creation time can't tell apart commands created in the same instant, so
+-- commands are numbered in the order they were queued.
+
+ALTER TABLE command ADD COLUMN seq BIGINT;
+
+UPDATE command SET seq = numbered.seq
+    FROM (SELECT id, ROW_NUMBER() OVER (ORDER BY created_at, id) AS seq FROM command) numbered
+    WHERE command.id = numbered.id;
+
+CREATE SEQUENCE command_seq_seq OWNED BY command.seq;
+SELECT setval('command_seq_seq', COALESCE(MAX(seq), 0) + 1, false) FROM command;
+ALTER TABLE command ALTER COLUMN seq SET DEFAULT nextval('command_seq_seq');
+ALTER TABLE command ALTER COLUMN seq SET NOT NULL;
+
+CREATE INDEX command_seq_idx ON command(user_id, agent_id, seq);
@@ -0,0 +1,21 @@
+-- Allow the deployment statuses added after the initial schema: 7 (update
+-- available), 8 (scheduled restart) and 9 (scheduled restart failed).
+-- SQLite cannot alter a CHECK constraint, so the table is rebuilt. Nothing
+-- references `deployment`, so dropping it leaves no dangling foreign keys.
+
+CREATE TABLE deployment_new (
+    id TEXT PRIMARY KEY,
+    digest VARCHAR(255) NOT NULL,
+    status INTEGER NOT NULL CHECK (status IN (0, 1, 2, 3, 4, 5, 6, 7, 8, 9)),
+    service_id TEXT NOT NULL REFERENCES service(id) ON DELETE CASCADE,
+    host_id TEXT REFERENCES host(id) ON DELETE SET NULL,
+    logs TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO deployment_new (id, digest, status, service_id, host_id, logs, created_at)
+SELECT id, digest, status, service_id, host_id, logs, created_at FROM deployment;
+
+DROP TABLE deployment;
+
+ALTER TABLE deployment_new RENAME TO deployment;
@@ -0,0 +1,8 @@
+-- Commands stay queued for agents that are offline until `expires_at`. Rows
+-- from before the queue expire right away.
+
+ALTER TABLE command ADD COLUMN expires_at TEXT NOT NULL DEFAULT '';
+
+UPDATE command SET expires_at = created_at;
+
+CREATE INDEX command_pending_idx ON command(user_id, status, created_at);
@@ -0,0 +1,8 @@
+-- Commands are addressed to one agent, and only that agent's connection
+-- receives them. Earlier rows belonged to no agent in particular.
+
+ALTER TABLE command ADD COLUMN agent_id TEXT NOT NULL DEFAULT '';
+
+DROP INDEX command_pending_idx;
+
+CREATE INDEX command_pending_idx ON command(user_id, agent_id, status, created_at);
@@ -0,0 +1,8 @@
+-- What the agent managing a host last reported about itself on connecting:
+-- version, platform, schedule, enabled features and engine. One row per host.
+
+CREATE TABLE agent_info (
+    host_id TEXT PRIMARY KEY REFERENCES host(id) ON DELETE CASCADE,
+    info TEXT NOT NULL,
+    reported_at TEXT NOT NULL
+);
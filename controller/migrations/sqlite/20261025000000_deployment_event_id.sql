@@ -0,0 +1,14 @@
+-- Agents give each report an event ID so a retried report is recorded once.
+-- It used to be the deployment's primary key, shared by all tenants; it is now
+-- unique per user only. Earlier rows used the event ID as their ID.
+
+ALTER TABLE deployment ADD COLUMN user_id VARCHAR(128) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE deployment ADD COLUMN event_id TEXT;
+
+UPDATE deployment SET
+    user_id = (SELECT p.user_id FROM service s JOIN project p ON s.project_id = p.id
+               WHERE s.id = deployment.service_id),
+    event_id = id;
+
+CREATE UNIQUE INDEX deployment_event_idx ON deployment(user_id, event_id);
@@ -0,0 +1,9 @@
+-- Agents resume their command queue after the last command they saw. The
+-- creation time can't tell apart commands created in the same instant, so
+-- commands are numbered in the order they were queued.
+
+ALTER TABLE command ADD COLUMN seq INTEGER;
+
+UPDATE command SET seq = rowid;
+
+CREATE INDEX command_seq_idx ON command(user_id, agent_id, seq);
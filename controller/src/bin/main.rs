@@ -1,5 +1,6 @@
 use controller::config::get_config;
 use controller::domain::billing::service::Service as BillingServiceImpl;
+use controller::domain::commands::service::Service as CommandService;
 use controller::domain::container_state::service::Service as ContainerStateService;
 use controller::domain::deployments::service::Service as DeploymentsService;
 use controller::domain::metrics::service::Service as MetricsService;
@@ -9,6 +10,8 @@ use controller::inbound::server::{
     AppState, InternalSecret, create_agent_router, create_internal_router,
 };
 use controller::outbound::Database;
+use controller::outbound::agent_registry::AgentRegistry;
+use controller::outbound::cleanup_memory::CleanupMemory;
 use controller::outbound::logs_memory::LogsMemory;
 use controller::outbound::pending_updates_memory::PendingUpdatesMemory;
 use controller::sse::UserScopedEvent;
@@ -108,12 +111,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         token_service: Arc::new(TokenService::new(db.clone())),
         notifier_service: Arc::new(NotifierService::new(db.clone())),
         billing_service: Arc::new(BillingServiceImpl::new(db.clone())),
-        metrics_service: Arc::new(MetricsService::new(db)),
+        metrics_service: Arc::new(MetricsService::new(db.clone())),
+        command_service: Arc::new(CommandService::new(db)),
         #[cfg(feature = "self-hosted")]
         api_secret: config.api_secret.clone(),
         event_tx,
         pending_updates,
         logs,
+        log_streams: Default::default(),
+        cleanup: CleanupMemory::default(),
+        agents: AgentRegistry::default(),
         email,
         dashboard_url: config.dashboard_url.clone(),
     };
@@ -1,4 +1,5 @@
 pub mod billing;
+pub mod commands;
 pub mod container_state;
 pub mod deployments;
 pub mod metrics;
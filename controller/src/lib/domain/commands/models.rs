@@ -0,0 +1,110 @@
+use chrono::{DateTime, Duration, Utc};
+use hoister_shared::{AgentId, HostName, ProjectName, ServiceName};
+use thiserror::Error;
+
+pub use hoister_shared::wire::{Command as AgentCommand, CommandStatus, ControllerEvent};
+
+/// A command sent to one of a user's agents and how far it got with it.
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub id: uuid::Uuid,
+    pub user_id: String,
+    /// The agent that manages the host and project the event names.
+    pub agent_id: AgentId,
+    pub event: ControllerEvent,
+    pub status: CommandStatus,
+    /// The agent's explanation of the outcome, or why the command timed out.
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Until when the command waits for its agent to connect and pick it up.
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Command {
+    pub fn new(user_id: String, agent_id: AgentId, event: ControllerEvent) -> Self {
+        let now = Utc::now();
+        let expires_at = now + delivery_window(&event);
+        Self {
+            id: uuid::Uuid::new_v4(),
+            user_id,
+            agent_id,
+            event,
+            status: CommandStatus::Sent,
+            detail: None,
+            created_at: now,
+            updated_at: now,
+            expires_at,
+        }
+    }
+
+    /// What goes out to the agent over SSE.
+    pub fn for_agent(&self) -> AgentCommand {
+        AgentCommand {
+            id: self.id,
+            event: self.event.clone(),
+        }
+    }
+
+    /// Short name of the command, e.g. `apply_update`.
+    pub fn kind(&self) -> &'static str {
+        match self.event {
+            ControllerEvent::Retry(_) => "retry",
+            ControllerEvent::ApplyUpdate(_) => "apply_update",
+            ControllerEvent::RequestLogs(_) => "request_logs",
+            ControllerEvent::StreamLogs(_) => "stream_logs",
+        }
+    }
+
+    /// The host the command is addressed to.
+    pub fn hostname(&self) -> &HostName {
+        event_target(&self.event).0
+    }
+
+    pub fn project_name(&self) -> &ProjectName {
+        event_target(&self.event).1
+    }
+
+    pub fn service_name(&self) -> Option<&ServiceName> {
+        match &self.event {
+            ControllerEvent::Retry(_) => None,
+            ControllerEvent::ApplyUpdate((_, _, service))
+            | ControllerEvent::RequestLogs((_, _, service))
+            | ControllerEvent::StreamLogs((_, _, service)) => Some(service),
+        }
+    }
+}
+
+/// The host and project `event` is for; the agent that registered them
+/// receives it.
+pub fn event_target(event: &ControllerEvent) -> (&HostName, &ProjectName) {
+    match event {
+        ControllerEvent::Retry((host, project, _))
+        | ControllerEvent::ApplyUpdate((host, project, _))
+        | ControllerEvent::RequestLogs((host, project, _))
+        | ControllerEvent::StreamLogs((host, project, _)) => (host, project),
+    }
+}
+
+/// How long a command waits for its agent, e.g. one whose connection dropped,
+/// before it times out. An update the user asked for should still happen when
+/// the agent comes back; logs are only wanted while someone is looking.
+fn delivery_window(event: &ControllerEvent) -> Duration {
+    match event {
+        ControllerEvent::Retry(_) | ControllerEvent::ApplyUpdate(_) => Duration::days(1),
+        ControllerEvent::RequestLogs(_) | ControllerEvent::StreamLogs(_) => Duration::minutes(2),
+    }
+}
+
+/// How long an acknowledged command may run without another report. Updates
+/// pull images and wait out health checks, so this is generous.
+pub const RESULT_TIMEOUT: Duration = Duration::minutes(30);
+
+/// How many of a user's most recent commands `list_commands` returns.
+pub const LIST_LIMIT: i64 = 50;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("Unknown error")]
+    UnknownError,
+}
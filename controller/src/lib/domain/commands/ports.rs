@@ -0,0 +1,128 @@
+use crate::domain::commands::models::{Command, CommandError, CommandStatus};
+use chrono::{DateTime, Utc};
+use hoister_shared::wire::AgentEngine;
+use hoister_shared::{AgentId, HostName, ProjectName};
+
+pub trait CommandRepository: Send + Sync + 'static + Clone {
+    fn create_command(
+        &self,
+        command: &Command,
+    ) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    /// Record an agent's report. Commands the agent already finished keep
+    /// their outcome; returns `Ok(false)` for those and for unknown IDs.
+    fn set_command_status(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+        status: CommandStatus,
+        detail: Option<&str>,
+    ) -> impl Future<Output = Result<bool, CommandError>> + Send;
+
+    fn get_command(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+    ) -> impl Future<Output = Result<Option<Command>, CommandError>> + Send;
+
+    /// The user's `limit` most recent commands, newest first.
+    fn list_commands(
+        &self,
+        user_id: &str,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Command>, CommandError>> + Send;
+
+    /// Commands for `agent_id` it didn't acknowledge yet that are still valid
+    /// at `now`, oldest first. With `after`, only those sent after that command.
+    fn pending_commands(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        after: Option<uuid::Uuid>,
+        now: DateTime<Utc>,
+    ) -> impl Future<Output = Result<Vec<Command>, CommandError>> + Send;
+
+    /// Mark commands never acknowledged before they expired at `now`, and
+    /// acknowledged ones without a report since `unfinished_before`, as timed
+    /// out.
+    fn expire_commands(
+        &self,
+        user_id: &str,
+        now: DateTime<Utc>,
+        unfinished_before: DateTime<Utc>,
+    ) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    /// Record the engines `agent_id` registered at `now`, replacing those it
+    /// registered before. Engines another agent registered move over to it.
+    fn register_agent_engines(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        engines: &[AgentEngine],
+        now: DateTime<Utc>,
+    ) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    /// The agent that last registered `hostname` in `project`, if any.
+    fn registered_agent(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> impl Future<Output = Result<Option<AgentId>, CommandError>> + Send;
+}
+
+pub trait CommandService: Send + Sync + 'static + Clone {
+    /// Store `command` as sent. Broadcasting it is up to the caller.
+    fn create_command(
+        &self,
+        command: &Command,
+    ) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    fn set_command_status(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+        status: CommandStatus,
+        detail: Option<&str>,
+    ) -> impl Future<Output = Result<bool, CommandError>> + Send;
+
+    /// A command, timed out first if its agent is overdue.
+    fn get_command(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+    ) -> impl Future<Output = Result<Option<Command>, CommandError>> + Send;
+
+    /// The user's most recent commands, timed out first if overdue.
+    fn list_commands(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Vec<Command>, CommandError>> + Send;
+
+    /// Commands waiting for one of the user's agents, oldest first, to replay
+    /// when it connects. `after` is the last command the agent saw, if any.
+    fn pending_commands(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        after: Option<uuid::Uuid>,
+    ) -> impl Future<Output = Result<Vec<Command>, CommandError>> + Send;
+
+    /// Remember the engines a connecting agent registered, so commands for
+    /// them can be queued for it while it is offline.
+    fn register_agent_engines(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        engines: &[AgentEngine],
+    ) -> impl Future<Output = Result<(), CommandError>> + Send;
+
+    /// The agent that last registered `hostname` in `project`, connected or
+    /// not.
+    fn registered_agent(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> impl Future<Output = Result<Option<AgentId>, CommandError>> + Send;
+}
@@ -0,0 +1,91 @@
+use crate::domain::commands::models::{
+    Command, CommandError, CommandStatus, LIST_LIMIT, RESULT_TIMEOUT,
+};
+use crate::domain::commands::ports::{CommandRepository, CommandService};
+use chrono::Utc;
+use hoister_shared::wire::AgentEngine;
+use hoister_shared::{AgentId, HostName, ProjectName};
+
+#[derive(Clone)]
+pub struct Service<CR: CommandRepository> {
+    repository: CR,
+}
+
+impl<CR: CommandRepository> Service<CR> {
+    pub fn new(repository: CR) -> Self {
+        Self { repository }
+    }
+
+    /// Timeouts are enforced when commands are read rather than by a timer.
+    async fn expire(&self, user_id: &str) -> Result<(), CommandError> {
+        let now = Utc::now();
+        self.repository
+            .expire_commands(user_id, now, now - RESULT_TIMEOUT)
+            .await
+    }
+}
+
+impl<CR: CommandRepository> CommandService for Service<CR> {
+    async fn create_command(&self, command: &Command) -> Result<(), CommandError> {
+        self.repository.create_command(command).await
+    }
+
+    async fn set_command_status(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+        status: CommandStatus,
+        detail: Option<&str>,
+    ) -> Result<bool, CommandError> {
+        self.repository
+            .set_command_status(user_id, id, status, detail)
+            .await
+    }
+
+    async fn get_command(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+    ) -> Result<Option<Command>, CommandError> {
+        self.expire(user_id).await?;
+        self.repository.get_command(user_id, id).await
+    }
+
+    async fn list_commands(&self, user_id: &str) -> Result<Vec<Command>, CommandError> {
+        self.expire(user_id).await?;
+        self.repository.list_commands(user_id, LIST_LIMIT).await
+    }
+
+    async fn pending_commands(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        after: Option<uuid::Uuid>,
+    ) -> Result<Vec<Command>, CommandError> {
+        self.repository
+            .pending_commands(user_id, agent_id, after, Utc::now())
+            .await
+    }
+
+    async fn register_agent_engines(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        engines: &[AgentEngine],
+    ) -> Result<(), CommandError> {
+        self.repository
+            .register_agent_engines(user_id, agent_id, engines, Utc::now())
+            .await
+    }
+
+    async fn registered_agent(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> Result<Option<AgentId>, CommandError> {
+        self.repository
+            .registered_agent(user_id, hostname, project)
+            .await
+    }
+}
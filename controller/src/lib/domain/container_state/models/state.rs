@@ -1,8 +1,9 @@
 use chrono::{DateTime, Utc};
 use hoister_shared::{HostName, ProjectName, ServiceName};
 use std::collections::HashMap;
+use thiserror::Error;
 
-pub use hoister_shared::wire::ServiceState;
+pub use hoister_shared::wire::{PostAgentInfo as AgentInfo, ServiceState};
 
 pub struct AddContainerStateRequest {
     /// Owning user. In hosted mode this is the Clerk user id resolved from the
@@ -11,6 +12,9 @@ pub struct AddContainerStateRequest {
     pub(crate) hostname: HostName,
     pub(crate) project_name: ProjectName,
     pub(crate) services: HashMap<ServiceName, ServiceState>,
+    /// The agent's version of `services`, which its next delta is based on.
+    /// `None` from agents that don't send deltas.
+    pub(crate) version: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -19,6 +23,68 @@ pub struct HostProjectState {
     pub last_updated: DateTime<Utc>,
 }
 
+/// The stored state of one (host, project) together with its version.
+pub struct VersionedProjectState {
+    pub version: u64,
+    pub services: HashMap<ServiceName, ServiceState>,
+}
+
+#[derive(Debug, Error)]
+pub enum StateDeltaError {
+    /// The stored state isn't the one the delta was made against: it has
+    /// another version, none, or doesn't exist.
+    #[error("Stored state is not at the delta's base version")]
+    Diverged,
+    #[error("Patched state is not valid: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
 /// One user's view of container state. The repository stores a separate copy
 /// of this per user so reads can never leak across tenants.
 pub(crate) type ContainerStateData = HashMap<HostName, HashMap<ProjectName, HostProjectState>>;
+
+/// What the agent managing a host last reported about itself.
+#[derive(Clone)]
+pub struct HostAgentInfo {
+    pub hostname: HostName,
+    pub info: AgentInfo,
+    pub reported_at: DateTime<Utc>,
+}
+
+/// The agent release this controller was built alongside. Hosts reporting an
+/// older agent are flagged as outdated; bump this with each agent release.
+pub const LATEST_AGENT_VERSION: &str = "1.1.0";
+
+/// Whether `version` is older than [`LATEST_AGENT_VERSION`]. Versions compare
+/// by their numeric parts, so a pre-release suffix doesn't count; an
+/// unreadable version does not count as outdated.
+pub fn is_outdated(version: &str) -> bool {
+    fn parts(version: &str) -> Option<Vec<u64>> {
+        version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    }
+    match (parts(version), parts(LATEST_AGENT_VERSION)) {
+        (Some(version), Some(latest)) => version < latest,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_agents_are_outdated() {
+        assert!(is_outdated("1.0.9"));
+        assert!(is_outdated("0.12.0"));
+        assert!(!is_outdated(LATEST_AGENT_VERSION));
+        assert!(!is_outdated("1.10.0"));
+        assert!(!is_outdated("v2.0.0-rc.1"));
+        assert!(!is_outdated("unknown"));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::domain::container_state::models::state::{
-    AddContainerStateRequest, ContainerStateData, HostProjectState,
+    AddContainerStateRequest, AgentInfo, ContainerStateData, HostAgentInfo, HostProjectState,
+    StateDeltaError, VersionedProjectState,
 };
+use hoister_shared::wire::PostContainerStateDelta;
 use hoister_shared::{HostName, ProjectName, ServiceName};
 
 pub trait ContainerStateRepository: Send + Sync + 'static + Clone {
@@ -17,6 +19,13 @@ pub trait ContainerStateRepository: Send + Sync + 'static + Clone {
     ) -> impl Future<Output = ContainerStateData> + Send;
     fn add_container_state(&self, req: AddContainerStateRequest)
     -> impl Future<Output = ()> + Send;
+    /// The stored state of a (host, project), if it exists and has a version.
+    fn get_versioned_state(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+    ) -> impl Future<Output = Option<VersionedProjectState>> + Send;
     /// Remove a single (host, project) entry for a user. Returns `true` when a
     /// row was deleted, `false` when nothing matched.
     fn delete_project(
@@ -35,6 +44,16 @@ pub trait ContainerStateRepository: Send + Sync + 'static + Clone {
         hostname: &HostName,
         project_name: &ProjectName,
     ) -> impl Future<Output = ()> + Send;
+    /// Store what the agent managing `hostname` reports about itself,
+    /// replacing its previous report.
+    fn set_agent_info(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        info: &AgentInfo,
+    ) -> impl Future<Output = ()> + Send;
+    /// The latest agent report of each of the user's hosts.
+    fn get_agent_infos(&self, user_id: &str) -> impl Future<Output = Vec<HostAgentInfo>> + Send;
 }
 
 pub trait ContainerStateService: Send + Sync + 'static + Clone {
@@ -51,6 +70,16 @@ pub trait ContainerStateService: Send + Sync + 'static + Clone {
     ) -> impl Future<Output = ContainerStateData> + Send;
     fn add_container_state(&self, req: AddContainerStateRequest)
     -> impl Future<Output = ()> + Send;
+    /// Apply an agent's merge patch to the stored state of a (host, project).
+    /// Fails with [`StateDeltaError::Diverged`] unless the stored state is at
+    /// the delta's base version, in which case the agent sends it in full.
+    fn apply_container_state_delta(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+        delta: PostContainerStateDelta,
+    ) -> impl Future<Output = Result<(), StateDeltaError>> + Send;
     /// Remove a single (host, project) entry for a user. Returns `true` when a
     /// row was deleted, `false` when nothing matched.
     fn delete_project(
@@ -65,4 +94,11 @@ pub trait ContainerStateService: Send + Sync + 'static + Clone {
         hostname: &HostName,
         project_name: &ProjectName,
     ) -> impl Future<Output = ()> + Send;
+    fn set_agent_info(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        info: &AgentInfo,
+    ) -> impl Future<Output = ()> + Send;
+    fn get_agent_infos(&self, user_id: &str) -> impl Future<Output = Vec<HostAgentInfo>> + Send;
 }
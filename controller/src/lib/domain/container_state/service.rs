@@ -1,7 +1,10 @@
 use crate::domain::container_state::models::state::{
-    AddContainerStateRequest, ContainerStateData, HostProjectState,
+    AddContainerStateRequest, AgentInfo, ContainerStateData, HostAgentInfo, HostProjectState,
+    StateDeltaError,
 };
 use crate::domain::container_state::port::{ContainerStateRepository, ContainerStateService};
+use hoister_shared::merge_patch;
+use hoister_shared::wire::PostContainerStateDelta;
 use hoister_shared::{HostName, ProjectName, ServiceName};
 
 #[derive(Clone)]
@@ -42,6 +45,35 @@ impl<CR: ContainerStateRepository> ContainerStateService for Service<CR> {
             .await
     }
 
+    async fn apply_container_state_delta(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+        delta: PostContainerStateDelta,
+    ) -> Result<(), StateDeltaError> {
+        let stored = self
+            .container_state_repository
+            .get_versioned_state(user_id, hostname, project_name)
+            .await
+            .filter(|stored| stored.version == delta.base_version)
+            .ok_or(StateDeltaError::Diverged)?;
+        let mut services = serde_json::to_value(&stored.services)?;
+        merge_patch::strip_nulls(&mut services);
+        merge_patch::apply(&mut services, &delta.patch);
+        let services = serde_json::from_value(services)?;
+        self.container_state_repository
+            .add_container_state(AddContainerStateRequest {
+                user_id: user_id.to_string(),
+                hostname: hostname.clone(),
+                project_name: project_name.clone(),
+                services,
+                version: Some(delta.version),
+            })
+            .await;
+        Ok(())
+    }
+
     async fn delete_project(
         &self,
         user_id: &str,
@@ -63,4 +95,16 @@ impl<CR: ContainerStateRepository> ContainerStateService for Service<CR> {
             .touch_container_state(user_id, hostname, project_name)
             .await
     }
+
+    async fn set_agent_info(&self, user_id: &str, hostname: &HostName, info: &AgentInfo) {
+        self.container_state_repository
+            .set_agent_info(user_id, hostname, info)
+            .await
+    }
+
+    async fn get_agent_infos(&self, user_id: &str) -> Vec<HostAgentInfo> {
+        self.container_state_repository
+            .get_agent_infos(user_id)
+            .await
+    }
 }
@@ -26,6 +26,9 @@ pub struct CreateDeploymentRequest {
     /// Owning tenant. Always resolved by the auth middleware before the
     /// handler sees the request, so this is never `None`.
     pub user_id: String,
+    /// The agent's ID for this report, unique per user, so a retried report
+    /// is recorded once.
+    pub event_id: Option<uuid::Uuid>,
 }
 
 impl CreateDeploymentRequest {
@@ -39,8 +42,19 @@ impl CreateDeploymentRequest {
             hostname: payload.hostname,
             logs: payload.logs,
             user_id,
+            event_id: payload.event_id,
         }
     }
+
+    /// Whether `deployment`, recorded under this request's event ID, is this
+    /// report rather than another one that reused the ID.
+    pub fn is_recorded_as(&self, deployment: &Deployment) -> bool {
+        deployment.service_name == self.service_name
+            && deployment.project_name == self.project_name
+            && deployment.hostname == self.hostname
+            && deployment.digest == self.image_digest.as_str()
+            && deployment.status.clone() as u8 == self.deployment_status.clone() as u8
+    }
 }
 
 #[derive(FromRow, Debug, Clone, Serialize, Deserialize, TS)]
@@ -62,6 +76,10 @@ pub struct Deployment {
 pub enum CreateDeploymentError {
     #[error("Unknown error")]
     UnknownError,
+    #[error("Deployment {0:?} was already recorded")]
+    Duplicate(DeploymentId),
+    #[error("Event {0} was already recorded for a different report")]
+    EventIdTaken(uuid::Uuid),
 }
 
 #[derive(Debug, Error)]
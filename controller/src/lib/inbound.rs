@@ -1,4 +1,5 @@
 pub mod audit_log;
+pub mod decompression;
 pub mod notifier_validation;
 pub mod rate_limit;
 pub mod server;
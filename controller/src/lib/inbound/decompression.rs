@@ -0,0 +1,92 @@
+//! Request decompression for the agent router.
+//!
+//! Agents gzip their larger reports and say so with `Content-Encoding:
+//! gzip`. The compressed body is still held to the router's body limit; the
+//! decompressed one gets its own, larger cap so a small body can't expand
+//! into an unbounded one.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{DefaultBodyLimit, Request},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use flate2::read::GzDecoder;
+use std::io::Read;
+
+use crate::inbound::server::AGENT_BODY_LIMIT;
+
+/// Cap on a request body after decompression. State reports compress well,
+/// so this is several times the compressed limit.
+pub const DECOMPRESSED_BODY_LIMIT: usize = 8 * 1024 * 1024;
+
+pub async fn decompression_middleware(request: Request, next: Next) -> Response {
+    let Some(encoding) = request.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(request).await;
+    };
+    if !encoding.as_bytes().eq_ignore_ascii_case(b"gzip") {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "only gzip content encoding is supported",
+        )
+            .into_response();
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let Ok(compressed) = to_bytes(body, AGENT_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let body = match gunzip(&compressed, DECOMPRESSED_BODY_LIMIT) {
+        Ok(Some(body)) => body,
+        Ok(None) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid gzip body").into_response(),
+    };
+
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut request = Request::from_parts(parts, Body::from(body));
+    DefaultBodyLimit::max(DECOMPRESSED_BODY_LIMIT).apply(&mut request);
+    next.run(request).await
+}
+
+/// Decompress `data`, or `None` when it expands to more than `limit` bytes.
+fn gunzip(data: &[u8], limit: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)?;
+    Ok((out.len() <= limit).then_some(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gunzip_roundtrips() {
+        let body = gunzip(&gzip(b"{\"a\":1}"), 1024).unwrap();
+        assert_eq!(body.as_deref(), Some(&b"{\"a\":1}"[..]));
+    }
+
+    #[test]
+    fn gunzip_stops_at_the_limit() {
+        let bomb = gzip(&vec![b'0'; 64 * 1024]);
+        assert!(bomb.len() < 1024);
+        assert_eq!(gunzip(&bomb, 1024).unwrap(), None);
+        assert!(gunzip(&bomb, 64 * 1024).unwrap().is_some());
+    }
+
+    #[test]
+    fn gunzip_rejects_garbage() {
+        assert!(gunzip(b"not gzip", 1024).is_err());
+    }
+}
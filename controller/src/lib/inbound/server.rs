@@ -1,5 +1,6 @@
 use axum::extract::DefaultBodyLimit;
 use axum::response::IntoResponse;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::{
     Extension, Router,
     extract::{Path, Request, State},
@@ -9,16 +10,22 @@ use axum::{
     routing::{get, post},
 };
 use chrono::{DateTime, Utc};
-use log::{debug, error};
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use std::sync::Arc;
 
 use crate::domain::billing::models::{Plan, PlanStatus, Usage};
 use crate::domain::billing::ports::BillingService;
-use crate::domain::container_state::models::state::{AddContainerStateRequest, ContainerStateData};
+use crate::domain::commands::models::{Command, CommandStatus, event_target};
+use crate::domain::commands::ports::CommandService;
+use crate::domain::container_state::models::state::{
+    AddContainerStateRequest, ContainerStateData, LATEST_AGENT_VERSION, StateDeltaError,
+    is_outdated,
+};
 use crate::domain::container_state::port::ContainerStateService;
 use crate::domain::deployments::models::deployment::{
-    CreateDeploymentRequest, Deployment, GetDeploymentError,
+    CreateDeploymentError, CreateDeploymentRequest, Deployment, GetDeploymentError,
 };
 use crate::domain::deployments::ports::DeploymentsService;
 use crate::domain::metrics::models::{AddMetricsRequest, RETENTION_DAYS};
@@ -28,8 +35,12 @@ use crate::domain::notifiers::ports::NotifierService;
 use crate::domain::tokens::models::ApiToken;
 use crate::domain::tokens::ports::TokenService;
 use crate::inbound::audit_log::audit_log_middleware;
+use crate::inbound::decompression::decompression_middleware;
 use crate::inbound::notifier_validation::validate_config as validate_notifier_config;
 use crate::inbound::rate_limit::{RateLimiter, rate_limit_middleware};
+use crate::outbound::agent_registry::AgentRegistry;
+use crate::outbound::cleanup_memory::CleanupMemory;
+use crate::outbound::log_streams_memory::LogStreams;
 use crate::outbound::logs_memory::LogsMemory;
 use crate::outbound::notification_dispatch::{
     EmailDispatchConfig, dispatch_one_async, dispatch_to_all,
@@ -41,14 +52,18 @@ use crate::sse::{ControllerEvent, UserScopedEvent, sse_handler};
 /// payloads with many containers and 16 KB log tails per container add up,
 /// so we leave generous headroom; this exists to shed abuse, not to enforce
 /// product limits.
-const AGENT_BODY_LIMIT: usize = 1024 * 1024;
+pub(crate) const AGENT_BODY_LIMIT: usize = 1024 * 1024;
 use chatterbox::message::Message;
-use hoister_shared::wire::{PostContainerLogsRequest, PostContainerMetricsRequest};
+use hoister_shared::wire::{
+    PostAgentInfo, PostCleanupReport, PostCommandStatus, PostContainerLogsRequest,
+    PostContainerMetricsRequest, PostContainerStateDelta, PostLogStreamChunk, PostPendingUpdate,
+};
 use hoister_shared::{
-    CreateDeployment, DeploymentStatus, HostName, ProjectName, ServiceName,
+    AgentId, CreateDeployment, DeploymentStatus, HostName, ProjectName, ServiceName,
     deployment_email_subject,
 };
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use ts_rs::TS;
 
 /// user ID extracted from a verified request, injected as a request extension.
@@ -73,6 +88,7 @@ pub struct AppState<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 > {
     pub deployments_service: Arc<DS>,
     pub container_state_service: Arc<CS>,
@@ -80,6 +96,7 @@ pub struct AppState<
     pub notifier_service: Arc<NS>,
     pub billing_service: Arc<BS>,
     pub metrics_service: Arc<MS>,
+    pub command_service: Arc<CmS>,
     #[cfg(feature = "self-hosted")]
     pub api_secret: Option<String>,
     pub event_tx: broadcast::Sender<UserScopedEvent>,
@@ -87,6 +104,12 @@ pub struct AppState<
     /// Ephemeral, per-user store of on-demand container logs. In memory only —
     /// logs are never persisted (they can carry secrets). See `LogsMemory`.
     pub logs: LogsMemory,
+    /// Live log streams being followed by an agent, see `LogStreams`.
+    pub log_streams: LogStreams,
+    /// Running totals of what agents' janitors removed. In memory only.
+    pub cleanup: CleanupMemory,
+    /// The agents connected over SSE and the engines each manages.
+    pub agents: AgentRegistry,
     /// Controller-wide email (Resend) delivery settings, or `None` when not
     /// configured. Email notifiers can't dispatch without this.
     pub email: Option<EmailDispatchConfig>,
@@ -129,8 +152,9 @@ async fn agent_auth_middleware<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
@@ -200,8 +224,9 @@ async fn internal_user_middleware<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(InternalSecret(expected)): Extension<InternalSecret>,
     mut request: Request,
     next: Next,
@@ -284,8 +309,9 @@ async fn get_me<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> Result<Json<ApiResponse<PlanStatus>>, StatusCode> {
     let plan = match state.billing_service.get_plan(&user_id).await {
@@ -340,8 +366,9 @@ async fn set_plan<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Json(req): Json<SetPlanRequest>,
 ) -> Result<StatusCode, StatusCode> {
@@ -364,8 +391,9 @@ async fn list_tokens<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> Result<Json<ApiResponse<Vec<ApiToken>>>, StatusCode> {
     match state.token_service.list_tokens(&user_id).await {
@@ -390,8 +418,9 @@ async fn create_token<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     body: Option<Json<CreateTokenRequest>>,
 ) -> Result<Json<ApiResponse<ApiToken>>, StatusCode> {
@@ -417,8 +446,9 @@ async fn delete_token<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path(token_id): Path<uuid::Uuid>,
 ) -> Result<StatusCode, StatusCode> {
@@ -440,8 +470,9 @@ async fn list_notifiers<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> Result<Json<ApiResponse<Vec<NotifierSummary>>>, StatusCode> {
     match state.notifier_service.list_notifiers(&user_id).await {
@@ -463,8 +494,9 @@ async fn create_notifier<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Json(config): Json<NotifierConfig>,
 ) -> Response {
@@ -525,8 +557,9 @@ async fn delete_notifier<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path(notifier_id): Path<uuid::Uuid>,
 ) -> Result<StatusCode, StatusCode> {
@@ -556,8 +589,9 @@ async fn set_notifier_enabled<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path(notifier_id): Path<uuid::Uuid>,
     Json(req): Json<SetEnabledRequest>,
@@ -593,8 +627,9 @@ async fn test_notifier<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path(notifier_id): Path<uuid::Uuid>,
 ) -> Response {
@@ -664,8 +699,9 @@ async fn get_deployments<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> Result<Json<ApiResponse<Vec<Deployment>>>, StatusCode> {
     match state
@@ -688,8 +724,9 @@ async fn get_deployments_by_service<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((project_name, service_name)): Path<(ProjectName, ServiceName)>,
 ) -> Result<Json<ApiResponse<Vec<Deployment>>>, StatusCode> {
@@ -715,8 +752,9 @@ async fn create_deployment<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Json(payload): Json<CreateDeployment>,
 ) -> Response {
@@ -771,28 +809,38 @@ async fn create_deployment<
     };
     let req = CreateDeploymentRequest::from_payload(payload, user_id.clone());
 
-    match state.deployments_service.create_deployment(&req).await {
-        Ok(id) => match state.deployments_service.get_deployment(id, &user_id).await {
-            Ok(deployment) => {
-                if let Some(message) = notify_payload {
-                    notify_user(
-                        state.notifier_service.clone(),
-                        state.billing_service.clone(),
-                        user_id.clone(),
-                        message,
-                        state.email.clone(),
-                    );
-                }
-                Json(ApiResponse::success(deployment)).into_response()
-            }
-            Err(e) => {
-                error!("Error retrieving created deployment: {e:?}");
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    let (id, created) = match state.deployments_service.create_deployment(&req).await {
+        Ok(id) => (id, true),
+        // The agent retried a report that already arrived; answer as before
+        // without notifying again.
+        Err(CreateDeploymentError::Duplicate(id)) => (id, false),
+        // Another report already used the event ID; resending it won't help.
+        Err(e @ CreateDeploymentError::EventIdTaken(_)) => {
+            log::warn!("Refused deployment report of {user_id}: {e}");
+            return (StatusCode::CONFLICT, e.to_string()).into_response();
+        }
+        // A database fault, not a bad report: the agent keeps it and retries.
+        Err(e @ CreateDeploymentError::UnknownError) => {
+            error!("Error creating deployment: {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match state.deployments_service.get_deployment(id, &user_id).await {
+        Ok(deployment) => {
+            if created && let Some(message) = notify_payload {
+                notify_user(
+                    state.notifier_service.clone(),
+                    state.billing_service.clone(),
+                    user_id.clone(),
+                    message,
+                    state.email.clone(),
+                );
             }
-        },
+            Json(ApiResponse::success(deployment)).into_response()
+        }
         Err(e) => {
-            error!("Error creating deployment: {e:?}");
-            StatusCode::BAD_REQUEST.into_response()
+            error!("Error retrieving created deployment: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
@@ -800,7 +848,10 @@ async fn create_deployment<
 fn should_notify_for(status: &DeploymentStatus) -> bool {
     matches!(
         status,
-        DeploymentStatus::Success | DeploymentStatus::Failed | DeploymentStatus::RollbackFinished,
+        DeploymentStatus::Success
+            | DeploymentStatus::Failed
+            | DeploymentStatus::RollbackFinished
+            | DeploymentStatus::RestartFailed,
     )
 }
 
@@ -855,8 +906,9 @@ async fn post_container_state<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name)): Path<(HostName, ProjectName)>,
     Json(payload): Json<PostContainerStateRequest>,
@@ -905,12 +957,48 @@ async fn post_container_state<
         hostname,
         project_name,
         services: payload.payload,
+        version: payload.version,
     };
     state.container_state_service.add_container_state(req).await;
 
     StatusCode::OK.into_response()
 }
 
+/// Agent endpoint: changes to a project's state as a merge patch against the
+/// version the controller stored last. 409 when the stored state is at
+/// another version; the agent then sends the state in full.
+async fn post_container_state_delta<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path((hostname, project_name)): Path<(HostName, ProjectName)>,
+    Json(delta): Json<PostContainerStateDelta>,
+) -> Response {
+    match state
+        .container_state_service
+        .apply_container_state_delta(&user_id, &hostname, &project_name, delta)
+        .await
+    {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(StateDeltaError::Diverged) => StatusCode::CONFLICT.into_response(),
+        Err(e) => {
+            debug!(
+                "Rejected state delta for {} / {}: {e}",
+                hostname.as_str(),
+                project_name.as_str()
+            );
+            StatusCode::CONFLICT.into_response()
+        }
+    }
+}
+
 async fn post_container_state_heartbeat<
     DS: DeploymentsService,
     CS: ContainerStateService,
@@ -918,8 +1006,9 @@ async fn post_container_state_heartbeat<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name)): Path<(HostName, ProjectName)>,
 ) -> Response {
@@ -930,6 +1019,107 @@ async fn post_container_state_heartbeat<
     StatusCode::OK.into_response()
 }
 
+/// Agent endpoint: the agent's handshake for one of its hosts, sent when it
+/// starts and whenever it connects for commands.
+async fn post_agent_info<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(hostname): Path<HostName>,
+    Json(info): Json<PostAgentInfo>,
+) -> Response {
+    state
+        .container_state_service
+        .set_agent_info(&user_id, &hostname, &info)
+        .await;
+    StatusCode::OK.into_response()
+}
+
+#[derive(TS, Serialize)]
+#[ts(export)]
+struct AgentInfoResponse {
+    hostname: HostName,
+    info: PostAgentInfo,
+    reported_at: DateTime<Utc>,
+    /// The agent is older than the release this controller was built with.
+    outdated: bool,
+    latest_version: String,
+}
+
+/// Internal endpoint: what the agent of each of the user's hosts last reported
+/// about itself, e.g. to warn about outdated agents and to hide actions an
+/// agent would ignore.
+async fn get_agent_infos<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+) -> Json<ApiResponse<Vec<AgentInfoResponse>>> {
+    let infos = state
+        .container_state_service
+        .get_agent_infos(&user_id)
+        .await
+        .into_iter()
+        .map(|host| AgentInfoResponse {
+            outdated: is_outdated(&host.info.version),
+            latest_version: LATEST_AGENT_VERSION.to_string(),
+            hostname: host.hostname,
+            info: host.info,
+            reported_at: host.reported_at,
+        })
+        .collect();
+    Json(ApiResponse::success(infos))
+}
+
+/// When the agent last reported state for a (host, project), including
+/// heartbeats. An agent self-update polls it to see the new agent report in.
+#[derive(Serialize)]
+struct LastSeenResponse {
+    last_updated: DateTime<Utc>,
+}
+
+async fn get_container_state_last_seen<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path((hostname, project_name)): Path<(HostName, ProjectName)>,
+) -> Response {
+    let states = state
+        .container_state_service
+        .get_container_states(&user_id)
+        .await;
+    match states
+        .get(&hostname)
+        .and_then(|projects| projects.get(&project_name))
+    {
+        Some(project) => Json(LastSeenResponse {
+            last_updated: project.last_updated,
+        })
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
 #[derive(TS, Serialize)]
 #[ts(export)]
 struct ContainerStateResponse {
@@ -942,6 +1132,8 @@ struct ContainerStateResponse {
     #[ts(type = "any")]
     container_inspections: serde_json::Value,
     last_logs: Option<String>,
+    /// Update-check schedule the agent reported for the service.
+    schedule: Option<String>,
     last_updated: DateTime<Utc>,
 }
 
@@ -967,6 +1159,7 @@ impl From<ContainerStateData> for ContainerStateResponses {
                         service_name: service_name.clone(),
                         container_inspections: inspect_to_sorted_value(&service_state.inspect),
                         last_logs: service_state.last_logs.clone(),
+                        schedule: service_state.schedule.clone(),
                         last_updated: host_project_state.last_updated,
                     };
                     responses.push(r);
@@ -990,8 +1183,9 @@ async fn get_container_state_by_service_name<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
 ) -> Result<Json<ApiResponse<ContainerStateResponse>>, StatusCode> {
@@ -1014,6 +1208,7 @@ async fn get_container_state_by_service_name<
         service_name,
         container_inspections: inspect_to_sorted_value(&service_state.inspect),
         last_logs: service_state.last_logs,
+        schedule: service_state.schedule,
         last_updated: host_project_state.last_updated,
     })))
 }
@@ -1031,8 +1226,9 @@ async fn delete_project<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name)): Path<(HostName, ProjectName)>,
 ) -> Result<StatusCode, StatusCode> {
@@ -1054,8 +1250,9 @@ async fn get_container_states<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> impl IntoResponse {
     debug!("Received request for container states (user: {user_id})");
@@ -1075,8 +1272,9 @@ async fn post_container_metrics<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name)): Path<(HostName, ProjectName)>,
     Json(payload): Json<PostContainerMetricsRequest>,
@@ -1158,8 +1356,9 @@ async fn get_service_metrics<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
 ) -> impl IntoResponse {
@@ -1198,8 +1397,9 @@ async fn get_latest_metrics<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> impl IntoResponse {
     debug!("Received request for latest metrics (user: {user_id})");
@@ -1225,15 +1425,6 @@ async fn get_latest_metrics<
     Json(LatestMetricsResponse(latest)).into_response()
 }
 
-#[derive(Deserialize)]
-struct PendingUpdateRequest {
-    hostname: HostName,
-    project_name: ProjectName,
-    service_name: ServiceName,
-    image_name: String,
-    new_digest: String,
-}
-
 async fn post_pending_update<
     DS: DeploymentsService,
     CS: ContainerStateService,
@@ -1241,21 +1432,26 @@ async fn post_pending_update<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
-    Json(payload): Json<PendingUpdateRequest>,
+    Json(payload): Json<PostPendingUpdate>,
 ) -> impl IntoResponse {
     let update = PendingUpdate {
         hostname: payload.hostname,
         project_name: payload.project_name,
         service_name: payload.service_name,
-        image_name: payload.image_name,
-        new_digest: payload.new_digest,
+        image_name: payload.image_name.0,
+        new_digest: payload.new_digest.0,
         detected_at: Utc::now(),
+        event_id: payload.event_id,
     };
     let message = pending_update_message(&update);
-    state.pending_updates.add(&user_id, update).await;
+    if !state.pending_updates.add(&user_id, update).await {
+        // A retry of a report that already arrived and was notified about.
+        return StatusCode::OK.into_response();
+    }
     notify_user(
         state.notifier_service.clone(),
         state.billing_service.clone(),
@@ -1292,8 +1488,9 @@ async fn get_pending_updates<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> impl IntoResponse {
     let updates = state.pending_updates.get_all(&user_id).await;
@@ -1307,18 +1504,23 @@ async fn apply_pending_update<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
-) -> impl IntoResponse {
+) -> Result<Json<ApiResponse<CommandResponse>>, StatusCode> {
+    let event = ControllerEvent::ApplyUpdate((
+        hostname.clone(),
+        project_name.clone(),
+        service_name.clone(),
+    ));
+    let command = send_command(&state, user_id.clone(), event).await?;
     state
         .pending_updates
         .remove(&user_id, &hostname, &project_name, &service_name)
         .await;
-    let event = ControllerEvent::ApplyUpdate((hostname, project_name, service_name));
-    let _ = state.event_tx.send((user_id, event));
-    StatusCode::OK.into_response()
+    Ok(Json(ApiResponse::success((&command).into())))
 }
 
 // ── On-demand container logs ──────────────────────────────────────────────────
@@ -1337,8 +1539,9 @@ async fn post_container_logs<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
     Json(payload): Json<PostContainerLogsRequest>,
@@ -1350,9 +1553,91 @@ async fn post_container_logs<
     StatusCode::OK.into_response()
 }
 
-/// Internal endpoint: ask the user's agents to ship the current log tail for one
-/// service. Fire-and-forget over SSE — the browser then polls
-/// `get_container_logs`. Returns 202 even when no agent is currently connected.
+/// Agent endpoint: the next chunk of a live log stream, passed on to its
+/// viewers. 410 once the stream is over, which tells the agent to stop.
+async fn post_log_stream_chunk<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(id): Path<uuid::Uuid>,
+    Json(chunk): Json<PostLogStreamChunk>,
+) -> Response {
+    if state
+        .log_streams
+        .publish(&user_id, id, chunk.logs, chunk.end)
+        .await
+    {
+        StatusCode::OK.into_response()
+    } else {
+        StatusCode::GONE.into_response()
+    }
+}
+
+/// What one janitor sweep of an agent removed; added to the running totals.
+async fn post_cleanup_report<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path((hostname, project_name)): Path<(HostName, ProjectName)>,
+    Json(payload): Json<PostCleanupReport>,
+) -> Response {
+    state
+        .cleanup
+        .record(&user_id, hostname, project_name, &payload)
+        .await;
+    StatusCode::OK.into_response()
+}
+
+/// Internal endpoint: cleanup totals of every host and project of the user.
+async fn get_cleanup_totals<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+) -> impl IntoResponse {
+    Json(state.cleanup.get_all(&user_id).await).into_response()
+}
+
+/// Internal endpoint: the user's connected agents and the engines they manage.
+async fn get_agents<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+) -> impl IntoResponse {
+    Json(state.agents.get_all(&user_id)).into_response()
+}
+
+/// Internal endpoint: ask the agent managing the host to ship the current log
+/// tail for one service. The browser then polls `get_container_logs`, or the
+/// returned command to learn that the agent declined. 409s when no agent of
+/// the host is connected.
 async fn request_container_logs<
     DS: DeploymentsService,
     CS: ContainerStateService,
@@ -1360,14 +1645,133 @@ async fn request_container_logs<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
-) -> StatusCode {
+) -> Result<(StatusCode, Json<ApiResponse<CommandResponse>>), StatusCode> {
     let event = ControllerEvent::RequestLogs((hostname, project_name, service_name));
-    let _ = state.event_tx.send((user_id, event));
-    StatusCode::ACCEPTED
+    let command = send_command(&state, user_id, event).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(ApiResponse::success((&command).into())),
+    ))
+}
+
+/// Internal endpoint: ask the agent managing the host to follow one service's
+/// logs. The stream's ID is the returned command's; the browser reads it from
+/// `get_log_stream` right away, since chunks sent before it subscribes are
+/// lost. 409s when no agent of the host is connected.
+async fn start_log_stream<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
+) -> Result<(StatusCode, Json<ApiResponse<CommandResponse>>), StatusCode> {
+    let event = ControllerEvent::StreamLogs((hostname, project_name, service_name));
+    let command = store_command(&state, user_id, event).await?;
+    // Open before the agent can send its first chunk.
+    state.log_streams.open(&command.user_id, command.id).await;
+    broadcast_command(&state, &command);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(ApiResponse::success((&command).into())),
+    ))
+}
+
+/// Ends a viewer's hold on a log stream when its connection goes away, which
+/// ends the stream if it was the last viewer.
+struct LogStreamViewer {
+    streams: LogStreams,
+    user_id: String,
+    id: uuid::Uuid,
+}
+
+impl Drop for LogStreamViewer {
+    fn drop(&mut self) {
+        let streams = self.streams.clone();
+        let user_id = std::mem::take(&mut self.user_id);
+        let id = self.id;
+        // Spawned, so it runs after the viewer's receiver is dropped too.
+        tokio::spawn(async move { streams.release(&user_id, id).await });
+    }
+}
+
+/// Internal endpoint: the chunks of a live log stream as server-sent events.
+/// Each `logs` event carries a JSON string of log lines; `end` follows the
+/// last one, and `lagged` says how many chunks a slow viewer missed. 404s
+/// for streams that are over or not the user's.
+async fn get_log_stream<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(id): Path<uuid::Uuid>,
+) -> Response {
+    let Some(mut rx) = state.log_streams.subscribe(&user_id, id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let viewer = LogStreamViewer {
+        streams: state.log_streams.clone(),
+        user_id,
+        id,
+    };
+    let stream = async_stream::stream! {
+        let _viewer = viewer;
+        loop {
+            let event = match rx.recv().await {
+                Ok(logs) => Event::default().event("logs").json_data(logs),
+                Err(RecvError::Lagged(missed)) => {
+                    Ok(Event::default().event("lagged").data(missed.to_string()))
+                }
+                Err(RecvError::Closed) => break,
+            };
+            match event {
+                Ok(event) => yield Ok::<_, Infallible>(event),
+                Err(e) => error!("Failed to encode log chunk: {e}"),
+            }
+        }
+        yield Ok(Event::default().event("end").data(""));
+    };
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Internal endpoint: cancel a live log stream. The agent stops at its next
+/// chunk, within seconds.
+async fn delete_log_stream<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(id): Path<uuid::Uuid>,
+) -> StatusCode {
+    if state.log_streams.close(&user_id, id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
 }
 
 #[derive(TS, Serialize)]
@@ -1386,8 +1790,9 @@ async fn get_container_logs<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
     Path((hostname, project_name, service_name)): Path<(HostName, ProjectName, ServiceName)>,
 ) -> Result<Json<ApiResponse<ContainerLogsResponse>>, StatusCode> {
@@ -1402,6 +1807,230 @@ async fn get_container_logs<
     })))
 }
 
+// ── Agent commands ────────────────────────────────────────────────────────────
+// Every event sent to an agent is stored as a command first, addressed to the
+// agent that manages the event's host and project, connected or not. That agent reports
+// back when it receives the command, starts on it and finishes it, so the
+// dashboard can follow e.g. an update to its outcome. Commands nobody reports
+// on time out (see `CommandService`).
+
+/// Store `event` as a command of the user and send it to the agent managing
+/// its host. Stored before it is sent, so even a quick acknowledgement finds
+/// it. If that agent is offline, the command waits for it to reconnect. 409s
+/// if no agent ever registered the host and project.
+async fn send_command<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    state: &AppState<DS, CS, TS, NS, BS, MS, CmS>,
+    user_id: String,
+    event: ControllerEvent,
+) -> Result<Command, StatusCode> {
+    let command = store_command(state, user_id, event).await?;
+    broadcast_command(state, &command);
+    Ok(command)
+}
+
+/// The storing half of `send_command`, for callers that prepare something
+/// for the command before the agent can see it.
+async fn store_command<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    state: &AppState<DS, CS, TS, NS, BS, MS, CmS>,
+    user_id: String,
+    event: ControllerEvent,
+) -> Result<Command, StatusCode> {
+    let (hostname, project_name) = event_target(&event);
+    let agent_id = match state.agents.target(&user_id, hostname, project_name) {
+        Some(agent_id) => Some(agent_id),
+        // The agent is offline: queue the command for it.
+        None => state
+            .command_service
+            .registered_agent(&user_id, hostname, project_name)
+            .await
+            .map_err(|e| {
+                error!("Error looking up the agent for {user_id}: {e:?}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+    };
+    let Some(agent_id) = agent_id else {
+        info!(
+            "No agent of {user_id} ever registered host {} in project {}",
+            hostname.as_str(),
+            project_name.as_str()
+        );
+        return Err(StatusCode::CONFLICT);
+    };
+    let command = Command::new(user_id.clone(), agent_id.clone(), event);
+    if let Err(e) = state.command_service.create_command(&command).await {
+        error!("Error storing command for {user_id}: {e:?}");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(command)
+}
+
+/// The sending half of `send_command`.
+fn broadcast_command<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    state: &AppState<DS, CS, TS, NS, BS, MS, CmS>,
+    command: &Command,
+) {
+    let _ = state.event_tx.send((
+        command.user_id.clone(),
+        command.agent_id.clone(),
+        command.for_agent(),
+    ));
+}
+
+#[derive(TS, Serialize)]
+#[ts(export)]
+struct CommandResponse {
+    id: uuid::Uuid,
+    /// `apply_update`, `request_logs`, `stream_logs` or `retry`.
+    kind: String,
+    /// The agent the command was sent to.
+    agent_id: AgentId,
+    hostname: HostName,
+    project_name: ProjectName,
+    service_name: Option<ServiceName>,
+    status: CommandStatus,
+    detail: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    /// When the command times out if no agent has picked it up.
+    expires_at: DateTime<Utc>,
+}
+
+impl From<&Command> for CommandResponse {
+    fn from(command: &Command) -> Self {
+        Self {
+            id: command.id,
+            kind: command.kind().to_string(),
+            agent_id: command.agent_id.clone(),
+            hostname: command.hostname().clone(),
+            project_name: command.project_name().clone(),
+            service_name: command.service_name().cloned(),
+            status: command.status,
+            detail: command.detail.clone(),
+            created_at: command.created_at,
+            updated_at: command.updated_at,
+            expires_at: command.expires_at,
+        }
+    }
+}
+
+/// Agent endpoint: progress on a command. 404s for unknown commands and for
+/// ones the agent already reported finished.
+async fn post_command_status<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(command_id): Path<uuid::Uuid>,
+    Json(payload): Json<PostCommandStatus>,
+) -> StatusCode {
+    // Only the controller sends commands and gives up on them.
+    if matches!(
+        payload.status,
+        CommandStatus::Sent | CommandStatus::TimedOut
+    ) {
+        return StatusCode::BAD_REQUEST;
+    }
+    match state
+        .command_service
+        .set_command_status(
+            &user_id,
+            command_id,
+            payload.status,
+            payload.detail.as_deref(),
+        )
+        .await
+    {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!("Error recording status of command {command_id} for {user_id}: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Internal endpoint: the user's most recent commands, newest first.
+async fn get_commands<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+) -> Result<Json<ApiResponse<Vec<CommandResponse>>>, StatusCode> {
+    match state.command_service.list_commands(&user_id).await {
+        Ok(commands) => Ok(Json(ApiResponse::success(
+            commands.iter().map(Into::into).collect(),
+        ))),
+        Err(e) => {
+            error!("Error listing commands for {user_id}: {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Internal endpoint: one command, e.g. polled after applying an update.
+async fn get_command<
+    DS: DeploymentsService,
+    CS: ContainerStateService,
+    TS: TokenService,
+    NS: NotifierService,
+    BS: BillingService,
+    MS: MetricsService,
+    CmS: CommandService,
+>(
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Path(command_id): Path<uuid::Uuid>,
+) -> Result<Json<ApiResponse<CommandResponse>>, StatusCode> {
+    match state
+        .command_service
+        .get_command(&user_id, command_id)
+        .await
+    {
+        Ok(Some(command)) => Ok(Json(ApiResponse::success((&command).into()))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Error getting command {command_id} for {user_id}: {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 /// Internal endpoint: delete a user and all their data via CASCADE.
 /// Called by the BFF after it has verified a Clerk `user.deleted` webhook.
 /// The BFF sets `X-User-Id` to the deleted user's Clerk ID before calling
@@ -1413,8 +2042,9 @@ async fn delete_user<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(user_id)): Extension<UserId>,
 ) -> StatusCode {
     if state.billing_service.delete_user(&user_id).await {
@@ -1433,52 +2063,80 @@ pub async fn create_agent_router<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    state: AppState<DS, CS, TS, NS, BS, MS>,
+    state: AppState<DS, CS, TS, NS, BS, MS, CmS>,
 ) -> Router {
     let rate_limiter = RateLimiter::new();
     Router::new()
         .route("/health", get(health))
-        .route("/sse", get(sse_handler))
+        .route("/sse", post(sse_handler))
         .route(
             "/deployments",
-            post(create_deployment::<DS, CS, TS, NS, BS, MS>),
+            post(create_deployment::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/state/{hostname}/{project_name}",
-            post(post_container_state::<DS, CS, TS, NS, BS, MS>),
+            post(post_container_state::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/state/{hostname}/{project_name}/heartbeat",
-            post(post_container_state_heartbeat::<DS, CS, TS, NS, BS, MS>),
+            post(post_container_state_heartbeat::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/container/state/{hostname}/{project_name}/delta",
+            post(post_container_state_delta::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/agent-info/{hostname}",
+            post(post_agent_info::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/container/state/{hostname}/{project_name}/last-seen",
+            get(get_container_state_last_seen::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/metrics/{hostname}/{project_name}",
-            post(post_container_metrics::<DS, CS, TS, NS, BS, MS>),
+            post(post_container_metrics::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/logs/{hostname}/{project_name}/{service_name}",
-            post(post_container_logs::<DS, CS, TS, NS, BS, MS>),
+            post(post_container_logs::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/log-streams/{id}",
+            post(post_log_stream_chunk::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/cleanup/{hostname}/{project_name}",
+            post(post_cleanup_report::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/commands/{id}",
+            post(post_command_status::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/pending-updates",
-            post(post_pending_update::<DS, CS, TS, NS, BS, MS>),
+            post(post_pending_update::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/pending-updates",
-            get(get_pending_updates::<DS, CS, TS, NS, BS, MS>),
+            get(get_pending_updates::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/pending-updates/{hostname}/{project_name}/{service_name}/apply",
-            post(apply_pending_update::<DS, CS, TS, NS, BS, MS>),
+            post(apply_pending_update::<DS, CS, TS, NS, BS, MS, CmS>),
         )
+        // Innermost, so only authenticated, rate-limited requests are
+        // decompressed, and it can raise the body limit set further out.
+        .layer(middleware::from_fn(decompression_middleware))
         // Rate limit runs AFTER auth so it can key on the resolved user_id.
         // Auth runs first because `.layer` applies in reverse order.
         .layer(middleware::from_fn(rate_limit_middleware))
         .layer(Extension(rate_limiter))
         .layer(middleware::from_fn_with_state(
             state.clone(),
-            agent_auth_middleware::<DS, CS, TS, NS, BS, MS>,
+            agent_auth_middleware::<DS, CS, TS, NS, BS, MS, CmS>,
         ))
         .layer(DefaultBodyLimit::max(AGENT_BODY_LIMIT))
         // Audit log is outermost so it sees the final response status,
@@ -1496,8 +2154,9 @@ pub async fn create_internal_router<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    state: AppState<DS, CS, TS, NS, BS, MS>,
+    state: AppState<DS, CS, TS, NS, BS, MS, CmS>,
     internal_secret: InternalSecret,
 ) -> Router {
     if internal_secret.0.as_deref().unwrap_or_default().is_empty() {
@@ -1510,86 +2169,123 @@ pub async fn create_internal_router<
     }
     Router::new()
         .route("/health", get(health))
-        .route("/me", get(get_me::<DS, CS, TS, NS, BS, MS>))
-        .route("/tokens", get(list_tokens::<DS, CS, TS, NS, BS, MS>))
-        .route("/tokens", post(create_token::<DS, CS, TS, NS, BS, MS>))
+        .route("/me", get(get_me::<DS, CS, TS, NS, BS, MS, CmS>))
+        .route("/tokens", get(list_tokens::<DS, CS, TS, NS, BS, MS, CmS>))
+        .route("/tokens", post(create_token::<DS, CS, TS, NS, BS, MS, CmS>))
         .route(
             "/tokens/{id}",
-            axum::routing::delete(delete_token::<DS, CS, TS, NS, BS, MS>),
+            axum::routing::delete(delete_token::<DS, CS, TS, NS, BS, MS, CmS>),
         )
-        .route("/notifiers", get(list_notifiers::<DS, CS, TS, NS, BS, MS>))
         .route(
             "/notifiers",
-            post(create_notifier::<DS, CS, TS, NS, BS, MS>),
+            get(list_notifiers::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/notifiers",
+            post(create_notifier::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/notifiers/{id}",
-            axum::routing::delete(delete_notifier::<DS, CS, TS, NS, BS, MS>),
+            axum::routing::delete(delete_notifier::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/notifiers/{id}/enabled",
-            axum::routing::patch(set_notifier_enabled::<DS, CS, TS, NS, BS, MS>),
+            axum::routing::patch(set_notifier_enabled::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/notifiers/{id}/test",
-            post(test_notifier::<DS, CS, TS, NS, BS, MS>),
+            post(test_notifier::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/deployments",
-            get(get_deployments::<DS, CS, TS, NS, BS, MS>),
+            get(get_deployments::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/billing/plan",
+            post(set_plan::<DS, CS, TS, NS, BS, MS, CmS>),
         )
-        .route("/billing/plan", post(set_plan::<DS, CS, TS, NS, BS, MS>))
         .route(
             "/deployments/{project_name}/{service_name}",
-            get(get_deployments_by_service::<DS, CS, TS, NS, BS, MS>),
+            get(get_deployments_by_service::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/state",
-            get(get_container_states::<DS, CS, TS, NS, BS, MS>),
+            get(get_container_states::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/state/{hostname}/{project_name}/{service_name}",
-            get(get_container_state_by_service_name::<DS, CS, TS, NS, BS, MS>),
+            get(get_container_state_by_service_name::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/state/{hostname}/{project_name}",
-            axum::routing::delete(delete_project::<DS, CS, TS, NS, BS, MS>),
+            axum::routing::delete(delete_project::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/metrics",
-            get(get_latest_metrics::<DS, CS, TS, NS, BS, MS>),
+            get(get_latest_metrics::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/metrics/{hostname}/{project_name}/{service_name}",
-            get(get_service_metrics::<DS, CS, TS, NS, BS, MS>),
+            get(get_service_metrics::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         // On-demand logs: the browser POSTs `.../request` to trigger an SSE
         // log request to the agent, then polls the GET to read the answer.
         .route(
             "/container/logs/{hostname}/{project_name}/{service_name}/request",
-            post(request_container_logs::<DS, CS, TS, NS, BS, MS>),
+            post(request_container_logs::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/container/logs/{hostname}/{project_name}/{service_name}",
-            get(get_container_logs::<DS, CS, TS, NS, BS, MS>),
+            get(get_container_logs::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        // Live logs: the browser POSTs `.../stream` to have the agent follow
+        // the logs, then reads the stream's events until it ends or is deleted.
+        .route(
+            "/container/logs/{hostname}/{project_name}/{service_name}/stream",
+            post(start_log_stream::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/log-streams/{id}",
+            get(get_log_stream::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/log-streams/{id}",
+            axum::routing::delete(delete_log_stream::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/cleanup",
+            get(get_cleanup_totals::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route("/agents", get(get_agents::<DS, CS, TS, NS, BS, MS, CmS>))
+        .route(
+            "/agent-info",
+            get(get_agent_infos::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         // Pending-update read/apply mirrored from the agent router so the
         // BFF can drive them. Writes (POST /pending-updates) stay agent-only.
         .route(
             "/pending-updates",
-            get(get_pending_updates::<DS, CS, TS, NS, BS, MS>),
+            get(get_pending_updates::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/pending-updates/{hostname}/{project_name}/{service_name}/apply",
-            post(apply_pending_update::<DS, CS, TS, NS, BS, MS>),
+            post(apply_pending_update::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/commands",
+            get(get_commands::<DS, CS, TS, NS, BS, MS, CmS>),
+        )
+        .route(
+            "/commands/{id}",
+            get(get_command::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .route(
             "/users",
-            axum::routing::delete(delete_user::<DS, CS, TS, NS, BS, MS>),
+            axum::routing::delete(delete_user::<DS, CS, TS, NS, BS, MS, CmS>),
         )
         .layer(middleware::from_fn_with_state(
             state.clone(),
-            internal_user_middleware::<DS, CS, TS, NS, BS, MS>,
+            internal_user_middleware::<DS, CS, TS, NS, BS, MS, CmS>,
         ))
         .layer(Extension(internal_secret))
         .layer(middleware::from_fn(audit_log_middleware))
@@ -1,3 +1,6 @@
+pub mod agent_registry;
+pub mod cleanup_memory;
+pub mod log_streams_memory;
 pub mod logs_memory;
 pub mod notification_dispatch;
 pub mod pending_updates_memory;
@@ -8,8 +11,11 @@ pub mod state_memory;
 
 use crate::domain::billing::models::{Plan, PlanError};
 use crate::domain::billing::ports::PlanRepository;
+use crate::domain::commands::models::{Command, CommandError, CommandStatus};
+use crate::domain::commands::ports::CommandRepository;
 use crate::domain::container_state::models::state::{
-    AddContainerStateRequest, ContainerStateData, HostProjectState,
+    AddContainerStateRequest, AgentInfo, ContainerStateData, HostAgentInfo, HostProjectState,
+    VersionedProjectState,
 };
 use crate::domain::container_state::port::ContainerStateRepository;
 use crate::domain::deployments::models::deployment::{
@@ -23,7 +29,8 @@ use crate::domain::notifiers::models::{Notifier, NotifierConfig, NotifierError};
 use crate::domain::notifiers::ports::NotifierRepository;
 use crate::domain::tokens::models::{ApiToken, TokenError};
 use crate::domain::tokens::ports::TokenRepository;
-use hoister_shared::{HostName, ProjectName, ServiceName};
+use hoister_shared::wire::AgentEngine;
+use hoister_shared::{AgentId, HostName, ProjectName, ServiceName};
 use log::info;
 use postgresql::Postgresql;
 use sqlite::Sqlite;
@@ -343,6 +350,34 @@ impl ContainerStateRepository for Database {
         }
     }
 
+    async fn get_versioned_state(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+    ) -> Option<VersionedProjectState> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as ContainerStateRepository>::get_versioned_state(
+                    db,
+                    user_id,
+                    hostname,
+                    project_name,
+                )
+                .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as ContainerStateRepository>::get_versioned_state(
+                    db,
+                    user_id,
+                    hostname,
+                    project_name,
+                )
+                .await
+            }
+        }
+    }
+
     async fn delete_project(
         &self,
         user_id: &str,
@@ -398,6 +433,32 @@ impl ContainerStateRepository for Database {
             }
         }
     }
+
+    async fn set_agent_info(&self, user_id: &str, hostname: &HostName, info: &AgentInfo) {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as ContainerStateRepository>::set_agent_info(db, user_id, hostname, info)
+                    .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as ContainerStateRepository>::set_agent_info(
+                    db, user_id, hostname, info,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn get_agent_infos(&self, user_id: &str) -> Vec<HostAgentInfo> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as ContainerStateRepository>::get_agent_infos(db, user_id).await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as ContainerStateRepository>::get_agent_infos(db, user_id).await
+            }
+        }
+    }
 }
 
 impl MetricsRepository for Database {
@@ -453,3 +514,144 @@ impl MetricsRepository for Database {
         }
     }
 }
+
+impl CommandRepository for Database {
+    async fn create_command(&self, command: &Command) -> Result<(), CommandError> {
+        match self {
+            Self::Sqlite(db) => <Sqlite as CommandRepository>::create_command(db, command).await,
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::create_command(db, command).await
+            }
+        }
+    }
+
+    async fn set_command_status(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+        status: CommandStatus,
+        detail: Option<&str>,
+    ) -> Result<bool, CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::set_command_status(db, user_id, id, status, detail)
+                    .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::set_command_status(
+                    db, user_id, id, status, detail,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn get_command(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+    ) -> Result<Option<Command>, CommandError> {
+        match self {
+            Self::Sqlite(db) => <Sqlite as CommandRepository>::get_command(db, user_id, id).await,
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::get_command(db, user_id, id).await
+            }
+        }
+    }
+
+    async fn list_commands(&self, user_id: &str, limit: i64) -> Result<Vec<Command>, CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::list_commands(db, user_id, limit).await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::list_commands(db, user_id, limit).await
+            }
+        }
+    }
+
+    async fn pending_commands(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        after: Option<uuid::Uuid>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<Command>, CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::pending_commands(db, user_id, agent_id, after, now)
+                    .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::pending_commands(
+                    db, user_id, agent_id, after, now,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn expire_commands(
+        &self,
+        user_id: &str,
+        now: chrono::DateTime<chrono::Utc>,
+        unfinished_before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::expire_commands(db, user_id, now, unfinished_before)
+                    .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::expire_commands(
+                    db,
+                    user_id,
+                    now,
+                    unfinished_before,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn register_agent_engines(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        engines: &[AgentEngine],
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::register_agent_engines(
+                    db, user_id, agent_id, engines, now,
+                )
+                .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::register_agent_engines(
+                    db, user_id, agent_id, engines, now,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn registered_agent(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> Result<Option<AgentId>, CommandError> {
+        match self {
+            Self::Sqlite(db) => {
+                <Sqlite as CommandRepository>::registered_agent(db, user_id, hostname, project)
+                    .await
+            }
+            Self::Postgresql(db) => {
+                <Postgresql as CommandRepository>::registered_agent(db, user_id, hostname, project)
+                    .await
+            }
+        }
+    }
+}
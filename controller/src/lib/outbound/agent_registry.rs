@@ -0,0 +1,249 @@
+use chrono::{DateTime, Utc};
+use hoister_shared::wire::{AgentEngine, AgentRegistration};
+use hoister_shared::{AgentId, HostName, ProjectName};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+type AgentsStore = HashMap<AgentId, ConnectedAgent>;
+
+/// An agent with an open SSE connection, as it registered.
+#[derive(Clone, Serialize)]
+pub struct ConnectedAgent {
+    pub agent_id: AgentId,
+    pub version: String,
+    pub engines: Vec<AgentEngine>,
+    pub connected_at: DateTime<Utc>,
+    /// Tells a reconnect of the same agent apart from its previous connection.
+    #[serde(skip)]
+    connection: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error(
+        "agent {} already manages host {} in project {}",
+        .agent_id.as_str(), .hostname.as_str(), .project_name.as_str()
+    )]
+    Claimed {
+        agent_id: AgentId,
+        hostname: HostName,
+        project_name: ProjectName,
+    },
+}
+
+/// The connected agents, partitioned by user_id like the other in-memory
+/// stores. Commands go to the agent that registered their host and project.
+///
+/// A connection leaves the registry when its `Connection` is dropped, which
+/// happens outside async code, hence the std lock. It is never held across an
+/// await.
+#[derive(Clone, Default)]
+pub struct AgentRegistry {
+    agents: Arc<RwLock<HashMap<String, AgentsStore>>>,
+    next_connection: Arc<AtomicU64>,
+}
+
+impl AgentRegistry {
+    /// Add a connecting agent. A reconnect replaces the agent's previous
+    /// connection. Fails if another agent already manages one of its engines,
+    /// since a command for that engine would then have two takers.
+    pub fn register(
+        &self,
+        user_id: &str,
+        registration: AgentRegistration,
+    ) -> Result<Connection, RegistryError> {
+        let mut guard = self.agents.write().expect("agent registry lock poisoned");
+        let store = guard.entry(user_id.to_string()).or_default();
+        let claimed = store
+            .values()
+            .filter(|agent| agent.agent_id != registration.agent_id)
+            .find_map(|agent| {
+                registration
+                    .engines
+                    .iter()
+                    .find(|engine| agent.engines.contains(engine))
+                    .map(|engine| (agent, engine))
+            });
+        if let Some((agent, engine)) = claimed {
+            return Err(RegistryError::Claimed {
+                agent_id: agent.agent_id.clone(),
+                hostname: engine.hostname.clone(),
+                project_name: engine.project_name.clone(),
+            });
+        }
+
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        store.insert(
+            registration.agent_id.clone(),
+            ConnectedAgent {
+                agent_id: registration.agent_id.clone(),
+                version: registration.version,
+                engines: registration.engines,
+                connected_at: Utc::now(),
+                connection,
+            },
+        );
+        Ok(Connection {
+            registry: self.clone(),
+            user_id: user_id.to_string(),
+            agent_id: registration.agent_id,
+            id: connection,
+        })
+    }
+
+    /// The connected agent that manages `hostname` in `project`, if any.
+    pub fn target(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> Option<AgentId> {
+        let guard = self.agents.read().expect("agent registry lock poisoned");
+        guard.get(user_id)?.values().find_map(|agent| {
+            agent
+                .engines
+                .iter()
+                .any(|engine| &engine.hostname == hostname && &engine.project_name == project)
+                .then(|| agent.agent_id.clone())
+        })
+    }
+
+    /// The user's connected agents, ordered by ID.
+    pub fn get_all(&self, user_id: &str) -> Vec<ConnectedAgent> {
+        let guard = self.agents.read().expect("agent registry lock poisoned");
+        let mut agents: Vec<_> = guard
+            .get(user_id)
+            .map(|store| store.values().cloned().collect())
+            .unwrap_or_default();
+        agents.sort_by(|a, b| a.agent_id.as_str().cmp(b.agent_id.as_str()));
+        agents
+    }
+
+    fn is_current(&self, user_id: &str, agent_id: &AgentId, connection: u64) -> bool {
+        let guard = self.agents.read().expect("agent registry lock poisoned");
+        guard
+            .get(user_id)
+            .and_then(|store| store.get(agent_id))
+            .is_some_and(|agent| agent.connection == connection)
+    }
+
+    fn unregister(&self, user_id: &str, agent_id: &AgentId, connection: u64) {
+        let mut guard = self.agents.write().expect("agent registry lock poisoned");
+        if let Some(store) = guard.get_mut(user_id) {
+            if store
+                .get(agent_id)
+                .is_some_and(|agent| agent.connection == connection)
+            {
+                store.remove(agent_id);
+            }
+            if store.is_empty() {
+                guard.remove(user_id);
+            }
+        }
+    }
+}
+
+/// One agent's registered connection. Dropping it, e.g. when the agent
+/// disconnects, removes the agent unless it has reconnected since.
+pub struct Connection {
+    registry: AgentRegistry,
+    user_id: String,
+    agent_id: AgentId,
+    id: u64,
+}
+
+impl Connection {
+    pub fn agent_id(&self) -> &AgentId {
+        &self.agent_id
+    }
+
+    /// False once the agent has reconnected; this connection should end.
+    pub fn is_current(&self) -> bool {
+        self.registry
+            .is_current(&self.user_id, &self.agent_id, self.id)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.registry
+            .unregister(&self.user_id, &self.agent_id, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(agent_id: &str, hostname: &str) -> AgentRegistration {
+        AgentRegistration {
+            agent_id: AgentId::new(agent_id),
+            version: "1.1.0".to_string(),
+            engines: vec![AgentEngine {
+                hostname: HostName::new(hostname),
+                project_name: ProjectName::new("proj"),
+            }],
+        }
+    }
+
+    #[test]
+    fn commands_find_the_agent_of_their_engine() {
+        let registry = AgentRegistry::default();
+        let _a = registry
+            .register("alice", registration("a", "host-1"))
+            .unwrap();
+        let _b = registry
+            .register("alice", registration("b", "host-2"))
+            .unwrap();
+        let project = ProjectName::new("proj");
+
+        let target = |host| registry.target("alice", &HostName::new(host), &project);
+        assert_eq!(target("host-1"), Some(AgentId::new("a")));
+        assert_eq!(target("host-2"), Some(AgentId::new("b")));
+        assert_eq!(target("host-3"), None);
+        assert_eq!(
+            registry.target("bob", &HostName::new("host-1"), &project),
+            None
+        );
+    }
+
+    #[test]
+    fn a_second_agent_cannot_claim_a_managed_engine() {
+        let registry = AgentRegistry::default();
+        let _a = registry
+            .register("alice", registration("a", "host-1"))
+            .unwrap();
+        assert!(matches!(
+            registry.register("alice", registration("b", "host-1")),
+            Err(RegistryError::Claimed { agent_id, .. }) if agent_id == AgentId::new("a")
+        ));
+        // Another user's agents are separate.
+        assert!(
+            registry
+                .register("bob", registration("b", "host-1"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn a_reconnect_replaces_the_previous_connection() {
+        let registry = AgentRegistry::default();
+        let old = registry
+            .register("alice", registration("a", "host-1"))
+            .unwrap();
+        let new = registry
+            .register("alice", registration("a", "host-1"))
+            .unwrap();
+        assert!(!old.is_current());
+        assert!(new.is_current());
+
+        // The old connection closing doesn't unregister the new one.
+        drop(old);
+        assert_eq!(registry.get_all("alice").len(), 1);
+        drop(new);
+        assert!(registry.get_all("alice").is_empty());
+    }
+}
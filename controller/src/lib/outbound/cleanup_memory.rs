@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use hoister_shared::wire::PostCleanupReport;
+use hoister_shared::{HostName, ProjectName};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+type CleanupStore = HashMap<(HostName, ProjectName), CleanupTotals>;
+
+/// What an agent's janitor has removed since the controller started.
+#[derive(Clone, Serialize)]
+pub struct CleanupTotals {
+    pub hostname: HostName,
+    pub project_name: ProjectName,
+    pub volumes: u64,
+    pub containers: u64,
+    pub images: u64,
+    pub reclaimed_bytes: u64,
+    pub last_sweep: DateTime<Utc>,
+}
+
+/// In-memory cleanup totals, partitioned by user_id like the pending-update
+/// store. Running totals are informational, so losing them on a restart is
+/// acceptable.
+#[derive(Clone, Default)]
+pub struct CleanupMemory {
+    totals: Arc<RwLock<HashMap<String, CleanupStore>>>,
+}
+
+impl CleanupMemory {
+    /// Add one janitor sweep to the totals of (`hostname`, `project`).
+    pub async fn record(
+        &self,
+        user_id: &str,
+        hostname: HostName,
+        project: ProjectName,
+        report: &PostCleanupReport,
+    ) {
+        let mut guard = self.totals.write().await;
+        let totals = guard
+            .entry(user_id.to_string())
+            .or_default()
+            .entry((hostname.clone(), project.clone()))
+            .or_insert_with(|| CleanupTotals {
+                hostname,
+                project_name: project,
+                volumes: 0,
+                containers: 0,
+                images: 0,
+                reclaimed_bytes: 0,
+                last_sweep: Utc::now(),
+            });
+        totals.volumes += u64::from(report.volumes);
+        totals.containers += u64::from(report.containers);
+        totals.images += u64::from(report.images);
+        totals.reclaimed_bytes += report.reclaimed_bytes;
+        totals.last_sweep = Utc::now();
+    }
+
+    pub async fn get_all(&self, user_id: &str) -> Vec<CleanupTotals> {
+        self.totals
+            .read()
+            .await
+            .get(user_id)
+            .map(|store| store.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sweeps_add_up_per_user() {
+        let mem = CleanupMemory::default();
+        let report = PostCleanupReport {
+            volumes: 2,
+            containers: 1,
+            images: 0,
+            reclaimed_bytes: 1024,
+        };
+        for _ in 0..2 {
+            mem.record(
+                "alice",
+                HostName::new("host"),
+                ProjectName::new("proj"),
+                &report,
+            )
+            .await;
+        }
+        let totals = mem.get_all("alice").await;
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].volumes, 4);
+        assert_eq!(totals[0].containers, 2);
+        assert_eq!(totals[0].reclaimed_bytes, 2048);
+        assert!(mem.get_all("bob").await.is_empty());
+    }
+}
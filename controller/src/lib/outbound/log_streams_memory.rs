@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use hoister_shared::wire::LOG_STREAM_LIMIT_SECS;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{RwLock, broadcast};
+use uuid::Uuid;
+
+/// How long past `LOG_STREAM_LIMIT_SECS` a stream still accepts chunks. Covers
+/// the wait for the agent to pick up the command and its final chunk.
+const GRACE_SECS: i64 = 150;
+
+/// Chunks a slow viewer may fall behind by before it misses some.
+const CHANNEL_CAPACITY: usize = 256;
+
+struct LogStream {
+    user_id: String,
+    sender: broadcast::Sender<String>,
+    expires_at: DateTime<Utc>,
+}
+
+/// Live log streams in progress, keyed by the ID of their `StreamLogs`
+/// command. The agent's chunks are passed on to whoever is watching and not
+/// kept: like `LogsMemory`, logs never reach the database.
+///
+/// A stream ends when the agent sends its last chunk, when it is cancelled or
+/// its last viewer leaves, and when it outlives the time limit. The agent
+/// learns about the latter ones from its next chunk being refused.
+#[derive(Clone, Default)]
+pub struct LogStreams {
+    streams: Arc<RwLock<HashMap<Uuid, LogStream>>>,
+}
+
+impl LogStreams {
+    /// Start accepting chunks for stream `id`.
+    pub async fn open(&self, user_id: &str, id: Uuid) {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let expires_at =
+            Utc::now() + chrono::Duration::seconds(LOG_STREAM_LIMIT_SECS as i64 + GRACE_SECS);
+        let mut streams = self.streams.write().await;
+        prune(&mut streams);
+        streams.insert(
+            id,
+            LogStream {
+                user_id: user_id.to_string(),
+                sender,
+                expires_at,
+            },
+        );
+    }
+
+    /// Receive the chunks of one of the user's streams from now on. The
+    /// receiver closes when the stream ends.
+    pub async fn subscribe(&self, user_id: &str, id: Uuid) -> Option<broadcast::Receiver<String>> {
+        let mut streams = self.streams.write().await;
+        prune(&mut streams);
+        streams
+            .get(&id)
+            .filter(|stream| stream.user_id == user_id)
+            .map(|stream| stream.sender.subscribe())
+    }
+
+    /// Pass a chunk on to the stream's viewers; the last one also ends the
+    /// stream. `false` when the stream is over and the agent should stop.
+    pub async fn publish(&self, user_id: &str, id: Uuid, logs: String, end: bool) -> bool {
+        let mut streams = self.streams.write().await;
+        prune(&mut streams);
+        let Some(stream) = streams.get(&id).filter(|stream| stream.user_id == user_id) else {
+            return false;
+        };
+        if !logs.is_empty() {
+            // Sending fails only without viewers, e.g. before the first one.
+            let _ = stream.sender.send(logs);
+        }
+        if end {
+            streams.remove(&id);
+        }
+        true
+    }
+
+    /// End one of the user's streams. `false` if there was none.
+    pub async fn close(&self, user_id: &str, id: Uuid) -> bool {
+        let mut streams = self.streams.write().await;
+        let owned = streams
+            .get(&id)
+            .is_some_and(|stream| stream.user_id == user_id);
+        owned && streams.remove(&id).is_some()
+    }
+
+    /// End the stream if nobody is watching it anymore. Called when a viewer
+    /// disconnects.
+    pub async fn release(&self, user_id: &str, id: Uuid) {
+        let mut streams = self.streams.write().await;
+        let unwatched = streams
+            .get(&id)
+            .is_some_and(|stream| stream.user_id == user_id && stream.sender.receiver_count() == 0);
+        if unwatched {
+            streams.remove(&id);
+        }
+    }
+}
+
+/// Drop the streams past their time limit.
+fn prune(streams: &mut HashMap<Uuid, LogStream>) {
+    let now = Utc::now();
+    streams.retain(|_, stream| stream.expires_at > now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunks_reach_subscribers_until_the_end() {
+        let streams = LogStreams::default();
+        let id = Uuid::new_v4();
+        streams.open("user", id).await;
+        let mut rx = streams.subscribe("user", id).await.unwrap();
+
+        assert!(streams.publish("user", id, "one\n".into(), false).await);
+        assert!(streams.publish("user", id, "two\n".into(), true).await);
+        assert_eq!(rx.recv().await.unwrap(), "one\n");
+        assert_eq!(rx.recv().await.unwrap(), "two\n");
+        assert!(rx.recv().await.is_err());
+        // The agent's next chunk is refused.
+        assert!(!streams.publish("user", id, "three\n".into(), false).await);
+    }
+
+    #[tokio::test]
+    async fn streams_are_scoped_per_user() {
+        let streams = LogStreams::default();
+        let id = Uuid::new_v4();
+        streams.open("alice", id).await;
+        assert!(streams.subscribe("bob", id).await.is_none());
+        assert!(!streams.publish("bob", id, "x".into(), false).await);
+        assert!(!streams.close("bob", id).await);
+        assert!(streams.close("alice", id).await);
+        assert!(!streams.publish("alice", id, "x".into(), false).await);
+    }
+
+    #[tokio::test]
+    async fn the_last_viewer_leaving_ends_the_stream() {
+        let streams = LogStreams::default();
+        let id = Uuid::new_v4();
+        streams.open("user", id).await;
+        let first = streams.subscribe("user", id).await.unwrap();
+        let second = streams.subscribe("user", id).await.unwrap();
+        drop(first);
+        streams.release("user", id).await;
+        assert!(streams.publish("user", id, String::new(), false).await);
+        drop(second);
+        streams.release("user", id).await;
+        assert!(!streams.publish("user", id, String::new(), false).await);
+    }
+
+    #[tokio::test]
+    async fn expired_streams_refuse_chunks() {
+        let streams = LogStreams::default();
+        let id = Uuid::new_v4();
+        streams.open("user", id).await;
+        streams
+            .streams
+            .write()
+            .await
+            .get_mut(&id)
+            .unwrap()
+            .expires_at = Utc::now();
+        assert!(!streams.publish("user", id, "late".into(), false).await);
+    }
+}
@@ -4,6 +4,7 @@ use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use uuid::Uuid;
 
 type UpdatesStore = HashMap<(HostName, ProjectName, ServiceName), PendingUpdate>;
 
@@ -15,6 +16,9 @@ pub struct PendingUpdate {
     pub image_name: String,
     pub new_digest: String,
     pub detected_at: DateTime<Utc>,
+    /// The agent's ID for the report, to recognise its retries.
+    #[serde(skip)]
+    pub event_id: Option<Uuid>,
 }
 
 /// In-memory pending-update store, partitioned by user_id so one user's
@@ -25,18 +29,25 @@ pub struct PendingUpdatesMemory {
 }
 
 impl PendingUpdatesMemory {
-    pub async fn add(&self, user_id: &str, update: PendingUpdate) {
+    /// Store `update`, replacing an earlier one for the same service. Returns
+    /// false, and keeps the stored update, if `update` is a retry of it.
+    pub async fn add(&self, user_id: &str, update: PendingUpdate) -> bool {
         let key = (
             update.hostname.clone(),
             update.project_name.clone(),
             update.service_name.clone(),
         );
-        self.updates
-            .write()
-            .await
-            .entry(user_id.to_string())
-            .or_default()
-            .insert(key, update);
+        let mut guard = self.updates.write().await;
+        let store = guard.entry(user_id.to_string()).or_default();
+        if update.event_id.is_some()
+            && store
+                .get(&key)
+                .is_some_and(|stored| stored.event_id == update.event_id)
+        {
+            return false;
+        }
+        store.insert(key, update);
+        true
     }
 
     pub async fn get_all(&self, user_id: &str) -> Vec<PendingUpdate> {
@@ -1,7 +1,10 @@
 use crate::domain::billing::models::{Plan, PlanError};
 use crate::domain::billing::ports::PlanRepository;
+use crate::domain::commands::models::{Command, CommandError, CommandStatus};
+use crate::domain::commands::ports::CommandRepository;
 use crate::domain::container_state::models::state::{
-    AddContainerStateRequest, ContainerStateData, HostProjectState, ServiceState,
+    AddContainerStateRequest, AgentInfo, ContainerStateData, HostAgentInfo, HostProjectState,
+    ServiceState, VersionedProjectState,
 };
 use crate::domain::container_state::port::ContainerStateRepository;
 use crate::domain::deployments::models::deployment::{
@@ -18,7 +21,8 @@ use crate::domain::notifiers::models::{Notifier, NotifierConfig, NotifierError,
 use crate::domain::notifiers::ports::NotifierRepository;
 use crate::domain::tokens::models::{ApiToken, TokenError};
 use crate::domain::tokens::ports::TokenRepository;
-use hoister_shared::{DeploymentStatus, HostName, ImageName, ProjectName, ServiceName};
+use hoister_shared::wire::AgentEngine;
+use hoister_shared::{AgentId, DeploymentStatus, HostName, ImageName, ProjectName, ServiceName};
 use log::error;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::sqlite::SqlitePoolOptions;
@@ -300,6 +304,40 @@ impl Sqlite {
         Ok(deployment)
     }
 
+    async fn deployment_for_event(
+        &self,
+        user_id: &str,
+        event_id: uuid::Uuid,
+    ) -> Result<Option<DeploymentId>, SqlxError> {
+        let id = sqlx::query_scalar::<_, uuid::Uuid>(
+            "SELECT id FROM deployment WHERE user_id = ? AND event_id = ?",
+        )
+        .bind(user_id)
+        .bind(event_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(id.map(DeploymentId))
+    }
+
+    /// The outcome for a report whose event ID is recorded as `id`: a
+    /// duplicate if it is the same report, a conflict otherwise.
+    async fn recorded(
+        &self,
+        req: &CreateDeploymentRequest,
+        event_id: uuid::Uuid,
+        id: DeploymentId,
+    ) -> Result<DeploymentId, CreateDeploymentError> {
+        let deployment = self.get_deployment(id, &req.user_id).await.map_err(|e| {
+            error!("Failed to read recorded deployment: {e:?}");
+            CreateDeploymentError::UnknownError
+        })?;
+        if req.is_recorded_as(&deployment) {
+            Err(CreateDeploymentError::Duplicate(deployment.id))
+        } else {
+            Err(CreateDeploymentError::EventIdTaken(event_id))
+        }
+    }
+
     /// Get deployments by service for a specific user.
     pub async fn get_deployments_of_service(
         &self,
@@ -349,10 +387,12 @@ impl Sqlite {
         Ok(deployments)
     }
 
+    /// `None` if the user already has a deployment with the request's event
+    /// ID.
     async fn create_deployment(
         &self,
         req: &CreateDeploymentRequest,
-    ) -> Result<DeploymentId, SqlxError> {
+    ) -> Result<Option<DeploymentId>, SqlxError> {
         let user_id = req.user_id.as_str();
         let host_id = self.upsert_host(&req.hostname, user_id).await?;
         let project_id = self
@@ -372,8 +412,10 @@ impl Sqlite {
         }
 
         let id = uuid::Uuid::new_v4();
-        sqlx::query(
-            "INSERT INTO deployment (id, digest, status, service_id, host_id, logs) VALUES (?, ?, ?, ?, ?, ?)",
+        let inserted = sqlx::query(
+            "INSERT INTO deployment (id, digest, status, service_id, host_id, logs, user_id, event_id)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT(user_id, event_id) DO NOTHING",
         )
         .bind(id)
         .bind(req.image_digest.as_str())
@@ -381,11 +423,12 @@ impl Sqlite {
         .bind(service_id)
         .bind(host_id)
         .bind(req.logs.as_deref())
+        .bind(user_id)
+        .bind(req.event_id)
         .execute(&self.pool)
-        .await
-        .expect("Failed to insert deployment");
+        .await?;
 
-        Ok(DeploymentId(id))
+        Ok((inserted.rows_affected() > 0).then_some(DeploymentId(id)))
     }
 }
 
@@ -483,10 +526,32 @@ impl DeploymentsRepository for Sqlite {
         &self,
         req: &CreateDeploymentRequest,
     ) -> Result<DeploymentId, CreateDeploymentError> {
-        self.create_deployment(req).await.map_err(|e| {
+        let failed = |e: SqlxError| {
             error!("Failed to create deployment: {e:?}");
             CreateDeploymentError::UnknownError
-        })
+        };
+        // A retried report is already recorded under its event ID.
+        if let Some(event_id) = req.event_id
+            && let Some(id) = self
+                .deployment_for_event(&req.user_id, event_id)
+                .await
+                .map_err(failed)?
+        {
+            return self.recorded(req, event_id, id).await;
+        }
+        if let Some(id) = self.create_deployment(req).await.map_err(failed)? {
+            return Ok(id);
+        }
+        // Nothing was inserted: a concurrent delivery recorded the event ID.
+        let event_id = req.event_id.ok_or(CreateDeploymentError::UnknownError)?;
+        match self
+            .deployment_for_event(&req.user_id, event_id)
+            .await
+            .map_err(failed)?
+        {
+            Some(id) => self.recorded(req, event_id, id).await,
+            None => Err(CreateDeploymentError::UnknownError),
+        }
     }
 
     async fn get_all_deployments(
@@ -782,6 +847,35 @@ impl ContainerStateRepository for Sqlite {
         out
     }
 
+    async fn get_versioned_state(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+    ) -> Option<VersionedProjectState> {
+        let (services_json, version): (String, Option<i64>) = sqlx::query_as(
+            "SELECT cs.services, cs.version
+                FROM compose_state cs
+                JOIN project p ON cs.project_id = p.id
+                JOIN host h ON p.host_id = h.id
+                WHERE p.user_id = ? AND h.hostname = ? AND p.name = ?",
+        )
+        .bind(user_id)
+        .bind(hostname.as_str())
+        .bind(project_name.as_str())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| error!("get_versioned_state failed: {e:?}"))
+        .ok()??;
+        let services = serde_json::from_str(&services_json)
+            .map_err(|e| error!("services blob decode failed: {e:?}"))
+            .ok()?;
+        Some(VersionedProjectState {
+            version: version? as u64,
+            services,
+        })
+    }
+
     async fn add_container_state(&self, req: AddContainerStateRequest) {
         let services_json = match serde_json::to_string(&req.services) {
             Ok(s) => s,
@@ -828,15 +922,17 @@ impl ContainerStateRepository for Sqlite {
 
         let now = chrono::Utc::now().to_rfc3339();
         if let Err(e) = sqlx::query(
-            "INSERT INTO compose_state (project_id, services, last_updated)
-                 VALUES (?, ?, ?)
+            "INSERT INTO compose_state (project_id, services, last_updated, version)
+                 VALUES (?, ?, ?, ?)
                  ON CONFLICT(project_id) DO UPDATE SET
                      services = excluded.services,
-                     last_updated = excluded.last_updated",
+                     last_updated = excluded.last_updated,
+                     version = excluded.version",
         )
         .bind(project_id)
         .bind(&services_json)
         .bind(&now)
+        .bind(req.version.map(|v| v as i64))
         .execute(&self.pool)
         .await
         {
@@ -896,6 +992,70 @@ impl ContainerStateRepository for Sqlite {
             }
         }
     }
+
+    async fn set_agent_info(&self, user_id: &str, hostname: &HostName, info: &AgentInfo) {
+        let info_json = match serde_json::to_string(info) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("encode agent info for {user_id} failed: {e:?}");
+                return;
+            }
+        };
+        let host_id = match self.upsert_host(hostname, user_id).await {
+            Ok(id) => id,
+            Err(e) => {
+                error!("set_agent_info upsert_host failed: {e:?}");
+                return;
+            }
+        };
+        if let Err(e) = sqlx::query(
+            "INSERT INTO agent_info (host_id, info, reported_at)
+                 VALUES (?, ?, ?)
+                 ON CONFLICT(host_id) DO UPDATE SET
+                     info = excluded.info,
+                     reported_at = excluded.reported_at",
+        )
+        .bind(host_id)
+        .bind(&info_json)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await
+        {
+            error!("set_agent_info failed: {e:?}");
+        }
+    }
+
+    async fn get_agent_infos(&self, user_id: &str) -> Vec<HostAgentInfo> {
+        let rows: Vec<(String, String, String)> = match sqlx::query_as(
+            "SELECT h.hostname, a.info, a.reported_at
+                FROM agent_info a
+                JOIN host h ON a.host_id = h.id
+                WHERE h.user_id = ?
+                ORDER BY h.hostname",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rs) => rs,
+            Err(e) => {
+                error!("get_agent_infos failed: {e:?}");
+                return Vec::new();
+            }
+        };
+        rows.into_iter()
+            .filter_map(|(hostname, info, reported_at)| {
+                let info = serde_json::from_str(&info)
+                    .map_err(|e| error!("agent info decode failed: {e:?}"))
+                    .ok()?;
+                Some(HostAgentInfo {
+                    hostname: HostName::new(hostname),
+                    info,
+                    reported_at: parse_ts(&reported_at),
+                })
+            })
+            .collect()
+    }
 }
 
 /// Parse a timestamp stored either as RFC3339 (our writes) or SQLite's
@@ -1130,3 +1290,250 @@ impl MetricsRepository for Sqlite {
             .collect()
     }
 }
+
+impl CommandRepository for Sqlite {
+    async fn create_command(&self, command: &Command) -> Result<(), CommandError> {
+        let event = serde_json::to_string(&command.event).map_err(|e| {
+            error!("create_command serialize failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        sqlx::query(
+            "INSERT INTO command
+                (id, user_id, agent_id, event, status, detail, created_at, updated_at,
+                 expires_at, seq)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?,
+                        (SELECT COALESCE(MAX(seq), 0) + 1 FROM command))",
+        )
+        .bind(command.id)
+        .bind(&command.user_id)
+        .bind(command.agent_id.as_str())
+        .bind(event)
+        .bind(command.status.as_str())
+        .bind(&command.detail)
+        .bind(command.created_at.to_rfc3339())
+        .bind(command.updated_at.to_rfc3339())
+        .bind(command.expires_at.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("create_command failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        Ok(())
+    }
+
+    async fn set_command_status(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+        status: CommandStatus,
+        detail: Option<&str>,
+    ) -> Result<bool, CommandError> {
+        let result = sqlx::query(
+            "UPDATE command SET status = ?, detail = ?, updated_at = ?
+                WHERE id = ? AND user_id = ?
+                  AND status NOT IN ('succeeded', 'rolled_back', 'failed')",
+        )
+        .bind(status.as_str())
+        .bind(detail)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("set_command_status failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_command(
+        &self,
+        user_id: &str,
+        id: uuid::Uuid,
+    ) -> Result<Option<Command>, CommandError> {
+        let row = sqlx::query(
+            "SELECT id, user_id, agent_id, event, status, detail, created_at, updated_at,
+                    expires_at
+                FROM command
+                WHERE id = ? AND user_id = ?",
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("get_command failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        row.map(|r| command_from_row(&r)).transpose()
+    }
+
+    async fn list_commands(&self, user_id: &str, limit: i64) -> Result<Vec<Command>, CommandError> {
+        let rows = sqlx::query(
+            "SELECT id, user_id, agent_id, event, status, detail, created_at, updated_at,
+                    expires_at
+                FROM command
+                WHERE user_id = ?
+                ORDER BY created_at DESC
+                LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("list_commands failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        rows.iter().map(command_from_row).collect()
+    }
+
+    async fn pending_commands(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        after: Option<uuid::Uuid>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<Command>, CommandError> {
+        // An unknown `after` (e.g. a command deleted since) replays everything.
+        let rows = sqlx::query(
+            "SELECT id, user_id, agent_id, event, status, detail, created_at, updated_at,
+                    expires_at
+                FROM command
+                WHERE user_id = ? AND agent_id = ? AND status = 'sent' AND expires_at > ?
+                  AND seq > COALESCE(
+                    (SELECT seq FROM command WHERE id = ? AND user_id = ?), 0)
+                ORDER BY seq ASC",
+        )
+        .bind(user_id)
+        .bind(agent_id.as_str())
+        .bind(now.to_rfc3339())
+        .bind(after)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("pending_commands failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        rows.iter().map(command_from_row).collect()
+    }
+
+    async fn expire_commands(
+        &self,
+        user_id: &str,
+        now: chrono::DateTime<chrono::Utc>,
+        unfinished_before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), CommandError> {
+        let now = now.to_rfc3339();
+        sqlx::query(
+            "UPDATE command
+                SET status = 'timed_out',
+                    detail = CASE status
+                        WHEN 'sent' THEN 'no agent picked the command up in time'
+                        ELSE 'the agent stopped reporting progress'
+                    END,
+                    updated_at = ?
+                WHERE user_id = ?
+                  AND ((status = 'sent' AND expires_at <= ?)
+                    OR (status IN ('received', 'running') AND updated_at < ?))",
+        )
+        .bind(&now)
+        .bind(user_id)
+        .bind(&now)
+        .bind(unfinished_before.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("expire_commands failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        Ok(())
+    }
+
+    async fn register_agent_engines(
+        &self,
+        user_id: &str,
+        agent_id: &AgentId,
+        engines: &[AgentEngine],
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), CommandError> {
+        let failed = |e: SqlxError| {
+            error!("register_agent_engines failed: {e:?}");
+            CommandError::UnknownError
+        };
+        let now = now.to_rfc3339();
+        let mut tx = self.pool.begin().await.map_err(failed)?;
+        sqlx::query("DELETE FROM agent_engine WHERE user_id = ? AND agent_id = ?")
+            .bind(user_id)
+            .bind(agent_id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(failed)?;
+        for engine in engines {
+            sqlx::query(
+                "INSERT INTO agent_engine
+                    (user_id, hostname, project_name, agent_id, registered_at)
+                    VALUES (?, ?, ?, ?, ?)
+                    ON CONFLICT (user_id, hostname, project_name)
+                    DO UPDATE SET agent_id = excluded.agent_id,
+                                  registered_at = excluded.registered_at",
+            )
+            .bind(user_id)
+            .bind(engine.hostname.as_str())
+            .bind(engine.project_name.as_str())
+            .bind(agent_id.as_str())
+            .bind(&now)
+            .execute(&mut *tx)
+            .await
+            .map_err(failed)?;
+        }
+        tx.commit().await.map_err(failed)
+    }
+
+    async fn registered_agent(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project: &ProjectName,
+    ) -> Result<Option<AgentId>, CommandError> {
+        let row = sqlx::query(
+            "SELECT agent_id FROM agent_engine
+                WHERE user_id = ? AND hostname = ? AND project_name = ?",
+        )
+        .bind(user_id)
+        .bind(hostname.as_str())
+        .bind(project.as_str())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            error!("registered_agent failed: {e:?}");
+            CommandError::UnknownError
+        })?;
+        Ok(row.map(|r| AgentId::new(r.get::<String, _>("agent_id"))))
+    }
+}
+
+fn command_from_row(r: &sqlx::sqlite::SqliteRow) -> Result<Command, CommandError> {
+    let event: String = r.get("event");
+    let status: String = r.get("status");
+    Ok(Command {
+        id: r.get("id"),
+        user_id: r.get("user_id"),
+        agent_id: AgentId::new(r.get::<String, _>("agent_id")),
+        event: serde_json::from_str(&event).map_err(|e| {
+            error!("stored command event is invalid: {e:?}");
+            CommandError::UnknownError
+        })?,
+        status: CommandStatus::parse(&status).ok_or_else(|| {
+            error!("stored command status {status} is invalid");
+            CommandError::UnknownError
+        })?,
+        detail: r.get("detail"),
+        created_at: parse_ts(r.get("created_at")),
+        updated_at: parse_ts(r.get("updated_at")),
+        expires_at: parse_ts(r.get("expires_at")),
+    })
+}
@@ -1,5 +1,6 @@
 use crate::domain::container_state::models::state::{
-    AddContainerStateRequest, ContainerStateData, HostProjectState,
+    AddContainerStateRequest, AgentInfo, ContainerStateData, HostAgentInfo, HostProjectState,
+    VersionedProjectState,
 };
 use crate::domain::container_state::port::ContainerStateRepository;
 use chrono::Utc;
@@ -8,12 +9,17 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// The version of each (host, project) state that has one.
+type StateVersions = HashMap<(HostName, ProjectName), u64>;
+
 /// In-memory container state, partitioned by user. Every read and write goes
 /// through a user_id key so two users with the same project/service names
 /// cannot see each other's containers.
 #[derive(Clone, Default)]
 pub struct StateMemory {
     state: Arc<RwLock<HashMap<String, ContainerStateData>>>,
+    agent_infos: Arc<RwLock<HashMap<String, HashMap<HostName, HostAgentInfo>>>>,
+    versions: Arc<RwLock<HashMap<String, StateVersions>>>,
 }
 
 impl ContainerStateRepository for StateMemory {
@@ -52,8 +58,17 @@ impl ContainerStateRepository for StateMemory {
             hostname,
             project_name,
             services,
+            version,
         } = request;
 
+        let key = (hostname.clone(), project_name.clone());
+        let mut versions = self.versions.write().await;
+        let user_versions = versions.entry(user_id.clone()).or_default();
+        match version {
+            Some(version) => user_versions.insert(key, version),
+            None => user_versions.remove(&key),
+        };
+
         let mut state = self.state.write().await;
         let user_data = state.entry(user_id).or_default();
         let entry = user_data
@@ -68,6 +83,26 @@ impl ContainerStateRepository for StateMemory {
         entry.last_updated = Utc::now();
     }
 
+    async fn get_versioned_state(
+        &self,
+        user_id: &str,
+        hostname: &HostName,
+        project_name: &ProjectName,
+    ) -> Option<VersionedProjectState> {
+        let version = *self
+            .versions
+            .read()
+            .await
+            .get(user_id)?
+            .get(&(hostname.clone(), project_name.clone()))?;
+        let state = self.state.read().await;
+        let services = state.get(user_id)?.get(hostname)?.get(project_name)?;
+        Some(VersionedProjectState {
+            version,
+            services: services.services.clone(),
+        })
+    }
+
     async fn touch_container_state(
         &self,
         user_id: &str,
@@ -98,6 +133,9 @@ impl ContainerStateRepository for StateMemory {
             return false;
         };
         let removed = projects.remove(project_name).is_some();
+        if let Some(versions) = self.versions.write().await.get_mut(user_id) {
+            versions.remove(&(hostname.clone(), project_name.clone()));
+        }
         // Drop the host bucket once its last project is gone so stale,
         // empty hosts don't linger in reads.
         if projects.is_empty() {
@@ -105,4 +143,32 @@ impl ContainerStateRepository for StateMemory {
         }
         removed
     }
+
+    async fn set_agent_info(&self, user_id: &str, hostname: &HostName, info: &AgentInfo) {
+        self.agent_infos
+            .write()
+            .await
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(
+                hostname.clone(),
+                HostAgentInfo {
+                    hostname: hostname.clone(),
+                    info: info.clone(),
+                    reported_at: Utc::now(),
+                },
+            );
+    }
+
+    async fn get_agent_infos(&self, user_id: &str) -> Vec<HostAgentInfo> {
+        let mut infos: Vec<_> = self
+            .agent_infos
+            .read()
+            .await
+            .get(user_id)
+            .map(|hosts| hosts.values().cloned().collect())
+            .unwrap_or_default();
+        infos.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        infos
+    }
 }
@@ -1,4 +1,5 @@
 use crate::domain::billing::ports::BillingService;
+use crate::domain::commands::ports::CommandService;
 use crate::domain::container_state::port::ContainerStateService;
 use crate::domain::deployments::ports::DeploymentsService;
 use crate::domain::metrics::port::MetricsService;
@@ -6,20 +7,27 @@ use crate::domain::notifiers::ports::NotifierService;
 use crate::domain::tokens::ports::TokenService;
 use crate::inbound::server::{AppState, UserId};
 use axum::Extension;
+use axum::Json;
 use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
-use futures_util::stream::Stream;
+use axum::response::{IntoResponse, Response};
+use hoister_shared::AgentId;
+use hoister_shared::wire::AgentRegistration;
+use std::collections::HashSet;
 use std::convert::Infallible;
 use tokio::sync::broadcast::error::RecvError;
 
 pub use hoister_shared::ContainerID;
-pub use hoister_shared::wire::ControllerEvent;
+pub use hoister_shared::wire::{Command, ControllerEvent};
 
-/// Internal broadcast payload. The first element is the owning user_id —
-/// `sse_handler` uses it to deliver each event only to that user's
-/// subscribers. The wire format sent to agents is just `ControllerEvent`.
-pub type UserScopedEvent = (String, ControllerEvent);
+/// Internal broadcast payload: the owning user_id and the agent the command
+/// is addressed to. `sse_handler` delivers each command only on that agent's
+/// connection. The wire format sent to agents is just the `Command`.
+pub type UserScopedEvent = (String, AgentId, Command);
 
+/// Agent endpoint: register the agent and stream it the commands addressed to
+/// it. 409s if another connected agent already manages one of its engines.
 pub(crate) async fn sse_handler<
     DS: DeploymentsService,
     CS: ContainerStateService,
@@ -27,39 +35,96 @@ pub(crate) async fn sse_handler<
     NS: NotifierService,
     BS: BillingService,
     MS: MetricsService,
+    CmS: CommandService,
 >(
-    State(state): State<AppState<DS, CS, TS, NS, BS, MS>>,
+    State(state): State<AppState<DS, CS, TS, NS, BS, MS, CmS>>,
     Extension(UserId(subscriber_user_id)): Extension<UserId>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    headers: HeaderMap,
+    Json(registration): Json<AgentRegistration>,
+) -> Response {
+    // Subscribe before reading the queue, so a command sent in between is
+    // either replayed or broadcast, never neither.
     let mut rx = state.event_tx.subscribe();
+    let engines = registration.engines.clone();
+    let connection = match state.agents.register(&subscriber_user_id, registration) {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::warn!("Refused agent connection for {subscriber_user_id}: {e}");
+            return (StatusCode::CONFLICT, e.to_string()).into_response();
+        }
+    };
+    let commands = state.command_service.clone();
+    // Remembered so commands for these engines are queued for the agent while
+    // it is offline. If that fails, they are only accepted while it is online.
+    if let Err(e) = commands
+        .register_agent_engines(&subscriber_user_id, connection.agent_id(), &engines)
+        .await
+    {
+        log::error!("Failed to store the engines of an agent of {subscriber_user_id}: {e:?}");
+    }
+    // The last command the agent saw. SSE clients send it back as
+    // `Last-Event-ID` when they reconnect.
+    let mut cursor = headers
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.parse::<uuid::Uuid>().ok());
 
     let stream = async_stream::stream! {
+        // Commands sent while the agent was away come from the database. So do
+        // those a lagging subscriber misses: they are all stored before being
+        // broadcast. A replayed command may be broadcast afterwards too.
+        let agent_id = connection.agent_id().clone();
+        let mut replay = true;
+        let mut replayed = HashSet::new();
         loop {
-            match rx.recv().await {
-                Ok((event_user_id, event)) => {
-                    if event_user_id != subscriber_user_id {
+            if replay {
+                replay = false;
+                match commands
+                    .pending_commands(&subscriber_user_id, &agent_id, cursor)
+                    .await
+                {
+                    Ok(pending) => {
+                        for command in pending {
+                            cursor = Some(command.id);
+                            replayed.insert(command.id);
+                            if let Some(event) = sse_event(&command.for_agent()) {
+                                yield Ok::<_, Infallible>(event);
+                            }
+                        }
+                    }
+                    Err(e) => log::error!(
+                        "Failed to replay queued commands for {subscriber_user_id}: {e:?}"
+                    ),
+                }
+            }
+            let received = rx.recv().await;
+            // The agent reconnected; its new connection takes over.
+            if !connection.is_current() {
+                break;
+            }
+            match received {
+                Ok((event_user_id, event_agent_id, command)) => {
+                    if event_user_id != subscriber_user_id
+                        || event_agent_id != agent_id
+                        || replayed.remove(&command.id)
+                    {
                         continue;
                     }
-                    if let Ok(sse_event) = Event::default().json_data(event) {
-                        yield Ok(sse_event);
+                    cursor = Some(command.id);
+                    if let Some(event) = sse_event(&command) {
+                        yield Ok(event);
                     }
                 }
-                // A slow consumer of this SSE stream — or a burst of events from
-                // any tenant, since all tenants share one broadcast channel —
-                // can push messages out of the ring buffer before this task
-                // reads them. `recv` then returns `Lagged(n)` for the n dropped
-                // messages. The previous `while let Ok(..)` treated this exactly
-                // like `Closed` and ended the stream silently, so the agent
-                // stopped receiving ApplyUpdate/RequestLogs commands until it
-                // happened to reconnect. Log it and keep going: `recv` resumes
-                // from the oldest still-buffered message on the next call.
+                // All tenants share one broadcast ring, so a slow consumer of
+                // this stream — or a burst from any tenant — can push messages
+                // out before this task reads them. `recv` then returns
+                // `Lagged(n)`; the missed commands are read back from the queue.
                 Err(RecvError::Lagged(skipped)) => {
                     log::warn!(
-                        "SSE subscriber {subscriber_user_id} lagged; dropped \
-                         {skipped} broadcast event(s). Some ApplyUpdate/RequestLogs \
-                         commands for this user may have been missed."
+                        "SSE subscriber {subscriber_user_id} lagged by {skipped} \
+                         broadcast event(s); replaying its queued commands."
                     );
-                    continue;
+                    replay = true;
                 }
                 // The sender was dropped (controller shutting down); no further
                 // events will ever arrive, so end the stream.
@@ -68,5 +133,15 @@ pub(crate) async fn sse_handler<
         }
     };
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// `command` as an SSE event whose ID is the command's, for `Last-Event-ID`.
+fn sse_event(command: &Command) -> Option<Event> {
+    Event::default()
+        .id(command.id.to_string())
+        .json_data(command)
+        .ok()
 }
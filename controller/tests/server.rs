@@ -6,12 +6,13 @@ mod tests {
         http::{Request, StatusCode},
     };
     use hoister_shared::{
-        CreateDeployment, DeploymentStatus, HostName, ImageDigest, ImageName, ProjectName,
+        AgentId, CreateDeployment, DeploymentStatus, HostName, ImageDigest, ImageName, ProjectName,
         ServiceName,
     };
 
     use controller::domain::billing::ports::BillingService as _;
     use controller::domain::billing::service::Service as BillingService;
+    use controller::domain::commands::service::Service as CommandService;
     use controller::domain::container_state::service::Service as ContainerStateService;
     use controller::domain::deployments::models::deployment::{
         CreateDeploymentRequest, Deployment,
@@ -66,12 +67,16 @@ mod tests {
             token_service: Arc::new(TokenService::new(db.clone())),
             notifier_service: Arc::new(NotifierService::new(db.clone())),
             billing_service: Arc::new(BillingService::new(db.clone())),
-            metrics_service: Arc::new(MetricsService::new(db)),
+            metrics_service: Arc::new(MetricsService::new(db.clone())),
+            command_service: Arc::new(CommandService::new(db)),
             #[cfg(feature = "self-hosted")]
             api_secret: Some("tests-secret".to_string()),
             event_tx,
             pending_updates: Default::default(),
             logs: Default::default(),
+            log_streams: Default::default(),
+            cleanup: Default::default(),
+            agents: Default::default(),
             email: None,
             dashboard_url: "https://hoister.io".to_string(),
         };
@@ -86,6 +91,46 @@ mod tests {
         (agent, internal, db_path)
     }
 
+    /// Opens the SSE stream of an agent managing test-host in tests-project.
+    /// The agent stays registered while the response is held.
+    async fn connect_agent(agent: &Router, agent_id: &str) -> axum::response::Response {
+        reconnect_agent(agent, agent_id, None).await
+    }
+
+    /// `connect_agent` for an agent that already saw the command `last_event_id`.
+    async fn reconnect_agent(
+        agent: &Router,
+        agent_id: &str,
+        last_event_id: Option<&str>,
+    ) -> axum::response::Response {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/sse")
+            .header("Authorization", "Bearer tests-secret")
+            .header("Content-Type", "application/json");
+        if let Some(id) = last_event_id {
+            request = request.header("Last-Event-ID", id);
+        }
+        agent
+            .clone()
+            .oneshot(
+                request
+                    .body(Body::from(
+                        serde_json::json!({
+                            "agent_id": agent_id,
+                            "version": "1.1.0",
+                            "engines": [
+                                { "hostname": "test-host", "project_name": "tests-project" }
+                            ],
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn test_health_endpoint_no_auth_required() {
         let (agent, _internal, _db) = setup_test_app().await;
@@ -165,6 +210,7 @@ mod tests {
             status: DeploymentStatus::Pending,
             hostname: HostName::new("test-host"),
             logs: None,
+            event_id: None,
         };
 
         let response = agent
@@ -183,6 +229,111 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_retried_reports_are_recorded_once() {
+        let (agent, internal, db_path) = setup_test_app().await;
+        let event_id = uuid::Uuid::new_v4();
+        let report = CreateDeployment {
+            project: ProjectName::new("tests-project"),
+            service: ServiceName::new("tests-service"),
+            image: ImageName::new("nginx:latest"),
+            digest: ImageDigest::new("sha256:abc123"),
+            status: DeploymentStatus::Success,
+            hostname: HostName::new("test-host"),
+            logs: None,
+            event_id: Some(event_id),
+        };
+        let deployment = serde_json::to_string(&report).unwrap();
+        let pending_update = serde_json::json!({
+            "hostname": "test-host",
+            "project_name": "tests-project",
+            "service_name": "tests-service",
+            "image_name": "nginx:latest",
+            "new_digest": "sha256:def456",
+            "event_id": uuid::Uuid::new_v4(),
+        })
+        .to_string();
+        let post = |uri: &'static str, body: String| {
+            let agent = agent.clone();
+            async move {
+                let response = agent
+                    .oneshot(
+                        Request::builder()
+                            .method("POST")
+                            .uri(uri)
+                            .header("Authorization", "Bearer tests-secret")
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).ok(),
+                )
+            }
+        };
+        let list = |uri: &'static str| {
+            let internal = internal.clone();
+            async move {
+                let response = internal
+                    .oneshot(
+                        Request::builder()
+                            .uri(uri)
+                            .header("X-User-Id", TEST_USER)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        // A retry answers with the deployment recorded the first time.
+        let (status, body) = post("/deployments", deployment.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let id = body.unwrap()["data"]["id"].clone();
+        let (status, body) = post("/deployments", deployment.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["data"]["id"], id);
+        assert_eq!(
+            list("/deployments").await["data"].as_array().unwrap().len(),
+            1
+        );
+        // A different report can't reuse the event ID.
+        let mut other = serde_json::to_value(&report).unwrap();
+        other["status"] = serde_json::to_value(DeploymentStatus::Failed).unwrap();
+        let (status, _) = post("/deployments", other.to_string()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        // Event IDs are only unique per user: another tenant's is recorded.
+        let db = connect_db(&db_path).await;
+        BillingService::new(db.clone())
+            .upsert_user("someone-else")
+            .await;
+        DeploymentsService::new(db)
+            .create_deployment(&CreateDeploymentRequest::from_payload(
+                report,
+                "someone-else".to_string(),
+            ))
+            .await
+            .expect("another tenant's report is recorded");
+
+        for _ in 0..2 {
+            let (status, _) = post("/pending-updates", pending_update.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(list("/pending-updates").await.as_array().unwrap().len(), 1);
+    }
+
     #[tokio::test]
     async fn test_get_deployment_by_service() {
         let (_agent, internal, db_path) = setup_test_app().await;
@@ -201,6 +352,7 @@ mod tests {
             hostname: HostName::new("test-host"),
             logs: None,
             user_id: TEST_USER.to_string(),
+            event_id: None,
         };
         database_service.create_deployment(&req).await.unwrap();
 
@@ -393,6 +545,115 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    async fn post_state(
+        agent: &Router,
+        uri: &str,
+        body: serde_json::Value,
+        encoding: Option<&str>,
+    ) -> StatusCode {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Authorization", "Bearer tests-secret")
+            .header("Content-Type", "application/json");
+        let body = match encoding {
+            Some(encoding) => {
+                request = request.header("Content-Encoding", encoding);
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body.to_string().as_bytes()).unwrap();
+                encoder.finish().unwrap()
+            }
+            None => body.to_string().into_bytes(),
+        };
+        agent
+            .clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn restart_count(internal: &Router) -> serde_json::Value {
+        let response = internal
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/container/state")
+                    .header("X-User-Id", TEST_USER)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let states: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        states[0]["container_inspections"]["RestartCount"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_state_deltas_apply_to_the_stored_version() {
+        let (agent, internal, _db) = setup_test_app().await;
+        let full = "/container/state/test-host/tests-project";
+        let delta = "/container/state/test-host/tests-project/delta";
+        let restarted = |base_version: u64, version: u64, count: u64| {
+            serde_json::json!({
+                "base_version": base_version,
+                "version": version,
+                "patch": { "web": { "inspect": { "RestartCount": count } } },
+            })
+        };
+
+        // Without a stored version there is nothing to patch.
+        assert_eq!(
+            post_state(&agent, delta, restarted(1, 2, 1), None).await,
+            StatusCode::CONFLICT
+        );
+
+        let state = serde_json::json!({
+            "project_name": "tests-project",
+            "payload": { "web": { "inspect": { "Id": "abc", "RestartCount": 0 } } },
+            "version": 1,
+        });
+        assert_eq!(
+            post_state(&agent, full, state.clone(), None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post_state(&agent, delta, restarted(1, 2, 3), None).await,
+            StatusCode::OK
+        );
+        assert_eq!(restart_count(&internal).await, 3);
+
+        // The stored state is at version 2 now, so a delta against 1 diverged.
+        assert_eq!(
+            post_state(&agent, delta, restarted(1, 3, 4), None).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(restart_count(&internal).await, 3);
+
+        // A full report resyncs, here gzipped.
+        assert_eq!(
+            post_state(&agent, full, state.clone(), Some("gzip")).await,
+            StatusCode::OK
+        );
+        assert_eq!(restart_count(&internal).await, 0);
+        assert_eq!(
+            post_state(&agent, delta, restarted(1, 2, 5), Some("gzip")).await,
+            StatusCode::OK
+        );
+        assert_eq!(restart_count(&internal).await, 5);
+
+        assert_eq!(
+            post_state(&agent, full, state, Some("br")).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
     #[tokio::test]
     async fn test_metrics_without_state_row_are_dropped() {
         let (agent, internal, _db) = setup_test_app().await;
@@ -421,4 +682,596 @@ mod tests {
 
         assert_eq!(latest_metrics(&internal).await.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_last_seen_follows_agent_reports() {
+        let (agent, _internal, _db) = setup_test_app().await;
+        let host = "test-host";
+        let project = "tests-project";
+        let last_seen = |agent: Router| async move {
+            let response = agent
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/container/state/{host}/{project}/last-seen"))
+                        .header("Authorization", "Bearer tests-secret")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).ok(),
+            )
+        };
+
+        // Nothing reported yet.
+        let (status, _) = last_seen(agent.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let state_body = serde_json::json!({
+            "project_name": project,
+            "payload": { "web": { "inspect": {} } }
+        });
+        let response = agent
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("/container/state/{host}/{project}"))
+                    .header("Authorization", "Bearer tests-secret")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(state_body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (status, body) = last_seen(agent).await;
+        assert_eq!(status, StatusCode::OK);
+        let last_updated = body.unwrap()["last_updated"].as_str().unwrap().to_string();
+        assert!(chrono::DateTime::parse_from_rfc3339(&last_updated).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_agent_info_is_listed_per_host() {
+        let (agent, internal, _db) = setup_test_app().await;
+        let info = |version: &str| {
+            serde_json::json!({
+                "agent_id": "agent-1",
+                "version": version,
+                "os": "linux",
+                "arch": "x86_64",
+                "schedule": "every 5m",
+                "features": { "auto_update": true, "report_logs": false, "report_metrics": true },
+                "engine": { "version": "27.3.1", "os": null, "kernel_version": null, "arch": null }
+            })
+        };
+        let post_info = |body: serde_json::Value| {
+            let agent = agent.clone();
+            async move {
+                agent
+                    .oneshot(
+                        Request::builder()
+                            .method("POST")
+                            .uri("/agent-info/test-host")
+                            .header("Authorization", "Bearer tests-secret")
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body.to_string()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        let list = || {
+            let internal = internal.clone();
+            async move {
+                let response = internal
+                    .oneshot(
+                        Request::builder()
+                            .uri("/agent-info")
+                            .header("X-User-Id", TEST_USER)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()["data"].clone()
+            }
+        };
+
+        assert_eq!(post_info(info("0.9.0")).await, StatusCode::OK);
+        let hosts = list().await;
+        assert_eq!(hosts.as_array().unwrap().len(), 1);
+        assert_eq!(hosts[0]["hostname"], "test-host");
+        assert_eq!(hosts[0]["info"]["engine"]["version"], "27.3.1");
+        assert_eq!(hosts[0]["outdated"], true);
+
+        // A later handshake replaces the earlier one.
+        assert_eq!(post_info(info("99.0.0")).await, StatusCode::OK);
+        let hosts = list().await;
+        assert_eq!(hosts.as_array().unwrap().len(), 1);
+        assert_eq!(hosts[0]["info"]["version"], "99.0.0");
+        assert_eq!(hosts[0]["outdated"], false);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_reports_add_up() {
+        let (agent, internal, _db) = setup_test_app().await;
+        let report = serde_json::json!({
+            "volumes": 2,
+            "containers": 1,
+            "images": 1,
+            "reclaimed_bytes": 4096
+        });
+        for _ in 0..2 {
+            let response = agent
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/cleanup/test-host/tests-project")
+                        .header("Authorization", "Bearer tests-secret")
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(report.to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = internal
+            .oneshot(
+                Request::builder()
+                    .uri("/cleanup")
+                    .header("X-User-Id", TEST_USER)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let totals: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(totals[0]["hostname"], "test-host");
+        assert_eq!(totals[0]["volumes"], 4);
+        assert_eq!(totals[0]["reclaimed_bytes"], 8192);
+    }
+
+    #[tokio::test]
+    async fn test_commands_follow_agent_reports() {
+        let (agent, internal, _db) = setup_test_app().await;
+        let internal_json = |request: Request<Body>| {
+            let internal = internal.clone();
+            async move {
+                let response = internal.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).ok(),
+                )
+            }
+        };
+        let report = |id: String, status: &'static str| {
+            let agent = agent.clone();
+            async move {
+                agent
+                    .oneshot(
+                        Request::builder()
+                            .method("POST")
+                            .uri(format!("/commands/{id}"))
+                            .header("Authorization", "Bearer tests-secret")
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(
+                                serde_json::json!({ "status": status }).to_string(),
+                            ))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        let apply = || {
+            Request::builder()
+                .method("POST")
+                .uri("/pending-updates/test-host/tests-project/web/apply")
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Commands for a host no agent ever registered are refused.
+        let (status, _) = internal_json(apply()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let stream = connect_agent(&agent, "agent-1").await;
+        assert_eq!(stream.status(), StatusCode::OK);
+        // A second agent can't take over a host another agent manages.
+        let rival = connect_agent(&agent, "agent-2").await;
+        assert_eq!(rival.status(), StatusCode::CONFLICT);
+
+        let (status, body) = internal_json(
+            Request::builder()
+                .uri("/agents")
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let agents = body.unwrap();
+        assert_eq!(agents.as_array().unwrap().len(), 1);
+        assert_eq!(agents[0]["agent_id"], "agent-1");
+        assert_eq!(agents[0]["version"], "1.1.0");
+
+        let (status, body) = internal_json(apply()).await;
+        assert_eq!(status, StatusCode::OK);
+        let command = body.unwrap()["data"].clone();
+        assert_eq!(command["kind"], "apply_update");
+        assert_eq!(command["agent_id"], "agent-1");
+        assert_eq!(command["hostname"], "test-host");
+        assert_eq!(command["status"], "sent");
+        let id = command["id"].as_str().unwrap().to_string();
+
+        assert_eq!(report(id.clone(), "received").await, StatusCode::OK);
+        assert_eq!(report(id.clone(), "rolled_back").await, StatusCode::OK);
+        // The outcome is final, and only the controller times commands out.
+        assert_eq!(report(id.clone(), "failed").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            report(id.clone(), "timed_out").await,
+            StatusCode::BAD_REQUEST
+        );
+        let unknown = "00000000-0000-0000-0000-000000000000".to_string();
+        assert_eq!(
+            report(unknown.clone(), "received").await,
+            StatusCode::NOT_FOUND
+        );
+
+        let (status, body) = internal_json(
+            Request::builder()
+                .uri(format!("/commands/{id}"))
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["data"]["status"], "rolled_back");
+
+        let (status, body) = internal_json(
+            Request::builder()
+                .uri("/commands")
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["data"].as_array().unwrap().len(), 1);
+
+        let (status, _) = internal_json(
+            Request::builder()
+                .uri(format!("/commands/{unknown}"))
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Once the agent disconnects, its commands wait for it to come back.
+        drop(stream);
+        let (status, body) = internal_json(apply()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["data"]["agent_id"], "agent-1");
+    }
+
+    #[tokio::test]
+    async fn test_commands_for_an_offline_agent_arrive_on_reconnect() {
+        use futures_util::StreamExt;
+
+        let (agent, internal, _db) = setup_test_app().await;
+        let apply = |hostname: &str| {
+            let internal = internal.clone();
+            let uri = format!("/pending-updates/{hostname}/tests-project/web/apply");
+            async move {
+                let response = internal
+                    .oneshot(
+                        Request::builder()
+                            .method("POST")
+                            .uri(uri)
+                            .header("X-User-Id", TEST_USER)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let id = serde_json::from_slice::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|body| body["data"]["id"].as_str().map(str::to_string));
+                (status, id)
+            }
+        };
+        let next_event = async |stream: axum::response::Response| {
+            let mut events = stream.into_body().into_data_stream();
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+                .await
+                .expect("event in time")
+                .expect("stream open")
+                .unwrap();
+            String::from_utf8(chunk.to_vec()).unwrap()
+        };
+
+        let stream = connect_agent(&agent, "agent-1").await;
+        let (status, seen) = apply("test-host").await;
+        assert_eq!(status, StatusCode::OK);
+        let seen = seen.unwrap();
+        assert!(next_event(stream).await.contains(&format!("id: {seen}")));
+
+        // The stream was dropped with the event: the agent is offline now.
+        let (status, queued) = apply("test-host").await;
+        assert_eq!(status, StatusCode::OK);
+        let queued = queued.unwrap();
+        let (status, _) = apply("other-host").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let stream = reconnect_agent(&agent, "agent-1", Some(&seen)).await;
+        assert_eq!(stream.status(), StatusCode::OK);
+        let event = next_event(stream).await;
+        assert!(event.contains(&format!("id: {queued}")), "{event}");
+        assert!(!event.contains(&seen), "{event}");
+    }
+
+    #[tokio::test]
+    async fn test_queued_commands_replay_after_cursor() {
+        use controller::domain::commands::models::{Command, CommandStatus, ControllerEvent};
+        use controller::domain::commands::ports::{CommandRepository as _, CommandService as _};
+
+        let (_agent, _internal, db_path) = setup_test_app().await;
+        let db = connect_db(&db_path).await;
+        let service = CommandService::new(db.clone());
+        let event = ControllerEvent::ApplyUpdate((
+            HostName::new("test-host"),
+            ProjectName::new("tests-project"),
+            ServiceName::new("web"),
+        ));
+
+        let agent_id = AgentId::new("agent-1");
+        let mut commands: Vec<Command> = (0..3)
+            .map(|_| Command::new(TEST_USER.to_string(), agent_id.clone(), event.clone()))
+            .collect();
+        for (age, command) in (1..=3).rev().zip(commands.iter_mut()) {
+            command.created_at -= chrono::Duration::seconds(age);
+            command.updated_at = command.created_at;
+            db.create_command(command).await.unwrap();
+        }
+        service
+            .set_command_status(TEST_USER, commands[1].id, CommandStatus::Received, None)
+            .await
+            .unwrap();
+
+        let ids = |pending: Vec<Command>| pending.into_iter().map(|c| c.id).collect::<Vec<_>>();
+        // A fresh connection gets everything still unacknowledged, oldest first.
+        let pending = service
+            .pending_commands(TEST_USER, &agent_id, None)
+            .await
+            .unwrap();
+        assert_eq!(ids(pending), vec![commands[0].id, commands[2].id]);
+        // A reconnecting agent only gets what came after the last command it saw.
+        let pending = service
+            .pending_commands(TEST_USER, &agent_id, Some(commands[0].id))
+            .await
+            .unwrap();
+        assert_eq!(ids(pending), vec![commands[2].id]);
+        // Commands queued in the same instant still replay in order.
+        let now = chrono::Utc::now();
+        let same_instant: Vec<Command> = (0..3)
+            .map(|_| {
+                let mut command =
+                    Command::new(TEST_USER.to_string(), agent_id.clone(), event.clone());
+                command.created_at = now;
+                command.updated_at = now;
+                command
+            })
+            .collect();
+        for command in &same_instant {
+            db.create_command(command).await.unwrap();
+        }
+        let pending = service
+            .pending_commands(TEST_USER, &agent_id, Some(same_instant[0].id))
+            .await
+            .unwrap();
+        assert_eq!(ids(pending), vec![same_instant[1].id, same_instant[2].id]);
+        // Other agents' and other tenants' queues are separate.
+        for (user_id, agent_id) in [
+            (TEST_USER, AgentId::new("agent-2")),
+            ("someone-else", agent_id),
+        ] {
+            assert!(
+                service
+                    .pending_commands(user_id, &agent_id, None)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overdue_commands_time_out() {
+        use controller::domain::commands::models::{Command, CommandStatus, ControllerEvent};
+        use controller::domain::commands::ports::{CommandRepository as _, CommandService as _};
+
+        let (_agent, _internal, db_path) = setup_test_app().await;
+        let db = connect_db(&db_path).await;
+        let service = CommandService::new(db.clone());
+        let event = ControllerEvent::RequestLogs((
+            HostName::new("test-host"),
+            ProjectName::new("tests-project"),
+            ServiceName::new("web"),
+        ));
+
+        let agent_id = AgentId::new("agent-1");
+        let fresh = Command::new(TEST_USER.to_string(), agent_id.clone(), event.clone());
+        let mut lost = Command::new(TEST_USER.to_string(), agent_id.clone(), event.clone());
+        lost.created_at -= chrono::Duration::minutes(5);
+        lost.updated_at = lost.created_at;
+        lost.expires_at = lost.created_at + chrono::Duration::minutes(2);
+        let mut stalled = Command::new(TEST_USER.to_string(), agent_id, event);
+        stalled.status = CommandStatus::Running;
+        stalled.created_at -= chrono::Duration::hours(2);
+        stalled.updated_at = stalled.created_at;
+        stalled.expires_at = stalled.created_at + chrono::Duration::minutes(2);
+        for command in [&fresh, &lost, &stalled] {
+            db.create_command(command).await.unwrap();
+        }
+
+        let status = |id| {
+            let service = service.clone();
+            async move {
+                service
+                    .get_command(TEST_USER, id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .status
+            }
+        };
+        assert_eq!(status(fresh.id).await, CommandStatus::Sent);
+        assert_eq!(status(lost.id).await, CommandStatus::TimedOut);
+        assert_eq!(status(stalled.id).await, CommandStatus::TimedOut);
+
+        // A late report still wins over the timeout.
+        assert!(
+            service
+                .set_command_status(TEST_USER, lost.id, CommandStatus::Succeeded, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(status(lost.id).await, CommandStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_live_log_stream_until_cancelled() {
+        use futures_util::StreamExt;
+
+        let (agent, internal, _db) = setup_test_app().await;
+        let send_chunk = |id: String, logs: &'static str| {
+            let agent = agent.clone();
+            async move {
+                agent
+                    .oneshot(
+                        Request::builder()
+                            .method("POST")
+                            .uri(format!("/log-streams/{id}"))
+                            .header("Authorization", "Bearer tests-secret")
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::json!({ "logs": logs }).to_string()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        let log_stream = |method: &str, id: &str| {
+            Request::builder()
+                .method(method)
+                .uri(format!("/log-streams/{id}"))
+                .header("X-User-Id", TEST_USER)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let _agent_stream = connect_agent(&agent, "agent-1").await;
+        let response = internal
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/container/logs/test-host/tests-project/web/stream")
+                    .header("X-User-Id", TEST_USER)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let command: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(command["data"]["kind"], "stream_logs");
+        let id = command["data"]["id"].as_str().unwrap().to_string();
+
+        let viewer = internal
+            .clone()
+            .oneshot(log_stream("GET", &id))
+            .await
+            .unwrap();
+        assert_eq!(viewer.status(), StatusCode::OK);
+        let mut events = viewer.into_body().into_data_stream();
+        let mut next_event = async || {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+                .await
+                .expect("event in time")
+                .expect("stream open")
+                .unwrap();
+            String::from_utf8(chunk.to_vec()).unwrap()
+        };
+
+        assert_eq!(send_chunk(id.clone(), "line one\n").await, StatusCode::OK);
+        let event = next_event().await;
+        assert!(event.contains("event: logs"), "{event}");
+        assert!(event.contains(r#"data: "line one\n""#), "{event}");
+
+        // Other tenants can't watch or cancel it.
+        let response = internal
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/log-streams/{id}"))
+                    .header("X-User-Id", "someone-else")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = internal
+            .clone()
+            .oneshot(log_stream("DELETE", &id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(next_event().await.contains("event: end"));
+        // The agent's next chunk tells it to stop.
+        assert_eq!(send_chunk(id.clone(), "").await, StatusCode::GONE);
+        let response = internal.oneshot(log_stream("GET", &id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -22,6 +22,7 @@ export default defineConfig({
 						{ label: 'Operating modes', slug: 'guides/operating-modes' },
 						{ label: 'Metrics & log forwarding', slug: 'guides/monitoring' },
 						{ label: 'Manual rollout', slug: 'guides/manual-rollout' },
+						{ label: 'Registry webhooks', slug: 'guides/webhooks' },
 						{ label: 'Notifications', slug: 'guides/notifications' },
 						{ label: 'Registries', slug: 'guides/registries' },
 						{ label: 'Dashboard', slug: 'guides/frontend' },
@@ -3,4 +3,14 @@ import type { HostName } from "./HostName";
 import type { ProjectName } from "./ProjectName";
 import type { ServiceName } from "./ServiceName";
 
-export type ContainerStateResponse = { hostname: HostName, project_name: ProjectName, service_name: ServiceName, container_inspections: any, last_logs: string | null, last_updated: string, };
+export type ContainerStateResponse = { hostname: HostName, project_name: ProjectName, service_name: ServiceName, 
+/**
+ * Routed through `serde_json::Value` (BTreeMap-backed by default) so
+ * nested maps — Labels, ExposedPorts, Networks, etc. — serialize in a
+ * stable alphabetical order rather than HashMap's arbitrary one.
+ */
+container_inspections: any, last_logs: string | null, 
+/**
+ * Update-check schedule the agent reported for the service.
+ */
+schedule: string | null, last_updated: string, };
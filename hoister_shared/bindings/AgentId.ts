@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Identifies one agent to the controller, stable across its restarts. Unlike
+ * a hostname it tells apart two agents on hosts of the same name.
+ */
+export type AgentId = string;
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Where a command stands. The agent reports everything but `Sent` and
+ * `TimedOut`, which the controller sets itself.
+ */
+export type CommandStatus = "sent" | "received" | "running" | "succeeded" | "rolled_back" | "failed" | "timed_out";
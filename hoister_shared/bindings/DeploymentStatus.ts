@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type DeploymentStatus = "Pending" | "Started" | "Success" | "RollbackFinished" | "NoUpdate" | "Failed" | "TestMessage" | "UpdateAvailable";
+export type DeploymentStatus = "Pending" | "Started" | "Success" | "RollbackFinished" | "NoUpdate" | "Failed" | "TestMessage" | "UpdateAvailable" | "Restarted" | "RestartFailed";
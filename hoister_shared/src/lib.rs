@@ -4,6 +4,7 @@ use sqlx::Type;
 use std::fmt::{Display, Formatter};
 use ts_rs::TS;
 
+pub mod merge_patch;
 pub mod wire;
 
 pub type ContainerID = String;
@@ -88,6 +89,22 @@ impl Default for HostName {
     }
 }
 
+/// Identifies one agent to the controller, stable across its restarts. Unlike
+/// a hostname it tells apart two agents on hosts of the same name.
+#[derive(TS, Deserialize, Serialize, Debug, Clone, Hash, Eq, PartialEq)]
+#[ts(export)]
+pub struct AgentId(pub String);
+
+impl AgentId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 #[derive(TS, Deserialize, Debug, Clone, Serialize, Type)]
 #[ts(export)]
 #[repr(u8)]
@@ -100,6 +117,11 @@ pub enum DeploymentStatus {
     Failed = 5,
     TestMessage = 6,
     UpdateAvailable = 7,
+    /// A `hoister.restart-schedule` restart came back healthy.
+    Restarted = 8,
+    /// A scheduled restart left the container unhealthy. There is no previous
+    /// version to roll back to.
+    RestartFailed = 9,
 }
 
 impl Display for DeploymentStatus {
@@ -113,6 +135,8 @@ impl Display for DeploymentStatus {
             &DeploymentStatus::Failed => write!(f, "Deployment Failed ❌"),
             &DeploymentStatus::TestMessage => write!(f, "Test Message"),
             &DeploymentStatus::UpdateAvailable => write!(f, "Update Available"),
+            &DeploymentStatus::Restarted => write!(f, "Scheduled restart 🔄"),
+            &DeploymentStatus::RestartFailed => write!(f, "Scheduled restart failed ❌"),
         }
     }
 }
@@ -132,6 +156,11 @@ pub struct CreateDeployment {
     /// agents that don't send this field wire-compatible.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub logs: Option<String>,
+    /// Set by the agent's outbound queue, which retries a report until the
+    /// controller takes it; the controller records each event ID only once.
+    /// `None` from agents that send without the queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<uuid::Uuid>,
 }
 
 impl Display for CreateDeployment {
@@ -150,6 +179,7 @@ impl CreateDeployment {
             status: DeploymentStatus::TestMessage,
             hostname: HostName::default(),
             logs: None,
+            event_id: None,
         }
     }
 
@@ -244,6 +274,7 @@ mod tests {
             status,
             hostname: HostName::new(host),
             logs: None,
+            event_id: None,
         }
     }
 
@@ -296,6 +327,7 @@ mod tests {
             status: DeploymentStatus::Success,
             hostname: HostName::new("web-01"),
             logs: None,
+            event_id: None,
         };
 
         // No base URL -> no link, body unchanged.
@@ -0,0 +1,121 @@
+//! JSON Merge Patch (RFC 7386), used to send container state as changes
+//! against the state the controller already has.
+//!
+//! A merge patch can't set a value to `null`, since `null` removes the key.
+//! State goes through [`strip_nulls`] before it is diffed, which for
+//! `Option` fields is the same as serde's `skip_serializing_if`.
+
+use serde_json::{Map, Value};
+
+/// The patch that turns `from` into `to`. Both must be free of `null`s.
+pub fn diff(from: &Value, to: &Value) -> Value {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            let mut patch = Map::new();
+            for (key, old) in from {
+                if !to.contains_key(key) {
+                    patch.insert(key.clone(), Value::Null);
+                } else if let Some(new) = to.get(key)
+                    && new != old
+                {
+                    patch.insert(key.clone(), diff(old, new));
+                }
+            }
+            for (key, new) in to {
+                if !from.contains_key(key) {
+                    patch.insert(key.clone(), new.clone());
+                }
+            }
+            Value::Object(patch)
+        }
+        _ => to.clone(),
+    }
+}
+
+/// Apply `patch` to `target`.
+pub fn apply(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().expect("target was made an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            apply(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Remove the `null` members of every object in `value`.
+pub fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip(from: Value, to: Value) -> Value {
+        let patch = diff(&from, &to);
+        let mut patched = from;
+        apply(&mut patched, &patch);
+        assert_eq!(patched, to);
+        patch
+    }
+
+    #[test]
+    fn a_patch_carries_only_what_changed() {
+        let from = json!({
+            "web": { "inspect": { "RestartCount": 0, "Image": "sha256:a" } },
+            "db": { "inspect": { "RestartCount": 1 } },
+        });
+        let to = json!({
+            "web": { "inspect": { "RestartCount": 1, "Image": "sha256:a" } },
+            "db": { "inspect": { "RestartCount": 1 } },
+        });
+        assert_eq!(
+            roundtrip(from, to),
+            json!({ "web": { "inspect": { "RestartCount": 1 } } })
+        );
+    }
+
+    #[test]
+    fn removed_and_added_keys() {
+        let patch = roundtrip(
+            json!({ "web": { "a": 1 }, "old": { "b": 2 } }),
+            json!({ "web": { "a": 1 }, "new": { "c": [1, 2] } }),
+        );
+        assert_eq!(patch, json!({ "old": null, "new": { "c": [1, 2] } }));
+
+        // Arrays and scalars are replaced whole, also when the type changes.
+        roundtrip(json!({ "a": [1, 2, 3] }), json!({ "a": [3] }));
+        roundtrip(json!({ "a": { "b": 1 } }), json!({ "a": "b" }));
+        roundtrip(json!({ "a": "b" }), json!({ "a": { "b": 1 } }));
+    }
+
+    #[test]
+    fn unchanged_state_is_an_empty_patch() {
+        let state = json!({ "web": { "inspect": { "Id": "abc" } } });
+        assert_eq!(roundtrip(state.clone(), state), json!({}));
+    }
+
+    #[test]
+    fn strip_nulls_reaches_into_arrays() {
+        let mut value = json!({ "a": null, "b": [{ "c": null, "d": 1 }] });
+        strip_nulls(&mut value);
+        assert_eq!(value, json!({ "b": [{ "d": 1 }] }));
+    }
+}
@@ -2,10 +2,12 @@
 //! controller. Live here so the agent can depend on these without linking
 //! the controller crate.
 
-use crate::{ContainerID, HostName, ProjectName, ServiceName};
+use crate::{AgentId, ContainerID, HostName, ImageDigest, ImageName, ProjectName, ServiceName};
 use bollard::models::ContainerInspectResponse;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use ts_rs::TS;
+use uuid::Uuid;
 
 /// One service's worth of state shipped from the agent: the inspect payload
 /// plus an optional tail of recent container logs. Logs are only attached
@@ -16,6 +18,10 @@ pub struct ServiceState {
     pub inspect: ContainerInspectResponse,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub last_logs: Option<String>,
+    /// The update-check schedule in effect for the service, e.g.
+    /// `every 5m (default)` or `cron 0 0 3 * * Sun *`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
 }
 
 /// Body of POST /container/state/{hostname}/{project_name}.
@@ -23,6 +29,21 @@ pub struct ServiceState {
 pub struct PostContainerStateRequest {
     pub project_name: ProjectName,
     pub payload: HashMap<ServiceName, ServiceState>,
+    /// Identifies this state for later deltas against it. Older agents don't
+    /// send deltas, nor this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+/// Body of POST /container/state/{hostname}/{project_name}/delta: the state
+/// as a JSON Merge Patch against the `payload` of version `base_version`, see
+/// `merge_patch.rs`. The controller answers 409 Conflict if it doesn't have
+/// that version, and the agent sends the full state instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostContainerStateDelta {
+    pub base_version: u64,
+    pub version: u64,
+    pub patch: serde_json::Value,
 }
 
 /// A single resource-usage sample for one service, captured by the agent
@@ -69,13 +90,195 @@ pub struct PostContainerLogsRequest {
     pub logs: String,
 }
 
-/// SSE events the controller broadcasts to subscribed agents.
+/// How long a live log stream (`ControllerEvent::StreamLogs`) runs at most.
+/// The agent stops following the logs then, and the controller stops
+/// accepting its chunks shortly after.
+pub const LOG_STREAM_LIMIT_SECS: u64 = 600;
+
+/// Body of POST /log-streams/{id}: the redacted log lines the agent read since
+/// its previous chunk, for the live stream started by command `id`. The agent
+/// also sends empty chunks while the container is quiet, and learns from a
+/// `410 Gone` answer that the stream was cancelled.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PostLogStreamChunk {
+    pub logs: String,
+    /// Set on the last chunk, after which the agent stops following the logs.
+    #[serde(default)]
+    pub end: bool,
+}
+
+/// Body of POST /pending-updates: an update found while `auto_update` is off.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostPendingUpdate {
+    pub hostname: HostName,
+    pub project_name: ProjectName,
+    pub service_name: ServiceName,
+    pub image_name: ImageName,
+    pub new_digest: ImageDigest,
+    /// Set by the agent's outbound queue, see `CreateDeployment::event_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<Uuid>,
+}
+
+/// Body of POST /cleanup/{hostname}/{project_name}: what one sweep of the
+/// agent's janitor removed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PostCleanupReport {
+    /// Backup volumes left behind by interrupted updates.
+    pub volumes: u32,
+    /// Old containers of updates that never finished cleaning up.
+    pub containers: u32,
+    /// Replaced images whose removal failed at the time.
+    pub images: u32,
+    /// Disk space freed, as far as Docker reports it.
+    pub reclaimed_bytes: u64,
+}
+
+/// SSE events the controller sends to the agent managing the host they name.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ControllerEvent {
-    Retry((ProjectName, ContainerID)),
+    Retry((HostName, ProjectName, ContainerID)),
     ApplyUpdate((HostName, ProjectName, ServiceName)),
     /// On-demand log request: ask the agent on `HostName` to ship the current
     /// log tail for one service. Honoured only when that agent was started with
     /// `HOISTER_REPORT_LOGS=true`; otherwise the agent ignores it.
     RequestLogs((HostName, ProjectName, ServiceName)),
+    /// Live log request: follow one service's logs and send them as
+    /// `PostLogStreamChunk`s under the command's ID until the stream is
+    /// cancelled or `LOG_STREAM_LIMIT_SECS` pass. Gated on `report_logs` like
+    /// `RequestLogs`.
+    StreamLogs((HostName, ProjectName, ServiceName)),
+}
+
+/// A `ControllerEvent` as sent over SSE, under the ID the agent reports its
+/// progress with (see `PostCommandStatus`).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Command {
+    pub id: Uuid,
+    pub event: ControllerEvent,
+}
+
+/// Where a command stands. The agent reports everything but `Sent` and
+/// `TimedOut`, which the controller sets itself.
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum CommandStatus {
+    /// Stored and broadcast; no agent has acknowledged it yet.
+    Sent,
+    /// The agent addressed by the command received it.
+    Received,
+    /// The agent is carrying the command out.
+    Running,
+    Succeeded,
+    /// The update was rolled out but failed its health check, so the previous
+    /// container runs again.
+    RolledBack,
+    Failed,
+    /// No acknowledgement or result arrived in time. A late report from the
+    /// agent still replaces this.
+    TimedOut,
+}
+
+impl CommandStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandStatus::Sent => "sent",
+            CommandStatus::Received => "received",
+            CommandStatus::Running => "running",
+            CommandStatus::Succeeded => "succeeded",
+            CommandStatus::RolledBack => "rolled_back",
+            CommandStatus::Failed => "failed",
+            CommandStatus::TimedOut => "timed_out",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "sent" => CommandStatus::Sent,
+            "received" => CommandStatus::Received,
+            "running" => CommandStatus::Running,
+            "succeeded" => CommandStatus::Succeeded,
+            "rolled_back" => CommandStatus::RolledBack,
+            "failed" => CommandStatus::Failed,
+            "timed_out" => CommandStatus::TimedOut,
+            _ => return None,
+        })
+    }
+
+    /// Whether the agent is done with the command.
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            CommandStatus::Succeeded | CommandStatus::RolledBack | CommandStatus::Failed
+        )
+    }
+}
+
+/// Body of POST /commands/{id}: the agent's progress on a command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostCommandStatus {
+    pub status: CommandStatus,
+    /// Why a command failed, or what a successful one amounted to, e.g. "no
+    /// newer image".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// One engine an agent manages: the host its containers are reported under
+/// and their Compose project.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AgentEngine {
+    pub hostname: HostName,
+    pub project_name: ProjectName,
+}
+
+/// Body of POST /sse: who is connecting. The controller sends each command
+/// only to the agent that registered the command's host and project.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentRegistration {
+    pub agent_id: AgentId,
+    /// The agent's release, e.g. `1.1.0`.
+    pub version: String,
+    pub engines: Vec<AgentEngine>,
+}
+
+/// Body of POST /agent-info/{hostname}: what the agent managing the host runs
+/// and which of its optional features are on. Sent when the agent starts and
+/// whenever it connects for commands.
+#[derive(TS, Clone, Debug, Serialize, Deserialize)]
+#[ts(export)]
+pub struct PostAgentInfo {
+    pub agent_id: AgentId,
+    /// The agent's release, e.g. `1.1.0`.
+    pub version: String,
+    /// The agent's platform as Rust names it, e.g. `linux` and `x86_64`.
+    pub os: String,
+    pub arch: String,
+    /// The global update-check schedule, e.g. `every 5m` or `cron 0 0 3 * * Sun *`.
+    pub schedule: Option<String>,
+    pub features: AgentFeatures,
+    pub engine: EngineInfo,
+}
+
+/// The agent's opt-in behaviour. A dashboard hides what the agent would ignore,
+/// e.g. log requests without `report_logs`.
+#[derive(TS, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[ts(export)]
+pub struct AgentFeatures {
+    pub auto_update: bool,
+    pub report_logs: bool,
+    pub report_metrics: bool,
+}
+
+/// The container engine of the host, as it describes itself.
+#[derive(TS, Clone, Debug, Default, Serialize, Deserialize)]
+#[ts(export)]
+pub struct EngineInfo {
+    /// The engine's release, e.g. `27.3.1`.
+    pub version: Option<String>,
+    /// The host's operating system, e.g. `Debian GNU/Linux 12 (bookworm)`.
+    pub os: Option<String>,
+    pub kernel_version: Option<String>,
+    pub arch: Option<String>,
 }